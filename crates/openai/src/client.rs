@@ -1,16 +1,16 @@
 use crate::auth::{self};
 use crate::context::args::Args;
-use crate::dns::{self, TrustDnsResolver};
+use crate::dns::{self, CountingResolver, TrustDnsResolver};
 use crate::{
     auth::AuthClient,
     proxy::{self, Ipv6CidrExt},
 };
 use moka::sync::Cache;
 use reqwest::{impersonate::Impersonate, Client};
-use std::sync::{Arc, OnceLock};
+use std::sync::{Arc, OnceLock, PoisonError, RwLock};
 use std::{
     net::IpAddr,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
     time::Duration,
 };
 use trust_dns_resolver::config::LookupIpStrategy;
@@ -71,6 +71,17 @@ impl LookupIpStrategyExt {
 
 static DNS_RESOLVER: OnceLock<Cache<LookupIpStrategyExt, Arc<TrustDnsResolver>>> = OnceLock::new();
 
+/// Connections opened by the API clients, counted by their host name lookups
+pub(crate) static UPSTREAM_CONNECTIONS_OPENED: AtomicU64 = AtomicU64::new(0);
+/// Upstream requests assigned an API client
+pub(crate) static UPSTREAM_REQUESTS: AtomicU64 = AtomicU64::new(0);
+/// Upstream requests in flight on the API clients with a streams per connection limit
+pub(crate) static UPSTREAM_STREAMS_INFLIGHT: AtomicUsize = AtomicUsize::new(0);
+/// API clients opened on top of the pool by the streams per connection limit
+pub(crate) static UPSTREAM_OVERFLOW_CLIENTS: AtomicUsize = AtomicUsize::new(0);
+/// Configured streams per connection limit of the API clients, 0 is unlimited
+pub(crate) static UPSTREAM_MAX_STREAMS_PER_CONN: AtomicUsize = AtomicUsize::new(0);
+
 struct Config {
     /// Use fastest DNS resolver
     fastest_dns: bool,
@@ -84,6 +95,18 @@ struct Config {
     pool_idle_timeout: u64,
     /// TCP keepalive interval.
     tcp_keepalive: u64,
+    /// Negotiate HTTP/2 with the upstream.
+    http2: bool,
+    /// HTTP/2 keepalive ping interval, 0 disables it.
+    http2_keepalive_interval: u64,
+    /// HTTP/2 initial stream window size.
+    http2_stream_window: Option<u32>,
+    /// HTTP/2 initial connection window size.
+    http2_connection_window: Option<u32>,
+    /// HTTP/2 adaptive flow control window.
+    http2_adaptive_window: bool,
+    /// Counter of the opened connections.
+    connections: Option<&'static AtomicU64>,
     /// Random User-Agent
     impersonate_uas: Option<Vec<Impersonate>>,
     /// Interfaces to bind to.
@@ -118,6 +141,69 @@ impl Config {
 pub struct ClientRoundRobinBalancer {
     config: Config,
     pool: (AtomicUsize, Vec<ClientAgent>),
+    /// Clients of each pool entry when the streams per connection are limited
+    coalesce: Option<Coalesce>,
+}
+
+/// Clients opened on top of a pool entry at most, the requests over share the least
+/// busy client beyond the streams limit
+const MAX_OVERFLOW_CLIENTS: usize = 32;
+/// Idle time after which a client opened on top of a pool entry is dropped
+const OVERFLOW_IDLE: Duration = Duration::from_secs(60);
+
+/// Streams per connection limit (`upstream_http2_max_streams_per_conn`). A pool entry
+/// multiplexes its requests to a host over one HTTP/2 connection, a request that finds
+/// every client of its entry at the limit opens a client, and so a connection, of its own.
+struct Coalesce {
+    max_streams: usize,
+    entries: Vec<CoalesceEntry>,
+}
+
+/// Clients of a pool entry, sharing its bind address and proxy
+struct CoalesceEntry {
+    bind: Option<IpAddr>,
+    proxy: Option<Url>,
+    /// Clients and their requests in flight, the pool client first
+    clients: RwLock<Vec<(ClientAgent, Arc<Streams>)>>,
+}
+
+/// Requests in flight on a client
+#[derive(Default)]
+struct Streams {
+    open: AtomicUsize,
+    /// When the last request ended, unix seconds
+    released: AtomicU64,
+}
+
+impl Streams {
+    fn idle(&self, now: u64) -> bool {
+        self.open.load(Ordering::Acquire) == 0
+            && now.saturating_sub(self.released.load(Ordering::Relaxed)) >= OVERFLOW_IDLE.as_secs()
+    }
+}
+
+/// Slot of an upstream request on its client, released when dropped
+pub struct UpstreamStream {
+    streams: Arc<Streams>,
+}
+
+impl UpstreamStream {
+    fn new(streams: Arc<Streams>) -> Self {
+        UPSTREAM_STREAMS_INFLIGHT.fetch_add(1, Ordering::Relaxed);
+        Self { streams }
+    }
+}
+
+impl Drop for UpstreamStream {
+    fn drop(&mut self) {
+        self.streams.released.store(now_secs(), Ordering::Relaxed);
+        self.streams.open.fetch_sub(1, Ordering::Release);
+        UPSTREAM_STREAMS_INFLIGHT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn now_secs() -> u64 {
+    crate::now_duration().map_or(0, |now| now.as_secs())
 }
 
 impl ClientRoundRobinBalancer {
@@ -132,7 +218,18 @@ impl ClientRoundRobinBalancer {
                 _ => None,
             })
             .collect();
-        Self::new_client_generic(args, ClientAgent::Api, p, build_client)
+        let balancer = Self::new_client_generic(
+            args,
+            ClientAgent::Api,
+            p,
+            build_client,
+            Some(&UPSTREAM_CONNECTIONS_OPENED),
+            args.upstream_http2_max_streams_per_conn,
+        )?;
+        if let Some(coalesce) = balancer.coalesce.as_ref() {
+            UPSTREAM_MAX_STREAMS_PER_CONN.store(coalesce.max_streams, Ordering::Relaxed);
+        }
+        Ok(balancer)
     }
 
     pub fn new_auth_client(args: &Args) -> anyhow::Result<Self> {
@@ -146,7 +243,7 @@ impl ClientRoundRobinBalancer {
                 _ => None,
             })
             .collect();
-        Self::new_client_generic(args, ClientAgent::Auth, p, build_auth_client)
+        Self::new_client_generic(args, ClientAgent::Auth, p, build_auth_client, None, 0)
     }
 
     pub fn new_arkose_client(args: &Args) -> anyhow::Result<Self> {
@@ -160,7 +257,7 @@ impl ClientRoundRobinBalancer {
                 _ => None,
             })
             .collect();
-        Self::new_client_generic(args, ClientAgent::Arkose, p, build_client)
+        Self::new_client_generic(args, ClientAgent::Arkose, p, build_client, None, 0)
     }
 
     fn new_client_generic<F, T>(
@@ -168,6 +265,8 @@ impl ClientRoundRobinBalancer {
         client_type: fn(T) -> ClientAgent,
         proxy: Vec<proxy::InnerProxy>,
         build_fn: F,
+        connections: Option<&'static AtomicU64>,
+        max_streams_per_conn: usize,
     ) -> anyhow::Result<Self>
     where
         F: Fn(&Config, Option<IpAddr>, Option<IpAddr>, Option<Url>, bool) -> T,
@@ -193,18 +292,26 @@ impl ClientRoundRobinBalancer {
             connect_timeout: args.connect_timeout as u64,
             pool_idle_timeout: args.pool_idle_timeout as u64,
             tcp_keepalive: args.tcp_keepalive as u64,
+            http2: args.upstream_http2,
+            http2_keepalive_interval: args.upstream_http2_keepalive_interval as u64,
+            http2_stream_window: args.upstream_http2_stream_window,
+            http2_connection_window: args.upstream_http2_connection_window,
+            http2_adaptive_window: args.upstream_http2_adaptive_window,
+            connections,
             interfaces: (AtomicUsize::new(0), interfaces),
             ipv6_subnets: (AtomicUsize::new(0), ipv6_subnets),
             impersonate_uas: args.impersonate_uas.clone(),
         };
 
-        // init client pool
+        // init client pool, along with the bind address and proxy of each client
         let mut pool = Vec::with_capacity(proxies.len() + 1);
+        let mut targets = Vec::with_capacity(proxies.len() + 1);
 
         // Helper function to join client to the pool
         let mut join_client = |bind: Option<IpAddr>, proxy: Option<Url>| {
-            let client = build_fn(&config, bind, None, proxy, args.no_keepalive);
+            let client = build_fn(&config, bind, None, proxy.clone(), args.no_keepalive);
             pool.push(client_type(client));
+            targets.push((bind, proxy));
         };
 
         // Join direct connection clients to pool
@@ -237,11 +344,32 @@ impl ClientRoundRobinBalancer {
                 None,
                 args.no_keepalive,
             )));
+            targets.push((None, None));
         }
 
+        // Streams per connection limit, the pooled HTTP/2 connections are required and
+        // the IPv6 subnets already build a client per request
+        let coalesce = (max_streams_per_conn > 0
+            && config.http2
+            && !args.no_keepalive
+            && config.ipv6_subnets.1.is_empty())
+        .then(|| Coalesce {
+            max_streams: max_streams_per_conn,
+            entries: pool
+                .iter()
+                .zip(targets)
+                .map(|(client, (bind, proxy))| CoalesceEntry {
+                    bind,
+                    proxy,
+                    clients: RwLock::new(vec![(client.clone(), Arc::default())]),
+                })
+                .collect(),
+        });
+
         Ok(Self {
             config,
             pool: (AtomicUsize::new(0), pool),
+            coalesce,
         })
     }
 }
@@ -291,6 +419,103 @@ impl ClientRoundRobinBalancer {
         let new = get_next_index(self.pool.1.len(), &self.pool.0);
         self.pool.1[new].clone()
     }
+
+    /// Get next client of an upstream request, with its stream slot when the streams
+    /// per connection are limited. The slot must be kept until the response is read.
+    pub fn next_stream(&self) -> (ClientAgent, Option<UpstreamStream>) {
+        UPSTREAM_REQUESTS.fetch_add(1, Ordering::Relaxed);
+        let coalesce = match self.coalesce.as_ref() {
+            Some(coalesce) => coalesce,
+            None => return (self.next(), None),
+        };
+        let index = match coalesce.entries.len() {
+            1 => 0,
+            len => get_next_index(len, &self.pool.0),
+        };
+        let (client, stream) = coalesce.entries[index].acquire(&self.config, coalesce.max_streams);
+        (client, Some(stream))
+    }
+}
+
+impl CoalesceEntry {
+    /// First client under the streams limit, a new client when they are all at the limit,
+    /// or the least busy one when `MAX_OVERFLOW_CLIENTS` are open
+    fn acquire(&self, config: &Config, max_streams: usize) -> (ClientAgent, UpstreamStream) {
+        let clients = self.clients.read().unwrap_or_else(PoisonError::into_inner);
+        // The last opened client is the first to go idle
+        let prune = clients.len() > 1 && clients[clients.len() - 1].1.idle(now_secs());
+        if !prune {
+            if let Some(acquired) = take_stream(&clients, max_streams) {
+                return acquired;
+            }
+        }
+        drop(clients);
+
+        let mut clients = self.clients.write().unwrap_or_else(PoisonError::into_inner);
+        prune_idle(&mut clients, now_secs());
+        // Another request may have opened a client meanwhile
+        if let Some(acquired) = take_stream(&clients, max_streams) {
+            return acquired;
+        }
+        if clients.len() > MAX_OVERFLOW_CLIENTS {
+            return share_stream(&clients);
+        }
+        let (bind, proxy) = (self.bind, self.proxy.clone());
+        let client = match &clients[0].0 {
+            ClientAgent::Auth(_) => {
+                ClientAgent::Auth(build_auth_client(config, bind, None, proxy, false))
+            }
+            ClientAgent::Api(_) => ClientAgent::Api(build_client(config, bind, None, proxy, false)),
+            ClientAgent::Arkose(_) => {
+                ClientAgent::Arkose(build_client(config, bind, None, proxy, false))
+            }
+        };
+        let streams = Arc::new(Streams {
+            open: AtomicUsize::new(1),
+            released: AtomicU64::default(),
+        });
+        clients.push((client.clone(), streams.clone()));
+        UPSTREAM_OVERFLOW_CLIENTS.fetch_add(1, Ordering::Relaxed);
+        (client, UpstreamStream::new(streams))
+    }
+}
+
+/// Take a stream slot on the first client under the limit
+fn take_stream(
+    clients: &[(ClientAgent, Arc<Streams>)],
+    max_streams: usize,
+) -> Option<(ClientAgent, UpstreamStream)> {
+    clients.iter().find_map(|(client, streams)| {
+        streams
+            .open
+            .fetch_update(Ordering::AcqRel, Ordering::Relaxed, |open| {
+                (open < max_streams).then_some(open + 1)
+            })
+            .ok()
+            .map(|_| (client.clone(), UpstreamStream::new(streams.clone())))
+    })
+}
+
+/// Take a stream slot on the least busy client, over the limit
+fn share_stream(clients: &[(ClientAgent, Arc<Streams>)]) -> (ClientAgent, UpstreamStream) {
+    let (client, streams) = clients
+        .iter()
+        .min_by_key(|(_, streams)| streams.open.load(Ordering::Relaxed))
+        .expect("the pool client is never pruned");
+    streams.open.fetch_add(1, Ordering::AcqRel);
+    (client.clone(), UpstreamStream::new(streams.clone()))
+}
+
+/// Drop the idle clients opened on top of the pool client, under the write lock so no
+/// request takes a stream on them meanwhile
+fn prune_idle(clients: &mut Vec<(ClientAgent, Arc<Streams>)>, now: u64) {
+    let len = clients.len();
+    let mut index = 0;
+    clients.retain(|(_, streams)| {
+        index += 1;
+        index == 1 || !streams.idle(now)
+    });
+    UPSTREAM_OVERFLOW_CLIENTS.fetch_sub(len - clients.len(), Ordering::Relaxed);
 }
 
 /// Build a client
@@ -344,16 +569,53 @@ fn build_client(
     // init dns resolver
     let trust_dns_resolver = get_or_init_dns_resolver(ip_s, config.fastest_dns);
 
-    builder
-        .impersonate(random_impersonate(config.impersonate_uas.as_ref()))
+    let builder = builder.impersonate(random_impersonate(config.impersonate_uas.as_ref()));
+
+    // HTTP/2 settings, applied after the impersonated client settings
+    let builder = http2_settings(builder, config)
         .danger_accept_invalid_certs(true)
         .permute_extensions(true)
         .enable_ech_grease(true)
         .connect_timeout(Duration::from_secs(config.connect_timeout))
-        .timeout(Duration::from_secs(config.timeout))
-        .dns_resolver(trust_dns_resolver)
-        .build()
-        .expect("Failed to build API client")
+        .timeout(Duration::from_secs(config.timeout));
+
+    // A resolution per opened connection
+    let builder = match config.connections {
+        Some(counter) => {
+            builder.dns_resolver(Arc::new(CountingResolver::new(trust_dns_resolver, counter)))
+        }
+        None => builder.dns_resolver(trust_dns_resolver),
+    };
+    builder.build().expect("Failed to build API client")
+}
+
+/// Upstream HTTP/2 settings. Requests to the same host share one multiplexed
+/// connection per pooled client, HTTP/1.1 is used when the upstream does not
+/// negotiate h2.
+fn http2_settings(mut builder: reqwest::ClientBuilder, config: &Config) -> reqwest::ClientBuilder {
+    if !config.http2 {
+        return builder.http1_only();
+    }
+
+    if config.http2_keepalive_interval > 0 {
+        builder = builder
+            .http2_keep_alive_interval(Duration::from_secs(config.http2_keepalive_interval))
+            .http2_keep_alive_while_idle(true);
+    }
+
+    if config.http2_adaptive_window {
+        return builder.http2_adaptive_window(true);
+    }
+
+    if let Some(window) = config.http2_stream_window {
+        builder = builder.http2_initial_stream_window_size(window);
+    }
+
+    if let Some(window) = config.http2_connection_window {
+        builder = builder.http2_initial_connection_window_size(window);
+    }
+
+    builder
 }
 
 /// Build an authenticated client.
@@ -458,3 +720,63 @@ fn random_impersonate(impersonate_uas: Option<&Vec<Impersonate>>) -> Impersonate
     // otherwise, randomly select one from the default list
     Impersonate::OkHttp4_9
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(clients: &[(ClientAgent, Arc<Streams>)]) -> Vec<usize> {
+        clients
+            .iter()
+            .map(|(_, streams)| streams.open.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    #[test]
+    fn test_take_stream() {
+        let clients = vec![
+            (ClientAgent::Api(Client::new()), Arc::default()),
+            (ClientAgent::Api(Client::new()), Arc::default()),
+        ];
+
+        let first = take_stream(&clients, 2).unwrap();
+        let second = take_stream(&clients, 2).unwrap();
+        assert_eq!(open(&clients), [2, 0]);
+        // The first client is at the limit
+        let third = take_stream(&clients, 2).unwrap();
+        assert_eq!(open(&clients), [2, 1]);
+
+        drop(first);
+        assert_eq!(open(&clients), [1, 1]);
+        let _fourth = take_stream(&clients, 2).unwrap();
+        let _fifth = take_stream(&clients, 2).unwrap();
+        assert!(take_stream(&clients, 2).is_none());
+        drop((second, third));
+    }
+
+    #[test]
+    fn test_share_and_prune() {
+        let mut clients: Vec<(ClientAgent, Arc<Streams>)> = (0..3)
+            .map(|_| (ClientAgent::Api(Client::new()), Arc::default()))
+            .collect();
+        let first = take_stream(&clients, 1).unwrap();
+        let second = take_stream(&clients, 1).unwrap();
+
+        // At the cap, the least busy client takes the request over the limit
+        let third = share_stream(&clients);
+        let fourth = share_stream(&clients);
+        assert_eq!(open(&clients), [2, 1, 1]);
+        drop((third, fourth));
+        assert_eq!(open(&clients), [1, 1, 0]);
+
+        // Only the overflow clients idle long enough are dropped, never the pool client
+        let (now, later) = (now_secs(), now_secs() + 2 * OVERFLOW_IDLE.as_secs());
+        prune_idle(&mut clients, now);
+        assert_eq!(clients.len(), 3);
+        prune_idle(&mut clients, later);
+        assert_eq!(open(&clients), [1, 1]);
+        drop((first, second));
+        prune_idle(&mut clients, later);
+        assert_eq!(open(&clients), [0]);
+    }
+}
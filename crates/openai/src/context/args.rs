@@ -1,8 +1,14 @@
-use crate::{arkose::funcaptcha::solver::ArkoseSolver, proxy};
-use reqwest::impersonate::Impersonate;
+use crate::{arkose::funcaptcha::solver::ArkoseSolver, proxy, secret::Secret};
+use reqwest::{
+    header::{HeaderName, HeaderValue},
+    impersonate::Impersonate,
+};
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::BTreeMap,
     net::{IpAddr, Ipv4Addr, SocketAddr},
     path::PathBuf,
+    str::FromStr,
 };
 use typed_builder::TypedBuilder;
 
@@ -12,14 +18,71 @@ pub struct Args {
     #[builder(setter(into), default = Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 7999)))]
     pub(crate) bind: Option<SocketAddr>,
 
+    /// Try the next N ports when the bind port is in use
+    #[builder(setter(into), default = 0)]
+    pub(crate) port_retry: u16,
+
+    /// Separate listener of the admin endpoints (`/metrics`, `/admin/*`)
+    #[builder(setter(into), default)]
+    pub(crate) admin_listen: Option<AdminListen>,
+
+    /// Background job lease store, `mem` (single instance) or `file` (instances sharing `leader_dir`)
+    #[builder(setter(into), default = "mem".to_owned())]
+    pub(crate) leader_store: String,
+
+    /// Background job lease directory of the `file` store
+    #[builder(setter(into), default)]
+    pub(crate) leader_dir: Option<PathBuf>,
+
+    /// Background job lease time (seconds), a job is taken over this long after its leader died
+    #[builder(setter(into), default = 30)]
+    pub(crate) leader_lease_ttl: u64,
+
+    /// Request header naming the priority class (high/normal/low), trusted from `priority_trusted_peers` only
+    #[builder(setter(into), default)]
+    pub(crate) priority_header: Option<String>,
+
+    /// Peers whose priority header is trusted, loopback if empty
+    #[builder(setter(into), default)]
+    pub(crate) priority_trusted_peers: Vec<cidr::IpCidr>,
+
+    /// Time (seconds) a waiting request takes to rise one priority class, 0 disables aging
+    #[builder(setter(into), default = 5)]
+    pub(crate) priority_aging: u64,
+
+    /// Requests waiting for a concurrent limit slot, 0 is unlimited. Low priority
+    /// requests are shed from half of it, a higher class preempts a lower waiter.
+    #[builder(setter(into), default = 0)]
+    pub(crate) priority_max_queue: usize,
+
+    /// Rules mapping routes and inbound identities to priority classes, first match wins
+    #[builder(setter(into), default)]
+    pub(crate) priority_rules: Vec<PriorityRule>,
+
+    /// Server runtime worker threads, 0 uses all CPU cores
+    #[builder(setter(into), default = 0)]
+    pub(crate) workers: usize,
+
     /// Server concurrent limit (Enforces a limit on the concurrent number of requests the underlying)
     #[builder(setter(into), default = 65535)]
     pub(crate) concurrent_limit: usize,
 
+    /// Concurrent streaming responses limit, 0 is unlimited
+    #[builder(setter(into), default = 0)]
+    pub(crate) max_concurrent_streams_inflight: usize,
+
+    /// Memory budget of the buffered request and response bodies (byte), 0 is unlimited
+    #[builder(setter(into), default = 0)]
+    pub(crate) max_buffered_memory: u64,
+
     /// Enabled Cookie Store
     #[builder(default = false)]
     pub(crate) cookie_store: bool,
 
+    /// Upstream cookies handling of the proxied requests
+    #[builder(setter(into), default)]
+    pub(crate) cookie_mode: CookieMode,
+
     /// Use fastest DNS resolver
     #[builder(default = false)]
     pub(crate) fastest_dns: bool,
@@ -36,6 +99,31 @@ pub struct Args {
     #[builder(setter(into), default = 90)]
     pub(crate) pool_idle_timeout: usize,
 
+    /// Negotiate HTTP/2 with the upstream (ALPN), falls back to HTTP/1.1 when not negotiated
+    #[builder(default = true)]
+    pub(crate) upstream_http2: bool,
+
+    /// Upstream HTTP/2 keepalive ping interval (seconds), 0 disables it
+    #[builder(setter(into), default = 0)]
+    pub(crate) upstream_http2_keepalive_interval: usize,
+
+    /// Upstream HTTP/2 initial stream window size, defaults to the impersonated client value
+    #[builder(setter(into), default)]
+    pub(crate) upstream_http2_stream_window: Option<u32>,
+
+    /// Upstream HTTP/2 initial connection window size, defaults to the impersonated client value
+    #[builder(setter(into), default)]
+    pub(crate) upstream_http2_connection_window: Option<u32>,
+
+    /// Upstream HTTP/2 adaptive flow control window, overrides the window sizes
+    #[builder(default = false)]
+    pub(crate) upstream_http2_adaptive_window: bool,
+
+    /// Upstream requests multiplexed on an HTTP/2 connection before another connection is
+    /// opened, 0 is unlimited
+    #[builder(setter(into), default = 0)]
+    pub(crate) upstream_http2_max_streams_per_conn: usize,
+
     /// Server/Client timeout
     #[builder(setter(into), default = 600)]
     pub(crate) timeout: usize,
@@ -44,6 +132,95 @@ pub struct Args {
     #[builder(setter(into), default = 60)]
     pub(crate) connect_timeout: usize,
 
+    /// Server header read timeout (second), 0 disables it
+    #[builder(setter(into), default = 30)]
+    pub(crate) header_read_timeout: usize,
+
+    /// Server TLS handshake timeout (second), 0 disables it
+    #[builder(setter(into), default = 5)]
+    pub(crate) tls_handshake_timeout: usize,
+
+    /// ALPN protocols advertised by the TLS listener in preference order, empty is `h2, http/1.1`
+    #[builder(setter(into), default)]
+    pub(crate) tls_alpn_protocols: Vec<String>,
+
+    /// User-Agent patterns of the clients downgraded to HTTP/1.1 on their next TLS connections
+    #[builder(setter(into), default)]
+    pub(crate) http2_disable_for: Vec<String>,
+
+    /// Server concurrent TLS handshakes limit, 0 is unlimited
+    #[builder(setter(into), default = 1024)]
+    pub(crate) max_concurrent_handshakes: usize,
+
+    /// Server open connections limit, 0 is unlimited
+    #[builder(setter(into), default = 0)]
+    pub(crate) max_connections: usize,
+
+    /// Admin listener open connections limit, 0 is unlimited
+    #[builder(setter(into), default = 0)]
+    pub(crate) admin_max_connections: usize,
+
+    /// Upstream keepalive ping interval (second), 0 disables it
+    #[builder(setter(into), default = 0)]
+    pub(crate) upstream_keepalive_interval: usize,
+
+    /// Upstream access token used by the keepalive ping
+    #[builder(setter(into), default)]
+    pub(crate) upstream_keepalive_token: Option<Secret>,
+
+    /// Fraction of the requests that are access-logged (0.0 - 1.0), server errors are always logged
+    #[builder(default = 1.0)]
+    pub(crate) log_sample_rate: f64,
+
+    /// Upstream retries of a request whose response matched a retry body pattern
+    #[builder(setter(into), default = 0)]
+    pub(crate) upstream_retries: usize,
+
+    /// Origin of the proxied requests in place of the platform and ChatGPT origins,
+    /// e.g. a mock upstream in tests
+    #[builder(setter(into), default)]
+    pub(crate) upstream_origin: Option<String>,
+
+    /// Replay the response of a retried POST with the same `Idempotency-Key`
+    #[builder(setter(into), default = false)]
+    pub(crate) idempotency_enable: bool,
+
+    /// Idempotent responses store strategy (mem/redb)
+    #[builder(setter(into), default = "mem".to_string())]
+    pub(crate) idempotency_store: String,
+
+    /// Idempotent responses replay window (second)
+    #[builder(setter(into), default = 600)]
+    pub(crate) idempotency_window: u64,
+
+    /// Save the in-memory stores (token buckets, budget spend) on shutdown and restore them on startup
+    #[builder(setter(into), default = false)]
+    pub(crate) checkpoint_enable: bool,
+
+    /// Checkpoint interval (second) of the in-memory stores, 0 saves on shutdown only
+    #[builder(setter(into), default = 300)]
+    pub(crate) checkpoint_interval: u64,
+
+    /// Never send client identifiers (forwarded addresses, user agent) upstream
+    #[builder(setter(into), default = false)]
+    pub(crate) privacy_mode: bool,
+
+    /// Extra request headers stripped from the upstream requests in privacy mode
+    #[builder(setter(into), default)]
+    pub(crate) privacy_strip_headers: Vec<String>,
+
+    /// Upstream request header carrying the tag of the inbound identity
+    #[builder(setter(into), default)]
+    pub(crate) upstream_tag_header: Option<String>,
+
+    /// Rules mapping the inbound identity to the upstream tag, the first match applies
+    #[builder(setter(into), default)]
+    pub(crate) upstream_tag_rules: Vec<UpstreamTagRule>,
+
+    /// Retry non-streaming responses whose body matches one of the patterns (regex), even on 2xx
+    #[builder(setter(into), default)]
+    pub(crate) retry_on_body_patterns: Vec<String>,
+
     /// Disable direct connection
     #[builder(default = false)]
     pub(crate) enable_direct: bool,
@@ -64,13 +241,98 @@ pub struct Args {
     #[builder(setter(into), default)]
     pub(crate) tls_key: Option<PathBuf>,
 
+    /// TLS key password (encrypted PKCS#8 key)
+    #[builder(setter(into), default)]
+    pub(crate) tls_key_password: Option<Secret>,
+
+    /// Upstream response header policy
+    #[builder(setter(into), default)]
+    pub(crate) response_headers: ResponseHeaderPolicy,
+
+    /// Response for unmatched routes
+    #[builder(setter(into), default)]
+    pub(crate) not_found_response: NotFoundResponse,
+
+    /// Flush mode of the re-emitted event streams
+    #[builder(setter(into), default)]
+    pub(crate) sse_flush_mode: SseFlushMode,
+
+    /// Batched flush mode batch size (byte)
+    #[builder(setter(into), default = 4096)]
+    pub(crate) sse_batch_size: usize,
+
+    /// Batched flush mode batch time (millisecond)
+    #[builder(setter(into), default = 10)]
+    pub(crate) sse_batch_time: usize,
+
+    /// Largest chunk of an event stream written to the client (byte), 0 for no limit
+    #[builder(setter(into), default = 0)]
+    pub(crate) stream_chunk_size: usize,
+
+    /// Upstream streaming mode of the chat completion requests, the responses are
+    /// converted back to the streaming mode requested by the client
+    #[builder(setter(into), default)]
+    pub(crate) stream_adapt: StreamAdapt,
+
+    /// Requested model aliases, alias -> canonical name
+    #[builder(setter(into), default)]
+    pub(crate) model_aliases: BTreeMap<String, String>,
+
+    /// Requested model name patterns, other models are rejected with 400, all models if empty
+    #[builder(setter(into), default)]
+    pub(crate) model_allowlist: Vec<String>,
+
+    /// Request body transforms, applied in order to the JSON request bodies
+    #[builder(setter(into), default)]
+    pub(crate) body_transforms: Vec<BodyTransform>,
+
+    /// Largest request body (byte) the body transforms are applied to
+    #[builder(setter(into), default = 1048576)]
+    pub(crate) body_transform_limit: usize,
+
+    /// Egress request signing
+    #[builder(setter(into), default)]
+    pub(crate) egress_auth: Option<EgressAuthConfig>,
+
+    /// Response redaction
+    #[builder(setter(into), default)]
+    pub(crate) redact: RedactConfig,
+
+    /// Dry-run traffic mirroring to a secondary upstream
+    #[builder(setter(into), default)]
+    pub(crate) mirror: MirrorConfig,
+
+    /// Access schedules file path (toml format file)
+    #[builder(setter(into), default)]
+    pub(crate) access_schedules: Option<PathBuf>,
+
+    /// Model policies file path (toml format file)
+    #[builder(setter(into), default)]
+    pub(crate) model_policies: Option<PathBuf>,
+
+    /// Client IP allow list file path, one CIDR per line
+    #[builder(setter(into), default)]
+    pub(crate) allow_ips_file: Option<PathBuf>,
+
+    /// Client IP deny list file path, one CIDR per line
+    #[builder(setter(into), default)]
+    pub(crate) deny_ips_file: Option<PathBuf>,
+
+    /// Per-model token prices, used by the key budgets
+    #[builder(setter(into), default)]
+    pub(crate) pricing: Pricing,
+
+    /// Key spend store strategy (mem/redb)
+    #[builder(setter(into), default = "redb".to_string())]
+    pub(crate) budget_store: String,
+
     /// Visitor email whitelist
     #[builder(setter(into), default)]
     pub(super) visitor_email_whitelist: Option<Vec<String>>,
 
     /// Login auth key
     #[builder(setter(into), default)]
-    pub(super) auth_key: Option<String>,
+    pub(crate) auth_key: Option<String>,
 
     /// Enable webui
     #[builder(setter(into), default = false)]
@@ -92,6 +354,23 @@ pub struct Args {
     #[builder(setter(into), default)]
     pub(crate) cf_secret_key: Option<String>,
 
+    /// Concurrent Turnstile siteverify calls, 0 is unlimited
+    #[builder(setter(into), default = 16)]
+    pub(crate) cf_verify_concurrency: usize,
+
+    /// Auth mechanisms of the proxied requests in evaluation order, the first that
+    /// accepts a request admits it. Empty checks the access token alone
+    #[builder(setter(into), default)]
+    pub(crate) auth_chain: Vec<AuthMechanism>,
+
+    /// HMAC-SHA256 secret of the signed requests (`sign` auth mechanism)
+    #[builder(setter(into), default)]
+    pub(crate) auth_sign_secret: Option<Secret>,
+
+    /// State key of the `GET /admin/state` backups, the endpoint is disabled without it
+    #[builder(setter(into), default)]
+    pub(crate) state_key: Option<Secret>,
+
     /// Arkose endpoint
     #[builder(setter(into), default)]
     pub(crate) arkose_endpoint: Option<String>,
@@ -145,6 +424,29 @@ pub struct Args {
     #[builder(setter(into), default = 86400)]
     pub(crate) tb_expired: u32,
 
+    /// Concurrent token bucket operations on the redb store, 0 is unbounded
+    #[cfg(feature = "limit")]
+    #[builder(setter(into), default = 32)]
+    pub(crate) tb_redb_max_concurrency: usize,
+
+    /// Token bucket overrides by request path pattern, each with its own buckets
+    #[cfg(feature = "limit")]
+    #[builder(setter(into), default)]
+    pub(crate) route_limits: BTreeMap<String, RouteLimit>,
+
+    /// Expose the token bucket key in the `X-RateLimit-Key` response header (debug, leaks the client address)
+    #[cfg(feature = "limit")]
+    #[builder(setter(into), default = false)]
+    pub(crate) tb_debug_key: bool,
+
+    /// Expose the `Server-Timing` response header (queue, upstream and proxy time)
+    #[builder(setter(into), default = false)]
+    pub(crate) expose_server_timing: bool,
+
+    /// Webhook URL receiving the lifecycle events (ready, reload, shutdown)
+    #[builder(setter(into), default)]
+    pub(crate) lifecycle_webhook: Option<String>,
+
     /// Preauth MITM server bind address
     #[cfg(feature = "preauth")]
     #[builder(setter(into), default)]
@@ -165,3 +467,858 @@ pub struct Args {
     #[builder(setter(into), default)]
     pub(crate) pkey: PathBuf,
 }
+
+/// Upstream response headers that are never forwarded: hop-by-hop headers,
+/// `content-length` (the body may be rewritten) and `set-cookie`
+/// (the puid/cf_clearance cookies are re-issued by the server itself)
+pub const RESPONSE_HEADERS_ALWAYS_SCRUB: [&str; 11] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "content-length",
+    "set-cookie",
+];
+
+/// Upstream server identifiers that are not forwarded, `scrub` adds to them
+pub const RESPONSE_HEADERS_DEFAULT_SCRUB: [&str; 8] = [
+    "server",
+    "x-powered-by",
+    "via",
+    "alt-svc",
+    "cf-ray",
+    "cf-cache-status",
+    "report-to",
+    "nel",
+];
+
+/// Upstream response header policy (`[response_headers]`).
+///
+/// The default policy forwards every upstream header except the always scrubbed
+/// ones and the upstream server identifiers.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResponseHeaderPolicy {
+    /// Upstream headers that are not forwarded, in addition to the server
+    /// identifiers (case-insensitive)
+    pub scrub: Vec<String>,
+    /// Forward only these upstream headers, empty forwards every header not scrubbed
+    pub allow: Vec<String>,
+    /// Static headers added to every response
+    pub add: BTreeMap<String, String>,
+}
+
+impl ResponseHeaderPolicy {
+    /// Check the header names and static header values
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for name in self.scrub.iter().chain(&self.allow) {
+            HeaderName::try_from(name.as_str())
+                .map_err(|_| anyhow::anyhow!("Invalid response header name: {name}"))?;
+        }
+        for (name, value) in &self.add {
+            HeaderName::try_from(name.as_str())
+                .map_err(|_| anyhow::anyhow!("Invalid response header name: {name}"))?;
+            HeaderValue::from_str(value)
+                .map_err(|_| anyhow::anyhow!("Invalid response header value: {name}"))?;
+        }
+        Ok(())
+    }
+
+    /// Whether the upstream header is forwarded to the client
+    pub fn forward(&self, name: &str) -> bool {
+        let matches = |list: &[String]| list.iter().any(|h| h.eq_ignore_ascii_case(name));
+        !RESPONSE_HEADERS_ALWAYS_SCRUB
+            .iter()
+            .chain(&RESPONSE_HEADERS_DEFAULT_SCRUB)
+            .any(|h| h.eq_ignore_ascii_case(name))
+            && !matches(&self.scrub)
+            && (self.allow.is_empty() || matches(&self.allow))
+    }
+}
+
+/// Response format for unmatched routes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotFoundResponse {
+    /// OpenAI API error schema
+    #[default]
+    Json,
+    /// HTML page (the WebUI 404 page when the WebUI is enabled)
+    Html,
+    /// HTML when the client accepts `text/html`, otherwise JSON
+    Auto,
+}
+
+impl FromStr for NotFoundResponse {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "html" => Ok(Self::Html),
+            "auto" => Ok(Self::Auto),
+            _ => anyhow::bail!("Only support `json` / `html` / `auto` not found response"),
+        }
+    }
+}
+
+impl ToString for NotFoundResponse {
+    fn to_string(&self) -> String {
+        match self {
+            Self::Json => "json".to_string(),
+            Self::Html => "html".to_string(),
+            Self::Auto => "auto".to_string(),
+        }
+    }
+}
+
+/// Admin listener address, `host:port` or `unix:/path/to/socket`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum AdminListen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl AdminListen {
+    /// Whether only the local clients can connect: a loopback address or a Unix socket
+    pub fn is_local(&self) -> bool {
+        match self {
+            Self::Tcp(addr) => addr.ip().is_loopback(),
+            Self::Unix(_) => true,
+        }
+    }
+
+    /// A listener reachable over the network requires the `auth_key`
+    pub fn validate(&self, auth_key: bool) -> anyhow::Result<()> {
+        if !self.is_local() && !auth_key {
+            anyhow::bail!(
+                "admin_listen {} is not a loopback address, it requires an auth_key",
+                self.to_string()
+            )
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for AdminListen {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => anyhow::bail!("Unix socket path is empty"),
+            Some(path) => Ok(Self::Unix(PathBuf::from(path))),
+            None => s.parse().map(Self::Tcp).map_err(|_| {
+                anyhow::anyhow!("Only support `host:port` / `unix:/path` admin listen")
+            }),
+        }
+    }
+}
+
+impl TryFrom<String> for AdminListen {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<AdminListen> for String {
+    fn from(listen: AdminListen) -> Self {
+        listen.to_string()
+    }
+}
+
+impl ToString for AdminListen {
+    fn to_string(&self) -> String {
+        match self {
+            Self::Tcp(addr) => addr.to_string(),
+            Self::Unix(path) => format!("unix:{}", path.display()),
+        }
+    }
+}
+
+/// Flush mode of the re-emitted event streams
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SseFlushMode {
+    /// Flush every event as it arrives (lowest latency)
+    #[default]
+    Immediate,
+    /// Coalesce small events up to the batch size or time (fewer writes)
+    Batched,
+}
+
+impl FromStr for SseFlushMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "immediate" => Ok(Self::Immediate),
+            "batched" => Ok(Self::Batched),
+            _ => anyhow::bail!("Only support `immediate` / `batched` sse flush mode"),
+        }
+    }
+}
+
+impl ToString for SseFlushMode {
+    fn to_string(&self) -> String {
+        match self {
+            Self::Immediate => "immediate".to_string(),
+            Self::Batched => "batched".to_string(),
+        }
+    }
+}
+
+/// Upstream streaming mode of the chat completion requests
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamAdapt {
+    /// Forward the client's `stream` flag
+    #[default]
+    Off,
+    /// Always stream from the upstream, aggregated into a single response for
+    /// non-streaming clients
+    Stream,
+    /// Never stream from the upstream, replayed as an event stream for streaming clients
+    Json,
+}
+
+impl FromStr for StreamAdapt {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "stream" => Ok(Self::Stream),
+            "json" => Ok(Self::Json),
+            _ => anyhow::bail!("Only support `off` / `stream` / `json` stream adapt mode"),
+        }
+    }
+}
+
+impl ToString for StreamAdapt {
+    fn to_string(&self) -> String {
+        match self {
+            Self::Off => "off".to_string(),
+            Self::Stream => "stream".to_string(),
+            Self::Json => "json".to_string(),
+        }
+    }
+}
+
+/// Upstream cookies handling of the proxied requests
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CookieMode {
+    /// Relay the `_puid` and `cf_clearance` cookies between the client and the upstream
+    #[default]
+    Passthrough,
+    /// Never relay cookies in either direction
+    Strip,
+    /// Keep the upstream cookies in a jar per session, never sent to the client
+    Jar,
+}
+
+impl FromStr for CookieMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "passthrough" => Ok(Self::Passthrough),
+            "strip" => Ok(Self::Strip),
+            "jar" => Ok(Self::Jar),
+            _ => anyhow::bail!("Only support `passthrough` / `strip` / `jar` cookie mode"),
+        }
+    }
+}
+
+impl ToString for CookieMode {
+    fn to_string(&self) -> String {
+        match self {
+            Self::Passthrough => "passthrough".to_string(),
+            Self::Strip => "strip".to_string(),
+            Self::Jar => "jar".to_string(),
+        }
+    }
+}
+
+/// Auth mechanism of the `auth_chain`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMechanism {
+    /// ChatGPT access token, API key or session key in the `Authorization` header
+    #[serde(alias = "access_tokens")]
+    AccessToken,
+    /// HMAC-SHA256 signature of the request with `auth_sign_secret`
+    Sign,
+    /// Cloudflare Turnstile token in the `CF-Turnstile-Response` header
+    Turnstile,
+}
+
+impl AuthMechanism {
+    /// Check the mechanisms of a chain, `sign_secret` and `turnstile` tell whether
+    /// `auth_sign_secret` and the Turnstile keys are set
+    pub fn validate_chain(
+        chain: &[Self],
+        sign_secret: bool,
+        turnstile: bool,
+    ) -> anyhow::Result<()> {
+        for (i, mechanism) in chain.iter().enumerate() {
+            if chain[..i].contains(mechanism) {
+                anyhow::bail!("auth_chain lists `{}` twice", mechanism.to_string())
+            }
+            match mechanism {
+                Self::Sign if !sign_secret => {
+                    anyhow::bail!("auth_chain `sign` requires auth_sign_secret")
+                }
+                Self::Turnstile if !turnstile => {
+                    anyhow::bail!("auth_chain `turnstile` requires cf_site_key and cf_secret_key")
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for AuthMechanism {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "access_token" | "access_tokens" => Ok(Self::AccessToken),
+            "sign" => Ok(Self::Sign),
+            "turnstile" => Ok(Self::Turnstile),
+            _ => anyhow::bail!("Only support `access_token` / `sign` / `turnstile` auth mechanism"),
+        }
+    }
+}
+
+impl ToString for AuthMechanism {
+    fn to_string(&self) -> String {
+        match self {
+            Self::AccessToken => "access_token".to_string(),
+            Self::Sign => "sign".to_string(),
+            Self::Turnstile => "turnstile".to_string(),
+        }
+    }
+}
+
+/// Request priority class, contended requests of a higher class are admitted first
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum PriorityClass {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl PriorityClass {
+    pub const ALL: [Self; 3] = [Self::High, Self::Normal, Self::Low];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Low => "low",
+            Self::Normal => "normal",
+            Self::High => "high",
+        }
+    }
+}
+
+impl FromStr for PriorityClass {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "low" => Ok(Self::Low),
+            "normal" => Ok(Self::Normal),
+            "high" => Ok(Self::High),
+            _ => anyhow::bail!("Only support `high` / `normal` / `low` priority classes"),
+        }
+    }
+}
+
+/// Dry-run traffic mirroring to a secondary upstream (`[mirror]`).
+///
+/// A sample of the proxied requests is copied to the mirror upstream, the mirror
+/// responses are discarded and only recorded in the metrics.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MirrorConfig {
+    /// Mirror upstream origin, e.g. `https://chat.example.com`, unset disables mirroring
+    pub origin: Option<url::Url>,
+    /// Percentage of the matching requests that are mirrored (0-100)
+    pub percent: f64,
+    /// Mirrored path prefixes, empty mirrors every proxied route
+    pub routes: Vec<String>,
+    /// Mirror upstream token, replaces the request authorization.
+    /// Without it the authorization is not sent to the mirror upstream.
+    pub authorization: Option<Secret>,
+    /// Mirrored requests in flight, further mirrors are dropped
+    pub max_inflight: usize,
+}
+
+impl Default for MirrorConfig {
+    fn default() -> Self {
+        Self {
+            origin: None,
+            percent: 0.0,
+            routes: Vec::new(),
+            authorization: None,
+            max_inflight: 64,
+        }
+    }
+}
+
+impl MirrorConfig {
+    /// Check the mirror origin and sampling percentage
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(origin) = self.origin.as_ref() {
+            if !matches!(origin.scheme(), "http" | "https") {
+                anyhow::bail!("Invalid mirror origin: {origin}, only support http / https")
+            }
+        }
+        if !(0.0..=100.0).contains(&self.percent) {
+            anyhow::bail!("Invalid mirror percent: {}, must be 0-100", self.percent)
+        }
+        Ok(())
+    }
+}
+
+/// Upstream tag rule (`[[upstream_tag_rules]]`), tags the upstream requests of the
+/// inbound identities whose `source` value matches.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UpstreamTagRule {
+    /// Source field: `key_id`, `email`, `policy` or `header:<name>`.
+    /// Unset always matches, e.g. for a default tag.
+    pub source: Option<String>,
+    /// Source value pattern, `*` matches any characters and `?` a single one
+    #[serde(default = "default_tag_match", rename = "match")]
+    pub pattern: String,
+    /// Tag value, `{value}` is replaced by the source value
+    #[serde(default = "default_tag_value")]
+    pub tag: String,
+}
+
+fn default_tag_match() -> String {
+    "*".to_owned()
+}
+
+fn default_tag_value() -> String {
+    "{value}".to_owned()
+}
+
+impl UpstreamTagRule {
+    /// Check the source field
+    pub fn validate(&self) -> anyhow::Result<()> {
+        validate_identity_source("upstream tag", self.source.as_deref())
+    }
+}
+
+/// Priority rule (`[[priority_rules]]`), sets the priority class of the requests to
+/// the matching routes from the inbound identities whose `source` value matches.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PriorityRule {
+    /// Request path prefixes, empty matches every route
+    #[serde(default)]
+    pub routes: Vec<String>,
+    /// Source field: `key_id`, `email`, `policy` or `header:<name>`.
+    /// Unset matches every identity.
+    pub source: Option<String>,
+    /// Source value pattern, `*` matches any characters and `?` a single one
+    #[serde(default = "default_tag_match", rename = "match")]
+    pub pattern: String,
+    /// Priority class of the matching requests
+    pub priority: PriorityClass,
+}
+
+impl PriorityRule {
+    /// Check the source field and the routes
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(route) = self.routes.iter().find(|route| !route.starts_with('/')) {
+            anyhow::bail!("Invalid priority rule route `{route}`, must start with `/`")
+        }
+        validate_identity_source("priority rule", self.source.as_deref())
+    }
+}
+
+/// Token bucket of a route limit (`[route_limits."<path pattern>"]`), overrides the
+/// global `tb_capacity` and `tb_fill_rate` for the matching routes
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RouteLimit {
+    /// Bucket capacity
+    pub capacity: u32,
+    /// Tokens refilled per second
+    pub fill_rate: u32,
+}
+
+impl RouteLimit {
+    /// Check the path pattern of the route and the capacity
+    pub fn validate(&self, route: &str) -> anyhow::Result<()> {
+        if !route.starts_with('/') {
+            anyhow::bail!("Invalid route limit `{route}`, the path pattern must start with `/`")
+        }
+        if self.capacity == 0 {
+            anyhow::bail!("Invalid route limit `{route}`, capacity must be at least 1")
+        }
+        Ok(())
+    }
+}
+
+/// Check an identity source field: `key_id`, `email`, `policy` or `header:<name>`
+fn validate_identity_source(what: &str, source: Option<&str>) -> anyhow::Result<()> {
+    match source {
+        None | Some("key_id" | "email" | "policy") => Ok(()),
+        Some(source) => match source.strip_prefix("header:") {
+            Some(name) => reqwest::header::HeaderName::try_from(name)
+                .map(|_| ())
+                .map_err(|err| anyhow::anyhow!("Invalid {what} source `{source}`: {err}")),
+            None => anyhow::bail!(
+                "Invalid {what} source `{source}`, must be key_id, email, policy or header:<name>"
+            ),
+        },
+    }
+}
+
+/// Egress request signing (`[egress_auth]`), for an API gateway in front of the
+/// upstream that requires HMAC-signed requests.
+///
+/// The signature is the hex HMAC-SHA256 of the `canonical` fields joined by `\n`,
+/// `METHOD\nPATH?QUERY\nDATE\nBODY_SHA256` by default.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EgressAuthConfig {
+    /// Key id of the signing secret
+    pub key_id: String,
+    /// Signing secret, supports `vault://` and `awssm://` references
+    pub secret: Secret,
+    /// Signature header
+    pub header: String,
+    /// Signature header value, `{key_id}`, `{signature}`, `{date}` and `{body_sha256}`
+    /// are replaced
+    pub format: String,
+    /// Header of the signed date (HTTP-date), set on every request
+    pub date_header: String,
+    /// Header of the body SHA-256 (hex), unset does not send it
+    pub body_hash_header: Option<String>,
+    /// Signed fields in order: `method`, `path` (with the query), `host`, `date`,
+    /// `body_sha256` or `header:<name>`
+    pub canonical: Vec<String>,
+}
+
+impl Default for EgressAuthConfig {
+    fn default() -> Self {
+        Self {
+            key_id: String::new(),
+            secret: Secret::default(),
+            header: "Authorization".to_owned(),
+            format: "HMAC-SHA256 KeyId={key_id}, Signature={signature}".to_owned(),
+            date_header: "Date".to_owned(),
+            body_hash_header: Some("X-Content-SHA256".to_owned()),
+            canonical: ["method", "path", "date", "body_sha256"]
+                .map(str::to_owned)
+                .to_vec(),
+        }
+    }
+}
+
+impl EgressAuthConfig {
+    /// Check the key, the headers and the signature format
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.key_id.is_empty() || self.secret.is_empty() {
+            anyhow::bail!("egress_auth requires a key_id and a secret")
+        }
+        for name in std::iter::once(&self.header)
+            .chain(std::iter::once(&self.date_header))
+            .chain(self.body_hash_header.iter())
+        {
+            reqwest::header::HeaderName::try_from(name.as_str())
+                .map_err(|err| anyhow::anyhow!("Invalid egress_auth header `{name}`: {err}"))?;
+        }
+        if !self.format.contains("{signature}") {
+            anyhow::bail!("Invalid egress_auth format: must contain `{{signature}}`")
+        }
+        if self.canonical.is_empty() {
+            anyhow::bail!("egress_auth canonical requires at least one field")
+        }
+        for field in &self.canonical {
+            match field.as_str() {
+                "method" | "path" | "host" | "date" | "body_sha256" => {}
+                _ => match field.strip_prefix("header:") {
+                    Some(name) => {
+                        reqwest::header::HeaderName::try_from(name).map_err(|err| {
+                            anyhow::anyhow!("Invalid egress_auth canonical field `{field}`: {err}")
+                        })?;
+                    }
+                    None => anyhow::bail!(
+                        "Invalid egress_auth canonical field `{field}`, must be method, path, host, date, body_sha256 or header:<name>"
+                    ),
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Response redaction (`[redact]`), the matches are replaced in the proxied response
+/// bodies, event streams included. Matches never span a line.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RedactConfig {
+    /// Literal strings, matched case-sensitively
+    pub literals: Vec<String>,
+    /// Regular expressions
+    pub patterns: Vec<String>,
+    /// Replacement of a match, inserted as is in JSON strings
+    pub mask: String,
+    /// Longest pattern match (byte) found across the chunks of an event stream
+    pub max_pattern_len: usize,
+}
+
+impl Default for RedactConfig {
+    fn default() -> Self {
+        Self {
+            literals: Vec::new(),
+            patterns: Vec::new(),
+            mask: "[REDACTED]".to_owned(),
+            max_pattern_len: 256,
+        }
+    }
+}
+
+impl RedactConfig {
+    pub fn is_empty(&self) -> bool {
+        self.literals.is_empty() && self.patterns.is_empty()
+    }
+
+    /// Check the patterns compile, and that neither the literals nor the mask can break
+    /// the event stream framing or the JSON strings
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for literal in &self.literals {
+            if literal.is_empty() || literal.contains(['\r', '\n']) {
+                anyhow::bail!(
+                    "Invalid redact literal: {literal:?}, must be a non-empty single line"
+                )
+            }
+        }
+        for pattern in &self.patterns {
+            regex::Regex::new(pattern)
+                .map_err(|err| anyhow::anyhow!("Invalid redact pattern `{pattern}`: {err}"))?;
+        }
+        if self
+            .mask
+            .chars()
+            .any(|c| c == '"' || c == '\\' || c.is_control())
+        {
+            anyhow::bail!("Invalid redact mask: {:?}, quotes, backslashes and control characters are not allowed", self.mask)
+        }
+        if self.max_pattern_len == 0 {
+            anyhow::bail!("Invalid redact max_pattern_len: must be greater than 0")
+        }
+        Ok(())
+    }
+}
+
+/// Token price of a model, USD per 1M tokens
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModelPrice {
+    pub input: f64,
+    pub output: f64,
+}
+
+/// Per-model token prices (`[pricing]`), keys are model names or globs (`gpt-4o-mini*`)
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Pricing(pub BTreeMap<String, ModelPrice>);
+
+impl Pricing {
+    /// Check the prices are not negative
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (model, price) in &self.0 {
+            if price.input < 0.0 || price.output < 0.0 {
+                anyhow::bail!("Invalid pricing for `{model}`: prices must not be negative")
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Segment of a [`JsonPath`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JsonPathSegment {
+    /// Object member, `.name` or `['name']`
+    Key(String),
+    /// Array element, `[0]`
+    Index(usize),
+    /// Every array element or object member, `[*]` or `.*`
+    Wildcard,
+}
+
+/// JSONPath subset of the body transforms: `$` followed by `.name`, `['name']`,
+/// `[index]`, `[*]` and `.*` segments. Filters, slices and recursive descent are not
+/// supported.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct JsonPath {
+    source: String,
+    segments: Vec<JsonPathSegment>,
+}
+
+impl JsonPath {
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    pub fn segments(&self) -> &[JsonPathSegment] {
+        &self.segments
+    }
+}
+
+impl FromStr for JsonPath {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rest = s
+            .strip_prefix('$')
+            .ok_or_else(|| anyhow::anyhow!("Invalid JSONPath `{s}`, must start with `$`"))?;
+        let mut segments = Vec::new();
+        while !rest.is_empty() {
+            if let Some(name) = rest.strip_prefix('.') {
+                let end = name.find(['.', '[']).unwrap_or(name.len());
+                segments.push(match &name[..end] {
+                    "" => anyhow::bail!("Invalid JSONPath `{s}`, empty member name"),
+                    "*" => JsonPathSegment::Wildcard,
+                    key => JsonPathSegment::Key(key.to_owned()),
+                });
+                rest = &name[end..];
+            } else if let Some(selector) = rest.strip_prefix('[') {
+                let end = selector
+                    .find(']')
+                    .ok_or_else(|| anyhow::anyhow!("Invalid JSONPath `{s}`, unclosed `[`"))?;
+                let inner = &selector[..end];
+                let quoted = ['\'', '"'].into_iter().find_map(|quote| {
+                    inner
+                        .strip_prefix(quote)
+                        .and_then(|inner| inner.strip_suffix(quote))
+                });
+                segments.push(match (inner, quoted) {
+                    ("*", _) => JsonPathSegment::Wildcard,
+                    (_, Some(key)) => JsonPathSegment::Key(key.to_owned()),
+                    _ => JsonPathSegment::Index(inner.parse().map_err(|_| {
+                        anyhow::anyhow!("Invalid JSONPath `{s}`, unsupported selector `[{inner}]`")
+                    })?),
+                });
+                rest = &selector[end + 1..];
+            } else {
+                anyhow::bail!("Invalid JSONPath `{s}`, expected `.` or `[` at `{rest}`")
+            }
+        }
+        if segments.is_empty() {
+            anyhow::bail!("Invalid JSONPath `{s}`, must select a field of the body")
+        }
+        Ok(Self {
+            source: s.to_owned(),
+            segments,
+        })
+    }
+}
+
+impl TryFrom<String> for JsonPath {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<JsonPath> for String {
+    fn from(path: JsonPath) -> Self {
+        path.source
+    }
+}
+
+/// Operation of a body transform
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BodyTransformOp {
+    /// Set the field to `value`, replacing it
+    Set,
+    /// Set the field to `value` when it is absent
+    Default,
+    /// Bound a number field to `min` and `max`
+    Clamp,
+    /// Remove the field
+    Remove,
+}
+
+/// Request body transform (`[[body_transforms]]`), applied in order to the JSON request
+/// bodies of the matching routes before they are forwarded
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BodyTransform {
+    /// Request path prefixes, empty matches every route
+    #[serde(default)]
+    pub routes: Vec<String>,
+    /// Transformed fields
+    pub path: JsonPath,
+    pub op: BodyTransformOp,
+    /// Value of `set` and `default`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<serde_json::Value>,
+    /// Lower bound of `clamp`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    /// Upper bound of `clamp`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+}
+
+impl BodyTransform {
+    /// Check the routes and that the operation has the fields it needs, and only those
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let path = self.path.as_str();
+        if let Some(route) = self.routes.iter().find(|route| !route.starts_with('/')) {
+            anyhow::bail!("Invalid body transform route `{route}`, must start with `/`")
+        }
+        let bounds = self.min.is_some() || self.max.is_some();
+        match self.op {
+            BodyTransformOp::Set | BodyTransformOp::Default if self.value.is_none() => {
+                anyhow::bail!("Invalid body transform of `{path}`: set and default need a value")
+            }
+            BodyTransformOp::Default
+                if self.path.segments().last() == Some(&JsonPathSegment::Wildcard) =>
+            {
+                anyhow::bail!(
+                    "Invalid body transform of `{path}`: default needs a member name or an index"
+                )
+            }
+            BodyTransformOp::Set | BodyTransformOp::Default if bounds => {
+                anyhow::bail!("Invalid body transform of `{path}`: min and max are clamp only")
+            }
+            BodyTransformOp::Clamp | BodyTransformOp::Remove if self.value.is_some() => {
+                anyhow::bail!("Invalid body transform of `{path}`: value is set and default only")
+            }
+            BodyTransformOp::Clamp if !bounds => {
+                anyhow::bail!("Invalid body transform of `{path}`: clamp needs min or max")
+            }
+            BodyTransformOp::Remove if bounds => {
+                anyhow::bail!("Invalid body transform of `{path}`: min and max are clamp only")
+            }
+            _ => {}
+        }
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            if min > max {
+                anyhow::bail!("Invalid body transform of `{path}`: min {min} is above max {max}")
+            }
+        }
+        Ok(())
+    }
+}
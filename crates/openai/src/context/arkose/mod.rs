@@ -9,12 +9,14 @@ use native_db::{Database, DatabaseBuilder};
 use std::path::PathBuf;
 use std::sync::{Arc, OnceLock};
 use std::time::Duration;
-use tokio::time::interval;
 use tracing::{info, warn};
 
 use super::WORKER_DIR;
 
 const INTERVAL_SECONDS: u16 = 3600;
+
+/// Interval of the arkose version upgrade
+pub const UPGRADE_INTERVAL: Duration = Duration::from_secs(INTERVAL_SECONDS as u64);
 static DATABASE_BUILDER: OnceLock<DatabaseBuilder> = OnceLock::new();
 
 pub struct ArkoseVersionContext<'a> {
@@ -71,18 +73,8 @@ impl ArkoseVersionContext<'_> {
         None
     }
 
-    /// Run a periodic task to upgrade the arkose version
-    pub async fn periodic_upgrade(&self) {
-        info!("Arkose Periodic task is running");
-        let mut interval = interval(Duration::from_secs(INTERVAL_SECONDS.into()));
-        loop {
-            interval.tick().await;
-            self.upgrade().await;
-        }
-    }
-
-    /// Upgrade the arkose version
-    async fn upgrade(&self) {
+    /// Upgrade the arkose version, every [`UPGRADE_INTERVAL`]
+    pub async fn upgrade(&self) {
         // Auth
         self.insert_version(Type::Auth).await;
         // GPT-4
@@ -44,6 +44,9 @@ fn init_context(args: Args) -> Context {
         arkose_solver_tguess_endpoint: args.arkose_solver_tguess_endpoint,
         arkose_solver_image_dir: args.arkose_solver_image_dir,
         enable_file_proxy: args.enable_file_proxy,
+        response_headers: args.response_headers,
+        upstream_retries: args.upstream_retries,
+        upstream_origin: args.upstream_origin,
         auth_key: args.auth_key,
         visitor_email_whitelist: args.visitor_email_whitelist,
         cf_turnstile: args.cf_site_key.and_then(|site_key| {
@@ -3,9 +3,11 @@ pub mod arkose;
 pub mod init;
 mod preauth;
 
-use self::preauth::PreauthCookieProvider;
+use self::{args::ResponseHeaderPolicy, preauth::PreauthCookieProvider};
 use crate::{
-    arkose::funcaptcha::solver::ArkoseSolver, auth::AuthClient, client::ClientRoundRobinBalancer,
+    arkose::funcaptcha::solver::ArkoseSolver,
+    auth::AuthClient,
+    client::{ClientRoundRobinBalancer, UpstreamStream},
 };
 use reqwest::Client;
 use std::{
@@ -52,6 +54,12 @@ pub struct Context {
     arkose_solver: Option<ArkoseSolver>,
     /// Enable files proxy
     enable_file_proxy: bool,
+    /// Upstream response header policy
+    response_headers: ResponseHeaderPolicy,
+    /// Upstream retries of a response matching a retry body pattern
+    upstream_retries: usize,
+    /// Origin of the proxied requests, replaces the upstream origins
+    upstream_origin: Option<String>,
     /// Login auth key
     auth_key: Option<String>,
     /// visitor_email_whitelist
@@ -78,6 +86,13 @@ impl Context {
         self.api_client.next().into()
     }
 
+    /// Get the reqwest client of a proxied upstream request, with its stream slot to
+    /// keep until the response body is read
+    pub fn api_client_stream(&self) -> (Client, Option<UpstreamStream>) {
+        let (client, stream) = self.api_client.next_stream();
+        (client.into(), stream)
+    }
+
     /// Get the reqwest auth client
     pub fn auth_client(&self) -> AuthClient {
         self.auth_client.next().into()
@@ -132,6 +147,21 @@ impl Context {
         self.enable_file_proxy
     }
 
+    /// Upstream response header policy
+    pub fn response_headers(&self) -> &ResponseHeaderPolicy {
+        &self.response_headers
+    }
+
+    /// Upstream retries of a response matching a retry body pattern
+    pub fn upstream_retries(&self) -> usize {
+        self.upstream_retries
+    }
+
+    /// Origin of the proxied requests, replaces the upstream origins
+    pub fn upstream_origin(&self) -> Option<&str> {
+        self.upstream_origin.as_deref()
+    }
+
     /// Get the visitor email whitelist
     pub fn visitor_email_whitelist(&self) -> Option<&[String]> {
         self.visitor_email_whitelist.as_deref()
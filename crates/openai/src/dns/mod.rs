@@ -10,6 +10,7 @@ use trust_dns_resolver::{lookup_ip::LookupIpIntoIter, system_conf, TokioAsyncRes
 
 use std::io;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 /// Wrapper around an `AsyncResolver`, which implements the `Resolve` trait.
@@ -37,6 +38,26 @@ impl TrustDnsResolver {
     }
 }
 
+/// Resolver counting its lookups. The client resolves the host of every connection it
+/// opens and none of the pooled ones it reuses, so the count is the opened connections.
+pub(crate) struct CountingResolver {
+    inner: Arc<TrustDnsResolver>,
+    counter: &'static AtomicU64,
+}
+
+impl CountingResolver {
+    pub(crate) fn new(inner: Arc<TrustDnsResolver>, counter: &'static AtomicU64) -> Self {
+        Self { inner, counter }
+    }
+}
+
+impl Resolve for CountingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        self.counter.fetch_add(1, Ordering::Relaxed);
+        self.inner.resolve(name)
+    }
+}
+
 struct SocketAddrs {
     iter: LookupIpIntoIter,
 }
@@ -11,9 +11,13 @@ pub mod homedir;
 mod log;
 pub mod platform;
 pub mod proxy;
+pub mod secret;
+#[cfg(any(feature = "serve", feature = "awssm"))]
+mod sign;
 
 #[cfg(feature = "serve")]
 pub mod serve;
+pub mod state;
 pub mod token;
 pub mod unescape;
 pub mod urldecoding;
@@ -0,0 +1,146 @@
+use crate::sign::{hex, hmac_sha256 as hmac, sha256_hex};
+use anyhow::Context;
+
+const SERVICE: &str = "secretsmanager";
+
+/// Read the AWS Secrets Manager secret `<name>`, or `<key>` of its JSON value.
+///
+/// Credentials come from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and
+/// `AWS_SESSION_TOKEN`, the region from `AWS_REGION` or `AWS_DEFAULT_REGION`.
+/// Requires the `secretsmanager:GetSecretValue` permission on the secret.
+pub(super) async fn fetch(reference: &str) -> anyhow::Result<String> {
+    let (name, key) = match reference.split_once('#') {
+        Some((name, key)) => (name, Some(key)),
+        None => (reference, None),
+    };
+    if name.is_empty() {
+        anyhow::bail!("AWS secret reference must be `awssm://<name>[#<key>]`")
+    }
+
+    let region = std::env::var("AWS_REGION")
+        .or_else(|_| std::env::var("AWS_DEFAULT_REGION"))
+        .context("AWS_REGION is not set")?;
+    let access_key = std::env::var("AWS_ACCESS_KEY_ID").context("AWS_ACCESS_KEY_ID is not set")?;
+    let secret_key =
+        std::env::var("AWS_SECRET_ACCESS_KEY").context("AWS_SECRET_ACCESS_KEY is not set")?;
+    let session_token = std::env::var("AWS_SESSION_TOKEN").ok();
+
+    let host = format!("{SERVICE}.{region}.amazonaws.com");
+    let body = serde_json::json!({ "SecretId": name }).to_string();
+    let now = chrono::Utc::now();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+
+    // Canonical headers, sorted by name
+    let mut headers = vec![
+        ("content-type", "application/x-amz-json-1.1".to_owned()),
+        ("host", host.clone()),
+        ("x-amz-date", amz_date.clone()),
+        ("x-amz-target", "secretsmanager.GetSecretValue".to_owned()),
+    ];
+    if let Some(token) = session_token {
+        headers.insert(3, ("x-amz-security-token", token));
+    }
+
+    let authorization = authorization(
+        &access_key,
+        &secret_key,
+        &region,
+        &amz_date,
+        &headers,
+        body.as_bytes(),
+    );
+
+    let mut request = super::client()?
+        .post(format!("https://{host}/"))
+        .header("authorization", authorization)
+        .body(body);
+    for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+        request = request.header(name, value);
+    }
+
+    let resp = request.send().await?;
+    let status = resp.status();
+    if !status.is_success() {
+        let err = resp.text().await.unwrap_or_default();
+        anyhow::bail!("AWS Secrets Manager returned {status} reading `{name}`: {err}")
+    }
+
+    let body = resp.json::<serde_json::Value>().await?;
+    let value = body
+        .get("SecretString")
+        .and_then(|value| value.as_str())
+        .with_context(|| format!("AWS secret `{name}` has no SecretString"))?;
+
+    match key {
+        Some(key) => {
+            let data = serde_json::from_str::<serde_json::Value>(value)
+                .with_context(|| format!("AWS secret `{name}` is not a JSON object"))?;
+            super::select(Some(&data), key).with_context(|| format!("AWS secret `{name}`"))
+        }
+        None => Ok(value.to_owned()),
+    }
+}
+
+/// AWS Signature Version 4 `Authorization` header of a `POST /` request
+fn authorization(
+    access_key: &str,
+    secret_key: &str,
+    region: &str,
+    amz_date: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> String {
+    let date = &amz_date[..8];
+    let scope = format!("{date}/{region}/{SERVICE}/aws4_request");
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_headers = headers
+        .iter()
+        .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+        .collect::<String>();
+    let canonical_request = format!(
+        "POST\n/\n\n{canonical_headers}\n{signed_headers}\n{}",
+        sha256_hex(body)
+    );
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+        sha256_hex(canonical_request.as_bytes())
+    );
+    let signature = hex(&hmac(
+        &signing_key(secret_key, date, region, SERVICE),
+        string_to_sign.as_bytes(),
+    ));
+    format!(
+        "AWS4-HMAC-SHA256 Credential={access_key}/{scope}, SignedHeaders={signed_headers}, Signature={signature}"
+    )
+}
+
+fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac(format!("AWS4{secret_key}").as_bytes(), date.as_bytes());
+    let key = hmac(&key, region.as_bytes());
+    let key = hmac(&key, service.as_bytes());
+    hmac(&key, b"aws4_request")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signing_key() {
+        // Example from the AWS Signature Version 4 documentation
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex(&key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+}
@@ -0,0 +1,137 @@
+#[cfg(feature = "awssm")]
+mod awssm;
+#[cfg(feature = "vault")]
+mod vault;
+
+use serde::{Deserialize, Serialize};
+use std::{convert::Infallible, fmt, str::FromStr};
+
+/// Sensitive string value (password, key, token).
+/// `Debug`/`Display` never print the inner value.
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    /// Expose the inner secret value
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(******)")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("******")
+    }
+}
+
+impl FromStr for Secret {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.to_owned()))
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+/// Secret reference to a HashiCorp Vault secret, `vault://<path>#<key>`
+pub const VAULT_SCHEME: &str = "vault://";
+/// Secret reference to an AWS Secrets Manager secret, `awssm://<name>[#<key>]`
+pub const AWSSM_SCHEME: &str = "awssm://";
+
+impl Secret {
+    /// Resolve a secret reference from its backend. Plain values are returned unchanged.
+    ///
+    /// Blocks on the backend request, call it before the runtime is started.
+    pub fn resolve(self) -> anyhow::Result<Self> {
+        if let Some(reference) = self.0.strip_prefix(VAULT_SCHEME) {
+            #[cfg(feature = "vault")]
+            return block_on(vault::fetch(reference)).map(Self);
+            #[cfg(not(feature = "vault"))]
+            anyhow::bail!("`{VAULT_SCHEME}{reference}` requires the `vault` feature");
+        }
+
+        if let Some(reference) = self.0.strip_prefix(AWSSM_SCHEME) {
+            #[cfg(feature = "awssm")]
+            return block_on(awssm::fetch(reference)).map(Self);
+            #[cfg(not(feature = "awssm"))]
+            anyhow::bail!("`{AWSSM_SCHEME}{reference}` requires the `awssm` feature");
+        }
+
+        Ok(self)
+    }
+}
+
+#[cfg(any(feature = "vault", feature = "awssm"))]
+fn block_on<F: std::future::Future>(future: F) -> anyhow::Result<F::Output> {
+    Ok(tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(future))
+}
+
+#[cfg(any(feature = "vault", feature = "awssm"))]
+fn client() -> anyhow::Result<reqwest::Client> {
+    Ok(reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(30))
+        .build()?)
+}
+
+/// Select `key` of a JSON secret object
+#[cfg(any(feature = "vault", feature = "awssm"))]
+fn select(data: Option<&serde_json::Value>, key: &str) -> anyhow::Result<String> {
+    match data.and_then(|data| data.get(key)) {
+        Some(serde_json::Value::String(value)) => Ok(value.to_owned()),
+        Some(value) if !value.is_null() => Ok(value.to_string()),
+        _ => anyhow::bail!("secret has no key `{key}`"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_plain_value() {
+        let secret = Secret::new("password").resolve().unwrap();
+        assert_eq!(secret.expose(), "password");
+    }
+
+    #[cfg(not(feature = "vault"))]
+    #[test]
+    fn test_resolve_requires_feature() {
+        let err = Secret::new("vault://secret/data/ninja#password")
+            .resolve()
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("requires the `vault` feature"), "{err}");
+    }
+
+    #[cfg(any(feature = "vault", feature = "awssm"))]
+    #[test]
+    fn test_select() {
+        let data = serde_json::json!({ "password": "secret", "port": 6379 });
+        assert_eq!(select(Some(&data), "password").unwrap(), "secret");
+        assert_eq!(select(Some(&data), "port").unwrap(), "6379");
+        assert!(select(Some(&data), "missing").is_err());
+    }
+}
@@ -0,0 +1,47 @@
+use anyhow::Context;
+
+/// Read `<key>` of the Vault secret at `<path>`.
+///
+/// Authenticates with `VAULT_TOKEN` (or `~/.vault-token`) against `VAULT_ADDR`,
+/// `VAULT_NAMESPACE` is sent when set. Both KV v1 and KV v2 secrets are supported,
+/// for KV v2 the path includes the `data/` segment, e.g. `secret/data/ninja`.
+pub(super) async fn fetch(reference: &str) -> anyhow::Result<String> {
+    let (path, key) = reference
+        .split_once('#')
+        .filter(|(path, key)| !path.is_empty() && !key.is_empty())
+        .ok_or_else(|| anyhow::anyhow!("Vault secret reference must be `vault://<path>#<key>`"))?;
+
+    let addr = std::env::var("VAULT_ADDR").context("VAULT_ADDR is not set")?;
+    let token = match std::env::var("VAULT_TOKEN") {
+        Ok(token) => token,
+        Err(_) => crate::homedir::home_dir()
+            .map(|home| home.join(".vault-token"))
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .map(|token| token.trim().to_owned())
+            .context("VAULT_TOKEN is not set and ~/.vault-token is missing")?,
+    };
+
+    let url = format!(
+        "{}/v1/{}",
+        addr.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    );
+    let mut request = super::client()?.get(url).header("X-Vault-Token", token);
+    if let Ok(namespace) = std::env::var("VAULT_NAMESPACE") {
+        request = request.header("X-Vault-Namespace", namespace);
+    }
+
+    let resp = request.send().await?;
+    let status = resp.status();
+    if !status.is_success() {
+        anyhow::bail!("Vault returned {status} reading `{path}`")
+    }
+
+    let body = resp.json::<serde_json::Value>().await?;
+    // KV v2 nests the secret under `data.data`
+    let data = body
+        .pointer("/data/data")
+        .filter(|data| data.is_object())
+        .or_else(|| body.get("data"));
+    super::select(data, key).with_context(|| format!("Vault secret `{path}`"))
+}
@@ -0,0 +1,752 @@
+use crate::serve::http2_downgrade;
+use crate::serve::metrics::{
+    CONNECTIONS_ACTIVE, CONNECTIONS_LIMIT, CONNECTIONS_PAUSED, CONNECTIONS_REJECTED, LISTENERS,
+    TLS_HANDSHAKES_FAILED_CLOSED, TLS_HANDSHAKES_INFLIGHT, TLS_HANDSHAKES_REJECTED,
+    TLS_HANDSHAKE_TIMEOUTS,
+};
+use crate::serve::tls::KeypairState;
+use crate::{debug, error, warn};
+use axum_hyper::server::conn::AddrStream;
+use axum_server::accept::Accept;
+use futures::future::BoxFuture;
+use pin_project_lite::pin_project;
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{atomic::Ordering, Arc},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Sleep;
+
+/// Bounds the TLS handshake of a connection and the handshakes in progress.
+/// Connections over the handshake limit are dropped immediately instead of queueing.
+/// A zero timeout or limit disables it.
+#[derive(Clone)]
+pub(crate) struct TlsHandshakeAcceptor<A> {
+    inner: A,
+    timeout: Duration,
+    handshakes: Option<Arc<Semaphore>>,
+}
+
+impl<A> TlsHandshakeAcceptor<A> {
+    pub(crate) fn new(inner: A, timeout: Duration, max_concurrent_handshakes: usize) -> Self {
+        Self {
+            inner,
+            timeout,
+            handshakes: (max_concurrent_handshakes > 0)
+                .then(|| Arc::new(Semaphore::new(max_concurrent_handshakes))),
+        }
+    }
+}
+
+impl<A, I, S> Accept<I, S> for TlsHandshakeAcceptor<A>
+where
+    A: Accept<I, S>,
+    A::Stream: Send + 'static,
+    A::Service: Send + 'static,
+    A::Future: Send + 'static,
+{
+    type Stream = A::Stream;
+    type Service = A::Service;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let permit = match self.handshakes.clone().map(Semaphore::try_acquire_owned) {
+            Some(Err(_)) => {
+                let rejected = TLS_HANDSHAKES_REJECTED.fetch_add(1, Ordering::Relaxed);
+                if rejected % 1000 == 0 {
+                    warn!(
+                        "TLS handshake limit reached, dropping connections ({} dropped)",
+                        rejected + 1
+                    );
+                }
+                drop(stream);
+                return Box::pin(async {
+                    Err(io::Error::new(
+                        io::ErrorKind::ConnectionRefused,
+                        "too many concurrent TLS handshakes",
+                    ))
+                });
+            }
+            Some(Ok(permit)) => Some(permit),
+            None => None,
+        };
+
+        let timeout = self.timeout;
+        let accept = self.inner.accept(stream, service);
+        Box::pin(async move {
+            TLS_HANDSHAKES_INFLIGHT.fetch_add(1, Ordering::Relaxed);
+            let result = if timeout.is_zero() {
+                accept.await
+            } else {
+                tokio::time::timeout(timeout, accept)
+                    .await
+                    .unwrap_or_else(|_| {
+                        TLS_HANDSHAKE_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+                        debug!("TLS handshake timeout, closing connection");
+                        Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "TLS handshake timeout",
+                        ))
+                    })
+            };
+            TLS_HANDSHAKES_INFLIGHT.fetch_sub(1, Ordering::Relaxed);
+            drop(permit);
+            result
+        })
+    }
+}
+
+/// Address of the peer of a connection
+pub(crate) trait PeerAddr {
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl PeerAddr for AddrStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.remote_addr())
+    }
+}
+
+/// Handshakes the connections of the addresses downgraded to HTTP/1.1
+/// (`http2_disable_for`) with the acceptor that does not offer `h2`
+#[derive(Clone)]
+pub(crate) struct Http2DowngradeAcceptor<A> {
+    inner: A,
+    http1: Option<A>,
+}
+
+impl<A> Http2DowngradeAcceptor<A> {
+    pub(crate) fn new(inner: A, http1: Option<A>) -> Self {
+        Self { inner, http1 }
+    }
+}
+
+impl<A, I, S> Accept<I, S> for Http2DowngradeAcceptor<A>
+where
+    A: Accept<I, S>,
+    I: PeerAddr,
+{
+    type Stream = A::Stream;
+    type Service = A::Service;
+    type Future = A::Future;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        match self.http1.as_ref() {
+            Some(http1)
+                if stream
+                    .peer_addr()
+                    .map_or(false, |addr| http2_downgrade::is_downgraded(addr.ip())) =>
+            {
+                http1.accept(stream, service)
+            }
+            _ => self.inner.accept(stream, service),
+        }
+    }
+}
+
+/// Rejects the TLS handshakes while the served keypair is invalid. The connection is
+/// closed, it is never served in plaintext.
+#[derive(Clone)]
+pub(crate) struct FailClosedAcceptor<A> {
+    inner: A,
+    keypair: KeypairState,
+}
+
+impl<A> FailClosedAcceptor<A> {
+    pub(crate) fn new(inner: A, keypair: KeypairState) -> Self {
+        Self { inner, keypair }
+    }
+}
+
+impl<A, I, S> Accept<I, S> for FailClosedAcceptor<A>
+where
+    A: Accept<I, S>,
+    A::Future: Send + 'static,
+{
+    type Stream = A::Stream;
+    type Service = A::Service;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        if !self.keypair.is_valid() {
+            let rejected = TLS_HANDSHAKES_FAILED_CLOSED.fetch_add(1, Ordering::Relaxed);
+            if rejected % 1000 == 0 {
+                error!(
+                    "TLS keypair is invalid, rejecting TLS handshakes ({} rejected)",
+                    rejected + 1
+                );
+            }
+            drop(stream);
+            return Box::pin(async {
+                Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    "TLS keypair is invalid",
+                ))
+            });
+        }
+        Box::pin(self.inner.accept(stream, service))
+    }
+}
+
+/// Bounds how long a client has to send the full request headers after connecting.
+/// A zero timeout disables it.
+#[derive(Clone)]
+pub(crate) struct HeaderReadTimeoutAcceptor<A> {
+    inner: A,
+    timeout: Duration,
+}
+
+impl<A> HeaderReadTimeoutAcceptor<A> {
+    pub(crate) fn new(inner: A, timeout: Duration) -> Self {
+        Self { inner, timeout }
+    }
+}
+
+impl<A, I, S> Accept<I, S> for HeaderReadTimeoutAcceptor<A>
+where
+    A: Accept<I, S>,
+    A::Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    A::Service: Send + 'static,
+    A::Future: Send + 'static,
+{
+    type Stream = HeaderReadTimeoutStream<A::Stream>;
+    type Service = A::Service;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let timeout = self.timeout;
+        let accept = self.inner.accept(stream, service);
+        Box::pin(async move {
+            let (stream, service) = accept.await?;
+            Ok((HeaderReadTimeoutStream::new(stream, timeout), service))
+        })
+    }
+}
+
+pin_project! {
+    /// Stream that fails with `TimedOut` if the first request headers are not
+    /// received before the deadline.
+    pub(crate) struct HeaderReadTimeoutStream<T> {
+        #[pin]
+        inner: T,
+        deadline: Option<Pin<Box<Sleep>>>,
+        newlines: u8,
+    }
+}
+
+impl<T> HeaderReadTimeoutStream<T> {
+    pub(crate) fn new(inner: T, timeout: Duration) -> Self {
+        Self {
+            inner,
+            deadline: (!timeout.is_zero()).then(|| Box::pin(tokio::time::sleep(timeout))),
+            newlines: 0,
+        }
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for HeaderReadTimeoutStream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+
+        let deadline = match this.deadline.as_mut() {
+            Some(deadline) => deadline,
+            None => return this.inner.poll_read(cx, buf),
+        };
+
+        if deadline.as_mut().poll(cx).is_ready() {
+            debug!("Header read timeout, closing connection");
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "header read timeout",
+            )));
+        }
+
+        let offset = buf.filled().len();
+        let poll = this.inner.poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            // End of headers: an empty line (CRLF CRLF or LF LF)
+            for byte in &buf.filled()[offset..] {
+                match byte {
+                    b'\n' => *this.newlines += 1,
+                    b'\r' => {}
+                    _ => *this.newlines = 0,
+                }
+                if *this.newlines == 2 {
+                    *this.deadline = None;
+                    break;
+                }
+            }
+        }
+        poll
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for HeaderReadTimeoutStream<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.project().inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+/// Caps the open connections of a listener. Over the cap, the server listener closes
+/// new connections and the admin listener stops accepting until an open one closes.
+/// A zero limit disables it, the connections are still counted.
+#[derive(Clone)]
+pub(crate) struct ConnectionLimit {
+    /// Index of the listener in `metrics::LISTENERS`
+    listener: usize,
+    slots: Option<Arc<Semaphore>>,
+}
+
+impl ConnectionLimit {
+    pub(crate) fn new(listener: usize, max_connections: usize) -> Self {
+        CONNECTIONS_LIMIT[listener].store(max_connections, Ordering::Relaxed);
+        Self {
+            listener,
+            slots: (max_connections > 0).then(|| Arc::new(Semaphore::new(max_connections))),
+        }
+    }
+
+    /// Take a connection slot, `None` when the limit is reached
+    pub(crate) fn try_acquire(&self) -> Option<Slot> {
+        let permit = match self.slots.clone().map(Semaphore::try_acquire_owned) {
+            Some(Err(_)) => {
+                let rejected = CONNECTIONS_REJECTED[self.listener].fetch_add(1, Ordering::Relaxed);
+                if rejected % 1000 == 0 {
+                    warn!(
+                        "Connection limit of the {} listener reached, closing connections ({} closed)",
+                        LISTENERS[self.listener],
+                        rejected + 1
+                    );
+                }
+                return None;
+            }
+            Some(Ok(permit)) => Some(permit),
+            None => None,
+        };
+        Some(Slot {
+            listener: self.listener,
+            _permit: permit,
+        })
+    }
+
+    /// Wait for a connection slot
+    pub(crate) async fn acquire(self) -> Slot {
+        let permit = match self.slots {
+            Some(slots) => match slots.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    let paused = CONNECTIONS_PAUSED[self.listener].fetch_add(1, Ordering::Relaxed);
+                    if paused % 1000 == 0 {
+                        warn!(
+                            "Connection limit of the {} listener reached, pausing connections ({} paused)",
+                            LISTENERS[self.listener],
+                            paused + 1
+                        );
+                    }
+                    // The semaphore is never closed
+                    slots.acquire_owned().await.ok()
+                }
+            },
+            None => None,
+        };
+        Slot {
+            listener: self.listener,
+            _permit: permit,
+        }
+    }
+}
+
+/// Slot of a connection in the limit, released when dropped
+pub(crate) struct Slot {
+    listener: usize,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Open connection counted in `ninja_connections_active`
+struct Active(usize);
+
+impl Active {
+    fn new(listener: usize) -> Self {
+        CONNECTIONS_ACTIVE[listener].fetch_add(1, Ordering::Relaxed);
+        Self(listener)
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        CONNECTIONS_ACTIVE[self.0].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pin_project! {
+    /// Connection holding its slot of the connection limit until it is closed
+    pub(crate) struct LimitedStream<T> {
+        #[pin]
+        inner: T,
+        _active: Active,
+        _slot: Slot,
+    }
+}
+
+impl<T> LimitedStream<T> {
+    pub(crate) fn new(inner: T, slot: Slot) -> Self {
+        Self {
+            inner,
+            _active: Active::new(slot.listener),
+            _slot: slot,
+        }
+    }
+}
+
+impl<T: PeerAddr> PeerAddr for LimitedStream<T> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for LimitedStream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.project().inner.poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for LimitedStream<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.project().inner.poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.project().inner.poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+/// Applies the connection limit before the inner acceptor, a connection over the limit
+/// is closed before it is handshaked
+#[derive(Clone)]
+pub(crate) struct ConnectionLimitAcceptor<A> {
+    inner: A,
+    limit: ConnectionLimit,
+}
+
+impl<A> ConnectionLimitAcceptor<A> {
+    pub(crate) fn new(inner: A, limit: ConnectionLimit) -> Self {
+        Self { inner, limit }
+    }
+}
+
+impl<A, I, S> Accept<I, S> for ConnectionLimitAcceptor<A>
+where
+    A: Accept<LimitedStream<I>, S>,
+    A::Future: Send + 'static,
+{
+    type Stream = A::Stream;
+    type Service = A::Service;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let Some(slot) = self.limit.try_acquire() else {
+            drop(stream);
+            return Box::pin(async {
+                Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    "connection limit reached",
+                ))
+            });
+        };
+        let accept = self.inner.accept(LimitedStream::new(stream, slot), service);
+        Box::pin(accept)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Handshake that completes when the client sends one byte
+    #[derive(Clone)]
+    struct ByteHandshake;
+
+    impl<S: Send + 'static> Accept<TcpStream, S> for ByteHandshake {
+        type Stream = TcpStream;
+        type Service = S;
+        type Future = BoxFuture<'static, io::Result<(TcpStream, S)>>;
+
+        fn accept(&self, mut stream: TcpStream, service: S) -> Self::Future {
+            Box::pin(async move {
+                stream.read_exact(&mut [0u8; 1]).await?;
+                Ok((stream, service))
+            })
+        }
+    }
+
+    /// Accept loop spawning a task per connection, the handshaked connections answer `ok`
+    async fn serve(acceptor: TlsHandshakeAcceptor<ByteHandshake>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok((mut stream, ())) = acceptor.accept(stream, ()).await {
+                        let _ = stream.write_all(b"ok").await;
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    async fn request(addr: SocketAddr) -> io::Result<Vec<u8>> {
+        let mut client = TcpStream::connect(addr).await?;
+        client.write_all(b"H").await?;
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await?;
+        Ok(buf)
+    }
+
+    #[tokio::test]
+    async fn test_stalled_handshakes_do_not_block_clients() {
+        let acceptor = TlsHandshakeAcceptor::new(ByteHandshake, Duration::from_secs(10), 1024);
+        let addr = serve(acceptor).await;
+
+        // Sockets that never complete the handshake
+        let mut stalled = Vec::new();
+        for _ in 0..64 {
+            stalled.push(TcpStream::connect(addr).await.unwrap());
+        }
+
+        let resp = tokio::time::timeout(Duration::from_secs(1), request(addr))
+            .await
+            .expect("client was not answered promptly")
+            .unwrap();
+        assert_eq!(resp, b"ok");
+    }
+
+    #[tokio::test]
+    async fn test_handshake_limit_drops_excess() {
+        let acceptor = TlsHandshakeAcceptor::new(ByteHandshake, Duration::from_secs(10), 2);
+        let addr = serve(acceptor).await;
+        let rejected = TLS_HANDSHAKES_REJECTED.load(Ordering::Relaxed);
+
+        let _stalled = [
+            TcpStream::connect(addr).await.unwrap(),
+            TcpStream::connect(addr).await.unwrap(),
+        ];
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // Over the limit, closed without waiting for the handshake
+        let mut excess = TcpStream::connect(addr).await.unwrap();
+        let mut buf = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(1), excess.read_to_end(&mut buf))
+            .await
+            .expect("excess connection was queued");
+        assert!(read.map_or(true, |n| n == 0));
+        assert!(TLS_HANDSHAKES_REJECTED.load(Ordering::Relaxed) > rejected);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let acceptor = TlsHandshakeAcceptor::new(ByteHandshake, Duration::from_millis(100), 0);
+        let addr = serve(acceptor).await;
+
+        let mut stalled = TcpStream::connect(addr).await.unwrap();
+        let mut buf = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(1), stalled.read_to_end(&mut buf))
+            .await
+            .expect("stalled handshake was not timed out");
+        assert!(read.map_or(true, |n| n == 0));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_keypair_fails_closed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let keypair = KeypairState::default();
+        let cert = crate::serve::tls::load_certs(
+            std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures/tls/cert.pem"),
+        )
+        .unwrap();
+        keypair.update(&cert[0]).unwrap();
+
+        let acceptor = FailClosedAcceptor::new(ByteHandshake, keypair.clone());
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok((mut stream, ())) = acceptor.accept(stream, ()).await {
+                        let _ = stream.write_all(b"ok").await;
+                    }
+                });
+            }
+        });
+        assert_eq!(request(addr).await.unwrap(), b"ok");
+
+        // Once the keypair is invalid, the connections are closed without a response
+        keypair.invalidate();
+        let rejected = TLS_HANDSHAKES_FAILED_CLOSED.load(Ordering::Relaxed);
+        let resp = request(addr).await;
+        assert!(resp.map_or(true, |resp| resp.is_empty()));
+        assert!(TLS_HANDSHAKES_FAILED_CLOSED.load(Ordering::Relaxed) > rejected);
+    }
+
+    #[tokio::test]
+    async fn test_slow_header_sender() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut server = HeaderReadTimeoutStream::new(server, Duration::from_millis(100));
+
+        // Send the request line, but never finish the headers
+        client.write_all(b"GET / HTTP/1.1\r\nHost: ").await.unwrap();
+
+        let mut buf = [0u8; 1024];
+        let n = server.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"GET / HTTP/1.1\r\nHost: ");
+
+        let err = server.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_complete_header_disarms_timeout() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut server = HeaderReadTimeoutStream::new(server, Duration::from_millis(100));
+
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; 1024];
+        server.read(&mut buf).await.unwrap();
+
+        // Idle keep-alive connection is no longer bound by the header deadline
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        client.write_all(b"GET").await.unwrap();
+        let n = server.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"GET");
+    }
+
+    /// Acceptor serving the connection as it is
+    #[derive(Clone)]
+    struct Plain;
+
+    impl<S: Send + 'static> Accept<LimitedStream<TcpStream>, S> for Plain {
+        type Stream = LimitedStream<TcpStream>;
+        type Service = S;
+        type Future = BoxFuture<'static, io::Result<(Self::Stream, S)>>;
+
+        fn accept(&self, stream: LimitedStream<TcpStream>, service: S) -> Self::Future {
+            Box::pin(async move { Ok((stream, service)) })
+        }
+    }
+
+    /// Whether the connection echoes a byte in time
+    async fn echo(client: &mut TcpStream, timeout: Duration) -> bool {
+        client.write_all(b"x").await.unwrap();
+        let mut byte = [0u8; 1];
+        matches!(
+            tokio::time::timeout(timeout, client.read_exact(&mut byte)).await,
+            Ok(Ok(_))
+        )
+    }
+
+    async fn connect_echo(addr: SocketAddr) -> TcpStream {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        assert!(echo(&mut client, Duration::from_secs(1)).await);
+        client
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit_closes_excess() {
+        // Metrics of the admin listener, not used by the other tests
+        const LISTENER: usize = crate::serve::metrics::ADMIN_LISTENER;
+        let acceptor = ConnectionLimitAcceptor::new(Plain, ConnectionLimit::new(LISTENER, 2));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok((mut stream, ())) = acceptor.accept(stream, ()).await {
+                        let mut byte = [0u8; 1];
+                        while stream.read_exact(&mut byte).await.is_ok() {
+                            let _ = stream.write_all(&byte).await;
+                        }
+                    }
+                });
+            }
+        });
+        let first = connect_echo(addr).await;
+        let _second = connect_echo(addr).await;
+        let rejected = CONNECTIONS_REJECTED[LISTENER].load(Ordering::Relaxed);
+
+        // Over the cap, the connection is closed without holding a task
+        let mut excess = TcpStream::connect(addr).await.unwrap();
+        let mut byte = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(1), excess.read(&mut byte))
+            .await
+            .expect("excess connection was left open");
+        assert!(matches!(read, Ok(0) | Err(_)));
+        assert_eq!(
+            CONNECTIONS_REJECTED[LISTENER].load(Ordering::Relaxed),
+            rejected + 1
+        );
+        assert_eq!(CONNECTIONS_ACTIVE[LISTENER].load(Ordering::Relaxed), 2);
+
+        // Served once an open connection closes
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        connect_echo(addr).await;
+    }
+}
@@ -0,0 +1,75 @@
+//! Access log of the main listener. The events are emitted by [`log`], which runs inside
+//! the request span of the trace layer, so the sampling decision and both events of a
+//! request are made in one place.
+
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use rand::Rng;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+/// Fraction of the requests that are access-logged
+static SAMPLE_RATE: OnceLock<f64> = OnceLock::new();
+
+pub(super) fn init(sample_rate: f64) -> anyhow::Result<()> {
+    if !(0.0..=1.0).contains(&sample_rate) {
+        anyhow::bail!("log_sample_rate must be between 0.0 and 1.0, got {sample_rate}")
+    }
+    let _ = SAMPLE_RATE.set(sample_rate);
+    Ok(())
+}
+
+/// Start and response events of the sampled requests, runs inside the trace span
+pub(super) async fn log<B>(request: Request<B>, next: Next<B>) -> axum::response::Response {
+    let rate = SAMPLE_RATE.get().copied().unwrap_or(1.0);
+    let sampled = sampled(rate, &mut rand::thread_rng());
+    if sampled {
+        tracing::info!("started processing request");
+    }
+
+    let start = Instant::now();
+    let resp = next.run(request).await;
+    if should_log_response(sampled, resp.status()) {
+        tracing::info!(
+            latency = format_args!("{} ms", start.elapsed().as_millis()),
+            status = resp.status().as_u16(),
+            "finished processing request"
+        );
+    }
+    resp
+}
+
+fn sampled(rate: f64, rng: &mut impl Rng) -> bool {
+    rate >= 1.0 || (rate > 0.0 && rng.gen_bool(rate))
+}
+
+/// Server errors are logged regardless of the sampling
+fn should_log_response(sampled: bool, status: StatusCode) -> bool {
+    sampled || status.is_server_error()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_rate_bounds() {
+        let mut rng = rand::thread_rng();
+        assert!((0..1000).all(|_| sampled(1.0, &mut rng)));
+        assert!((0..1000).all(|_| !sampled(0.0, &mut rng)));
+
+        let hits = (0..10_000).filter(|_| sampled(0.1, &mut rng)).count();
+        assert!((500..1500).contains(&hits), "{hits}");
+
+        assert!(init(1.5).is_err());
+        assert!(init(-0.1).is_err());
+    }
+
+    #[test]
+    fn test_errors_bypass_sampling() {
+        assert!(!should_log_response(false, StatusCode::OK));
+        assert!(!should_log_response(false, StatusCode::TOO_MANY_REQUESTS));
+        assert!(should_log_response(false, StatusCode::BAD_GATEWAY));
+        assert!(should_log_response(true, StatusCode::OK));
+    }
+}
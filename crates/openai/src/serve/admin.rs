@@ -0,0 +1,75 @@
+//! Access to the admin endpoints reachable over the network. Without `admin_listen`
+//! the admin endpoints share the public listener, so they require the `auth_key`, or
+//! a loopback client when no `auth_key` is set. An `admin_listen` that is not a
+//! loopback address requires the `auth_key` and is gated the same way.
+
+use crate::serve::error::{ProxyError, ResponseError};
+use crate::sign::constant_time_eq;
+use crate::with_context;
+use axum::extract::ConnectInfo;
+use axum::http::{header, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::net::{IpAddr, SocketAddr};
+
+/// Gate of the admin endpoints on the main listener or a non-loopback admin listener
+pub(super) async fn gate<B>(request: Request<B>, next: Next<B>) -> Result<Response, ResponseError> {
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    check(with_context!(auth_key), bearer, peer)?;
+    Ok(next.run(request).await)
+}
+
+fn check(
+    auth_key: Option<&str>,
+    bearer: Option<&str>,
+    peer: Option<IpAddr>,
+) -> Result<(), ResponseError> {
+    match auth_key {
+        Some(auth_key) => match bearer {
+            None => Err(ResponseError::Unauthorized(ProxyError::AuthKeyRequired)),
+            Some(bearer) if !constant_time_eq(bearer.as_bytes(), auth_key.as_bytes()) => {
+                Err(ResponseError::Forbidden(ProxyError::AuthKeyError))
+            }
+            Some(_) => Ok(()),
+        },
+        None if peer.map_or(false, |ip| ip.is_loopback()) => Ok(()),
+        None => Err(ResponseError::Forbidden(ProxyError::AdminLoopbackOnly)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::response::IntoResponse;
+
+    fn status(result: Result<(), ResponseError>) -> u16 {
+        match result {
+            Ok(()) => 200,
+            Err(err) => err.into_response().status().as_u16(),
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let local = Some("127.0.0.1".parse().unwrap());
+        let remote = Some("203.0.113.7".parse().unwrap());
+
+        // Without auth_key, loopback clients only
+        assert_eq!(status(check(None, None, local)), 200);
+        assert_eq!(status(check(None, None, remote)), 403);
+        assert_eq!(status(check(None, None, None)), 403);
+
+        // With auth_key, any client presenting it
+        assert_eq!(status(check(Some("key"), Some("key"), remote)), 200);
+        assert_eq!(status(check(Some("key"), None, local)), 401);
+        assert_eq!(status(check(Some("key"), Some("other"), local)), 403);
+    }
+}
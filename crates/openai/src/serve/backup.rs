@@ -0,0 +1,73 @@
+//! State backup endpoint, `GET /admin/state` answers a state archive of the worker
+//! directory in the format of `ninja state export`, for scheduled backups.
+
+use crate::homedir::home_dir;
+use crate::secret::Secret;
+use crate::serve::error::{ProxyError, ResponseError};
+use crate::{context, info, state, with_context};
+use axum::headers::authorization::Bearer;
+use axum::headers::Authorization;
+use axum::http::{header, HeaderValue};
+use axum::response::{IntoResponse, Response};
+use axum::TypedHeader;
+use std::sync::OnceLock;
+
+/// State key of the archives, `None` disables the endpoint
+static STATE_KEY: OnceLock<Option<Secret>> = OnceLock::new();
+
+pub(super) fn init(state_key: Option<&Secret>) {
+    if state_key.is_some() {
+        info!("State backup endpoint enabled");
+    }
+    let _ = STATE_KEY.set(state_key.cloned());
+}
+
+/// GET /admin/state
+pub(super) async fn state(
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<Response, ResponseError> {
+    // Require auth key
+    if let Some(auth_key) = with_context!(auth_key) {
+        let bearer =
+            bearer.ok_or_else(|| ResponseError::Unauthorized(ProxyError::AuthKeyRequired))?;
+        if auth_key.ne(bearer.token()) {
+            return Err(ResponseError::Forbidden(ProxyError::AuthKeyError));
+        }
+    }
+
+    let key = STATE_KEY
+        .get()
+        .and_then(Option::as_ref)
+        .cloned()
+        .ok_or_else(|| ResponseError::NotFound(anyhow::anyhow!("State key is not configured")))?;
+    let state_dir = home_dir()
+        .ok_or_else(|| {
+            ResponseError::InternalServerError(anyhow::anyhow!("Failed to get home directory"))
+        })?
+        .join(context::WORKER_DIR);
+
+    let archive = tokio::task::spawn_blocking(move || {
+        let mut archive = Vec::new();
+        let manifest = state::export(&state_dir, &key, &mut archive)?;
+        info!("State backup of {} files", manifest.files.len());
+        anyhow::Ok(archive)
+    })
+    .await
+    .map_err(ResponseError::InternalServerError)?
+    .map_err(ResponseError::InternalServerError)?;
+
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/x-tar"),
+            ),
+            (
+                header::CONTENT_DISPOSITION,
+                HeaderValue::from_static("attachment; filename=\"state.tar\""),
+            ),
+        ],
+        archive,
+    )
+        .into_response())
+}
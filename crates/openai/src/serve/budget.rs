@@ -0,0 +1,988 @@
+use crate::context::args::{ModelPrice, Pricing};
+use crate::homedir::home_dir;
+use crate::serve::checkpoint::Volatile;
+use crate::serve::error::{ProxyError, ResponseError};
+use crate::serve::memory;
+use crate::serve::model_policy::{self, glob_match, KeyPolicy, ModelPolicy};
+use crate::{context, error, info, token, warn, with_context};
+use axum::body::{Bytes, HttpBody};
+use axum::extract::Path;
+use axum::headers::authorization::Bearer;
+use axum::headers::Authorization;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::{Json, TypedHeader};
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use native_db::*;
+use native_model::{native_model, Model};
+use pin_project_lite::pin_project;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Mutex, OnceLock};
+use std::task::{Context, Poll};
+
+/// Remaining budget of the key (USD), before the request
+pub(super) const BUDGET_REMAINING: &str = "x-budget-remaining";
+
+/// Event stream lines longer than this are not inspected for the usage
+const MAX_LINE: usize = 1024 * 1024;
+
+/// Bytes per token of the usage estimates, of the reservations and of the responses
+/// without a reported usage
+const BYTES_PER_TOKEN: u64 = 4;
+
+/// Key budgets, `None` until the model policies are configured
+static BUDGETS: OnceLock<Budgets> = OnceLock::new();
+
+/// Spend records, in micro-dollars
+pub(super) trait SpendStore: Volatile {
+    /// Add the amount to every record in a single transaction
+    fn add(&self, ids: &[String], micros: u64) -> anyhow::Result<()>;
+
+    fn get(&self, id: &str) -> anyhow::Result<u64>;
+}
+
+#[derive(Default)]
+pub(super) struct MemSpendStore(Mutex<HashMap<String, u64>>);
+
+impl SpendStore for MemSpendStore {
+    fn add(&self, ids: &[String], micros: u64) -> anyhow::Result<()> {
+        let mut records = self
+            .0
+            .lock()
+            .map_err(|_| anyhow::anyhow!("spend store lock poisoned"))?;
+        for id in ids {
+            let record = records.entry(id.clone()).or_default();
+            *record = record.saturating_add(micros);
+        }
+        Ok(())
+    }
+
+    fn get(&self, id: &str) -> anyhow::Result<u64> {
+        let records = self
+            .0
+            .lock()
+            .map_err(|_| anyhow::anyhow!("spend store lock poisoned"))?;
+        Ok(records.get(id).copied().unwrap_or_default())
+    }
+}
+
+impl Volatile for MemSpendStore {
+    fn snapshot(&self) -> Option<serde_json::Value> {
+        let records = self.0.lock().ok()?;
+        serde_json::to_value(&*records).ok()
+    }
+
+    /// Spend records are per period, the larger of the saved and current record is kept
+    fn restore(&self, saved: serde_json::Value, _expired_before: u64) -> anyhow::Result<()> {
+        let saved = serde_json::from_value::<HashMap<String, u64>>(saved)?;
+        let mut records = self
+            .0
+            .lock()
+            .map_err(|_| anyhow::anyhow!("spend store lock poisoned"))?;
+        for (id, micros) in saved {
+            let record = records.entry(id).or_default();
+            *record = (*record).max(micros);
+        }
+        Ok(())
+    }
+}
+
+static DATABASE_BUILDER: OnceLock<DatabaseBuilder> = OnceLock::new();
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[native_model(id = 1, version = 1)]
+#[native_db]
+struct SpendRecord {
+    #[primary_key]
+    id: String,
+    micros: u64,
+}
+
+struct ReDBSpendStore(Database<'static>);
+
+impl ReDBSpendStore {
+    fn new() -> anyhow::Result<Self> {
+        let builder = DATABASE_BUILDER.get_or_init(|| {
+            let mut builder = DatabaseBuilder::new();
+            builder
+                .define::<SpendRecord>()
+                .expect("define table failed");
+            builder
+        });
+        let db = builder.create(
+            home_dir()
+                .unwrap_or_default()
+                .join(context::WORKER_DIR)
+                .join("budget.db"),
+        )?;
+        Ok(Self(db))
+    }
+}
+
+impl Volatile for ReDBSpendStore {
+    fn snapshot(&self) -> Option<serde_json::Value> {
+        None
+    }
+
+    fn restore(&self, _saved: serde_json::Value, _expired_before: u64) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+impl SpendStore for ReDBSpendStore {
+    fn add(&self, ids: &[String], micros: u64) -> anyhow::Result<()> {
+        let rw = self.0.rw_transaction()?;
+        for id in ids {
+            let record: Option<SpendRecord> = rw.get().primary(id.clone())?;
+            match record {
+                Some(old) => {
+                    let new = SpendRecord {
+                        id: id.clone(),
+                        micros: old.micros.saturating_add(micros),
+                    };
+                    rw.update(old, new)?;
+                }
+                None => rw.insert(SpendRecord {
+                    id: id.clone(),
+                    micros,
+                })?,
+            }
+        }
+        rw.commit()?;
+        Ok(())
+    }
+
+    fn get(&self, id: &str) -> anyhow::Result<u64> {
+        let r = self.0.r_transaction()?;
+        let record: Option<SpendRecord> = r.get().primary(id.to_owned())?;
+        Ok(record.map_or(0, |record| record.micros))
+    }
+}
+
+/// Budget periods of an instant, UTC day and month
+#[derive(Clone, Debug, PartialEq)]
+struct Periods {
+    day: NaiveDate,
+}
+
+impl Periods {
+    fn of(now: DateTime<Utc>) -> Self {
+        Self {
+            day: now.date_naive(),
+        }
+    }
+
+    fn day_id(&self, key_id: &str) -> String {
+        format!("{key_id}/day/{}", self.day.format("%Y-%m-%d"))
+    }
+
+    fn month_id(&self, key_id: &str) -> String {
+        format!("{key_id}/month/{}", self.day.format("%Y-%m"))
+    }
+
+    /// Start of the next period of the exhausted budget
+    fn resets_at(&self, period: Period) -> Option<DateTime<Utc>> {
+        let next = match period {
+            Period::Daily => self.day.succ_opt()?,
+            Period::Monthly => {
+                let (year, month) = match self.day.month() {
+                    12 => (self.day.year() + 1, 1),
+                    month => (self.day.year(), month + 1),
+                };
+                NaiveDate::from_ymd_opt(year, month, 1)?
+            }
+        };
+        Some(next.and_hms_opt(0, 0, 0)?.and_utc())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Period {
+    Daily,
+    Monthly,
+}
+
+impl Period {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Daily => "daily",
+            Self::Monthly => "monthly",
+        }
+    }
+}
+
+/// Token usage reported by the upstream
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+struct Usage {
+    #[serde(default, alias = "input_tokens")]
+    prompt_tokens: u64,
+    #[serde(default, alias = "output_tokens")]
+    completion_tokens: u64,
+}
+
+/// Response body, or event stream chunk, carrying the usage
+#[derive(Deserialize)]
+struct UsageReport {
+    model: Option<String>,
+    usage: Option<Usage>,
+    #[serde(default)]
+    choices: Vec<serde_json::Value>,
+}
+
+impl UsageReport {
+    /// Length of the completion text of the choices, the message of a response or
+    /// the delta of a stream chunk
+    fn completion_len(&self) -> usize {
+        self.choices
+            .iter()
+            .filter_map(|choice| {
+                choice
+                    .pointer("/delta/content")
+                    .or_else(|| choice.pointer("/message/content"))
+                    .or_else(|| choice.get("text"))
+                    .and_then(serde_json::Value::as_str)
+            })
+            .map(str::len)
+            .sum()
+    }
+}
+
+/// Output token limits of a request body
+#[derive(Deserialize)]
+struct RequestLimits {
+    max_tokens: Option<u64>,
+    max_completion_tokens: Option<u64>,
+    max_output_tokens: Option<u64>,
+}
+
+/// Usage estimate of a request: the prompt from the body size, the completion from
+/// its output token limit (none when unset)
+fn estimate(body: Option<&[u8]>) -> Usage {
+    let Some(body) = body else {
+        return Usage::default();
+    };
+    let completion_tokens = serde_json::from_slice::<RequestLimits>(body)
+        .ok()
+        .and_then(|limits| {
+            limits
+                .max_completion_tokens
+                .or(limits.max_tokens)
+                .or(limits.max_output_tokens)
+        })
+        .unwrap_or_default();
+    Usage {
+        prompt_tokens: body.len() as u64 / BYTES_PER_TOKEN,
+        completion_tokens,
+    }
+}
+
+/// Spend of a key in the current periods (micro-dollars)
+struct Spend {
+    daily: u64,
+    monthly: u64,
+}
+
+enum Admission {
+    Allowed(u64),
+    Exceeded(Period),
+}
+
+pub(super) struct Budgets {
+    store: Box<dyn SpendStore>,
+    pricing: Pricing,
+    /// Estimated spend of the requests admitted by this instance and not yet recorded,
+    /// by spend record. Admission checks and reserves under its lock.
+    pending: Mutex<HashMap<String, u64>>,
+}
+
+impl Budgets {
+    /// Price of the model, an exact name before the longest matching glob
+    fn price(&self, model: &str) -> Option<ModelPrice> {
+        if let Some(price) = self.pricing.0.get(model) {
+            return Some(*price);
+        }
+        self.pricing
+            .0
+            .iter()
+            .filter(|(pattern, _)| glob_match(pattern, model))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map(|(_, price)| *price)
+    }
+
+    /// Cost of the usage (micro-dollars), the prices are USD per 1M tokens
+    fn cost(&self, model: &str, usage: Usage) -> u64 {
+        match self.price(model) {
+            Some(price) => price_usage(price, usage),
+            None => {
+                warn!("No pricing for model `{model}`, its usage is not accounted");
+                0
+            }
+        }
+    }
+
+    fn spend(&self, key_id: &str, periods: &Periods) -> anyhow::Result<Spend> {
+        Ok(Spend {
+            daily: self.store.get(&periods.day_id(key_id))?,
+            monthly: self.store.get(&periods.month_id(key_id))?,
+        })
+    }
+
+    /// Check the key's spend, with the pending reservations, against its limits and
+    /// reserve `reserve` micro-dollars for the admitted request. Returns the remaining
+    /// budget before the request.
+    fn admit(
+        &self,
+        key_id: &str,
+        policy: &ModelPolicy,
+        periods: &Periods,
+        reserve: u64,
+    ) -> anyhow::Result<Admission> {
+        let mut pending = self
+            .pending
+            .lock()
+            .map_err(|_| anyhow::anyhow!("budget reservations lock poisoned"))?;
+        let spend = self.spend(key_id, periods)?;
+        let ids = [periods.day_id(key_id), periods.month_id(key_id)];
+        let mut remaining = u64::MAX;
+        for ((period, limit, spent), id) in [
+            (Period::Daily, policy.budget_daily, spend.daily),
+            (Period::Monthly, policy.budget_monthly, spend.monthly),
+        ]
+        .into_iter()
+        .zip(&ids)
+        {
+            if let Some(limit) = limit.map(usd_to_micros) {
+                let spent = spent.saturating_add(pending.get(id).copied().unwrap_or_default());
+                if spent >= limit {
+                    return Ok(Admission::Exceeded(period));
+                }
+                remaining = remaining.min(limit - spent);
+            }
+        }
+        if reserve > 0 {
+            for id in ids {
+                *pending.entry(id).or_default() += reserve;
+            }
+        }
+        Ok(Admission::Allowed(remaining))
+    }
+
+    /// Release the reservation of a request, once its spend is recorded
+    fn release(&self, key_id: &str, periods: &Periods, reserved: u64) {
+        if reserved == 0 {
+            return;
+        }
+        let Ok(mut pending) = self.pending.lock() else {
+            return;
+        };
+        for id in [periods.day_id(key_id), periods.month_id(key_id)] {
+            if let Some(micros) = pending.get_mut(&id) {
+                *micros = micros.saturating_sub(reserved);
+                if *micros == 0 {
+                    pending.remove(&id);
+                }
+            }
+        }
+    }
+
+    fn record(&self, key_id: &str, periods: &Periods, model: &str, usage: Usage) {
+        let cost = self.cost(model, usage);
+        if cost == 0 {
+            return;
+        }
+        let ids = [periods.day_id(key_id), periods.month_id(key_id)];
+        if let Err(err) = self.store.add(&ids, cost) {
+            error!("Failed to record the spend of `{key_id}`: {err}");
+        }
+    }
+}
+
+/// Cost of the usage at a price (micro-dollars)
+fn price_usage(price: ModelPrice, usage: Usage) -> u64 {
+    (price.input * usage.prompt_tokens as f64 + price.output * usage.completion_tokens as f64)
+        .round() as u64
+}
+
+fn usd_to_micros(usd: f64) -> u64 {
+    (usd * 1_000_000.0).round().max(0.0) as u64
+}
+
+fn micros_to_usd(micros: u64) -> f64 {
+    micros as f64 / 1_000_000.0
+}
+
+/// Enable the key budgets with the given spend store strategy (mem/redb)
+pub(super) fn init(store: &str, pricing: Pricing) -> anyhow::Result<()> {
+    let store: Box<dyn SpendStore> = match store {
+        "mem" => Box::<MemSpendStore>::default(),
+        "redb" => Box::new(ReDBSpendStore::new()?),
+        _ => anyhow::bail!("budget store: {store} is not supported"),
+    };
+    info!("Key budgets: {} priced models", pricing.0.len());
+    let _ = BUDGETS.set(Budgets {
+        store,
+        pricing,
+        pending: Mutex::default(),
+    });
+    Ok(())
+}
+
+/// Spend records of the key budgets in the checkpoint, empty until the budgets are enabled
+pub(super) struct BudgetSpend;
+
+impl Volatile for BudgetSpend {
+    fn snapshot(&self) -> Option<serde_json::Value> {
+        BUDGETS.get()?.store.snapshot()
+    }
+
+    fn restore(&self, saved: serde_json::Value, expired_before: u64) -> anyhow::Result<()> {
+        match BUDGETS.get() {
+            Some(budgets) => budgets.store.restore(saved, expired_before),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Budget of a request admitted under the key's limits, its reservation is released
+/// when dropped
+pub(super) struct Budget {
+    budgets: &'static Budgets,
+    key_id: String,
+    periods: Periods,
+    /// Remaining before the request (micro-dollars)
+    remaining: u64,
+    /// Usage estimate of the request
+    estimate: Usage,
+    /// Reserved at admission (micro-dollars)
+    reserved: u64,
+}
+
+impl Drop for Budget {
+    fn drop(&mut self) {
+        self.budgets
+            .release(&self.key_id, &self.periods, self.reserved);
+    }
+}
+
+/// Check the key's budget before the request is sent upstream, and reserve the
+/// estimated cost of the request until its spend is recorded, so concurrent requests
+/// are not all admitted on the same remaining budget.
+/// Returns the 429 response if the key's budget is exhausted.
+pub(super) fn admit(
+    key_policy: Option<&KeyPolicy>,
+    model: Option<&str>,
+    body: Option<&[u8]>,
+) -> Result<Option<Budget>, Response> {
+    let (budgets, key_policy) = match (BUDGETS.get(), key_policy) {
+        (Some(budgets), Some(key_policy)) => (budgets, key_policy),
+        _ => return Ok(None),
+    };
+    let policy = &key_policy.policy;
+    if policy.budget_daily.is_none() && policy.budget_monthly.is_none() {
+        return Ok(None);
+    }
+
+    let key_id = key_policy.key.id();
+    let periods = Periods::of(Utc::now());
+    let estimate = estimate(body);
+    let reserve = model
+        .and_then(|model| budgets.price(model))
+        .map_or(0, |price| price_usage(price, estimate));
+    match budgets.admit(&key_id, policy, &periods, reserve) {
+        Ok(Admission::Allowed(remaining)) => Ok(Some(Budget {
+            budgets,
+            key_id,
+            periods,
+            remaining,
+            estimate,
+            reserved: reserve,
+        })),
+        Ok(Admission::Exceeded(period)) => Err(budget_exceeded_response(&periods, period)),
+        Err(err) => {
+            // The request is not blocked when the store fails
+            error!("Failed to read the spend of `{key_id}`: {err}");
+            Ok(None)
+        }
+    }
+}
+
+/// Add the remaining budget header and record the response usage, once the body
+/// is finished for event streams
+pub(super) async fn account(
+    mut resp: Response,
+    budget: Option<Budget>,
+    model: Option<String>,
+) -> Result<Response, ResponseError> {
+    let budget = match budget {
+        Some(budget) => budget,
+        None => return Ok(resp),
+    };
+
+    if let Ok(value) = HeaderValue::from_str(&format!("{:.6}", micros_to_usd(budget.remaining))) {
+        resp.headers_mut().insert(BUDGET_REMAINING, value);
+    }
+
+    if !resp.status().is_success() {
+        return Ok(resp);
+    }
+
+    let mut meter = Meter {
+        budget,
+        model,
+        usage: None,
+        completion_len: 0,
+    };
+
+    if is_content_type(resp.headers(), mime::TEXT_EVENT_STREAM.as_ref()) {
+        return Ok(resp.map(|body| {
+            axum::body::boxed(MeteredStream {
+                inner: body,
+                line: Vec::new(),
+                meter: Some(meter),
+            })
+        }));
+    }
+
+    if !is_content_type(resp.headers(), mime::APPLICATION_JSON.as_ref()) {
+        return Ok(resp);
+    }
+
+    let (parts, body) = resp.into_parts();
+    let (data, reservation) = memory::read(body).await?;
+    meter.observe(&data);
+    drop(meter);
+
+    Ok(Response::from_parts(parts, memory::body(data, reservation)))
+}
+
+fn is_content_type(headers: &HeaderMap, content_type: &str) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.starts_with(content_type))
+}
+
+/// Usage of a response, recorded when dropped. Without a usage reported by the upstream
+/// (event streams without `stream_options.include_usage`, ChatGPT backend responses),
+/// the prompt estimate and the completion text length are charged.
+struct Meter {
+    budget: Budget,
+    model: Option<String>,
+    usage: Option<Usage>,
+    /// Completion text bytes seen
+    completion_len: usize,
+}
+
+impl Meter {
+    fn observe(&mut self, json: &[u8]) {
+        if let Ok(report) = serde_json::from_slice::<UsageReport>(json) {
+            self.completion_len += report.completion_len();
+            if let Some(usage) = report.usage {
+                self.usage = Some(usage);
+            }
+            if let Some(model) = report.model {
+                self.model = Some(model);
+            }
+        }
+    }
+}
+
+impl Drop for Meter {
+    fn drop(&mut self) {
+        let budget = &self.budget;
+        let usage = self.usage.take().unwrap_or(Usage {
+            prompt_tokens: budget.estimate.prompt_tokens,
+            completion_tokens: self.completion_len as u64 / BYTES_PER_TOKEN,
+        });
+        if let Some(model) = self.model.as_deref() {
+            budget
+                .budgets
+                .record(&budget.key_id, &budget.periods, model, usage);
+        }
+    }
+}
+
+pin_project! {
+    /// Event stream body that picks the usage from the `data:` events. The spend is
+    /// recorded when the stream completes (or is dropped).
+    struct MeteredStream<B> {
+        #[pin]
+        inner: B,
+        line: Vec<u8>,
+        meter: Option<Meter>,
+    }
+}
+
+impl<B: HttpBody<Data = Bytes>> HttpBody for MeteredStream<B> {
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.project();
+        let poll = this.inner.poll_data(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if let Some(meter) = this.meter.as_mut() {
+                    for byte in chunk.iter() {
+                        if *byte != b'\n' {
+                            if this.line.len() < MAX_LINE {
+                                this.line.push(*byte);
+                            }
+                            continue;
+                        }
+                        if let Some(data) = this.line.strip_prefix(b"data:") {
+                            meter.observe(data);
+                        }
+                        this.line.clear();
+                    }
+                }
+            }
+            // Stream completed, record the spend
+            Poll::Ready(None) => drop(this.meter.take()),
+            _ => {}
+        }
+        poll
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        self.project().inner.poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+}
+
+/// 429 response for a key whose budget is exhausted
+fn budget_exceeded_response(periods: &Periods, period: Period) -> Response {
+    let body = serde_json::json!({
+        "error": {
+            "message": format!("The {} budget of this key is exhausted", period.as_str()),
+            "type": "insufficient_quota",
+            "param": null,
+            "code": "budget_exceeded"
+        }
+    });
+    let mut resp = (StatusCode::TOO_MANY_REQUESTS, Json(body)).into_response();
+    resp.headers_mut()
+        .insert(BUDGET_REMAINING, HeaderValue::from_static("0.000000"));
+    if let Some(resets_at) = periods.resets_at(period) {
+        let retry_after = (resets_at - Utc::now()).num_seconds().max(0);
+        resp.headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+    }
+    resp
+}
+
+#[derive(Serialize)]
+pub(super) struct KeyUsage {
+    key: String,
+    day: String,
+    month: String,
+    spent_daily: f64,
+    spent_monthly: f64,
+    budget_daily: Option<f64>,
+    budget_monthly: Option<f64>,
+}
+
+/// GET /admin/usage/:id
+/// Current spend of a key, given by its identifier: the email, or the `key:` digest of
+/// a `sk-`/`sess-` key. Keys are refused, they do not belong in URLs and access logs.
+pub(super) async fn usage(
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Path(id): Path<String>,
+) -> Result<Json<KeyUsage>, ResponseError> {
+    // Require auth key
+    if let Some(auth_key) = with_context!(auth_key) {
+        let bearer =
+            bearer.ok_or_else(|| ResponseError::Unauthorized(ProxyError::AuthKeyRequired))?;
+        if auth_key.ne(bearer.token()) {
+            return Err(ResponseError::Forbidden(ProxyError::AuthKeyError));
+        }
+    }
+
+    let budgets = BUDGETS.get().ok_or_else(|| {
+        ResponseError::NotFound(anyhow::anyhow!("Key budgets are not configured"))
+    })?;
+    if token::check_sk_or_sess(&id) {
+        return Err(ResponseError::BadRequest(anyhow::anyhow!(
+            "Pass the key identifier (`key:` and the first 32 hex digits of its SHA-256), not the key"
+        )));
+    }
+    let key_id = id.to_ascii_lowercase();
+    let policy = model_policy::find_by_id(&key_id);
+    let periods = Periods::of(Utc::now());
+    let spend = budgets
+        .spend(&key_id, &periods)
+        .map_err(ResponseError::InternalServerError)?;
+
+    Ok(Json(KeyUsage {
+        day: periods.day.format("%Y-%m-%d").to_string(),
+        month: periods.day.format("%Y-%m").to_string(),
+        key: key_id,
+        spent_daily: micros_to_usd(spend.daily),
+        spent_monthly: micros_to_usd(spend.monthly),
+        budget_daily: policy.as_ref().and_then(|p| p.budget_daily),
+        budget_monthly: policy.as_ref().and_then(|p| p.budget_monthly),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::StreamBody;
+    use chrono::TimeZone;
+    use std::convert::Infallible;
+
+    fn budgets() -> &'static Budgets {
+        let pricing = Pricing(
+            [
+                (
+                    "gpt-4o".to_owned(),
+                    ModelPrice {
+                        input: 2.5,
+                        output: 10.0,
+                    },
+                ),
+                (
+                    "gpt-4o-mini*".to_owned(),
+                    ModelPrice {
+                        input: 0.15,
+                        output: 0.6,
+                    },
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        Box::leak(Box::new(Budgets {
+            store: Box::<MemSpendStore>::default(),
+            pricing,
+            pending: Mutex::default(),
+        }))
+    }
+
+    fn policy(budget_daily: Option<f64>, budget_monthly: Option<f64>) -> ModelPolicy {
+        toml::from_str::<ModelPolicy>(&format!(
+            "name = \"test\"\n{}{}",
+            budget_daily.map_or(String::new(), |b| format!("budget_daily = {b}\n")),
+            budget_monthly.map_or(String::new(), |b| format!("budget_monthly = {b}\n")),
+        ))
+        .unwrap()
+    }
+
+    fn at(y: i32, m: u32, d: u32, h: u32, min: u32, s: u32) -> Periods {
+        Periods::of(Utc.with_ymd_and_hms(y, m, d, h, min, s).unwrap())
+    }
+
+    fn usage(prompt_tokens: u64, completion_tokens: u64) -> Usage {
+        Usage {
+            prompt_tokens,
+            completion_tokens,
+        }
+    }
+
+    #[test]
+    fn test_mixed_models() {
+        let budgets = budgets();
+        let periods = at(2024, 3, 1, 12, 0, 0);
+
+        // 1000 * 2.5 + 500 * 10 micro-dollars
+        budgets.record("a", &periods, "gpt-4o", usage(1000, 500));
+        // The dated model is priced by the glob: 1M * 0.15 + 1000 * 0.6
+        budgets.record(
+            "a",
+            &periods,
+            "gpt-4o-mini-2024-07-18",
+            usage(1_000_000, 1000),
+        );
+        // Unpriced models are not accounted
+        budgets.record("a", &periods, "o1-preview", usage(1000, 1000));
+
+        let spend = budgets.spend("a", &periods).unwrap();
+        assert_eq!(spend.daily, 7_500 + 150_600);
+        assert_eq!(spend.monthly, spend.daily);
+        assert_eq!(budgets.spend("b", &periods).unwrap().daily, 0);
+    }
+
+    #[test]
+    fn test_rollover_boundary() {
+        let budgets = budgets();
+        let policy = policy(Some(0.01), Some(0.015));
+
+        let last_second = at(2024, 1, 31, 23, 59, 59);
+        budgets.record("a", &last_second, "gpt-4o", usage(0, 1000));
+        assert!(matches!(
+            budgets.admit("a", &policy, &last_second, 0).unwrap(),
+            Admission::Exceeded(Period::Daily)
+        ));
+        assert_eq!(
+            last_second.resets_at(Period::Daily),
+            Some(Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap())
+        );
+
+        // The next UTC day and month start from zero
+        let next_day = at(2024, 2, 1, 0, 0, 0);
+        assert!(matches!(
+            budgets.admit("a", &policy, &next_day, 0).unwrap(),
+            Admission::Allowed(10_000)
+        ));
+
+        // The monthly budget spans the days of the month
+        budgets.record("a", &next_day, "gpt-4o", usage(0, 800));
+        let later = at(2024, 2, 2, 8, 0, 0);
+        assert!(matches!(
+            budgets.admit("a", &policy, &later, 0).unwrap(),
+            Admission::Allowed(7_000)
+        ));
+        budgets.record("a", &later, "gpt-4o", usage(0, 700));
+        assert!(matches!(
+            budgets.admit("a", &policy, &later, 0).unwrap(),
+            Admission::Exceeded(Period::Monthly)
+        ));
+        assert_eq!(
+            at(2024, 12, 31, 10, 0, 0).resets_at(Period::Monthly),
+            Some(Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_finishes_after_budget_exceeded() {
+        let budgets = budgets();
+        let policy = policy(Some(0.01), None);
+        let periods = Periods::of(Utc::now());
+
+        // A stream is admitted while the key is under budget
+        let remaining = match budgets.admit("a", &policy, &periods, 0).unwrap() {
+            Admission::Allowed(remaining) => remaining,
+            Admission::Exceeded(_) => panic!("stream was not admitted"),
+        };
+        let stream = futures::stream::iter(
+            [
+                "data: {\"model\":\"gpt-4o\",\"choices\":[],\"usage\":null}\n\n",
+                "data: {\"model\":\"gpt-4o\",\"choices\":[],\"usa",
+                "ge\":{\"prompt_tokens\":400,\"completion_tokens\":100}}\n\n",
+                "data: [DONE]\n\n",
+            ]
+            .map(Ok::<_, Infallible>),
+        );
+        let mut body = Box::pin(MeteredStream {
+            inner: StreamBody::new(stream),
+            line: Vec::new(),
+            meter: Some(Meter {
+                budget: Budget {
+                    budgets,
+                    key_id: "a".to_owned(),
+                    periods: periods.clone(),
+                    remaining,
+                    estimate: Usage::default(),
+                    reserved: 0,
+                },
+                model: None,
+                usage: None,
+                completion_len: 0,
+            }),
+        });
+
+        // Meanwhile another request exhausts the budget
+        budgets.record("a", &periods, "gpt-4o", usage(0, 1200));
+        assert!(matches!(
+            budgets.admit("a", &policy, &periods, 0).unwrap(),
+            Admission::Exceeded(Period::Daily)
+        ));
+
+        // The stream is still served to the end, and its spend is recorded on completion
+        assert_eq!(budgets.spend("a", &periods).unwrap().daily, 12_000);
+        while let Some(chunk) = body.data().await {
+            chunk.unwrap();
+        }
+        // 400 * 2.5 + 100 * 10
+        assert_eq!(budgets.spend("a", &periods).unwrap().daily, 12_000 + 2_000);
+    }
+
+    #[test]
+    fn test_admission_reserves() {
+        let budgets = budgets();
+        let policy = policy(Some(0.01), None);
+        let periods = at(2024, 3, 1, 12, 0, 0);
+
+        // A request estimated at the whole budget holds it until recorded
+        assert!(matches!(
+            budgets.admit("a", &policy, &periods, 10_000).unwrap(),
+            Admission::Allowed(10_000)
+        ));
+        assert!(matches!(
+            budgets.admit("a", &policy, &periods, 0).unwrap(),
+            Admission::Exceeded(Period::Daily)
+        ));
+        assert!(matches!(
+            budgets.admit("b", &policy, &periods, 0).unwrap(),
+            Admission::Allowed(10_000)
+        ));
+
+        // Its actual spend replaces the reservation
+        budgets.record("a", &periods, "gpt-4o", usage(0, 400));
+        budgets.release("a", &periods, 10_000);
+        assert!(matches!(
+            budgets.admit("a", &policy, &periods, 0).unwrap(),
+            Admission::Allowed(6_000)
+        ));
+        assert!(budgets.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_estimate() {
+        let body = br#"{"model":"gpt-4o","max_tokens":100,"messages":[]}"#;
+        let usage = estimate(Some(body));
+        assert_eq!(usage.prompt_tokens, body.len() as u64 / BYTES_PER_TOKEN);
+        assert_eq!(usage.completion_tokens, 100);
+        assert_eq!(estimate(Some(b"not json")).completion_tokens, 0);
+        assert_eq!(estimate(None).prompt_tokens, 0);
+    }
+
+    #[tokio::test]
+    async fn test_stream_without_usage_estimated() {
+        let budgets = budgets();
+        let periods = Periods::of(Utc::now());
+        let stream = futures::stream::iter(
+            [
+                "data: {\"model\":\"gpt-4o\",\"choices\":[{\"delta\":{\"content\":\"Hello, \"}}]}\n\n",
+                "data: {\"model\":\"gpt-4o\",\"choices\":[{\"delta\":{\"content\":\"world!\"}}]}\n\n",
+                "data: [DONE]\n\n",
+            ]
+            .map(Ok::<_, Infallible>),
+        );
+        let mut body = Box::pin(MeteredStream {
+            inner: StreamBody::new(stream),
+            line: Vec::new(),
+            meter: Some(Meter {
+                budget: Budget {
+                    budgets,
+                    key_id: "a".to_owned(),
+                    periods: periods.clone(),
+                    remaining: u64::MAX,
+                    estimate: usage(1000, 500),
+                    reserved: 0,
+                },
+                model: None,
+                usage: None,
+                completion_len: 0,
+            }),
+        });
+        while let Some(chunk) = body.data().await {
+            chunk.unwrap();
+        }
+        drop(body);
+
+        // The prompt estimate and the 13 completion bytes: 1000 * 2.5 + 3 * 10
+        assert_eq!(budgets.spend("a", &periods).unwrap().daily, 2_530);
+    }
+}
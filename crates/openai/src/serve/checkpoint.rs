@@ -0,0 +1,306 @@
+//! Checkpoint of the in-memory stores (token buckets, budget spend), saved to the
+//! worker directory periodically and on graceful shutdown, restored on startup.
+//!
+//! Persistent backends (redb) are not checkpointed, they keep their records anyway.
+
+use crate::{debug, error, info, now_duration, warn};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+/// Checkpoint file, relative to the worker directory
+pub(super) const CHECKPOINT_FILE: &str = "checkpoint.json";
+
+/// Current checkpoint format version
+const CHECKPOINT_VERSION: u32 = 1;
+
+/// Checkpointing, `None` until enabled
+static CHECKPOINT: OnceLock<Checkpointer> = OnceLock::new();
+
+/// In-memory store saved in the checkpoint
+pub(super) trait Volatile: Send + Sync {
+    /// Records of the store, `None` when the store is persistent
+    fn snapshot(&self) -> Option<serde_json::Value>;
+
+    /// Merge the saved records into the store, records last used before
+    /// `expired_before` (unix seconds) are dropped
+    fn restore(&self, saved: serde_json::Value, expired_before: u64) -> anyhow::Result<()>;
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Checkpoint {
+    version: u32,
+    /// Unix seconds
+    saved_at: u64,
+    /// Records by store name
+    stores: BTreeMap<String, serde_json::Value>,
+}
+
+impl Checkpoint {
+    fn capture(stores: &[(&'static str, Arc<dyn Volatile>)], now: u64) -> Self {
+        Self {
+            version: CHECKPOINT_VERSION,
+            saved_at: now,
+            stores: stores
+                .iter()
+                .filter_map(|(name, store)| Some((name.to_string(), store.snapshot()?)))
+                .collect(),
+        }
+    }
+
+    /// Merge into the stores, a checkpoint older than `expired` (seconds) is ignored
+    fn restore(
+        self,
+        stores: &[(&'static str, Arc<dyn Volatile>)],
+        now: u64,
+        expired: u64,
+    ) -> anyhow::Result<usize> {
+        if self.version > CHECKPOINT_VERSION {
+            anyhow::bail!(
+                "checkpoint version {} is newer than the supported version {CHECKPOINT_VERSION}",
+                self.version
+            )
+        }
+        let expired_before = now.saturating_sub(expired);
+        if self.saved_at < expired_before {
+            return Ok(0);
+        }
+        let mut saved = self.stores;
+        let mut restored = 0;
+        for (name, store) in stores {
+            if let Some(records) = saved.remove(*name) {
+                store
+                    .restore(records, expired_before)
+                    .with_context(|| format!("Failed to restore `{name}` from the checkpoint"))?;
+                restored += 1;
+            }
+        }
+        Ok(restored)
+    }
+}
+
+/// Write through a temporary file, a crash never leaves a partial checkpoint
+fn write(path: &Path, checkpoint: &Checkpoint) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec(checkpoint)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn read(path: &Path) -> anyhow::Result<Option<Checkpoint>> {
+    match std::fs::read(path) {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data).with_context(|| {
+            format!("Failed to parse checkpoint {}", path.display())
+        })?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+struct Checkpointer {
+    path: PathBuf,
+    stores: Vec<(&'static str, Arc<dyn Volatile>)>,
+    /// Serializes the writes of the periodic and shutdown checkpoints
+    writing: Arc<Mutex<()>>,
+}
+
+impl Checkpointer {
+    /// Snapshot the stores, then write the file off the runtime threads
+    async fn save(&self) -> anyhow::Result<()> {
+        let checkpoint = Checkpoint::capture(&self.stores, now_duration()?.as_secs());
+        let path = self.path.clone();
+        let writing = self.writing.clone();
+        tokio::task::spawn_blocking(move || {
+            let _guard = writing.lock().unwrap_or_else(|e| e.into_inner());
+            write(&path, &checkpoint)
+        })
+        .await?
+    }
+}
+
+/// Restore the stores from the checkpoint of the worker directory, then save them
+/// every `interval` seconds (`0` saves on shutdown only)
+pub(super) fn init(
+    state_dir: &Path,
+    stores: Vec<(&'static str, Arc<dyn Volatile>)>,
+    interval: u64,
+    expired: u64,
+) -> anyhow::Result<()> {
+    let path = state_dir.join(CHECKPOINT_FILE);
+    match read(&path) {
+        Ok(Some(checkpoint)) => {
+            let saved_at = checkpoint.saved_at;
+            match checkpoint.restore(&stores, now_duration()?.as_secs(), expired)? {
+                0 => info!("Checkpoint is expired or empty, not restored"),
+                n => info!("Restored {n} stores from the checkpoint saved at {saved_at}"),
+            }
+        }
+        Ok(None) => debug!("No checkpoint to restore"),
+        // A corrupted checkpoint must not keep the server down
+        Err(err) => warn!("Checkpoint not restored: {err}"),
+    }
+
+    let checkpointer = Checkpointer {
+        path,
+        stores,
+        writing: Arc::new(Mutex::new(())),
+    };
+    if CHECKPOINT.set(checkpointer).is_err() {
+        return Ok(());
+    }
+
+    if interval > 0 {
+        info!("Checkpoint interval: {interval}s");
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if let Err(err) = save().await {
+                    error!("Failed to save the checkpoint: {err}");
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Save the checkpoint now, a no-op when checkpointing is disabled
+pub(super) async fn save() -> anyhow::Result<()> {
+    match CHECKPOINT.get() {
+        Some(checkpointer) => checkpointer.save().await,
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serve::budget::MemSpendStore;
+    use crate::serve::middleware::tokenbucket::{MemTokenBucket, TokenBucket};
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "ninja-checkpoint-{}-{}",
+            std::process::id(),
+            rand::random::<u32>()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A fresh server's stores
+    fn stores() -> (Arc<MemTokenBucket>, Arc<MemSpendStore>) {
+        (
+            Arc::new(MemTokenBucket::new(true, 10, 0, 86400)),
+            Arc::new(MemSpendStore::default()),
+        )
+    }
+
+    fn volatile(
+        buckets: &Arc<MemTokenBucket>,
+        spend: &Arc<MemSpendStore>,
+    ) -> Vec<(&'static str, Arc<dyn Volatile>)> {
+        vec![
+            ("token_bucket", buckets.clone() as Arc<dyn Volatile>),
+            ("budget_spend", spend.clone() as Arc<dyn Volatile>),
+        ]
+    }
+
+    #[test]
+    fn test_stop_start_preserves_state() {
+        use crate::serve::budget::SpendStore;
+
+        let ip = "203.0.113.7".parse().unwrap();
+        let path = temp_dir().join(CHECKPOINT_FILE);
+        let now = now_duration().unwrap().as_secs();
+
+        // Half-drained bucket and a partially-consumed quota, then stop
+        let (buckets, spend) = stores();
+        for _ in 0..5 {
+            assert!(buckets.acquire(ip).unwrap().is_allowed());
+        }
+        spend.add(&["key:day".to_owned()], 1_500_000).unwrap();
+        write(
+            &path,
+            &Checkpoint::capture(&volatile(&buckets, &spend), now),
+        )
+        .unwrap();
+
+        // Start
+        let (buckets, spend) = stores();
+        let checkpoint = read(&path).unwrap().unwrap();
+        assert_eq!(
+            checkpoint
+                .restore(&volatile(&buckets, &spend), now, 86400)
+                .unwrap(),
+            2
+        );
+        for _ in 0..5 {
+            assert!(buckets.acquire(ip).unwrap().is_allowed());
+        }
+        assert!(!buckets.acquire(ip).unwrap().is_allowed());
+        assert_eq!(spend.get("key:day").unwrap(), 1_500_000);
+    }
+
+    #[test]
+    fn test_expired_checkpoint_ignored() {
+        let ip = "203.0.113.7".parse().unwrap();
+        let now = now_duration().unwrap().as_secs();
+
+        let (buckets, spend) = stores();
+        assert!(buckets.acquire(ip).unwrap().is_allowed());
+        let checkpoint = Checkpoint::capture(&volatile(&buckets, &spend), now - 7200);
+
+        let (buckets, spend) = stores();
+        assert_eq!(
+            checkpoint
+                .restore(&volatile(&buckets, &spend), now, 3600)
+                .unwrap(),
+            0
+        );
+        for _ in 0..10 {
+            assert!(buckets.acquire(ip).unwrap().is_allowed());
+        }
+    }
+
+    #[test]
+    fn test_restore_merges_with_backend() {
+        use crate::serve::budget::SpendStore;
+
+        let now = now_duration().unwrap().as_secs();
+        let (buckets, spend) = stores();
+        spend.add(&["key:day".to_owned()], 2_000_000).unwrap();
+        spend.add(&["key:month".to_owned()], 1_000).unwrap();
+        let checkpoint = Checkpoint::capture(&volatile(&buckets, &spend), now);
+
+        // Spent since the start, the larger record is kept
+        let (buckets, spend) = stores();
+        spend.add(&["key:day".to_owned()], 3_000_000).unwrap();
+        spend.add(&["key:other".to_owned()], 7).unwrap();
+        checkpoint
+            .restore(&volatile(&buckets, &spend), now, 86400)
+            .unwrap();
+        assert_eq!(spend.get("key:day").unwrap(), 3_000_000);
+        assert_eq!(spend.get("key:month").unwrap(), 1_000);
+        assert_eq!(spend.get("key:other").unwrap(), 7);
+    }
+
+    #[test]
+    fn test_newer_version_rejected() {
+        let (buckets, spend) = stores();
+        let checkpoint = Checkpoint {
+            version: CHECKPOINT_VERSION + 1,
+            ..Default::default()
+        };
+        assert!(checkpoint
+            .restore(&volatile(&buckets, &spend), 0, 86400)
+            .is_err());
+    }
+}
@@ -14,6 +14,10 @@ pub enum ProxyError {
     AuthKeyError,
     #[error("AccessToken is required")]
     AccessTokenRequired,
+    #[error("Credentials are required")]
+    CredentialsRequired,
+    #[error("Request signature error ({0})")]
+    SignatureError(&'static str),
     #[error("Model required")]
     ModelRequired,
     #[error("Body required")]
@@ -34,10 +38,22 @@ pub enum ProxyError {
     InvalidUploadField,
     #[error("Too Many Requests")]
     TooManyRequests,
+    #[error("Too many concurrent streams")]
+    TooManyConcurrentStreams,
+    #[error("Server is busy, request shed from the queue")]
+    RequestShed,
+    #[error("Server is busy, buffered memory limit exceeded")]
+    BufferedMemoryExceeded,
     #[error("Your access is not in the whitelist")]
     AccessNotInWhitelist,
+    #[error("Your IP address is not allowed")]
+    IpNotAllowed,
+    #[error("Rate limit store is busy, please try again")]
+    RateLimitStoreBusy,
     #[error("Auth Key required!")]
     AuthKeyRequired,
+    #[error("Admin endpoints are only served to local clients without an auth key")]
+    AdminLoopbackOnly,
     #[error("Event-source stream error ({0})")]
     EventSourceStreamError(EventStreamError<reqwest::Error>),
     #[error("Deserialize error ({0})")]
@@ -52,8 +68,12 @@ pub enum ProxyError {
     /// Cloudflare error
     #[error("Missing cf_captcha_response")]
     CfMissingCaptcha,
+    #[error("Turnstile verification is busy, please try again")]
+    CfVerifyBusy,
     #[error("Cloudflare error ({0})")]
     CfError(reqwest::Error),
+    #[error("Turnstile verification failed ({0})")]
+    CfVerifyFailed(String),
 
     /// Request error
     #[error("Request error ({0})")]
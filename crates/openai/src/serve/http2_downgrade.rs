@@ -0,0 +1,115 @@
+//! HTTP/2 downgrade of the clients matching `http2_disable_for`.
+//!
+//! ALPN picks the HTTP version during the TLS handshake, before the User-Agent is
+//! known, so the request that matches is still served over HTTP/2. Its client address
+//! is remembered, and the next TLS handshakes of the address are not offered `h2`.
+
+use crate::debug;
+use crate::serve::model_policy::glob_match;
+use axum::extract::ConnectInfo;
+use axum::http::{header, Request, Version};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// A downgraded address is served HTTP/1.1 for this long after its last matching request
+const DOWNGRADE_TTL: Duration = Duration::from_secs(3600);
+
+static DOWNGRADE: OnceLock<Downgrade> = OnceLock::new();
+
+struct Downgrade {
+    /// User-Agent patterns, `*` matches any characters and `?` a single one
+    patterns: Vec<String>,
+    /// Downgraded addresses and when they expire
+    peers: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+pub(super) fn init(patterns: &[String]) {
+    if patterns.is_empty() {
+        return;
+    }
+    let _ = DOWNGRADE.set(Downgrade {
+        patterns: patterns.to_vec(),
+        peers: Mutex::default(),
+    });
+}
+
+/// Whether the connections of an address are served HTTP/1.1 only
+pub(super) fn is_downgraded(ip: IpAddr) -> bool {
+    DOWNGRADE.get().map_or(false, |downgrade| {
+        downgrade.is_downgraded(ip, Instant::now())
+    })
+}
+
+impl Downgrade {
+    fn matches(&self, user_agent: &str) -> bool {
+        self.patterns
+            .iter()
+            .any(|pattern| glob_match(pattern, user_agent))
+    }
+
+    fn is_downgraded(&self, ip: IpAddr, now: Instant) -> bool {
+        self.peers
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .get(&ip)
+            .map_or(false, |expires_at| *expires_at > now)
+    }
+
+    fn downgrade(&self, ip: IpAddr, now: Instant) {
+        let mut peers = self.peers.lock().unwrap_or_else(|err| err.into_inner());
+        peers.retain(|_, expires_at| *expires_at > now);
+        if peers.insert(ip, now + DOWNGRADE_TTL).is_none() {
+            debug!("Downgrading the connections of {ip} to HTTP/1.1");
+        }
+    }
+}
+
+/// Remember the addresses of the HTTP/2 requests with a matching User-Agent
+pub(super) async fn record<B>(request: Request<B>, next: Next<B>) -> Response {
+    if let Some(downgrade) = DOWNGRADE.get() {
+        if request.version() == Version::HTTP_2 {
+            let user_agent = request
+                .headers()
+                .get(header::USER_AGENT)
+                .and_then(|value| value.to_str().ok());
+            let peer = request
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip());
+            if let (Some(user_agent), Some(ip)) = (user_agent, peer) {
+                if downgrade.matches(user_agent) {
+                    downgrade.downgrade(ip, Instant::now());
+                }
+            }
+        }
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_downgrade_expires() {
+        let downgrade = Downgrade {
+            patterns: vec!["okhttp/3.*".to_owned(), "LegacyClient *".to_owned()],
+            peers: Mutex::default(),
+        };
+        assert!(downgrade.matches("okhttp/3.12.1"));
+        assert!(downgrade.matches("LegacyClient 1.0 (Windows)"));
+        assert!(!downgrade.matches("okhttp/4.9.0"));
+
+        let ip = "10.0.0.1".parse().unwrap();
+        let now = Instant::now();
+        assert!(!downgrade.is_downgraded(ip, now));
+        downgrade.downgrade(ip, now);
+        assert!(downgrade.is_downgraded(ip, now + Duration::from_secs(60)));
+        assert!(!downgrade.is_downgraded(ip, now + DOWNGRADE_TTL));
+        assert!(!downgrade.is_downgraded("10.0.0.2".parse().unwrap(), now));
+    }
+}
@@ -0,0 +1,747 @@
+use super::leader;
+use super::memory::{self, Buffer, Reservation};
+use crate::homedir::home_dir;
+use crate::{context, debug, error, info, now_duration};
+use axum::body::{Body, Bytes, Full, HttpBody};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use native_db::*;
+use native_model::{native_model, Model};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::Duration;
+
+/// Request header carrying the client's idempotency key
+pub(super) const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/// Response header set on the replayed responses
+pub(super) const IDEMPOTENT_REPLAYED: &str = "idempotent-replayed";
+
+const MAX_KEY_LEN: usize = 255;
+
+/// Responses larger than this are not cached
+const MAX_CACHED_BODY: usize = 4 * 1024 * 1024;
+
+/// Responses kept at most by the mem store
+const MAX_MEM_RESPONSES: usize = 10_000;
+
+/// Bytes of the responses kept at most by the mem store, and at most a quarter of
+/// the buffered memory limit
+const MAX_MEM_BYTES: usize = 256 * 1024 * 1024;
+
+/// Longest interval between two sweeps of the mem store
+const MEM_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Request bodies larger than this are refused, the body limit of the proxy routes
+pub(super) const MAX_REQUEST_BODY: usize = 200 * 1024 * 1024;
+
+/// Response headers that are not replayed
+const SKIP_HEADERS: [HeaderName; 4] = [
+    header::CONNECTION,
+    header::CONTENT_LENGTH,
+    header::TRANSFER_ENCODING,
+    header::DATE,
+];
+
+/// Idempotency handling, `None` until enabled
+static IDEMPOTENCY: OnceLock<Idempotency> = OnceLock::new();
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[native_model(id = 1, version = 1)]
+#[native_db]
+struct CachedResponse {
+    /// Scoped key, see `scope`
+    #[primary_key]
+    key: String,
+    status: u16,
+    headers: Vec<(String, Vec<u8>)>,
+    body: Vec<u8>,
+    /// Request body hash, a key reused with another body is rejected
+    fingerprint: String,
+    /// Expiry (unix milliseconds)
+    expires_at: u64,
+}
+
+impl CachedResponse {
+    /// Bytes held by the response
+    fn size(&self) -> usize {
+        self.key.len()
+            + self.body.len()
+            + self.fingerprint.len()
+            + self
+                .headers
+                .iter()
+                .map(|(name, value)| name.len() + value.len())
+                .sum::<usize>()
+    }
+}
+
+trait ResponseStore: Send + Sync {
+    fn get(&self, key: &str, now: u64) -> anyhow::Result<Option<CachedResponse>>;
+
+    fn put(&self, response: CachedResponse) -> anyhow::Result<()>;
+}
+
+struct MemResponseStore(Arc<Mutex<MemResponses>>);
+
+impl MemResponseStore {
+    fn new(window: Duration) -> Self {
+        let max_bytes = match memory::limit() {
+            0 => MAX_MEM_BYTES,
+            limit => MAX_MEM_BYTES.min(limit as usize / 4),
+        };
+        let responses = Arc::new(Mutex::new(MemResponses::new(MAX_MEM_RESPONSES, max_bytes)));
+        clear_expired_mem_responses_every(Arc::downgrade(&responses), window);
+        Self(responses)
+    }
+}
+
+/// Cached responses in expiry order, bounded in count and bytes. Their bytes are
+/// reserved in the buffered memory budget while they are kept.
+struct MemResponses {
+    responses: HashMap<String, (CachedResponse, Reservation)>,
+    /// Expiry and key of the responses, the first expires first. An entry of a
+    /// response replaced since is skipped.
+    order: VecDeque<(u64, String)>,
+    bytes: usize,
+    max_responses: usize,
+    max_bytes: usize,
+}
+
+impl MemResponses {
+    fn new(max_responses: usize, max_bytes: usize) -> Self {
+        Self {
+            responses: HashMap::new(),
+            order: VecDeque::new(),
+            bytes: 0,
+            max_responses,
+            max_bytes,
+        }
+    }
+
+    /// Drop the responses expired at `now`
+    fn clear_expired(&mut self, now: u64) {
+        while self
+            .order
+            .front()
+            .map_or(false, |(expires_at, _)| *expires_at <= now)
+        {
+            self.evict_first();
+        }
+    }
+
+    /// Drop the response that expires first, `false` when there is none
+    fn evict_first(&mut self) -> bool {
+        let Some((expires_at, key)) = self.order.pop_front() else {
+            return false;
+        };
+        if self
+            .responses
+            .get(&key)
+            .map_or(false, |(response, _)| response.expires_at == expires_at)
+        {
+            if let Some((response, _)) = self.responses.remove(&key) {
+                self.bytes -= response.size();
+            }
+        }
+        true
+    }
+
+    /// Keep a response, the responses that expire first make room for it. It is not
+    /// kept when it does not fit the caps or the buffered memory budget.
+    fn put(&mut self, response: CachedResponse) -> bool {
+        let size = response.size();
+        if size > self.max_bytes {
+            return false;
+        }
+        if let Some((previous, _)) = self.responses.remove(&response.key) {
+            self.bytes -= previous.size();
+        }
+        while self.responses.len() >= self.max_responses || self.bytes + size > self.max_bytes {
+            if !self.evict_first() {
+                break;
+            }
+        }
+        let mut reservation = Reservation::new();
+        if !reservation.grow(size) {
+            return false;
+        }
+        self.bytes += size;
+        self.order
+            .push_back((response.expires_at, response.key.clone()));
+        self.responses
+            .insert(response.key.clone(), (response, reservation));
+        true
+    }
+}
+
+fn clear_expired_mem_responses_every(responses: Weak<Mutex<MemResponses>>, window: Duration) {
+    let interval = window.clamp(Duration::from_secs(1), MEM_SWEEP_INTERVAL);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        // Stopped with the store
+        let Some(responses) = responses.upgrade() else {
+            return;
+        };
+        let now = now_duration().map_or(0, |now| now.as_millis() as u64);
+        if let Ok(mut responses) = responses.lock() {
+            responses.clear_expired(now);
+        }
+    });
+}
+
+impl ResponseStore for MemResponseStore {
+    fn get(&self, key: &str, now: u64) -> anyhow::Result<Option<CachedResponse>> {
+        let responses = self
+            .0
+            .lock()
+            .map_err(|_| anyhow::anyhow!("idempotency store lock poisoned"))?;
+        Ok(responses
+            .responses
+            .get(key)
+            .map(|(response, _)| response)
+            .filter(|r| r.expires_at > now)
+            .cloned())
+    }
+
+    fn put(&self, response: CachedResponse) -> anyhow::Result<()> {
+        let mut responses = self
+            .0
+            .lock()
+            .map_err(|_| anyhow::anyhow!("idempotency store lock poisoned"))?;
+        if !responses.put(response) {
+            debug!("Idempotent response not cached, over the mem store limits");
+        }
+        Ok(())
+    }
+}
+
+static DATABASE_BUILDER: OnceLock<DatabaseBuilder> = OnceLock::new();
+
+struct ReDBResponseStore(Arc<Database<'static>>);
+
+impl ReDBResponseStore {
+    fn new(window: Duration) -> anyhow::Result<Self> {
+        let builder = DATABASE_BUILDER.get_or_init(|| {
+            let mut builder = DatabaseBuilder::new();
+            builder
+                .define::<CachedResponse>()
+                .expect("define table failed");
+            builder
+        });
+        let db = Arc::new(
+            builder.create(
+                home_dir()
+                    .unwrap_or_default()
+                    .join(context::WORKER_DIR)
+                    .join("idempotency.db"),
+            )?,
+        );
+        clear_expired_responses_every(db.clone(), window);
+        Ok(Self(db))
+    }
+}
+
+fn clear_expired_responses_every(db: Arc<Database<'static>>, window: Duration) {
+    let interval = window.max(Duration::from_secs(1));
+    std::thread::spawn(move || loop {
+        std::thread::sleep(leader::poll_period(interval));
+        if !leader::claim_local("idempotency_sweep", interval) {
+            continue;
+        }
+
+        debug!("ReDB Clearing expired idempotent responses...");
+        let clear = || -> anyhow::Result<()> {
+            let now = now_duration()?.as_millis() as u64;
+            let r = db.r_transaction()?;
+            let expired = r
+                .scan()
+                .primary::<CachedResponse>()?
+                .all()
+                .filter(|response| response.expires_at <= now)
+                .collect::<Vec<_>>();
+            if expired.is_empty() {
+                return Ok(());
+            }
+            let rw = db.rw_transaction()?;
+            for response in expired {
+                rw.remove(response)?;
+            }
+            rw.commit()?;
+            Ok(())
+        };
+        if let Err(err) = clear() {
+            error!("Error clearing expired idempotent responses: {err}");
+        }
+    });
+}
+
+impl ResponseStore for ReDBResponseStore {
+    fn get(&self, key: &str, now: u64) -> anyhow::Result<Option<CachedResponse>> {
+        let r = self.0.r_transaction()?;
+        let response: Option<CachedResponse> = r.get().primary(key.to_owned())?;
+        Ok(response.filter(|r| r.expires_at > now))
+    }
+
+    fn put(&self, response: CachedResponse) -> anyhow::Result<()> {
+        let rw = self.0.rw_transaction()?;
+        let previous: Option<CachedResponse> = rw.get().primary(response.key.clone())?;
+        match previous {
+            Some(previous) => rw.update(previous, response)?,
+            None => rw.insert(response)?,
+        }
+        rw.commit()?;
+        Ok(())
+    }
+}
+
+struct Idempotency {
+    store: Box<dyn ResponseStore>,
+    window: Duration,
+    /// Scoped keys of the requests in progress
+    inflight: Mutex<HashSet<String>>,
+}
+
+/// Enable the idempotency handling with the given store strategy (mem/redb)
+pub(super) fn init(enable: bool, store: &str, window: u64) -> anyhow::Result<()> {
+    if !enable {
+        return Ok(());
+    }
+    let window = Duration::from_secs(window);
+    let store: Box<dyn ResponseStore> = match store {
+        "mem" => Box::new(MemResponseStore::new(window)),
+        "redb" => Box::new(ReDBResponseStore::new(window)?),
+        _ => anyhow::bail!("idempotency store: {store} is not supported"),
+    };
+    info!(
+        "Idempotency keys: responses replayed for {}s",
+        window.as_secs()
+    );
+    let _ = IDEMPOTENCY.set(Idempotency {
+        store,
+        window,
+        inflight: Mutex::new(HashSet::new()),
+    });
+    Ok(())
+}
+
+/// Replay the cached response of a retried POST carrying an `Idempotency-Key`,
+/// instead of sending it upstream again. Streaming requests are not deduplicated.
+pub(super) async fn middleware(request: Request<Body>, next: Next<Body>) -> Response {
+    match IDEMPOTENCY.get() {
+        Some(idempotency) => handle(idempotency, request, next).await,
+        None => next.run(request).await,
+    }
+}
+
+async fn handle(idempotency: &Idempotency, request: Request<Body>, next: Next<Body>) -> Response {
+    if request.method() != Method::POST {
+        return next.run(request).await;
+    }
+    let key = match request.headers().get(IDEMPOTENCY_KEY) {
+        Some(key) => match key.to_str() {
+            Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LEN => key.to_owned(),
+            _ => {
+                return error_response(
+                    StatusCode::BAD_REQUEST,
+                    "The Idempotency-Key header must be 1 to 255 visible ASCII characters",
+                    "invalid_idempotency_key",
+                )
+            }
+        },
+        None => return next.run(request).await,
+    };
+
+    let (mut parts, body) = request.into_parts();
+    let body = match read_body(&parts.headers, body, MAX_REQUEST_BODY).await {
+        Ok((body, reservation)) => {
+            // The proxy handler takes over the reservation of the body it extracts
+            if parts.headers.contains_key(header::CONTENT_TYPE) {
+                parts.extensions.insert(Arc::new(reservation));
+            }
+            body
+        }
+        Err(resp) => return resp,
+    };
+    if is_streaming(&body) {
+        return next.run(Request::from_parts(parts, Body::from(body))).await;
+    }
+
+    let scope = scope(&parts.headers, parts.uri.path(), &key);
+    let fingerprint = hex(&Sha256::digest(&body));
+    let now = now_duration().map_or(0, |now| now.as_millis() as u64);
+
+    match idempotency.store.get(&scope, now) {
+        Ok(Some(cached)) if cached.fingerprint != fingerprint => {
+            return error_response(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "The Idempotency-Key was already used with a different request body",
+                "idempotency_key_reused",
+            )
+        }
+        Ok(Some(cached)) => return replay(cached),
+        Ok(None) => {}
+        Err(err) => {
+            // Not deduplicated when the store fails
+            error!("Failed to read the idempotent response: {err}");
+            return next.run(Request::from_parts(parts, Body::from(body))).await;
+        }
+    }
+
+    // A retry that arrives while the first request is still in progress
+    let _inflight = match InFlight::acquire(idempotency, &scope) {
+        Some(inflight) => inflight,
+        None => {
+            return error_response(
+                StatusCode::CONFLICT,
+                "A request with this Idempotency-Key is in progress",
+                "idempotency_key_in_use",
+            )
+        }
+    };
+
+    let resp = next.run(Request::from_parts(parts, Body::from(body))).await;
+    if !resp.status().is_success() || is_event_stream(resp.headers()) {
+        return resp;
+    }
+
+    let (parts, body) = resp.into_parts();
+    let (data, reservation) = match memory::buffer(body).await {
+        Ok(Buffer::Full(data, reservation)) => (data, reservation),
+        // Not cached over the buffered memory limit, a retry runs the request again
+        Ok(Buffer::Exceeded(body)) => return Response::from_parts(parts, body),
+        Err(err) => {
+            error!("Failed to read the response of `{key}`: {err}");
+            return StatusCode::BAD_GATEWAY.into_response();
+        }
+    };
+
+    if data.len() <= MAX_CACHED_BODY {
+        let cached = CachedResponse {
+            key: scope,
+            status: parts.status.as_u16(),
+            headers: parts
+                .headers
+                .iter()
+                .filter(|(name, _)| !SKIP_HEADERS.contains(name))
+                .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+                .collect(),
+            body: data.clone(),
+            fingerprint,
+            expires_at: now + idempotency.window.as_millis() as u64,
+        };
+        if let Err(err) = idempotency.store.put(cached) {
+            error!("Failed to store the idempotent response: {err}");
+        }
+    }
+
+    Response::from_parts(parts, memory::body(data, reservation))
+}
+
+/// Marks a scoped key in progress until dropped
+struct InFlight<'a> {
+    idempotency: &'a Idempotency,
+    scope: String,
+}
+
+impl<'a> InFlight<'a> {
+    fn acquire(idempotency: &'a Idempotency, scope: &str) -> Option<Self> {
+        let mut inflight = idempotency.inflight.lock().ok()?;
+        inflight.insert(scope.to_owned()).then(|| Self {
+            idempotency,
+            scope: scope.to_owned(),
+        })
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut inflight) = self.idempotency.inflight.lock() {
+            inflight.remove(&self.scope);
+        }
+    }
+}
+
+/// Read the request body within the buffered memory budget, at most `max` bytes
+pub(super) async fn read_body(
+    headers: &HeaderMap,
+    mut body: Body,
+    max: usize,
+) -> Result<(Bytes, Reservation), Response> {
+    let too_large = || {
+        error_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "The request body is too large",
+            "request_too_large",
+        )
+    };
+    let declared = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if declared.map_or(false, |len| len > max) {
+        return Err(too_large());
+    }
+
+    let mut reservation =
+        memory::reserve_request(headers).ok_or_else(|| memory::exceeded().into_response())?;
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| {
+            error_response(StatusCode::BAD_REQUEST, &err.to_string(), "invalid_body")
+        })?;
+        let len = data.len() + chunk.len();
+        if len > max {
+            return Err(too_large());
+        }
+        if !reservation.fit(len) {
+            return Err(memory::exceeded().into_response());
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok((Bytes::from(data), reservation))
+}
+
+/// Idempotency keys are scoped to the credential and the route
+fn scope(headers: &HeaderMap, path: &str, key: &str) -> String {
+    let mut hasher = Sha256::new();
+    if let Some(authorization) = headers.get(header::AUTHORIZATION) {
+        hasher.update(authorization.as_bytes());
+    }
+    hasher.update(b"\n");
+    hasher.update(path.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(key.as_bytes());
+    hex(&hasher.finalize())
+}
+
+fn is_streaming(body: &[u8]) -> bool {
+    serde_json::from_slice::<serde_json::Value>(body)
+        .ok()
+        .and_then(|body| body.get("stream").and_then(serde_json::Value::as_bool))
+        .unwrap_or(false)
+}
+
+fn is_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.starts_with(mime::TEXT_EVENT_STREAM.as_ref()))
+}
+
+fn replay(cached: CachedResponse) -> Response {
+    let mut resp = Response::new(axum::body::boxed(Full::from(cached.body)));
+    *resp.status_mut() = StatusCode::from_u16(cached.status).unwrap_or(StatusCode::OK);
+    for (name, value) in cached.headers {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::from_bytes(&value))
+        {
+            resp.headers_mut().append(name, value);
+        }
+    }
+    resp.headers_mut()
+        .insert(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"));
+    resp
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn error_response(status: StatusCode, message: &str, code: &str) -> Response {
+    let body = serde_json::json!({
+        "error": {
+            "message": message,
+            "type": "invalid_request_error",
+            "param": null,
+            "code": code
+        }
+    });
+    (status, Json(body)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::post;
+    use axum::Router;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tower::Service;
+
+    /// Router counting the requests that reach the upstream
+    fn router(window: Duration) -> (Router, Arc<AtomicUsize>) {
+        let idempotency: &'static Idempotency = Box::leak(Box::new(Idempotency {
+            store: Box::new(MemResponseStore::new(window)),
+            window,
+            inflight: Mutex::new(HashSet::new()),
+        }));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let router = Router::new()
+            .route(
+                "/v1/chat/completions",
+                post(move || async move {
+                    let call = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                    Json(serde_json::json!({ "id": format!("chatcmpl-{call}") }))
+                }),
+            )
+            .layer(axum::middleware::from_fn(move |request, next| {
+                handle(idempotency, request, next)
+            }));
+        (router, calls)
+    }
+
+    async fn send(router: &mut Router, key: Option<&str>, body: &str) -> Response {
+        let mut request = Request::post("/v1/chat/completions")
+            .header(header::AUTHORIZATION, "Bearer sk-test")
+            .header(header::CONTENT_TYPE, "application/json");
+        if let Some(key) = key {
+            request = request.header(IDEMPOTENCY_KEY, key);
+        }
+        router
+            .call(request.body(Body::from(body.to_owned())).unwrap())
+            .await
+            .unwrap()
+    }
+
+    async fn text(resp: Response) -> String {
+        let mut body = resp.into_body();
+        let mut data = Vec::new();
+        while let Some(chunk) = body.data().await {
+            data.extend_from_slice(&chunk.unwrap());
+        }
+        String::from_utf8(data).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_replay_within_window() {
+        let (mut router, calls) = router(Duration::from_millis(300));
+        let body = r#"{"model":"gpt-4o","messages":[]}"#;
+
+        let first = send(&mut router, Some("retry-1"), body).await;
+        assert!(first.headers().get(IDEMPOTENT_REPLAYED).is_none());
+        let first = text(first).await;
+
+        // The retry is answered from the cache
+        let retry = send(&mut router, Some("retry-1"), body).await;
+        assert_eq!(retry.status(), StatusCode::OK);
+        assert_eq!(retry.headers()[IDEMPOTENT_REPLAYED], "true");
+        assert_eq!(retry.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(text(retry).await, first);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Another key is a new request
+        let other = send(&mut router, Some("retry-2"), body).await;
+        assert_ne!(text(other).await, first);
+
+        // The same key with another body is rejected
+        let reused = send(&mut router, Some("retry-1"), r#"{"model":"gpt-4o-mini"}"#).await;
+        assert_eq!(reused.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        // Once the window is over, the request is sent again
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        let expired = send(&mut router, Some("retry-1"), body).await;
+        assert!(expired.headers().get(IDEMPOTENT_REPLAYED).is_none());
+        assert_ne!(text(expired).await, first);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_not_deduplicated() {
+        let (mut router, calls) = router(Duration::from_secs(60));
+
+        // Without a key
+        send(&mut router, None, "{}").await;
+        send(&mut router, None, "{}").await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Streaming requests
+        let body = r#"{"model":"gpt-4o","stream":true}"#;
+        send(&mut router, Some("stream-1"), body).await;
+        send(&mut router, Some("stream-1"), body).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        let invalid = send(&mut router, Some(&"k".repeat(256)), "{}").await;
+        assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+    }
+
+    fn cached(key: &str, body_len: usize, expires_at: u64) -> CachedResponse {
+        CachedResponse {
+            key: key.to_owned(),
+            status: 200,
+            headers: Vec::new(),
+            body: vec![b'x'; body_len],
+            fingerprint: String::new(),
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn test_mem_responses_bounded() {
+        // 3 responses or 300 bytes at most
+        let mut responses = MemResponses::new(3, 300);
+        for (i, key) in ["a", "b", "c", "d"].into_iter().enumerate() {
+            assert!(responses.put(cached(key, 9, 1000 + i as u64)));
+        }
+        // The first to expire made room
+        assert!(!responses.responses.contains_key("a"));
+        assert_eq!(responses.responses.len(), 3);
+        assert_eq!(responses.bytes, 30);
+
+        assert!(responses.put(cached("e", 289, 1004)));
+        assert_eq!(responses.responses.len(), 2);
+        assert!(responses.responses.contains_key("d"));
+        assert_eq!(responses.bytes, 300);
+        // Larger than the cap, never kept
+        assert!(!responses.put(cached("f", 300, 1005)));
+
+        // A replaced response is counted once
+        assert!(responses.put(cached("d", 9, 1006)));
+        assert_eq!(responses.responses.len(), 2);
+        assert_eq!(responses.bytes, 300);
+
+        responses.clear_expired(1004);
+        assert_eq!(responses.responses.len(), 1);
+        assert_eq!(responses.bytes, 10);
+        responses.clear_expired(1006);
+        assert!(responses.responses.is_empty());
+        assert!(responses.order.is_empty());
+        assert_eq!(responses.bytes, 0);
+    }
+
+    #[test]
+    fn test_scope() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer a"));
+        let a = scope(&headers, "/v1/chat/completions", "key");
+        assert_eq!(a, scope(&headers, "/v1/chat/completions", "key"));
+        assert_ne!(a, scope(&headers, "/v1/embeddings", "key"));
+
+        // Keys of different credentials never collide
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer b"));
+        assert_ne!(a, scope(&headers, "/v1/chat/completions", "key"));
+    }
+
+    #[tokio::test]
+    async fn test_read_body_bounded() {
+        let mut headers = HeaderMap::new();
+        let (body, _reservation) = read_body(&headers, Body::from("{}"), 8).await.unwrap();
+        assert_eq!(body, "{}");
+
+        // Over the cap, read or declared
+        let resp = read_body(&headers, Body::from("x".repeat(9)), 8)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("9"));
+        let resp = read_body(&headers, Body::from("{}"), 8)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -0,0 +1,313 @@
+//! Client IP allow and deny lists (`allow_ips_file` / `deny_ips_file`), hot-reloaded.
+//!
+//! Each file holds one CIDR or address per line, `#` starts a comment. A reload
+//! compiles the whole list before swapping it in, so requests see either the previous
+//! or the new list, never a partial one. An allow list without a valid entry is refused,
+//! at startup and on reload.
+
+use crate::serve::error::{ProxyError, ResponseError};
+use crate::serve::lifecycle;
+use crate::{debug, error, info, warn};
+use anyhow::Context;
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use hotwatch::{Event, EventKind, Hotwatch};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock, RwLock};
+
+static FILTER: OnceLock<Filter> = OnceLock::new();
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Allow,
+    Deny,
+}
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Allow => "allow",
+            Self::Deny => "deny",
+        }
+    }
+
+    fn setting(&self) -> &'static str {
+        match self {
+            Self::Allow => "allow_ips_file",
+            Self::Deny => "deny_ips_file",
+        }
+    }
+}
+
+struct Filter {
+    allow: Option<List>,
+    deny: Option<List>,
+}
+
+/// List file and its compiled entries, replaced as a whole on reload
+struct List {
+    kind: Kind,
+    path: PathBuf,
+    entries: RwLock<Arc<Vec<cidr::IpCidr>>>,
+}
+
+impl List {
+    fn new(kind: Kind, path: PathBuf) -> anyhow::Result<Self> {
+        let entries = load(kind, &path)?;
+        info!(
+            "Loaded {} {} IP entries from {}",
+            entries.len(),
+            kind.as_str(),
+            path.display()
+        );
+        Ok(Self {
+            kind,
+            path,
+            entries: RwLock::new(Arc::new(entries)),
+        })
+    }
+
+    /// Reload the file, the previous entries stay in use if it cannot be read or is
+    /// refused
+    fn reload(&self) {
+        match load(self.kind, &self.path) {
+            Ok(entries) => {
+                info!(
+                    "Reloaded {} {} IP entries from {}",
+                    entries.len(),
+                    self.kind.as_str(),
+                    self.path.display()
+                );
+                *self.entries.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(entries);
+                lifecycle::notify(lifecycle::Kind::Reload, Some(self.kind.setting()));
+            }
+            Err(err) => error!("Failed to reload {} IP list: {err}", self.kind.as_str()),
+        }
+    }
+
+    fn entries(&self) -> Arc<Vec<cidr::IpCidr>> {
+        self.entries
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    fn watch(&'static self, hotwatch: &mut Hotwatch) -> anyhow::Result<()> {
+        info!(
+            "Start watching {} IP list file: {}",
+            self.kind.as_str(),
+            self.path.display()
+        );
+        hotwatch.watch(&self.path, move |event: Event| {
+            if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                self.reload();
+            }
+        })?;
+        Ok(())
+    }
+}
+
+fn load(kind: Kind, path: &Path) -> anyhow::Result<Vec<cidr::IpCidr>> {
+    let data = std::fs::read_to_string(path).with_context(|| {
+        format!(
+            "Failed to read {} IP list {}",
+            kind.as_str(),
+            path.display()
+        )
+    })?;
+    checked(kind, path, parse(kind, path, &data))
+}
+
+/// Refuse an allow list without entries, it would let every address in
+fn checked(
+    kind: Kind,
+    path: &Path,
+    entries: Vec<cidr::IpCidr>,
+) -> anyhow::Result<Vec<cidr::IpCidr>> {
+    if let Kind::Allow = kind {
+        if entries.is_empty() {
+            anyhow::bail!(
+                "The allow IP list {} has no valid entry, unset {} to allow every address",
+                path.display(),
+                kind.setting()
+            )
+        }
+    }
+    Ok(entries)
+}
+
+/// Entries of a list file, invalid entries are logged and skipped
+fn parse(kind: Kind, path: &Path, data: &str) -> Vec<cidr::IpCidr> {
+    data.lines()
+        .enumerate()
+        .filter_map(|(n, line)| {
+            let entry = line.split('#').next().unwrap_or_default().trim();
+            if entry.is_empty() {
+                return None;
+            }
+            match cidr::IpCidr::from_str(entry) {
+                Ok(cidr) => Some(cidr),
+                Err(err) => {
+                    warn!(
+                        "Skipping invalid {} IP entry `{entry}` at {}:{}: {err}",
+                        kind.as_str(),
+                        path.display(),
+                        n + 1
+                    );
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Load the IP list files and hot-reload them when they change
+pub(super) fn init(
+    allow_file: Option<PathBuf>,
+    deny_file: Option<PathBuf>,
+) -> anyhow::Result<Option<Hotwatch>> {
+    if allow_file.is_none() && deny_file.is_none() {
+        return Ok(None);
+    }
+    let filter = Filter {
+        allow: allow_file
+            .map(|path| List::new(Kind::Allow, path))
+            .transpose()?,
+        deny: deny_file
+            .map(|path| List::new(Kind::Deny, path))
+            .transpose()?,
+    };
+    let filter = match FILTER.set(filter) {
+        Ok(()) => FILTER.get().expect("IP filter is set"),
+        Err(_) => anyhow::bail!("IP filter is already initialized"),
+    };
+
+    let mut hotwatch = Hotwatch::new()?;
+    for list in filter.allow.iter().chain(filter.deny.iter()) {
+        list.watch(&mut hotwatch)?;
+    }
+    Ok(Some(hotwatch))
+}
+
+/// Reload the IP list files, `false` when none is configured
+pub(super) fn reload() -> bool {
+    match FILTER.get() {
+        Some(filter) => {
+            for list in filter.allow.iter().chain(filter.deny.iter()) {
+                list.reload();
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+impl Filter {
+    /// A denied address is rejected, then an address is allowed if the allow list is
+    /// unset or lists it
+    fn allows(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack listener are seen as IPv4-mapped IPv6 addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        if let Some(deny) = self.deny.as_ref() {
+            if deny.entries().iter().any(|cidr| cidr.contains(&ip)) {
+                return false;
+            }
+        }
+        match self.allow.as_ref() {
+            Some(allow) => allow.entries().iter().any(|cidr| cidr.contains(&ip)),
+            None => true,
+        }
+    }
+}
+
+/// Reject the requests of peers denied by the IP lists with 403
+pub(super) async fn check<B>(request: Request<B>, next: Next<B>) -> Response {
+    let filter = match FILTER.get() {
+        Some(filter) => filter,
+        None => return next.run(request).await,
+    };
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    match peer {
+        Some(ip) if !filter.allows(ip) => {
+            debug!("Request from {ip} rejected by the IP lists");
+            ResponseError::Forbidden(ProxyError::IpNotAllowed).into_response()
+        }
+        _ => next.run(request).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(kind: Kind, data: &str) -> List {
+        let path = PathBuf::from("ips.txt");
+        List {
+            kind,
+            entries: RwLock::new(Arc::new(parse(kind, &path, data))),
+            path,
+        }
+    }
+
+    #[test]
+    fn test_parse_skips_comments_and_invalid_entries() {
+        let entries = parse(
+            Kind::Allow,
+            Path::new("ips.txt"),
+            "# office\n10.0.0.0/8\n\n  192.168.1.7 # admin\nnot-an-ip\n10.0.0.1/8\n2001:db8::/32\n",
+        );
+        let expected = ["10.0.0.0/8", "192.168.1.7/32", "2001:db8::/32"]
+            .map(|cidr| cidr::IpCidr::from_str(cidr).unwrap());
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn test_deny_before_allow() {
+        let filter = Filter {
+            allow: Some(list(Kind::Allow, "10.0.0.0/8\n")),
+            deny: Some(list(Kind::Deny, "10.1.0.0/16\n")),
+        };
+        assert!(filter.allows("10.2.3.4".parse().unwrap()));
+        assert!(!filter.allows("10.1.3.4".parse().unwrap()));
+        assert!(!filter.allows("192.168.1.1".parse().unwrap()));
+        assert!(filter.allows("::ffff:10.2.3.4".parse().unwrap()));
+
+        // An allow list without entries lets nobody in
+        let filter = Filter {
+            allow: Some(list(Kind::Allow, "# nobody yet\n")),
+            deny: None,
+        };
+        assert!(!filter.allows("192.168.1.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_empty_allow_list_refused() {
+        let path = Path::new("ips.txt");
+        let allow = |data| checked(Kind::Allow, path, parse(Kind::Allow, path, data));
+        assert!(allow("# nobody yet\n").is_err());
+        assert!(allow("not-an-ip\n").is_err());
+        assert_eq!(allow("10.0.0.0/8\n").unwrap().len(), 1);
+        assert!(checked(Kind::Deny, path, Vec::new()).unwrap().is_empty());
+
+        // A reload to an empty list keeps the previous entries
+        let list = List {
+            kind: Kind::Allow,
+            path: std::env::temp_dir().join(format!("ninja-allow-{}.txt", std::process::id())),
+            entries: RwLock::new(Arc::new(parse(Kind::Allow, path, "10.0.0.0/8\n"))),
+        };
+        std::fs::write(&list.path, "# emptied\n").unwrap();
+        list.reload();
+        std::fs::remove_file(&list.path).unwrap();
+        assert_eq!(list.entries().len(), 1);
+    }
+}
@@ -0,0 +1,73 @@
+use super::leader;
+use crate::secret::Secret;
+use crate::{debug, info, warn, with_context, URL_CHATGPT_API};
+use std::time::Duration;
+
+/// Ping the upstream on an interval, keeps the upstream session and the
+/// client connections warm between idle periods
+pub(super) async fn upstream_keepalive(token: Secret, interval: Duration) {
+    info!(
+        "Upstream keepalive task is running, interval {} seconds",
+        interval.as_secs()
+    );
+    // The server has just started, the first ping waits for an interval
+    tokio::time::sleep(interval).await;
+    leader::every("upstream_keepalive", interval, || async {
+        let client = with_context!(api_client);
+        match ping(&client, URL_CHATGPT_API, &token).await {
+            Ok(_) => debug!("Upstream keepalive ping ok"),
+            Err(err) => warn!("Upstream keepalive ping failed: {err}"),
+        }
+    })
+    .await
+}
+
+/// GET the models of the `base` upstream with the keepalive token
+async fn ping(client: &reqwest::Client, base: &str, token: &Secret) -> anyhow::Result<()> {
+    client
+        .get(format!("{base}/backend-api/models"))
+        .bearer_auth(token.expose())
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Upstream answering `status` once, returns the request it received
+    async fn upstream(status: u16) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let request = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 4096];
+            let n = stream.read(&mut buf).await.unwrap();
+            let resp =
+                format!("HTTP/1.1 {status} X\r\ncontent-length: 0\r\nconnection: close\r\n\r\n");
+            let _ = stream.write_all(resp.as_bytes()).await;
+            String::from_utf8_lossy(&buf[..n]).to_lowercase()
+        });
+        (base, request)
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        let client = reqwest::Client::new();
+        let token = Secret::new("keepalive-token");
+
+        let (base, request) = upstream(200).await;
+        assert!(ping(&client, &base, &token).await.is_ok());
+        let request = request.await.unwrap();
+        assert!(request.starts_with("get /backend-api/models "));
+        assert!(request.contains("authorization: bearer keepalive-token"));
+
+        // An expired session is reported, the task keeps running
+        let (base, _) = upstream(401).await;
+        assert!(ping(&client, &base, &token).await.is_err());
+    }
+}
@@ -0,0 +1,507 @@
+use crate::homedir::home_dir;
+use crate::{context, debug, info, now_duration, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+/// Lease guard files older than this are left by a dead process and removed
+const STALE_GUARD: Duration = Duration::from_secs(10);
+/// Shortest poll period of a job
+const MIN_POLL: Duration = Duration::from_millis(10);
+
+/// Job leases of this instance
+static LEASES: OnceLock<Leases> = OnceLock::new();
+/// Jobs seen by this instance: whether it holds the lease and the runs it made
+static JOBS: Mutex<BTreeMap<&'static str, JobState>> = Mutex::new(BTreeMap::new());
+
+/// Lease of a periodic job. The last run is kept across owners, so a new leader
+/// does not run the job again before its interval has passed.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Lease {
+    owner: String,
+    /// Unix timestamp (millisecond)
+    expires_at: u64,
+    /// Unix timestamp (millisecond)
+    last_run: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Claim {
+    /// Another owner holds the lease
+    Follower,
+    /// The lease is held, `run` if the job is due
+    Leader { run: bool },
+}
+
+/// Take or renew the lease, returns the new lease when it changed
+fn decide(
+    lease: Option<&Lease>,
+    owner: &str,
+    now: u64,
+    ttl: u64,
+    interval: u64,
+) -> (Option<Lease>, Claim) {
+    if let Some(lease) = lease {
+        if lease.owner != owner && lease.expires_at > now {
+            return (None, Claim::Follower);
+        }
+    }
+    let last_run = lease.map_or(0, |lease| lease.last_run);
+    let run = now >= last_run.saturating_add(interval);
+    let lease = Lease {
+        owner: owner.to_owned(),
+        expires_at: now + ttl,
+        last_run: if run { now } else { last_run },
+    };
+    (Some(lease), Claim::Leader { run })
+}
+
+trait LeaseStore: Send + Sync {
+    /// Take or renew the lease of `job` and claim its run when it is due
+    fn claim(
+        &self,
+        job: &str,
+        owner: &str,
+        now: u64,
+        ttl: u64,
+        interval: u64,
+    ) -> anyhow::Result<Claim>;
+
+    /// Give up the lease of `job` if `owner` holds it
+    fn release(&self, job: &str, owner: &str) -> anyhow::Result<()>;
+}
+
+/// In-process leases, a single owner per job within the process
+#[derive(Default)]
+struct MemLeases(Mutex<HashMap<String, Lease>>);
+
+impl LeaseStore for MemLeases {
+    fn claim(
+        &self,
+        job: &str,
+        owner: &str,
+        now: u64,
+        ttl: u64,
+        interval: u64,
+    ) -> anyhow::Result<Claim> {
+        let mut leases = self
+            .0
+            .lock()
+            .map_err(|_| anyhow::anyhow!("leases lock poisoned"))?;
+        let (lease, claim) = decide(leases.get(job), owner, now, ttl, interval);
+        if let Some(lease) = lease {
+            leases.insert(job.to_owned(), lease);
+        }
+        Ok(claim)
+    }
+
+    fn release(&self, job: &str, owner: &str) -> anyhow::Result<()> {
+        let mut leases = self
+            .0
+            .lock()
+            .map_err(|_| anyhow::anyhow!("leases lock poisoned"))?;
+        if let Some(lease) = leases.get_mut(job).filter(|lease| lease.owner == owner) {
+            lease.expires_at = 0;
+        }
+        Ok(())
+    }
+}
+
+/// Leases in a directory shared by the instances, one file per job.
+/// Changes are made under a guard file created exclusively.
+struct FileLeases {
+    dir: PathBuf,
+}
+
+impl FileLeases {
+    fn new(dir: PathBuf) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn lease_path(&self, job: &str) -> PathBuf {
+        self.dir.join(format!("{job}.lease"))
+    }
+
+    /// Run `f` while holding the guard of `job`
+    fn guarded<T>(&self, job: &str, f: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+        let guard = self.dir.join(format!("{job}.lock"));
+        let mut attempts = 0;
+        loop {
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&guard)
+            {
+                Ok(_) => break,
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                    if is_stale(&guard) {
+                        warn!("Removing the stale lease guard {}", guard.display());
+                        let _ = std::fs::remove_file(&guard);
+                        continue;
+                    }
+                    attempts += 1;
+                    if attempts > 500 {
+                        anyhow::bail!("Lease guard {} is busy", guard.display())
+                    }
+                    std::thread::sleep(Duration::from_millis(2));
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        let result = f();
+        std::fs::remove_file(&guard)?;
+        result
+    }
+
+    fn read(&self, job: &str) -> anyhow::Result<Option<Lease>> {
+        match std::fs::read(self.lease_path(job)) {
+            Ok(data) => Ok(serde_json::from_slice(&data).ok()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn write(&self, job: &str, lease: &Lease) -> anyhow::Result<()> {
+        let path = self.lease_path(job);
+        let tmp = path.with_extension("lease.tmp");
+        std::fs::write(&tmp, serde_json::to_vec(lease)?)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+fn is_stale(guard: &Path) -> bool {
+    std::fs::metadata(guard)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .map_or(false, |age| age > STALE_GUARD)
+}
+
+impl LeaseStore for FileLeases {
+    fn claim(
+        &self,
+        job: &str,
+        owner: &str,
+        now: u64,
+        ttl: u64,
+        interval: u64,
+    ) -> anyhow::Result<Claim> {
+        self.guarded(job, || {
+            let (lease, claim) = decide(self.read(job)?.as_ref(), owner, now, ttl, interval);
+            if let Some(lease) = lease {
+                self.write(job, &lease)?;
+            }
+            Ok(claim)
+        })
+    }
+
+    fn release(&self, job: &str, owner: &str) -> anyhow::Result<()> {
+        self.guarded(job, || match self.read(job)? {
+            Some(mut lease) if lease.owner == owner => {
+                lease.expires_at = 0;
+                self.write(job, &lease)
+            }
+            _ => Ok(()),
+        })
+    }
+}
+
+struct Leases {
+    store: Box<dyn LeaseStore>,
+    owner: String,
+    ttl: Duration,
+}
+
+#[derive(Default)]
+struct JobState {
+    leader: bool,
+    runs: u64,
+}
+
+/// Owner id of this instance
+fn owner_id() -> String {
+    let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "localhost".to_owned());
+    format!(
+        "{host}-{}-{:08x}",
+        std::process::id(),
+        rand::random::<u32>()
+    )
+}
+
+/// Select the lease store: `mem` coordinates the jobs within the process, `file`
+/// across the instances sharing the lease directory
+pub(super) fn init(store: &str, dir: Option<PathBuf>, ttl: u64) -> anyhow::Result<()> {
+    let store: Box<dyn LeaseStore> = match store {
+        "mem" => Box::<MemLeases>::default(),
+        "file" => {
+            let dir = dir.unwrap_or_else(|| {
+                home_dir()
+                    .unwrap_or_default()
+                    .join(context::WORKER_DIR)
+                    .join("leases")
+            });
+            info!("Background job leases: {}", dir.display());
+            Box::new(FileLeases::new(dir)?)
+        }
+        _ => anyhow::bail!("Invalid leader_store: {store}, must be mem or file"),
+    };
+    let leases = Leases {
+        store,
+        owner: owner_id(),
+        ttl: Duration::from_secs(ttl.max(1)),
+    };
+    info!("Background job owner: {}", leases.owner);
+    let _ = LEASES.set(leases);
+    Ok(())
+}
+
+fn leases() -> &'static Leases {
+    LEASES.get_or_init(|| Leases {
+        store: Box::<MemLeases>::default(),
+        owner: owner_id(),
+        ttl: Duration::from_secs(30),
+    })
+}
+
+/// Poll period of a job, short enough to renew its lease before it expires
+pub(crate) fn poll_period(interval: Duration) -> Duration {
+    interval.min(leases().ttl / 3).max(MIN_POLL)
+}
+
+/// Whether this instance runs `job` now: it holds the job lease, and `interval` has
+/// passed since the last run of the job on any instance. Renews the lease.
+pub(crate) fn claim(job: &'static str, interval: Duration) -> bool {
+    let leases = leases();
+    claim_in(leases.store.as_ref(), &leases.owner, job, interval)
+}
+
+/// Like [`claim`] for the jobs on the local state of the instance (its own ReDB
+/// files), a single owner within the process whatever the lease store
+pub(crate) fn claim_local(job: &'static str, interval: Duration) -> bool {
+    static LOCAL: OnceLock<MemLeases> = OnceLock::new();
+    let local = LOCAL.get_or_init(MemLeases::default);
+    claim_in(local, &leases().owner, job, interval)
+}
+
+fn claim_in(store: &dyn LeaseStore, owner: &str, job: &'static str, interval: Duration) -> bool {
+    let claim = now_duration().and_then(|now| {
+        store.claim(
+            job,
+            owner,
+            now.as_millis() as u64,
+            leases().ttl.as_millis() as u64,
+            interval.as_millis() as u64,
+        )
+    });
+    let claim = match claim {
+        Ok(claim) => claim,
+        Err(err) => {
+            warn!("Failed to claim the lease of job {job}: {err}");
+            Claim::Follower
+        }
+    };
+
+    if let Ok(mut jobs) = JOBS.lock() {
+        let state = jobs.entry(job).or_default();
+        let leader = matches!(claim, Claim::Leader { .. });
+        if leader != state.leader {
+            debug!("Job {job}: {}", if leader { "leader" } else { "follower" });
+        }
+        state.leader = leader;
+        if claim == (Claim::Leader { run: true }) {
+            state.runs += 1;
+        }
+    }
+    claim == Claim::Leader { run: true }
+}
+
+/// Run `job` every `interval` on the instance holding its lease. The claim runs on
+/// the blocking pool, the file store waits for its guard file.
+pub(crate) async fn every<F, Fut>(job: &'static str, interval: Duration, mut f: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut poll = tokio::time::interval(poll_period(interval));
+    poll.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        poll.tick().await;
+        let claimed = tokio::task::spawn_blocking(move || claim(job, interval))
+            .await
+            .unwrap_or(false);
+        if claimed {
+            f().await;
+        }
+    }
+}
+
+/// Give up the leases held by this instance, another instance takes the jobs over
+/// without waiting for the leases to expire. Blocking, see [`every`].
+pub(super) fn release_all() {
+    let leases = match LEASES.get() {
+        Some(leases) => leases,
+        None => return,
+    };
+    let jobs = JOBS
+        .lock()
+        .map(|jobs| {
+            jobs.iter()
+                .filter(|(_, state)| state.leader)
+                .map(|(job, _)| *job)
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    for job in jobs {
+        if let Err(err) = leases.store.release(job, &leases.owner) {
+            warn!("Failed to release the lease of job {job}: {err}");
+        }
+    }
+}
+
+/// Jobs seen by this instance: name, whether it is the leader and its run count
+pub(super) fn jobs() -> Vec<(&'static str, bool, u64)> {
+    JOBS.lock()
+        .map(|jobs| {
+            jobs.iter()
+                .map(|(job, state)| (*job, state.leader, state.runs))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    const TTL: u64 = 300;
+    const INTERVAL: u64 = 100;
+
+    fn temp_leases(name: &str) -> FileLeases {
+        let dir = std::env::temp_dir().join(format!("ninja-leases-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        FileLeases::new(dir).unwrap()
+    }
+
+    #[test]
+    fn test_decide() {
+        let (lease, claim) = decide(None, "a", 1000, TTL, INTERVAL);
+        let lease = lease.unwrap();
+        assert_eq!(claim, Claim::Leader { run: true });
+        assert_eq!((lease.expires_at, lease.last_run), (1300, 1000));
+
+        // Another owner waits for the lease to expire
+        assert_eq!(
+            decide(Some(&lease), "b", 1299, TTL, INTERVAL).1,
+            Claim::Follower
+        );
+        // The owner renews, not due yet
+        let (renewed, claim) = decide(Some(&lease), "a", 1050, TTL, INTERVAL);
+        assert_eq!(claim, Claim::Leader { run: false });
+        assert_eq!(renewed.unwrap().expires_at, 1350);
+
+        // Takeover of an expired lease keeps the last run
+        let (taken, claim) = decide(Some(&lease), "b", 1300, TTL, 1000);
+        assert_eq!(claim, Claim::Leader { run: false });
+        assert_eq!(taken.unwrap().last_run, 1000);
+    }
+
+    /// Two instances poll the job every 10ms on a simulated clock, the leader dies
+    /// halfway through
+    fn leader_failure(store: &dyn LeaseStore) {
+        let mut runs = Vec::new();
+        for now in (0..2000).step_by(10) {
+            // `a` dies at 1000
+            if now < 1000
+                && store.claim("job", "a", now, TTL, INTERVAL).unwrap()
+                    == (Claim::Leader { run: true })
+            {
+                runs.push(("a", now));
+            }
+            if store.claim("job", "b", now, TTL, INTERVAL).unwrap() == (Claim::Leader { run: true })
+            {
+                runs.push(("b", now));
+            }
+        }
+
+        // Exactly once per interval while the leader lives
+        let before: Vec<_> = runs.iter().filter(|(_, at)| *at < 1000).collect();
+        assert_eq!(before.len(), 10);
+        assert!(before.iter().all(|(owner, _)| *owner == "a"));
+        // `b` takes over once the lease expired, from the last renewal at 990
+        let after: Vec<_> = runs.iter().filter(|(_, at)| *at >= 1000).collect();
+        assert_eq!(after.first(), Some(&&("b", 1290)));
+        assert!(after.iter().all(|(owner, _)| *owner == "b"));
+        for pair in runs.windows(2) {
+            assert!(pair[1].1 - pair[0].1 >= INTERVAL, "{runs:?}");
+        }
+    }
+
+    #[test]
+    fn test_leader_failure() {
+        leader_failure(&MemLeases::default());
+        leader_failure(&temp_leases("failure"));
+    }
+
+    #[test]
+    fn test_release_hands_over() {
+        let store = temp_leases("release");
+        assert_eq!(
+            store.claim("job", "a", 0, TTL, INTERVAL).unwrap(),
+            Claim::Leader { run: true }
+        );
+        assert_eq!(
+            store.claim("job", "b", 10, TTL, INTERVAL).unwrap(),
+            Claim::Follower
+        );
+        store.release("job", "a").unwrap();
+        // `b` takes over at once, the interval still applies
+        assert_eq!(
+            store.claim("job", "b", 20, TTL, INTERVAL).unwrap(),
+            Claim::Leader { run: false }
+        );
+        assert_eq!(
+            store.claim("job", "b", 100, TTL, INTERVAL).unwrap(),
+            Claim::Leader { run: true }
+        );
+    }
+
+    #[test]
+    fn test_concurrent_instances_run_once_per_interval() {
+        let store = Arc::new(temp_leases("concurrent"));
+        let interval = 50;
+        let runs = Arc::new(Mutex::new(Vec::new()));
+        let instances = ["a", "b"].map(|owner| {
+            let (store, runs) = (store.clone(), runs.clone());
+            std::thread::spawn(move || {
+                for _ in 0..100 {
+                    let now = now_duration().unwrap().as_millis() as u64;
+                    if store.claim("job", owner, now, TTL, interval).unwrap()
+                        == (Claim::Leader { run: true })
+                    {
+                        runs.lock().unwrap().push((owner, now));
+                    }
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+            })
+        });
+        for instance in instances {
+            instance.join().unwrap();
+        }
+
+        let mut runs = runs.lock().unwrap().clone();
+        runs.sort_by_key(|(_, at)| *at);
+        assert!(!runs.is_empty());
+        // A single leader, no two runs in the same interval
+        assert!(runs.iter().all(|(owner, _)| *owner == runs[0].0));
+        for pair in runs.windows(2) {
+            assert!(pair[1].1 - pair[0].1 >= interval, "{runs:?}");
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//! Lifecycle events of the instance posted to `lifecycle_webhook`: ready, reload and
+//! shutdown. Best effort, an event is sent in the background with a few retries and
+//! never delays the startup, the shutdown waits a moment for its event.
+
+use crate::{debug, now_duration, warn, LIB_VERSION};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+
+/// Attempts of an event, the first one included
+const MAX_ATTEMPTS: u32 = 3;
+/// Wait before the first retry, doubled on each retry
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+/// Timeout of an attempt
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Wait for the shutdown event before the server stops, the process may exit after
+const SHUTDOWN_WAIT: Duration = Duration::from_secs(2);
+
+static WEBHOOK: OnceLock<Webhook> = OnceLock::new();
+
+struct Webhook {
+    url: String,
+    client: reqwest::Client,
+    /// Runtime of the server, the reloads are reported from the file watcher threads
+    runtime: Handle,
+    address: SocketAddr,
+    host: String,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum Kind {
+    Ready,
+    Reload,
+    Shutdown,
+}
+
+#[derive(Serialize, Debug)]
+struct Event<'a> {
+    event: Kind,
+    /// What was reloaded, or the signal of the shutdown
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'a str>,
+    host: String,
+    pid: u32,
+    address: SocketAddr,
+    version: &'static str,
+    /// Unix seconds
+    timestamp: u64,
+}
+
+/// Enable the events, must be called on the server runtime
+pub(super) fn init(url: Option<&str>, address: SocketAddr) -> anyhow::Result<()> {
+    let Some(url) = url else {
+        return Ok(());
+    };
+    let parsed = url::Url::parse(url)?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        anyhow::bail!("lifecycle_webhook must be an http or https URL: {url}")
+    }
+    let client = reqwest::Client::builder()
+        .timeout(ATTEMPT_TIMEOUT)
+        .build()?;
+    let _ = WEBHOOK.set(Webhook {
+        url: url.to_owned(),
+        client,
+        runtime: Handle::current(),
+        address,
+        host: hostname(),
+    });
+    Ok(())
+}
+
+/// Post a lifecycle event in the background, a no-op without `lifecycle_webhook`
+pub(super) fn notify(kind: Kind, reason: Option<&str>) {
+    send(kind, reason);
+}
+
+/// Post the shutdown event and wait up to `SHUTDOWN_WAIT` for its delivery
+pub(super) async fn shutdown(reason: Option<&str>) {
+    if let Some(task) = send(Kind::Shutdown, reason) {
+        if tokio::time::timeout(SHUTDOWN_WAIT, task).await.is_err() {
+            debug!("Lifecycle event Shutdown still pending, shutting down");
+        }
+    }
+}
+
+fn send(kind: Kind, reason: Option<&str>) -> Option<JoinHandle<()>> {
+    let webhook = WEBHOOK.get()?;
+    let event = Event {
+        event: kind,
+        reason,
+        host: webhook.host.clone(),
+        pid: std::process::id(),
+        address: webhook.address,
+        version: LIB_VERSION,
+        timestamp: now_duration().map_or(0, |now| now.as_secs()),
+    };
+    let body = match serde_json::to_vec(&event) {
+        Ok(body) => body,
+        Err(err) => {
+            warn!("Failed to encode the lifecycle event: {err}");
+            return None;
+        }
+    };
+    let (client, url) = (webhook.client.clone(), webhook.url.clone());
+    Some(webhook.runtime.spawn(async move {
+        match deliver(&client, &url, body, RETRY_BACKOFF).await {
+            Ok(()) => debug!("Lifecycle event {kind:?} sent"),
+            Err(err) => warn!("Failed to send the lifecycle event {kind:?}: {err}"),
+        }
+    }))
+}
+
+/// Host name of the machine, `HOSTNAME` is a shell variable most services do not get
+fn hostname() -> String {
+    #[cfg(target_family = "unix")]
+    if let Ok(name) = nix::unistd::gethostname() {
+        return name.to_string_lossy().into_owned();
+    }
+    #[cfg(windows)]
+    if let Ok(name) = std::env::var("COMPUTERNAME") {
+        return name;
+    }
+    "localhost".to_owned()
+}
+
+/// Post the event, retrying the failed requests and the 5xx and 429 responses
+async fn deliver(
+    client: &reqwest::Client,
+    url: &str,
+    body: Vec<u8>,
+    backoff: Duration,
+) -> anyhow::Result<()> {
+    let mut backoff = backoff;
+    let mut attempt = 1;
+    loop {
+        let result = client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.clone())
+            .send()
+            .await;
+        let retryable = match result {
+            Ok(resp) if resp.status().is_success() => return Ok(()),
+            Ok(resp) => {
+                let status = resp.status();
+                if !(status.is_server_error() || status.as_u16() == 429) {
+                    anyhow::bail!("webhook answered {status}")
+                }
+                anyhow::anyhow!("webhook answered {status}")
+            }
+            Err(err) => err.into(),
+        };
+        if attempt == MAX_ATTEMPTS {
+            return Err(retryable.context(format!("gave up after {MAX_ATTEMPTS} attempts")));
+        }
+        debug!("Lifecycle event attempt {attempt} failed: {retryable}");
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Webhook answering the statuses in order, one connection per request
+    async fn webhook(statuses: &'static [u16]) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let _ = stream.read(&mut [0u8; 4096]).await;
+                let resp = format!(
+                    "HTTP/1.1 {status} X\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                );
+                let _ = stream.write_all(resp.as_bytes()).await;
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_event() {
+        let event = Event {
+            event: Kind::Reload,
+            reason: Some("tls_keypair"),
+            host: "node-1".to_owned(),
+            pid: 42,
+            address: "0.0.0.0:7999".parse().unwrap(),
+            version: "1.0.0",
+            timestamp: 1_700_000_000,
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({
+                "event": "reload",
+                "reason": "tls_keypair",
+                "host": "node-1",
+                "pid": 42,
+                "address": "0.0.0.0:7999",
+                "version": "1.0.0",
+                "timestamp": 1_700_000_000
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deliver_retries() {
+        let client = reqwest::Client::new();
+        let backoff = Duration::from_millis(1);
+
+        let addr = webhook(&[503, 200]).await;
+        let url = format!("http://{addr}/events");
+        assert!(deliver(&client, &url, b"{}".to_vec(), backoff)
+            .await
+            .is_ok());
+
+        // The retries are capped
+        let addr = webhook(&[503, 502, 503, 200]).await;
+        let url = format!("http://{addr}/events");
+        assert!(deliver(&client, &url, b"{}".to_vec(), backoff)
+            .await
+            .is_err());
+
+        // A client error is not retried
+        let addr = webhook(&[404, 200]).await;
+        let url = format!("http://{addr}/events");
+        assert!(deliver(&client, &url, b"{}".to_vec(), backoff)
+            .await
+            .is_err());
+    }
+}
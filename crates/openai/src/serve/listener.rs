@@ -0,0 +1,353 @@
+use crate::context::args::AdminListen;
+use crate::serve::accept::{ConnectionLimit, LimitedStream, Slot};
+use crate::{info, warn};
+use axum::Router;
+use axum_hyper::server::accept::Accept;
+use axum_hyper::server::conn::AddrIncoming;
+use futures::future::BoxFuture;
+use std::future::Future;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+/// Bind the server listener, trying the next `port_retry` ports when the port is in use
+pub(crate) fn bind(addr: SocketAddr, port_retry: u16) -> anyhow::Result<TcpListener> {
+    let last = addr.port().saturating_add(port_retry);
+    for port in addr.port()..=last {
+        let mut next = addr;
+        next.set_port(port);
+        match TcpListener::bind(next) {
+            Ok(listener) => {
+                listener.set_nonblocking(true)?;
+                if port != addr.port() {
+                    warn!("Port {} is in use, bound to port {port}", addr.port());
+                }
+                return Ok(listener);
+            }
+            Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+                if port_retry > 0 {
+                    warn!("{}", addr_in_use_message(port));
+                }
+            }
+            Err(err) => anyhow::bail!("Failed to bind {next}: {err}"),
+        }
+    }
+
+    if port_retry > 0 {
+        anyhow::bail!(
+            "ports {}-{last} are in use; change --bind or stop the processes using them",
+            addr.port()
+        )
+    }
+    anyhow::bail!(
+        "{}; change --bind or stop that process",
+        addr_in_use_message(addr.port())
+    )
+}
+
+/// Listener of the admin endpoints
+pub(crate) enum AdminListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener, PathBuf),
+}
+
+/// Bind the admin listener, a Unix socket is accessible by the owner only
+pub(crate) fn bind_admin(listen: &AdminListen) -> anyhow::Result<AdminListener> {
+    match listen {
+        AdminListen::Tcp(addr) => Ok(AdminListener::Tcp(bind(*addr, 0)?)),
+        #[cfg(unix)]
+        AdminListen::Unix(path) => Ok(AdminListener::Unix(bind_unix(path)?, path.clone())),
+        #[cfg(not(unix))]
+        AdminListen::Unix(_) => anyhow::bail!("Unix socket admin listen is not supported"),
+    }
+}
+
+/// Bind a Unix socket with 0600 permissions. The socket is bound in a private 0700
+/// directory next to the path, restricted, then renamed into place, so it is never
+/// reachable by other users, whatever the umask.
+/// A stale socket of a previous run is replaced, a live one is an error.
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path) -> anyhow::Result<std::os::unix::net::UnixListener> {
+    use std::fs::{self, DirBuilder, Permissions};
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            anyhow::bail!("{} exists and is not a socket", path.display())
+        }
+        if UnixStream::connect(path).is_ok() {
+            anyhow::bail!("{} is in use by a running server", path.display())
+        }
+        fs::remove_file(path)?;
+    }
+
+    let mut private = path.as_os_str().to_owned();
+    private.push(format!(".{}.tmp", std::process::id()));
+    let private = PathBuf::from(private);
+    let _ = fs::remove_dir_all(&private);
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&private)
+        .map_err(|err| anyhow::anyhow!("Failed to bind {}: {err}", path.display()))?;
+
+    let tmp = private.join("admin.sock");
+    let bound = UnixListener::bind(&tmp).and_then(|listener| {
+        fs::set_permissions(&tmp, Permissions::from_mode(0o600))?;
+        fs::rename(&tmp, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&private);
+    let listener =
+        bound.map_err(|err| anyhow::anyhow!("Failed to bind {}: {err}", path.display()))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Serve the admin router until the task is aborted
+pub(crate) async fn serve_admin(
+    listener: AdminListener,
+    router: Router,
+    limit: ConnectionLimit,
+) -> anyhow::Result<()> {
+    match listener {
+        AdminListener::Tcp(listener) => {
+            info!("Admin endpoints at http://{}", listener.local_addr()?);
+            let incoming =
+                AddrIncoming::from_listener(tokio::net::TcpListener::from_std(listener)?)?;
+            axum::Server::builder(LimitedAccept::new(incoming, limit))
+                .serve(router.into_make_service())
+                .await?;
+        }
+        #[cfg(unix)]
+        AdminListener::Unix(listener, path) => {
+            info!("Admin endpoints at unix:{}", path.display());
+            // Remove the socket file when the server stops
+            let _socket = SocketFile(path);
+            let listener = tokio::net::UnixListener::from_std(listener)?;
+            axum::Server::builder(LimitedAccept::new(UnixAccept(listener), limit))
+                .serve(router.into_make_service())
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+struct SocketFile(PathBuf);
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(unix)]
+struct UnixAccept(tokio::net::UnixListener);
+
+#[cfg(unix)]
+impl Accept for UnixAccept {
+    type Conn = tokio::net::UnixStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.0
+            .poll_accept(cx)
+            .map(|result| Some(result.map(|(stream, _)| stream)))
+    }
+}
+
+/// Stops accepting while the connection limit is reached, the pending connections
+/// wait in the listen backlog
+struct LimitedAccept<A> {
+    inner: A,
+    limit: ConnectionLimit,
+    slot: Option<Slot>,
+    waiting: Option<BoxFuture<'static, Slot>>,
+}
+
+impl<A> LimitedAccept<A> {
+    fn new(inner: A, limit: ConnectionLimit) -> Self {
+        Self {
+            inner,
+            limit,
+            slot: None,
+            waiting: None,
+        }
+    }
+}
+
+impl<A: Accept + Unpin> Accept for LimitedAccept<A> {
+    type Conn = LimitedStream<A::Conn>;
+    type Error = A::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+        if this.slot.is_none() {
+            let limit = &this.limit;
+            let waiting = this
+                .waiting
+                .get_or_insert_with(|| Box::pin(limit.clone().acquire()));
+            this.slot = Some(ready!(waiting.as_mut().poll(cx)));
+            this.waiting = None;
+        }
+
+        let conn = ready!(Pin::new(&mut this.inner).poll_accept(cx));
+        Poll::Ready(conn.map(|conn| {
+            conn.map(|conn| LimitedStream::new(conn, this.slot.take().expect("slot acquired")))
+        }))
+    }
+}
+
+fn addr_in_use_message(port: u16) -> String {
+    match port_owner(port) {
+        Some((pid, name)) => format!("port {port} is in use by pid {pid} ({name})"),
+        None => format!("port {port} is in use"),
+    }
+}
+
+/// Find the process listening on the given port (Linux only, via /proc)
+#[cfg(target_os = "linux")]
+fn port_owner(port: u16) -> Option<(u32, String)> {
+    // Socket inodes listening on the port
+    let inodes = ["/proc/net/tcp", "/proc/net/tcp6"]
+        .iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .flat_map(|table| {
+            table
+                .lines()
+                .skip(1)
+                .filter_map(|line| {
+                    let fields = line.split_whitespace().collect::<Vec<_>>();
+                    // local_address is `ADDR:PORT` (hex), state 0A is LISTEN
+                    let local_port = fields.get(1)?.rsplit(':').next()?;
+                    let state = fields.get(3)?;
+                    let inode = fields.get(9)?;
+                    (u16::from_str_radix(local_port, 16).ok()? == port && *state == "0A")
+                        .then(|| format!("socket:[{inode}]"))
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    if inodes.is_empty() {
+        return None;
+    }
+
+    std::fs::read_dir("/proc")
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let pid = entry.file_name().to_str()?.parse::<u32>().ok()?;
+            Some((pid, entry.path()))
+        })
+        .find_map(|(pid, path)| {
+            let owned = std::fs::read_dir(path.join("fd"))
+                .ok()?
+                .filter_map(|fd| fd.ok())
+                .filter_map(|fd| std::fs::read_link(fd.path()).ok())
+                .any(|link| inodes.iter().any(|inode| link.to_string_lossy().eq(inode)));
+            owned.then(|| {
+                let name = std::fs::read_to_string(path.join("comm")).unwrap_or_default();
+                (pid, name.trim().to_owned())
+            })
+        })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn port_owner(_port: u16) -> Option<(u32, String)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bind_addr_in_use() {
+        let holder = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = holder.local_addr().unwrap();
+
+        let err = bind(addr, 0).unwrap_err().to_string();
+        assert!(
+            err.starts_with(&format!("port {} is in use", addr.port())),
+            "{err}"
+        );
+        assert!(err.ends_with("change --bind or stop that process"), "{err}");
+
+        #[cfg(target_os = "linux")]
+        assert!(
+            err.contains(&format!("by pid {}", std::process::id())),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_parse_admin_listen() {
+        assert_eq!(
+            "127.0.0.1:7998".parse::<AdminListen>().unwrap(),
+            AdminListen::Tcp("127.0.0.1:7998".parse().unwrap())
+        );
+        assert_eq!(
+            "unix:/run/ninja/admin.sock".parse::<AdminListen>().unwrap(),
+            AdminListen::Unix(PathBuf::from("/run/ninja/admin.sock"))
+        );
+        assert!("unix:".parse::<AdminListen>().is_err());
+        assert!("localhost".parse::<AdminListen>().is_err());
+    }
+
+    #[test]
+    fn test_admin_listen_requires_auth_key() {
+        for listen in ["127.0.0.1:7998", "[::1]:7998", "unix:/run/ninja/admin.sock"] {
+            let listen = listen.parse::<AdminListen>().unwrap();
+            assert!(listen.is_local() && listen.validate(false).is_ok());
+        }
+        let public = "0.0.0.0:7998".parse::<AdminListen>().unwrap();
+        assert!(!public.is_local());
+        assert!(public.validate(false).is_err());
+        assert!(public.validate(true).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_bind_unix_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("ninja-admin-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("admin.sock");
+
+        let listener = bind_unix(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // The private bind directory is removed
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        // A live socket is not replaced
+        assert!(bind_unix(&path).is_err());
+
+        // A stale socket is
+        drop(listener);
+        bind_unix(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_bind_port_retry() {
+        let holder = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = holder.local_addr().unwrap();
+
+        let listener = bind(addr, 8).unwrap();
+        assert_ne!(listener.local_addr().unwrap().port(), addr.port());
+    }
+}
@@ -0,0 +1,368 @@
+//! Budget of the bodies buffered in memory (`max_buffered_memory`).
+//!
+//! The request bodies read by the proxy handlers and the responses buffered for the
+//! model listing filter, accounting, redaction, retries, stream adaptation and idempotency
+//! reserve their size until they are dropped. A body that does not fit the budget is
+//! answered with `503`, or streamed unbuffered where buffering is optional (retry
+//! inspection, idempotency caching).
+
+use crate::info;
+use crate::serve::error::{ProxyError, ResponseError};
+use axum::body::{BoxBody, Bytes, Full, HttpBody, StreamBody};
+use axum::http::HeaderMap;
+use futures::StreamExt;
+use pin_project_lite::pin_project;
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+
+/// Configured budget, unlimited until initialized
+static BUDGET: OnceLock<Budget> = OnceLock::new();
+static UNLIMITED: Budget = Budget::new(0);
+
+/// Bodies refused or streamed unbuffered because the budget was exhausted
+pub(crate) static EXCEEDED: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn init(limit: u64) {
+    if limit > 0 {
+        info!("Buffered memory limit: {limit} bytes");
+    }
+    let _ = BUDGET.set(Budget::new(limit));
+}
+
+fn budget() -> &'static Budget {
+    BUDGET.get().unwrap_or(&UNLIMITED)
+}
+
+/// Bytes buffered now
+pub(crate) fn buffered() -> u64 {
+    budget().buffered.load(Ordering::Relaxed)
+}
+
+/// Configured limit, 0 is unlimited
+pub(crate) fn limit() -> u64 {
+    budget().limit
+}
+
+/// Error of a body that must be buffered and does not fit the budget
+pub(crate) fn exceeded() -> ResponseError {
+    ResponseError::ServiceUnavailable(ProxyError::BufferedMemoryExceeded)
+}
+
+pub(crate) struct Budget {
+    /// Bytes, 0 is unlimited
+    limit: u64,
+    buffered: AtomicU64,
+}
+
+impl Budget {
+    const fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            buffered: AtomicU64::new(0),
+        }
+    }
+
+    fn try_reserve(&self, bytes: u64) -> bool {
+        if self.limit == 0 {
+            self.buffered.fetch_add(bytes, Ordering::Relaxed);
+            return true;
+        }
+        self.buffered
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |buffered| {
+                buffered
+                    .checked_add(bytes)
+                    .filter(|buffered| *buffered <= self.limit)
+            })
+            .is_ok()
+    }
+}
+
+/// Bytes reserved in the budget, released when dropped
+pub(crate) struct Reservation {
+    budget: &'static Budget,
+    bytes: u64,
+}
+
+impl Reservation {
+    pub(crate) fn new() -> Self {
+        Self::from_budget(budget())
+    }
+
+    fn from_budget(budget: &'static Budget) -> Self {
+        Self { budget, bytes: 0 }
+    }
+
+    /// Reserve `bytes` more, the reservation is left unchanged when they do not fit
+    pub(crate) fn grow(&mut self, bytes: usize) -> bool {
+        let bytes = bytes as u64;
+        if !self.budget.try_reserve(bytes) {
+            EXCEEDED.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        self.bytes += bytes;
+        true
+    }
+
+    /// Reserve up to `len` bytes in total
+    pub(crate) fn fit(&mut self, len: usize) -> bool {
+        match (len as u64).checked_sub(self.bytes) {
+            Some(more) if more > 0 => self.grow(more as usize),
+            _ => true,
+        }
+    }
+
+    /// Release the bytes reserved over `len`
+    pub(crate) fn shrink(&mut self, len: usize) {
+        if let Some(less) = self.bytes.checked_sub(len as u64) {
+            self.budget.buffered.fetch_sub(less, Ordering::Relaxed);
+            self.bytes -= less;
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget
+            .buffered
+            .fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+/// Reserve a request body of the declared `Content-Length` before it is read
+pub(crate) fn reserve_request(headers: &HeaderMap) -> Option<Reservation> {
+    let mut reservation = Reservation::new();
+    let len = headers
+        .get(axum::http::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(0);
+    reservation.grow(len).then_some(reservation)
+}
+
+pub(crate) enum Buffer {
+    /// The whole body, reserved in the budget
+    Full(Vec<u8>, Reservation),
+    /// Over the budget or the size cap: the chunks read so far, then the rest of the
+    /// body, unbuffered
+    Exceeded(BoxBody),
+}
+
+/// Read a body into memory within the budget
+pub(crate) async fn buffer(body: BoxBody) -> Result<Buffer, axum::Error> {
+    buffer_with(Reservation::new(), body, usize::MAX).await
+}
+
+/// Read a body into memory within the budget, a body over `max` bytes is not buffered
+/// whatever its declared size
+pub(crate) async fn buffer_at_most(body: BoxBody, max: usize) -> Result<Buffer, axum::Error> {
+    buffer_with(Reservation::new(), body, max).await
+}
+
+async fn buffer_with(
+    mut reservation: Reservation,
+    mut body: BoxBody,
+    max: usize,
+) -> Result<Buffer, axum::Error> {
+    // A body known to be over the cap or the budget is not read
+    let hint = body.size_hint().lower() as usize;
+    if hint > max || !reservation.grow(hint) {
+        return Ok(Buffer::Exceeded(body));
+    }
+
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        let len = data.len() + chunk.len();
+        if len > max || !reservation.fit(len) {
+            let read = futures::stream::iter([Ok(Bytes::from(data)), Ok(chunk)]);
+            let rest = futures::stream::poll_fn(move |cx| Pin::new(&mut body).poll_data(cx));
+            return Ok(Buffer::Exceeded(axum::body::boxed(StreamBody::new(
+                read.chain(rest),
+            ))));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(Buffer::Full(data, reservation))
+}
+
+/// Read a body that must be buffered, `503` when it does not fit the budget
+pub(crate) async fn read(body: BoxBody) -> Result<(Vec<u8>, Reservation), ResponseError> {
+    match buffer(body).await.map_err(ResponseError::BadGateway)? {
+        Buffer::Full(data, reservation) => Ok((data, reservation)),
+        Buffer::Exceeded(_) => Err(exceeded()),
+    }
+}
+
+/// Body of buffered data, the reservation is released when the body is dropped
+pub(crate) fn body(data: impl Into<Bytes>, reservation: Reservation) -> BoxBody {
+    axum::body::boxed(Held {
+        inner: Full::new(data.into()),
+        _reservation: reservation,
+    })
+}
+
+pin_project! {
+    struct Held {
+        #[pin]
+        inner: Full<Bytes>,
+        _reservation: Reservation,
+    }
+}
+
+impl HttpBody for Held {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        self.project().inner.poll_data(cx)
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        self.project().inner.poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget(limit: u64) -> &'static Budget {
+        Box::leak(Box::new(Budget::new(limit)))
+    }
+
+    fn chunked(chunks: &'static [&'static str]) -> BoxBody {
+        axum::body::boxed(StreamBody::new(futures::stream::iter(
+            chunks.iter().map(|chunk| Ok::<_, Infallible>(*chunk)),
+        )))
+    }
+
+    #[test]
+    fn test_reservation_released_on_drop() {
+        let budget = budget(10);
+        let mut first = Reservation::from_budget(budget);
+        assert!(first.grow(6));
+        let mut second = Reservation::from_budget(budget);
+        assert!(!second.grow(5));
+        assert!(second.grow(4));
+        assert_eq!(budget.buffered.load(Ordering::Relaxed), 10);
+
+        drop(first);
+        assert_eq!(budget.buffered.load(Ordering::Relaxed), 4);
+        assert!(second.fit(9));
+        // Shrunk to what is still held
+        second.shrink(3);
+        assert_eq!(budget.buffered.load(Ordering::Relaxed), 3);
+        second.shrink(5);
+        assert_eq!(budget.buffered.load(Ordering::Relaxed), 3);
+        drop(second);
+        assert_eq!(budget.buffered.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_buffer_within_budget() {
+        let budget = budget(16);
+        let body = chunked(&["0123", "4567"]);
+        let (data, reservation) =
+            match buffer_with(Reservation::from_budget(budget), body, usize::MAX)
+                .await
+                .unwrap()
+            {
+                Buffer::Full(data, reservation) => (data, reservation),
+                Buffer::Exceeded(_) => panic!("body shed within the budget"),
+            };
+        assert_eq!(data, b"01234567");
+
+        // Held until the response body is dropped
+        let body = super::body(data, reservation);
+        assert_eq!(budget.buffered.load(Ordering::Relaxed), 8);
+        drop(body);
+        assert_eq!(budget.buffered.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_shed_over_budget() {
+        let budget = budget(6);
+
+        // A concurrent buffered body holds most of the budget
+        let mut held = Reservation::from_budget(budget);
+        assert!(held.grow(4));
+        let body = chunked(&["012", "345", "678"]);
+        let body = match buffer_with(Reservation::from_budget(budget), body, usize::MAX)
+            .await
+            .unwrap()
+        {
+            Buffer::Full(..) => panic!("body buffered over the budget"),
+            Buffer::Exceeded(body) => body,
+        };
+        // The partial reservation is released, the body streams unbuffered and complete
+        assert_eq!(budget.buffered.load(Ordering::Relaxed), 4);
+        let data = hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(data, "012345678");
+
+        // Freed memory admits new bodies again
+        drop(held);
+        let body = chunked(&["012", "345"]);
+        assert!(matches!(
+            buffer_with(Reservation::from_budget(budget), body, usize::MAX)
+                .await
+                .unwrap(),
+            Buffer::Full(..)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_buffer_at_most() {
+        let budget = budget(0);
+
+        // Chunked, no declared size: the cap is applied to the bytes read
+        let body = chunked(&["0123", "4567", "89"]);
+        let body = match buffer_with(Reservation::from_budget(budget), body, 6)
+            .await
+            .unwrap()
+        {
+            Buffer::Full(..) => panic!("body buffered over the cap"),
+            Buffer::Exceeded(body) => body,
+        };
+        assert_eq!(budget.buffered.load(Ordering::Relaxed), 0);
+        let data = hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(data, "0123456789");
+
+        let body = chunked(&["0123", "45"]);
+        assert!(matches!(
+            buffer_with(Reservation::from_budget(budget), body, 6)
+                .await
+                .unwrap(),
+            Buffer::Full(..)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_declared_size_not_read() {
+        let budget = budget(4);
+        let body = axum::body::boxed(Full::from("0123456789"));
+        assert!(matches!(
+            buffer_with(Reservation::from_budget(budget), body, usize::MAX)
+                .await
+                .unwrap(),
+            Buffer::Exceeded(_)
+        ));
+        assert_eq!(budget.buffered.load(Ordering::Relaxed), 0);
+    }
+}
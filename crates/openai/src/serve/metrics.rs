@@ -0,0 +1,400 @@
+use crate::client::{
+    UPSTREAM_CONNECTIONS_OPENED, UPSTREAM_MAX_STREAMS_PER_CONN, UPSTREAM_OVERFLOW_CLIENTS,
+    UPSTREAM_REQUESTS, UPSTREAM_STREAMS_INFLIGHT,
+};
+use crate::context::args::PriorityClass;
+use axum::http::{header, Request};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+/// Requests admitted by the general concurrency limit and not yet answered
+pub(super) static REQUESTS_INFLIGHT: AtomicUsize = AtomicUsize::new(0);
+/// Active streaming responses
+pub(super) static STREAMS_INFLIGHT: AtomicUsize = AtomicUsize::new(0);
+/// Streaming requests rejected by the stream limit
+pub(super) static STREAMS_REJECTED: AtomicU64 = AtomicU64::new(0);
+
+/// Listeners of the connection metrics, indexes of the arrays below
+pub(super) const LISTENERS: [&str; 2] = ["main", "admin"];
+pub(super) const MAIN_LISTENER: usize = 0;
+pub(super) const ADMIN_LISTENER: usize = 1;
+/// Open connections by listener
+pub(super) static CONNECTIONS_ACTIVE: [AtomicUsize; 2] = [ZERO_USIZE; 2];
+/// Configured connection limits by listener, 0 is unlimited
+pub(super) static CONNECTIONS_LIMIT: [AtomicUsize; 2] = [ZERO_USIZE; 2];
+/// Accepts paused by the connection limit, by listener
+pub(super) static CONNECTIONS_PAUSED: [AtomicU64; 2] = [ZERO; 2];
+/// Connections closed by the connection limit, by listener
+pub(super) static CONNECTIONS_REJECTED: [AtomicU64; 2] = [ZERO; 2];
+
+/// TLS handshakes in progress
+pub(super) static TLS_HANDSHAKES_INFLIGHT: AtomicUsize = AtomicUsize::new(0);
+/// Connections dropped by the concurrent TLS handshakes limit
+pub(super) static TLS_HANDSHAKES_REJECTED: AtomicU64 = AtomicU64::new(0);
+/// TLS handshakes that did not complete in time
+pub(super) static TLS_HANDSHAKE_TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+/// TLS handshakes rejected while the served keypair is invalid
+pub(super) static TLS_HANDSHAKES_FAILED_CLOSED: AtomicU64 = AtomicU64::new(0);
+/// TLS keypair hot-reloads that failed
+pub(super) static TLS_KEYPAIR_RELOAD_ERRORS: AtomicU64 = AtomicU64::new(0);
+/// Mirrored requests sent to the mirror upstream
+pub(super) static MIRROR_REQUESTS: AtomicU64 = AtomicU64::new(0);
+/// Mirrored requests dropped by the mirror in-flight limit
+pub(super) static MIRROR_DROPPED: AtomicU64 = AtomicU64::new(0);
+/// Mirrored requests that failed without a response
+pub(super) static MIRROR_ERRORS: AtomicU64 = AtomicU64::new(0);
+/// Mirror responses by status class (1xx to 5xx)
+pub(super) static MIRROR_RESPONSES: [AtomicU64; 5] = [ZERO; 5];
+/// Total mirror response latency (millisecond)
+pub(super) static MIRROR_LATENCY_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Token bucket operations running on the redb store
+pub(super) static TB_STORE_INFLIGHT: AtomicUsize = AtomicUsize::new(0);
+/// Token bucket operations waiting for a redb store slot
+pub(super) static TB_STORE_QUEUED: AtomicUsize = AtomicUsize::new(0);
+/// Requests answered 503 after waiting for a redb store slot
+pub(super) static TB_STORE_TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+/// Configured redb store concurrency, 0 is unbounded
+pub(super) static TB_STORE_CONCURRENCY: AtomicUsize = AtomicUsize::new(0);
+
+const ZERO: AtomicU64 = AtomicU64::new(0);
+const ZERO_USIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Configured limits, exported next to the counters
+static LIMITS: OnceLock<(usize, usize)> = OnceLock::new();
+
+pub(super) fn init(concurrent_limit: usize, max_concurrent_streams_inflight: usize) {
+    let _ = LIMITS.set((concurrent_limit, max_concurrent_streams_inflight));
+}
+
+/// Gauge increment, decremented when dropped, so a cancelled request is not counted
+pub(super) struct Gauge(&'static AtomicUsize);
+
+impl Gauge {
+    pub(super) fn inc(gauge: &'static AtomicUsize) -> Self {
+        gauge.fetch_add(1, Ordering::Relaxed);
+        Self(gauge)
+    }
+}
+
+impl Drop for Gauge {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Count the requests in flight
+pub(super) async fn track_inflight<B>(request: Request<B>, next: Next<B>) -> Response {
+    let _inflight = Gauge::inc(&REQUESTS_INFLIGHT);
+    next.run(request).await
+}
+
+/// GET /metrics (Prometheus text format)
+pub(super) async fn metrics() -> impl IntoResponse {
+    let (concurrent_limit, max_streams) = LIMITS.get().copied().unwrap_or_default();
+    let mut body = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+        let _ = write!(
+            body,
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+        );
+    };
+
+    metric(
+        "ninja_requests_inflight",
+        "gauge",
+        "Requests in flight",
+        REQUESTS_INFLIGHT.load(Ordering::Relaxed) as u64,
+    );
+    metric(
+        "ninja_concurrent_limit",
+        "gauge",
+        "Concurrent requests limit",
+        concurrent_limit as u64,
+    );
+    metric(
+        "ninja_requests_queued",
+        "gauge",
+        "Requests waiting for a concurrent limit slot",
+        super::priority::queued() as u64,
+    );
+    for (name, help, counters) in [
+        (
+            "ninja_requests_admitted_total",
+            "Requests admitted by the concurrent limit by priority class",
+            &super::priority::ADMITTED,
+        ),
+        (
+            "ninja_requests_shed_total",
+            "Requests shed from the concurrent limit queue by priority class",
+            &super::priority::SHED,
+        ),
+    ] {
+        let _ = write!(body, "# HELP {name} {help}\n# TYPE {name} counter\n");
+        for class in PriorityClass::ALL {
+            let _ = writeln!(
+                body,
+                "{name}{{class=\"{}\"}} {}",
+                class.as_str(),
+                counters[super::priority::index(class)].load(Ordering::Relaxed)
+            );
+        }
+    }
+    metric(
+        "ninja_streams_inflight",
+        "gauge",
+        "Active streaming responses",
+        STREAMS_INFLIGHT.load(Ordering::Relaxed) as u64,
+    );
+    metric(
+        "ninja_max_concurrent_streams_inflight",
+        "gauge",
+        "Concurrent streaming responses limit, 0 is unlimited",
+        max_streams as u64,
+    );
+    metric(
+        "ninja_streams_rejected_total",
+        "counter",
+        "Streaming requests rejected by the streams limit",
+        STREAMS_REJECTED.load(Ordering::Relaxed),
+    );
+
+    metric(
+        "ninja_buffered_memory_bytes",
+        "gauge",
+        "Memory of the buffered request and response bodies",
+        super::memory::buffered(),
+    );
+    metric(
+        "ninja_max_buffered_memory_bytes",
+        "gauge",
+        "Buffered bodies memory limit, 0 is unlimited",
+        super::memory::limit(),
+    );
+    metric(
+        "ninja_buffered_memory_exceeded_total",
+        "counter",
+        "Bodies refused or streamed unbuffered over the buffered memory limit",
+        super::memory::EXCEEDED.load(Ordering::Relaxed),
+    );
+
+    metric(
+        "ninja_tls_handshakes_inflight",
+        "gauge",
+        "TLS handshakes in progress",
+        TLS_HANDSHAKES_INFLIGHT.load(Ordering::Relaxed) as u64,
+    );
+    metric(
+        "ninja_tls_handshakes_rejected_total",
+        "counter",
+        "Connections dropped by the concurrent TLS handshakes limit",
+        TLS_HANDSHAKES_REJECTED.load(Ordering::Relaxed),
+    );
+    metric(
+        "ninja_tls_handshake_timeouts_total",
+        "counter",
+        "TLS handshakes that did not complete in time",
+        TLS_HANDSHAKE_TIMEOUTS.load(Ordering::Relaxed),
+    );
+    metric(
+        "ninja_tls_handshakes_failed_closed_total",
+        "counter",
+        "TLS handshakes rejected while the served keypair is invalid",
+        TLS_HANDSHAKES_FAILED_CLOSED.load(Ordering::Relaxed),
+    );
+    metric(
+        "ninja_tls_keypair_reload_errors_total",
+        "counter",
+        "TLS keypair hot-reloads that failed",
+        TLS_KEYPAIR_RELOAD_ERRORS.load(Ordering::Relaxed),
+    );
+    if let Some(keypair) = super::tls::served_keypair() {
+        metric(
+            "ninja_tls_keypair_valid",
+            "gauge",
+            "Whether the served TLS keypair is valid, the TLS listener rejects handshakes otherwise",
+            keypair.is_valid() as u64,
+        );
+    }
+    metric(
+        "ninja_tb_store_inflight",
+        "gauge",
+        "Token bucket operations running on the redb store",
+        TB_STORE_INFLIGHT.load(Ordering::Relaxed) as u64,
+    );
+    metric(
+        "ninja_tb_store_queued",
+        "gauge",
+        "Token bucket operations waiting for a redb store slot",
+        TB_STORE_QUEUED.load(Ordering::Relaxed) as u64,
+    );
+    metric(
+        "ninja_tb_redb_max_concurrency",
+        "gauge",
+        "Concurrent token bucket operations limit of the redb store, 0 is unbounded",
+        TB_STORE_CONCURRENCY.load(Ordering::Relaxed) as u64,
+    );
+    metric(
+        "ninja_tb_store_timeouts_total",
+        "counter",
+        "Requests answered 503 after waiting for a redb store slot",
+        TB_STORE_TIMEOUTS.load(Ordering::Relaxed),
+    );
+    metric(
+        "ninja_upstream_requests_total",
+        "counter",
+        "Proxied upstream requests assigned an upstream client",
+        UPSTREAM_REQUESTS.load(Ordering::Relaxed),
+    );
+    metric(
+        "ninja_upstream_connections_opened_total",
+        "counter",
+        "Host name lookups of the upstream API clients opening a connection, the connections through a proxy or to an IP address are not counted",
+        UPSTREAM_CONNECTIONS_OPENED.load(Ordering::Relaxed),
+    );
+    metric(
+        "ninja_upstream_http2_streams_inflight",
+        "gauge",
+        "Upstream requests in flight counted by the streams per connection limit",
+        UPSTREAM_STREAMS_INFLIGHT.load(Ordering::Relaxed) as u64,
+    );
+    metric(
+        "ninja_upstream_http2_overflow_clients",
+        "gauge",
+        "Upstream clients opened on top of the pool by the streams per connection limit",
+        UPSTREAM_OVERFLOW_CLIENTS.load(Ordering::Relaxed) as u64,
+    );
+    metric(
+        "ninja_upstream_http2_max_streams_per_conn",
+        "gauge",
+        "Upstream requests per HTTP/2 connection limit, 0 is unlimited",
+        UPSTREAM_MAX_STREAMS_PER_CONN.load(Ordering::Relaxed) as u64,
+    );
+    metric(
+        "ninja_mirror_requests_total",
+        "counter",
+        "Mirrored requests sent to the mirror upstream",
+        MIRROR_REQUESTS.load(Ordering::Relaxed),
+    );
+    metric(
+        "ninja_mirror_dropped_total",
+        "counter",
+        "Mirrored requests dropped by the mirror in-flight limit",
+        MIRROR_DROPPED.load(Ordering::Relaxed),
+    );
+    metric(
+        "ninja_mirror_errors_total",
+        "counter",
+        "Mirrored requests that failed without a response",
+        MIRROR_ERRORS.load(Ordering::Relaxed),
+    );
+
+    let _ = write!(
+        body,
+        "# HELP ninja_mirror_responses_total Mirror responses by status class\n# TYPE ninja_mirror_responses_total counter\n"
+    );
+    let mut responses = 0;
+    for (class, count) in MIRROR_RESPONSES.iter().enumerate() {
+        let count = count.load(Ordering::Relaxed);
+        responses += count;
+        let _ = writeln!(
+            body,
+            "ninja_mirror_responses_total{{class=\"{}xx\"}} {count}",
+            class + 1
+        );
+    }
+    let _ = write!(
+        body,
+        "# HELP ninja_mirror_latency_seconds Mirror response latency\n# TYPE ninja_mirror_latency_seconds summary\nninja_mirror_latency_seconds_sum {}\nninja_mirror_latency_seconds_count {responses}\n",
+        MIRROR_LATENCY_MS.load(Ordering::Relaxed) as f64 / 1000.0
+    );
+
+    for (name, kind, help, values) in [
+        (
+            "ninja_connections_active",
+            "gauge",
+            "Open connections by listener",
+            &CONNECTIONS_ACTIVE,
+        ),
+        (
+            "ninja_max_connections",
+            "gauge",
+            "Connection limit by listener, 0 is unlimited",
+            &CONNECTIONS_LIMIT,
+        ),
+    ] {
+        let _ = write!(body, "# HELP {name} {help}\n# TYPE {name} {kind}\n");
+        for (listener, value) in LISTENERS.iter().zip(values) {
+            let _ = writeln!(
+                body,
+                "{name}{{listener=\"{listener}\"}} {}",
+                value.load(Ordering::Relaxed)
+            );
+        }
+    }
+    for (name, help, values) in [
+        (
+            "ninja_connections_paused_total",
+            "Accepts paused by the connection limit",
+            &CONNECTIONS_PAUSED,
+        ),
+        (
+            "ninja_connections_rejected_total",
+            "Connections closed by the connection limit",
+            &CONNECTIONS_REJECTED,
+        ),
+    ] {
+        let _ = write!(body, "# HELP {name} {help}\n# TYPE {name} counter\n");
+        for (listener, value) in LISTENERS.iter().zip(values) {
+            let _ = writeln!(
+                body,
+                "{name}{{listener=\"{listener}\"}} {}",
+                value.load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    let jobs = super::leader::jobs();
+    if !jobs.is_empty() {
+        let _ = write!(
+            body,
+            "# HELP ninja_job_leader Whether this instance holds the lease of the background job\n# TYPE ninja_job_leader gauge\n"
+        );
+        for (job, leader, _) in &jobs {
+            let _ = writeln!(body, "ninja_job_leader{{job=\"{job}\"}} {}", *leader as u8);
+        }
+        let _ = write!(
+            body,
+            "# HELP ninja_job_runs_total Background job runs on this instance\n# TYPE ninja_job_runs_total counter\n"
+        );
+        for (job, _, runs) in &jobs {
+            let _ = writeln!(body, "ninja_job_runs_total{{job=\"{job}\"}} {runs}");
+        }
+    }
+
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_gauge_released_when_cancelled() {
+        static GAUGE: AtomicUsize = AtomicUsize::new(0);
+        let request = async {
+            let _inflight = Gauge::inc(&GAUGE);
+            assert_eq!(GAUGE.load(Ordering::Relaxed), 1);
+            std::future::pending::<()>().await
+        };
+        // The client went away, the request future is dropped mid-await
+        assert!(tokio::time::timeout(Duration::from_millis(10), request)
+            .await
+            .is_err());
+        assert_eq!(GAUGE.load(Ordering::Relaxed), 0);
+    }
+}
@@ -1,12 +1,122 @@
+//! Auth of the proxied requests. Without an `auth_chain` the access token of the
+//! `Authorization` header is checked alone. With one, its mechanisms are evaluated in
+//! order and the first that accepts the request admits it; the request is rejected
+//! only when they all fail.
+
+use crate::context::args::AuthMechanism;
+use crate::secret::Secret;
 use crate::serve::error::{ProxyError, ResponseError};
-use crate::serve::whitelist;
-use crate::token;
-use axum::http::header;
+use crate::serve::idempotency::{read_body, MAX_REQUEST_BODY};
+use crate::serve::{schedule, turnstile, whitelist};
+use crate::sign::{constant_time_eq, hex, hmac_sha256, sha256_hex};
+use crate::token::{self, TokenProfile};
+use crate::{debug, info, now_duration};
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::http::{header, HeaderName};
 use axum::{http::Request, middleware::Next, response::Response};
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+
+/// Unix seconds of a signed request
+const SIGN_TIMESTAMP: HeaderName = HeaderName::from_static("x-auth-timestamp");
+/// Hex HMAC-SHA256 of a signed request
+const SIGN_SIGNATURE: HeaderName = HeaderName::from_static("x-auth-signature");
+/// Hex SHA-256 of the body of a signed request
+const SIGN_CONTENT_SHA256: HeaderName = HeaderName::from_static("x-auth-content-sha256");
+/// Turnstile token of a request
+const TURNSTILE_RESPONSE: HeaderName = HeaderName::from_static("cf-turnstile-response");
+/// Largest clock skew of a signed request (second)
+const SIGN_MAX_SKEW: u64 = 300;
+/// Signatures remembered at most, a new one is refused while they are all in their window
+const MAX_SIGNATURES: usize = 100_000;
+
+static CHAIN: OnceLock<Chain> = OnceLock::new();
+
+struct Chain {
+    mechanisms: Vec<AuthMechanism>,
+    sign_secret: Option<Secret>,
+    /// Signatures accepted within the skew window, each is accepted once
+    signatures: Mutex<Signatures>,
+}
+
+/// Accepted signatures, in acceptance order
+#[derive(Default)]
+struct Signatures {
+    seen: HashSet<String>,
+    order: VecDeque<(u64, String)>,
+}
 
-pub(crate) async fn auth_middleware<B>(
-    request: Request<B>,
-    next: Next<B>,
+impl Signatures {
+    /// Record a signature accepted at `now`, `Some(false)` when it was already used. A
+    /// timestamp is at most `SIGN_MAX_SKEW` ahead of the acceptance and valid up to
+    /// `SIGN_MAX_SKEW` after, so a signature is kept twice the skew. A signature still
+    /// in its window is never forgotten, else it could be replayed: `None` when
+    /// `MAX_SIGNATURES` of them are kept.
+    fn insert(&mut self, signature: &str, now: u64) -> Option<bool> {
+        while let Some((accepted, _)) = self.order.front() {
+            if now.saturating_sub(*accepted) <= 2 * SIGN_MAX_SKEW {
+                break;
+            }
+            if let Some((_, signature)) = self.order.pop_front() {
+                self.seen.remove(&signature);
+            }
+        }
+        if self.seen.contains(signature) {
+            return Some(false);
+        }
+        if self.order.len() >= MAX_SIGNATURES {
+            return None;
+        }
+        self.seen.insert(signature.to_owned());
+        self.order.push_back((now, signature.to_owned()));
+        Some(true)
+    }
+}
+
+/// Signature of a request verified over its headers, its body is not read yet
+struct Signature {
+    expected: String,
+    content_sha256: String,
+}
+
+/// Outcome of a mechanism for a request
+enum Verdict {
+    Accepted(Option<TokenProfile>),
+    /// The request carries no credentials of the mechanism
+    Absent,
+    Rejected(ResponseError),
+}
+
+pub(crate) fn init(
+    mechanisms: &[AuthMechanism],
+    sign_secret: Option<&Secret>,
+    turnstile: bool,
+) -> anyhow::Result<()> {
+    if mechanisms.is_empty() {
+        return Ok(());
+    }
+    AuthMechanism::validate_chain(mechanisms, sign_secret.is_some(), turnstile)?;
+    info!(
+        "Auth chain: {}",
+        mechanisms
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(" -> ")
+    );
+    let _ = CHAIN.set(Chain {
+        mechanisms: mechanisms.to_vec(),
+        sign_secret: sign_secret.cloned(),
+        signatures: Mutex::default(),
+    });
+    Ok(())
+}
+
+pub(crate) async fn auth_middleware(
+    request: Request<Body>,
+    next: Next<Body>,
 ) -> Result<Response, ResponseError> {
     // Allow access to the public folder
     if ["/backend-api/public", "/backend-api/o11y/v1/traces"]
@@ -16,22 +126,435 @@ pub(crate) async fn auth_middleware<B>(
         return Ok(next.run(request).await);
     };
 
-    // Check if the request has an authorization header
-    let token = match request.headers().get(header::AUTHORIZATION) {
-        Some(token) => token,
-        None => return Err(ResponseError::Unauthorized(ProxyError::AccessTokenRequired)),
+    let (request, profile) = match CHAIN.get() {
+        Some(chain) => {
+            let (request, sign) = match chain.signed_body(request).await {
+                Ok(signed) => signed,
+                Err(resp) => return Ok(resp),
+            };
+            let profile = chain.check(&request, sign).await?;
+            (request, profile)
+        }
+        None => {
+            // Check if the request has an authorization header
+            let token = match request.headers().get(header::AUTHORIZATION) {
+                Some(token) => token,
+                None => return Err(ResponseError::Unauthorized(ProxyError::AccessTokenRequired)),
+            };
+            let profile = match access_token(token.as_bytes()) {
+                Verdict::Accepted(profile) => profile,
+                Verdict::Rejected(err) => return Err(err),
+                Verdict::Absent => None,
+            };
+            (request, profile)
+        }
     };
 
-    // Check if the token is valid
-    match token::check_for_u8(token.as_bytes()) {
-        Ok(Some(profile)) => {
-            whitelist::check_whitelist(profile.email()).map_err(ResponseError::Forbidden)?;
-            Ok(next.run(request).await)
+    if let Some(profile) = profile {
+        if let Err(resumes_at) = schedule::check_schedule(profile.email()) {
+            return Ok(schedule::outside_schedule_response(resumes_at));
         }
-        Ok(None) => {
-            // for now, we don't allow anonymous access
-            Ok(next.run(request).await)
+    }
+    Ok(next.run(request).await)
+}
+
+impl Chain {
+    /// Verdict of the `sign` mechanism. The signature is verified over the headers
+    /// first, the body of a validly signed request is then read under the memory
+    /// budget and its digest compared with the signed one. The other requests are
+    /// forwarded as streamed.
+    async fn signed_body(
+        &self,
+        request: Request<Body>,
+    ) -> Result<(Request<Body>, Verdict), Response> {
+        let Some(secret) = self
+            .sign_secret
+            .as_ref()
+            .filter(|_| self.mechanisms.contains(&AuthMechanism::Sign))
+        else {
+            return Ok((request, Verdict::Absent));
+        };
+        let now = now_duration().map_or(0, |now| now.as_secs());
+        let signature = match self.sign(&request, secret, now) {
+            Ok(signature) => signature,
+            Err(verdict) => return Ok((request, verdict)),
+        };
+        let (mut parts, body) = request.into_parts();
+        let (body, reservation) = read_body(&parts.headers, body, MAX_REQUEST_BODY).await?;
+        let verdict = self.accept(signature, &body, now);
+        // The proxy handler takes over the reservation of the body it extracts
+        if parts.headers.contains_key(header::CONTENT_TYPE) {
+            parts.extensions.insert(Arc::new(reservation));
+        }
+        Ok((Request::from_parts(parts, Body::from(body)), verdict))
+    }
+
+    /// Evaluate the mechanisms in order, `sign` is the verdict of the `sign` mechanism.
+    /// On total failure the first rejection is returned, or a 401 when the request
+    /// carries no credentials of any mechanism.
+    async fn check<B>(
+        &self,
+        request: &Request<B>,
+        sign: Verdict,
+    ) -> Result<Option<TokenProfile>, ResponseError> {
+        let mut sign = Some(sign);
+        let mut rejected = None;
+        for mechanism in &self.mechanisms {
+            let verdict = match mechanism {
+                AuthMechanism::AccessToken => match request.headers().get(header::AUTHORIZATION) {
+                    Some(token) => access_token(token.as_bytes()),
+                    None => Verdict::Absent,
+                },
+                AuthMechanism::Sign => sign.take().unwrap_or(Verdict::Absent),
+                AuthMechanism::Turnstile => turnstile_response(request).await,
+            };
+            match verdict {
+                Verdict::Accepted(profile) => {
+                    debug!(
+                        "Request {} accepted by the `{}` auth mechanism",
+                        request.uri().path(),
+                        mechanism.to_string()
+                    );
+                    return Ok(profile);
+                }
+                Verdict::Rejected(err) => {
+                    rejected.get_or_insert(err);
+                }
+                Verdict::Absent => {}
+            }
+        }
+        Err(rejected
+            .unwrap_or_else(|| ResponseError::Unauthorized(ProxyError::CredentialsRequired)))
+    }
+
+    /// HMAC-SHA256 of `{timestamp}\n{method}\n{path and query}\n{body sha256}` with the
+    /// sign secret, hex encoded in the `X-Auth-Signature` header. The body digest is sent
+    /// in the `X-Auth-Content-SHA256` header. `now` is in unix seconds.
+    fn sign<B>(
+        &self,
+        request: &Request<B>,
+        secret: &Secret,
+        now: u64,
+    ) -> Result<Signature, Verdict> {
+        let headers = request.headers();
+        let (timestamp, signature) =
+            match (headers.get(SIGN_TIMESTAMP), headers.get(SIGN_SIGNATURE)) {
+                (None, None) => return Err(Verdict::Absent),
+                (Some(timestamp), Some(signature)) => (timestamp, signature),
+                _ => {
+                    return Err(rejected(
+                        "both X-Auth-Timestamp and X-Auth-Signature are required",
+                    ))
+                }
+            };
+        let Some(timestamp) = timestamp
+            .to_str()
+            .ok()
+            .and_then(|timestamp| timestamp.parse::<u64>().ok())
+        else {
+            return Err(rejected("invalid timestamp"));
+        };
+        if timestamp.abs_diff(now) > SIGN_MAX_SKEW {
+            return Err(rejected("expired timestamp"));
+        }
+        let Some(content_sha256) = headers
+            .get(SIGN_CONTENT_SHA256)
+            .and_then(|value| value.to_str().ok())
+        else {
+            return Err(rejected("X-Auth-Content-SHA256 is required"));
+        };
+
+        let path = request
+            .uri()
+            .path_and_query()
+            .map_or("/", |path| path.as_str());
+        let data = format!(
+            "{timestamp}\n{}\n{path}\n{content_sha256}",
+            request.method()
+        );
+        let expected = hex(&hmac_sha256(secret.expose().as_bytes(), data.as_bytes()));
+        let signature = signature.as_bytes().to_ascii_lowercase();
+        if !constant_time_eq(expected.as_bytes(), &signature) {
+            return Err(rejected("signature mismatch"));
+        }
+        Ok(Signature {
+            expected,
+            content_sha256: content_sha256.to_owned(),
+        })
+    }
+
+    /// Accept a signature once its body matches the signed digest, each at most once
+    fn accept(&self, signature: Signature, body: &[u8], now: u64) -> Verdict {
+        if !signature
+            .content_sha256
+            .eq_ignore_ascii_case(&sha256_hex(body))
+        {
+            return rejected("body digest mismatch");
+        }
+        let mut signatures = self
+            .signatures
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match signatures.insert(&signature.expected, now) {
+            Some(true) => Verdict::Accepted(None),
+            Some(false) => rejected("signature already used"),
+            None => Verdict::Rejected(ResponseError::ServiceUnavailable(
+                ProxyError::SignatureError("too many signed requests, retry later"),
+            )),
         }
-        Err(err) => Err(ResponseError::Forbidden(err)),
+    }
+}
+
+fn rejected(reason: &'static str) -> Verdict {
+    Verdict::Rejected(ResponseError::Forbidden(ProxyError::SignatureError(reason)))
+}
+
+/// Access token, API key or session key of the `Authorization` header
+fn access_token(token: &[u8]) -> Verdict {
+    match token::check_for_u8(token) {
+        Ok(Some(profile)) => match whitelist::check_whitelist(profile.email()) {
+            Ok(()) => Verdict::Accepted(Some(profile)),
+            Err(err) => Verdict::Rejected(ResponseError::Forbidden(err)),
+        },
+        // for now, we don't allow anonymous access
+        Ok(None) => Verdict::Accepted(None),
+        Err(err) => Verdict::Rejected(ResponseError::Forbidden(err)),
+    }
+}
+
+/// Turnstile token of the `CF-Turnstile-Response` header, verified with the siteverify
+async fn turnstile_response<B>(request: &Request<B>) -> Verdict {
+    let Some(response) = request
+        .headers()
+        .get(TURNSTILE_RESPONSE)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty())
+    else {
+        return Verdict::Absent;
+    };
+    let Some(ConnectInfo(addr)) = request.extensions().get::<ConnectInfo<SocketAddr>>() else {
+        return Verdict::Absent;
+    };
+    match turnstile::cf_turnstile_check(addr.ip(), Some(response)).await {
+        Ok(()) => Verdict::Accepted(None),
+        Err(ProxyError::CfVerifyBusy) => {
+            Verdict::Rejected(ResponseError::ServiceUnavailable(ProxyError::CfVerifyBusy))
+        }
+        Err(err) => Verdict::Rejected(ResponseError::Forbidden(err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+
+    const NOW: u64 = 1_700_000_000;
+    const BODY: &[u8] = br#"{"model":"gpt-4o"}"#;
+
+    fn chain(mechanisms: Vec<AuthMechanism>) -> Chain {
+        Chain {
+            mechanisms,
+            sign_secret: Some(Secret::new("secret")),
+            signatures: Mutex::default(),
+        }
+    }
+
+    fn signed(uri: &str, secret: &str, timestamp: u64) -> Request<()> {
+        let content_sha256 = sha256_hex(BODY);
+        let data = format!("{timestamp}\nPOST\n{uri}\n{content_sha256}");
+        Request::post(uri)
+            .header(SIGN_TIMESTAMP, timestamp)
+            .header(SIGN_CONTENT_SHA256, content_sha256)
+            .header(
+                SIGN_SIGNATURE,
+                hex(&hmac_sha256(secret.as_bytes(), data.as_bytes())),
+            )
+            .body(())
+            .unwrap()
+    }
+
+    /// Verdict of the `sign` mechanism for a request carrying `body`
+    fn verify<B>(chain: &Chain, request: &Request<B>, body: &[u8], now: u64) -> Verdict {
+        let secret = chain.sign_secret.as_ref().unwrap();
+        match chain.sign(request, secret, now) {
+            Ok(signature) => chain.accept(signature, body, now),
+            Err(verdict) => verdict,
+        }
+    }
+
+    fn status(verdict: Verdict) -> Option<u16> {
+        match verdict {
+            Verdict::Accepted(_) => Some(200),
+            Verdict::Absent => None,
+            Verdict::Rejected(err) => Some(err.into_response().status().as_u16()),
+        }
+    }
+
+    #[test]
+    fn test_sign() {
+        let chain = chain(vec![AuthMechanism::Sign]);
+        let uri = "/v1/chat/completions?stream=true";
+        let sign = |request: &Request<()>, body: &[u8], now: u64| {
+            status(verify(&chain, request, body, now))
+        };
+
+        assert_eq!(sign(&signed(uri, "secret", NOW), BODY, NOW + 10), Some(200));
+        assert_eq!(sign(&signed(uri, "other", NOW), BODY, NOW), Some(403));
+        assert_eq!(
+            sign(&signed(uri, "secret", NOW + 1), BODY, NOW + 600),
+            Some(403)
+        );
+
+        // The signature covers the path and query, and the body
+        let mut request = signed(uri, "secret", NOW + 2);
+        *request.uri_mut() = "/v1/chat/completions".parse().unwrap();
+        assert_eq!(sign(&request, BODY, NOW), Some(403));
+        let request = signed(uri, "secret", NOW + 3);
+        assert_eq!(sign(&request, br#"{"model":"o1"}"#, NOW), Some(403));
+        let mut request = signed(uri, "secret", NOW + 4);
+        request.headers_mut().remove(SIGN_CONTENT_SHA256);
+        assert_eq!(sign(&request, BODY, NOW), Some(403));
+
+        // Unsigned requests are left to the next mechanism
+        let request = Request::post(uri).body(()).unwrap();
+        assert_eq!(sign(&request, BODY, NOW), None);
+    }
+
+    #[test]
+    fn test_sign_replay() {
+        let chain = chain(vec![AuthMechanism::Sign]);
+        let uri = "/v1/chat/completions";
+
+        let request = signed(uri, "secret", NOW);
+        assert_eq!(status(verify(&chain, &request, BODY, NOW)), Some(200));
+        // Replayed within the skew window
+        assert_eq!(status(verify(&chain, &request, BODY, NOW + 60)), Some(403));
+        // A new timestamp is a new signature
+        let request = signed(uri, "secret", NOW + 1);
+        assert_eq!(status(verify(&chain, &request, BODY, NOW + 60)), Some(200));
+
+        // Forgotten once no timestamp of their window can be valid
+        let mut signatures = Signatures::default();
+        assert_eq!(signatures.insert("a", NOW), Some(true));
+        assert_eq!(signatures.insert("a", NOW + 2 * SIGN_MAX_SKEW), Some(false));
+        assert_eq!(
+            signatures.insert("b", NOW + 2 * SIGN_MAX_SKEW + 1),
+            Some(true)
+        );
+        assert_eq!(signatures.order.len(), 1);
+    }
+
+    #[test]
+    fn test_signatures_full() {
+        let mut signatures = Signatures::default();
+        for i in 0..MAX_SIGNATURES {
+            assert_eq!(signatures.insert(&i.to_string(), NOW), Some(true));
+        }
+        // Full of signatures in their window: none is evicted to make room
+        assert_eq!(signatures.insert("new", NOW + 1), None);
+        assert_eq!(signatures.insert("0", NOW + 1), Some(false));
+        assert_eq!(signatures.order.len(), MAX_SIGNATURES);
+        // Room again once they expire
+        assert_eq!(
+            signatures.insert("new", NOW + 2 * SIGN_MAX_SKEW + 1),
+            Some(true)
+        );
+        assert_eq!(signatures.order.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_chain_failure_status() {
+        let chain = chain(vec![AuthMechanism::Sign, AuthMechanism::Turnstile]);
+
+        // No credentials of any mechanism
+        let request = Request::post("/v1/models").body(()).unwrap();
+        let err = chain.check(&request, Verdict::Absent).await.err().unwrap();
+        assert_eq!(err.into_response().status(), StatusCode::UNAUTHORIZED);
+
+        // Credentials presented and rejected
+        let request = Request::post("/v1/models")
+            .header(SIGN_TIMESTAMP, "not a timestamp")
+            .header(SIGN_SIGNATURE, "00")
+            .body(())
+            .unwrap();
+        let sign = verify(&chain, &request, b"", NOW);
+        let err = chain.check(&request, sign).await.err().unwrap();
+        assert_eq!(err.into_response().status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_chain_later_mechanism_accepts() {
+        let now = now_duration().unwrap().as_secs();
+
+        // The signature is rejected, the API key accepts
+        let sign_first = chain(vec![AuthMechanism::Sign, AuthMechanism::AccessToken]);
+        let mut request = signed("/v1/models", "other", now);
+        request
+            .headers_mut()
+            .insert(header::AUTHORIZATION, "Bearer sk-test".parse().unwrap());
+        let sign = verify(&sign_first, &request, BODY, now);
+        assert!(sign_first.check(&request, sign).await.is_ok());
+
+        // The access token is rejected, the signature accepts
+        let token_first = chain(vec![AuthMechanism::AccessToken, AuthMechanism::Sign]);
+        let mut request = signed("/v1/models", "secret", now);
+        request
+            .headers_mut()
+            .insert(header::AUTHORIZATION, "Bearer invalid".parse().unwrap());
+        let sign = verify(&token_first, &request, BODY, now);
+        assert!(token_first.check(&request, sign).await.is_ok());
+        // Replayed, both reject and the first rejection answers
+        let sign = verify(&token_first, &request, BODY, now);
+        let err = token_first.check(&request, sign).await.err().unwrap();
+        assert_eq!(err.into_response().status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_signed_body_read_after_signature() {
+        let chain = chain(vec![AuthMechanism::Sign]);
+        let now = now_duration().unwrap().as_secs();
+        let request = |secret: &str, body: Body| {
+            let (parts, ()) = signed("/v1/chat/completions", secret, now).into_parts();
+            Request::from_parts(parts, body)
+        };
+
+        // An invalid signature is rejected without waiting for the body
+        let (_sender, body) = Body::channel();
+        let signed_body = chain.signed_body(request("other", body));
+        let (_, verdict) = tokio::time::timeout(std::time::Duration::from_secs(1), signed_body)
+            .await
+            .expect("the body was read")
+            .unwrap();
+        assert_eq!(status(verdict), Some(403));
+
+        // A valid one reads the body and forwards it
+        let (request, verdict) = chain
+            .signed_body(request("secret", Body::from(BODY)))
+            .await
+            .unwrap();
+        assert_eq!(status(verdict), Some(200));
+        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+        assert_eq!(&body[..], BODY);
+
+        // The body must match the signed digest
+        let (parts, ()) = signed("/v1/chat/completions", "secret", now + 1).into_parts();
+        let request = Request::from_parts(parts, Body::from(r#"{"model":"o1"}"#));
+        let (_, verdict) = chain.signed_body(request).await.unwrap();
+        assert_eq!(status(verdict), Some(403));
+    }
+
+    #[test]
+    fn test_validate_chain() {
+        use AuthMechanism::*;
+        assert_eq!(
+            "access_tokens".parse::<AuthMechanism>().unwrap(),
+            AccessToken
+        );
+        assert!(AuthMechanism::validate_chain(&[AccessToken, Sign, Turnstile], true, true).is_ok());
+        assert!(AuthMechanism::validate_chain(&[AccessToken, Sign], false, true).is_err());
+        assert!(AuthMechanism::validate_chain(&[Turnstile], true, false).is_err());
+        assert!(AuthMechanism::validate_chain(&[AccessToken, AccessToken], true, true).is_err());
     }
 }
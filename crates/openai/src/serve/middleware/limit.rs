@@ -1,25 +1,204 @@
 use crate::serve::error::{ProxyError, ResponseError};
+use crate::serve::metrics::{
+    Gauge, TB_STORE_CONCURRENCY, TB_STORE_INFLIGHT, TB_STORE_QUEUED, TB_STORE_TIMEOUTS,
+};
+use crate::{now_duration, warn};
 use axum::{
     extract::{ConnectInfo, State},
-    http::Request,
+    http::{header, HeaderName, HeaderValue, Request},
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
 };
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::tokenbucket::{Acquire, RouteBuckets, TokenBucket, TokenBucketProvider};
+
+/// Response header of the token bucket key, set when `tb_debug_key` is enabled
+const RATE_LIMIT_KEY: HeaderName = HeaderName::from_static("x-ratelimit-key");
+/// Response header of the time (unix seconds) the next token is refilled
+const RATE_LIMIT_RESET: HeaderName = HeaderName::from_static("x-ratelimit-reset");
+/// Longest wait for a store operation slot, the request is answered 503 after it
+const STORE_QUEUE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone)]
+pub(crate) struct LimitState {
+    pub(crate) provider: Arc<TokenBucketProvider>,
+    /// Buckets of the route limits, before the global ones
+    pub(crate) routes: Arc<RouteBuckets>,
+    /// Expose the bucket key in the `X-RateLimit-Key` response header
+    pub(crate) debug_key: bool,
+    /// Slots of the concurrent operations on the redb store, `None` runs them unbounded
+    pub(crate) store_slots: Option<Arc<Semaphore>>,
+}
+
+impl LimitState {
+    /// Bound the redb store operations to `max_concurrency` slots, 0 is unbounded. The
+    /// in-memory store is never bounded.
+    pub(crate) fn new(
+        provider: Arc<TokenBucketProvider>,
+        routes: Arc<RouteBuckets>,
+        debug_key: bool,
+        max_concurrency: usize,
+    ) -> Self {
+        let bounded = matches!(*provider, TokenBucketProvider::ReDB(_)) && max_concurrency > 0;
+        if bounded {
+            TB_STORE_CONCURRENCY.store(max_concurrency, Ordering::Relaxed);
+        }
+        Self {
+            provider,
+            routes,
+            debug_key,
+            store_slots: bounded.then(|| Arc::new(Semaphore::new(max_concurrency))),
+        }
+    }
 
-use super::tokenbucket::{TokenBucket, TokenBucketProvider};
+    /// Take a token from the bucket of the route limit of the path, or the global one.
+    /// The store operations over the concurrency bound queue for a slot and run on the
+    /// blocking threads, redb transactions block. The slot is held by the blocking task,
+    /// a cancelled request does not free it before its operation ends.
+    async fn acquire(&self, path: &str, ip: IpAddr) -> Result<Acquire, ResponseError> {
+        let provider = self.routes.select(path).unwrap_or(&self.provider);
+        let Some(slots) = self.store_slots.as_ref() else {
+            return provider.acquire(ip).map_err(ResponseError::BadGateway);
+        };
+        let permit = store_slot(slots.clone(), STORE_QUEUE_TIMEOUT).await?;
+        let inflight = Gauge::inc(&TB_STORE_INFLIGHT);
+        let provider = provider.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let _slot = (permit, inflight);
+            provider.acquire(ip)
+        })
+        .await;
+        match result {
+            Ok(acquire) => acquire.map_err(ResponseError::BadGateway),
+            Err(err) => Err(ResponseError::BadGateway(err)),
+        }
+    }
+}
+
+/// Wait for a store operation slot, up to `timeout`
+async fn store_slot(
+    slots: Arc<Semaphore>,
+    timeout: Duration,
+) -> Result<OwnedSemaphorePermit, ResponseError> {
+    let slots = match slots.try_acquire_owned() {
+        Ok(permit) => return Ok(permit),
+        Err(_) => slots,
+    };
+    let queued = Gauge::inc(&TB_STORE_QUEUED);
+    let permit = tokio::time::timeout(timeout, slots.acquire_owned()).await;
+    drop(queued);
+    match permit {
+        Ok(Ok(permit)) => Ok(permit),
+        _ => {
+            let timeouts = TB_STORE_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+            if timeouts % 1000 == 0 {
+                warn!(
+                    "Token bucket store queue timed out, tb_redb_max_concurrency reached ({} timed out)",
+                    timeouts + 1
+                );
+            }
+            Err(ResponseError::ServiceUnavailable(
+                ProxyError::RateLimitStoreBusy,
+            ))
+        }
+    }
+}
 
 pub(crate) async fn limit_middleware<B>(
-    State(limit): State<std::sync::Arc<TokenBucketProvider>>,
+    State(limit): State<LimitState>,
     ConnectInfo(socket_addr): ConnectInfo<std::net::SocketAddr>,
     request: Request<B>,
     next: Next<B>,
 ) -> Result<Response, ResponseError> {
     let addr = socket_addr.ip();
-    match limit.acquire(addr) {
-        Ok(condition) => match condition {
-            true => Ok(next.run(request).await),
-            false => Err(ResponseError::TooManyRequests(ProxyError::TooManyRequests)),
-        },
-        Err(err) => Err(ResponseError::BadGateway(err)),
+    let acquire = limit.acquire(request.uri().path(), addr).await?;
+    let resp = match acquire {
+        Acquire::Allowed => next.run(request).await,
+        Acquire::Limited { available_at } => {
+            let resp = ResponseError::TooManyRequests(ProxyError::TooManyRequests).into_response();
+            match available_at {
+                Some(available_at) => with_reset(resp, available_at, now_duration()?.as_secs()),
+                None => resp,
+            }
+        }
+    };
+    Ok(match limit.debug_key {
+        true => with_key(resp, addr),
+        false => resp,
+    })
+}
+
+/// Set the `Retry-After` and `X-RateLimit-Reset` headers of the refill time of the bucket
+fn with_reset(mut resp: Response, available_at: u64, now: u64) -> Response {
+    let headers = resp.headers_mut();
+    headers.insert(
+        header::RETRY_AFTER,
+        HeaderValue::from(available_at.saturating_sub(now).max(1)),
+    );
+    headers.insert(RATE_LIMIT_RESET, HeaderValue::from(available_at));
+    resp
+}
+
+/// Set the bucket key header, the client address is the key of the bucket
+fn with_key(mut resp: Response, key: IpAddr) -> Response {
+    if let Ok(value) = HeaderValue::from_str(&key.to_string()) {
+        resp.headers_mut().insert(RATE_LIMIT_KEY, value);
+    }
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+
+    #[test]
+    fn test_with_key() {
+        let resp = with_key(StatusCode::OK.into_response(), "10.0.0.1".parse().unwrap());
+        assert_eq!(resp.headers()["x-ratelimit-key"], "10.0.0.1");
+
+        let resp = with_key(StatusCode::OK.into_response(), "::1".parse().unwrap());
+        assert_eq!(resp.headers()["x-ratelimit-key"], "::1");
+    }
+
+    #[test]
+    fn test_with_reset() {
+        let resp = with_reset(StatusCode::TOO_MANY_REQUESTS.into_response(), 1_000, 998);
+        assert_eq!(resp.headers()["retry-after"], "2");
+        assert_eq!(resp.headers()["x-ratelimit-reset"], "1000");
+
+        // The clock of this instance is ahead of the shared bucket
+        let resp = with_reset(StatusCode::TOO_MANY_REQUESTS.into_response(), 1_000, 1_003);
+        assert_eq!(resp.headers()["retry-after"], "1");
+    }
+
+    #[tokio::test]
+    async fn test_store_slot_timeout() {
+        let slots = Arc::new(Semaphore::new(1));
+        let permit = store_slot(slots.clone(), Duration::from_millis(10))
+            .await
+            .unwrap();
+        let resp = store_slot(slots.clone(), Duration::from_millis(10))
+            .await
+            .unwrap_err()
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // A request dropped while it waits is no longer counted as queued
+        let queued = TB_STORE_QUEUED.load(Ordering::Relaxed);
+        let waiting = tokio::spawn(store_slot(slots.clone(), Duration::from_secs(60)));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(TB_STORE_QUEUED.load(Ordering::Relaxed) > queued);
+        waiting.abort();
+        let _ = waiting.await;
+        assert_eq!(TB_STORE_QUEUED.load(Ordering::Relaxed), queued);
+
+        drop(permit);
+        assert!(store_slot(slots, Duration::from_millis(10)).await.is_ok());
     }
 }
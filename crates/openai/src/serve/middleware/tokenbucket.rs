@@ -1,14 +1,54 @@
 use moka::sync::Cache;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::net::IpAddr;
 use std::sync::{Arc, OnceLock};
 use std::time::Duration;
 
+use crate::context::args::RouteLimit;
 use crate::homedir::home_dir;
+use crate::serve::checkpoint::Volatile;
+use crate::serve::leader;
+use crate::serve::model_policy::glob_match;
 use crate::{context, debug, error, now_duration};
 
 pub trait TokenBucket: Send + Sync {
-    fn acquire(&self, ip: IpAddr) -> anyhow::Result<bool>;
+    fn acquire(&self, ip: IpAddr) -> anyhow::Result<Acquire>;
+}
+
+/// Outcome of a token bucket acquire
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Acquire {
+    Allowed,
+    /// The bucket is empty, `available_at` is the time (unix seconds) its next token is
+    /// refilled, derived from the stored bucket state. `None` when it is never refilled.
+    Limited {
+        available_at: Option<u64>,
+    },
+}
+
+impl Acquire {
+    pub fn is_allowed(&self) -> bool {
+        matches!(self, Self::Allowed)
+    }
+}
+
+/// Refill a bucket up to `now` and take a token. The bucket state is only changed
+/// when a token is taken, so the refill time of an empty bucket stays the stored one.
+fn take(tokens: &mut u32, last_time: &mut u64, capacity: u32, fill_rate: u32, now: u64) -> Acquire {
+    let elapsed = now.saturating_sub(*last_time);
+    let refilled = (*tokens as u64 + elapsed * fill_rate as u64).min(capacity as u64) as u32;
+    if refilled > 0 {
+        *tokens = refilled - 1;
+        *last_time = now;
+        Acquire::Allowed
+    } else {
+        // Empty with a fill rate means no whole second elapsed since the last refill
+        Acquire::Limited {
+            available_at: (fill_rate > 0).then(|| *last_time + 1),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -68,9 +108,9 @@ impl MemTokenBucket {
 }
 
 impl TokenBucket for MemTokenBucket {
-    fn acquire(&self, ip: IpAddr) -> anyhow::Result<bool> {
+    fn acquire(&self, ip: IpAddr) -> anyhow::Result<Acquire> {
         if !self.enable {
-            return Ok(true);
+            return Ok(Acquire::Allowed);
         }
 
         let now_timestamp = now_duration()?.as_secs();
@@ -84,18 +124,17 @@ impl TokenBucket for MemTokenBucket {
             })
             .into_value();
 
-        let elapsed = now_timestamp - bucket.last_time;
-        let tokens_to_add = (elapsed as u32) * self.fill_rate;
-        bucket.tokens = (bucket.tokens + tokens_to_add).min(self.capacity);
-        bucket.last_time = now_timestamp;
-
-        if bucket.tokens > 0 {
-            bucket.tokens -= 1;
+        let acquire = take(
+            &mut bucket.tokens,
+            &mut bucket.last_time,
+            self.capacity,
+            self.fill_rate,
+            now_timestamp,
+        );
+        if acquire.is_allowed() {
             self.buckets.insert(ip, bucket);
-            Ok(true)
-        } else {
-            Ok(false)
         }
+        Ok(acquire)
     }
 }
 
@@ -105,6 +144,16 @@ use native_model::{native_model, Model};
 
 static DATABASE_BUILDER: OnceLock<DatabaseBuilder> = OnceLock::new();
 
+fn database_builder() -> &'static DatabaseBuilder {
+    DATABASE_BUILDER.get_or_init(|| {
+        let mut builder = DatabaseBuilder::new();
+        builder
+            .define::<ReDBBucketState>()
+            .expect("define table failed");
+        builder
+    })
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 #[native_model(id = 1, version = 1)]
 #[native_db]
@@ -124,20 +173,15 @@ pub struct RedisTokenBucket<'a> {
     fill_rate: u32,
     /// native db
     db: Arc<native_db::Database<'a>>,
+    /// Namespace of the buckets of a route limit, the route pattern
+    #[builder(default)]
+    namespace: Option<String>,
 }
 
 impl<'a> RedisTokenBucket<'a> {
     pub fn new(enable: bool, capacity: u32, fill_rate: u32, expired: u32) -> Self {
         // create database
-        let builder = DATABASE_BUILDER.get_or_init(|| {
-            let mut builder = DatabaseBuilder::new();
-            builder
-                .define::<ReDBBucketState>()
-                .expect("define table failed");
-            builder
-        });
-
-        let db = builder
+        let db = database_builder()
             .create(
                 home_dir()
                     .expect("Failed to get home directory")
@@ -153,14 +197,42 @@ impl<'a> RedisTokenBucket<'a> {
             capacity,
             fill_rate,
             db,
+            namespace: None,
+        }
+    }
+
+    /// Buckets of a route limit, in the same database under the namespace of the route
+    fn route(&self, route: &str, capacity: u32, fill_rate: u32) -> Self {
+        Self {
+            enable: self.enable,
+            capacity,
+            fill_rate,
+            db: self.db.clone(),
+            namespace: Some(route.to_owned()),
+        }
+    }
+
+    /// Primary key of the bucket of an address. The buckets of a route are keyed by the
+    /// digest of the route and the address, apart from the address numbers.
+    fn key(&self, ip: IpAddr) -> u128 {
+        match self.namespace.as_deref() {
+            None => ip_to_number(ip),
+            Some(route) => {
+                let digest = Sha256::digest(format!("{route}\n{ip}").as_bytes());
+                u128::from_be_bytes(digest[..16].try_into().expect("digest is 32 bytes"))
+            }
         }
     }
 }
 
 fn clear_expired_buckets_every(db: Arc<Database<'static>>, expired: u32) {
     use std::thread;
+    let interval = Duration::from_secs(expired.into());
     thread::spawn(move || loop {
-        thread::sleep(Duration::from_secs(expired.into()));
+        thread::sleep(leader::poll_period(interval));
+        if !leader::claim_local("tokenbucket_sweep", interval) {
+            continue;
+        }
 
         debug!("ReDB Clearing expired buckets...");
 
@@ -211,14 +283,20 @@ fn clear_expired_buckets_every(db: Arc<Database<'static>>, expired: u32) {
 }
 
 impl TokenBucket for RedisTokenBucket<'_> {
-    fn acquire(&self, ip: IpAddr) -> anyhow::Result<bool> {
+    fn acquire(&self, ip: IpAddr) -> anyhow::Result<Acquire> {
         if !self.enable {
-            return Ok(true);
+            return Ok(Acquire::Allowed);
         }
+        self.acquire_at(ip, now_duration()?.as_secs())
+    }
+}
 
+impl RedisTokenBucket<'_> {
+    /// Take a token in a transaction of the shared database, the refill time of an
+    /// empty bucket is the one of the stored bucket, whichever instance last took a token
+    fn acquire_at(&self, ip: IpAddr, now_timestamp: u64) -> anyhow::Result<Acquire> {
         let rw = self.db.rw_transaction()?;
-        let pk = ip_to_number(ip);
-        let now_timestamp = now_duration()?.as_secs();
+        let pk = self.key(ip);
         let mut bucket: ReDBBucketState = match rw.get().primary(pk)? {
             Some(bucket) => bucket,
             None => ReDBBucketState {
@@ -228,19 +306,18 @@ impl TokenBucket for RedisTokenBucket<'_> {
             },
         };
 
-        let elapsed = now_timestamp - bucket.last_time;
-        let tokens_to_add = (elapsed as u32) * self.fill_rate;
-        bucket.tokens = (bucket.tokens + tokens_to_add).min(self.capacity);
-        bucket.last_time = now_timestamp;
-
-        if bucket.tokens > 0 {
-            bucket.tokens -= 1;
+        let acquire = take(
+            &mut bucket.tokens,
+            &mut bucket.last_time,
+            self.capacity,
+            self.fill_rate,
+            now_timestamp,
+        );
+        if acquire.is_allowed() {
             rw.insert(bucket)?;
             rw.commit()?;
-            Ok(true)
-        } else {
-            Ok(false)
         }
+        Ok(acquire)
     }
 }
 
@@ -290,8 +367,125 @@ impl From<(Strategy, bool, u32, u32, u32)> for TokenBucketProvider {
     }
 }
 
+impl TokenBucketProvider {
+    /// Buckets of a route limit, in the store of this provider under their own namespace
+    fn route(&self, route: &str, capacity: u32, fill_rate: u32, expired: u32) -> Self {
+        match self {
+            Self::Mem(t) => Self::Mem(MemTokenBucket::new(t.enable, capacity, fill_rate, expired)),
+            Self::ReDB(t) => Self::ReDB(t.route(route, capacity, fill_rate)),
+        }
+    }
+}
+
+/// Token buckets of the route limits (`route_limits`), the most specific pattern first
+#[derive(Default)]
+pub struct RouteBuckets {
+    routes: Vec<(String, Arc<TokenBucketProvider>)>,
+}
+
+impl RouteBuckets {
+    pub fn new(
+        provider: &TokenBucketProvider,
+        limits: &BTreeMap<String, RouteLimit>,
+        expired: u32,
+    ) -> Self {
+        let mut routes = limits
+            .iter()
+            .map(|(pattern, limit)| {
+                let bucket = provider.route(pattern, limit.capacity, limit.fill_rate, expired);
+                (pattern.clone(), Arc::new(bucket))
+            })
+            .collect::<Vec<_>>();
+        routes.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()));
+        Self { routes }
+    }
+
+    /// Buckets of the route limit of a request path, the longest matching pattern.
+    /// `None` falls back to the global buckets.
+    pub fn select(&self, path: &str) -> Option<&Arc<TokenBucketProvider>> {
+        self.routes
+            .iter()
+            .find(|(pattern, _)| glob_match(pattern, path))
+            .map(|(_, bucket)| bucket)
+    }
+}
+
+/// Bucket of the checkpoint
+#[derive(Serialize, Deserialize)]
+struct SavedBucket {
+    ip: IpAddr,
+    #[serde(flatten)]
+    state: BucketState,
+}
+
+impl Volatile for MemTokenBucket {
+    fn snapshot(&self) -> Option<serde_json::Value> {
+        let buckets = self
+            .buckets
+            .iter()
+            .map(|(ip, state)| SavedBucket { ip: *ip, state })
+            .collect::<Vec<_>>();
+        serde_json::to_value(buckets).ok()
+    }
+
+    fn restore(&self, saved: serde_json::Value, expired_before: u64) -> anyhow::Result<()> {
+        for saved in serde_json::from_value::<Vec<SavedBucket>>(saved)? {
+            if saved.state.last_time < expired_before {
+                continue;
+            }
+            // Buckets used since the start keep the fewer tokens
+            let state = match self.buckets.get(&saved.ip) {
+                Some(state) if state.tokens <= saved.state.tokens => continue,
+                _ => saved.state,
+            };
+            self.buckets.insert(saved.ip, state);
+        }
+        Ok(())
+    }
+}
+
+impl Volatile for TokenBucketProvider {
+    fn snapshot(&self) -> Option<serde_json::Value> {
+        match self {
+            Self::Mem(t) => t.snapshot(),
+            // Persistent
+            Self::ReDB(_) => None,
+        }
+    }
+
+    fn restore(&self, saved: serde_json::Value, expired_before: u64) -> anyhow::Result<()> {
+        match self {
+            Self::Mem(t) => t.restore(saved, expired_before),
+            Self::ReDB(_) => Ok(()),
+        }
+    }
+}
+
+/// The in-memory route buckets by route pattern
+impl Volatile for RouteBuckets {
+    fn snapshot(&self) -> Option<serde_json::Value> {
+        let routes = self
+            .routes
+            .iter()
+            .filter_map(|(pattern, bucket)| Some((pattern.clone(), bucket.snapshot()?)))
+            .collect::<serde_json::Map<_, _>>();
+        (!routes.is_empty()).then_some(serde_json::Value::Object(routes))
+    }
+
+    fn restore(&self, saved: serde_json::Value, expired_before: u64) -> anyhow::Result<()> {
+        let mut saved = serde_json::from_value::<BTreeMap<String, serde_json::Value>>(saved)?;
+        // The buckets of a route no longer limited are dropped
+        for (pattern, bucket) in &self.routes {
+            if let Some(saved) = saved.remove(pattern) {
+                bucket.restore(saved, expired_before)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 impl TokenBucket for TokenBucketProvider {
-    fn acquire(&self, ip: IpAddr) -> anyhow::Result<bool> {
+    fn acquire(&self, ip: IpAddr) -> anyhow::Result<Acquire> {
         let condition = match self {
             Self::Mem(t) => t.acquire(ip),
             Self::ReDB(t) => t.acquire(ip),
@@ -299,3 +493,179 @@ impl TokenBucket for TokenBucketProvider {
         Ok(condition?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_bucket_refill_time() {
+        // Two instances of the same shared store
+        let db = Arc::new(database_builder().create_in_memory().unwrap());
+        let instance = || {
+            RedisTokenBucket::builder()
+                .enable(true)
+                .capacity(2)
+                .fill_rate(1)
+                .db(db.clone())
+                .build()
+        };
+        let (a, b) = (instance(), instance());
+        let ip = "10.0.0.1".parse().unwrap();
+
+        assert_eq!(a.acquire_at(ip, 100).unwrap(), Acquire::Allowed);
+        assert_eq!(a.acquire_at(ip, 100).unwrap(), Acquire::Allowed);
+        // The bucket drained by `a` is empty for `b`, whose clock is behind
+        let limited = Acquire::Limited {
+            available_at: Some(101),
+        };
+        assert_eq!(b.acquire_at(ip, 99).unwrap(), limited);
+        assert_eq!(a.acquire_at(ip, 100).unwrap(), limited);
+
+        // `b` takes the refilled token, both see the next refill time
+        assert_eq!(b.acquire_at(ip, 101).unwrap(), Acquire::Allowed);
+        let limited = Acquire::Limited {
+            available_at: Some(102),
+        };
+        assert_eq!(a.acquire_at(ip, 101).unwrap(), limited);
+        assert_eq!(b.acquire_at(ip, 101).unwrap(), limited);
+    }
+
+    #[test]
+    fn test_route_buckets_independent() {
+        let global = TokenBucketProvider::Mem(MemTokenBucket::new(true, 2, 0, 60));
+        let limits = BTreeMap::from([
+            (
+                "/v1/chat/completions".to_owned(),
+                RouteLimit {
+                    capacity: 1,
+                    fill_rate: 0,
+                },
+            ),
+            (
+                "/v1/*".to_owned(),
+                RouteLimit {
+                    capacity: 3,
+                    fill_rate: 0,
+                },
+            ),
+        ]);
+        let routes = RouteBuckets::new(&global, &limits, 60);
+        let ip = "10.0.0.1".parse().unwrap();
+        let acquire = |path: &str| {
+            routes
+                .select(path)
+                .map_or(&global, |bucket| bucket.as_ref())
+                .acquire(ip)
+                .unwrap()
+                .is_allowed()
+        };
+
+        // Strict on completions, the longest pattern wins over `/v1/*`
+        assert!(acquire("/v1/chat/completions"));
+        assert!(!acquire("/v1/chat/completions"));
+        // Lenient on the other `/v1` routes, their bucket is untouched
+        assert!(acquire("/v1/models"));
+        assert!(acquire("/v1/models"));
+        assert!(acquire("/v1/models"));
+        assert!(!acquire("/v1/models"));
+        // Unmatched routes take the global bucket
+        assert!(acquire("/backend-api/conversation"));
+        assert!(acquire("/backend-api/conversation"));
+        assert!(!acquire("/backend-api/conversation"));
+    }
+
+    #[test]
+    fn test_route_namespace_in_shared_store() {
+        let db = Arc::new(database_builder().create_in_memory().unwrap());
+        let global = RedisTokenBucket::builder()
+            .enable(true)
+            .capacity(1)
+            .fill_rate(0)
+            .db(db)
+            .build();
+        let route = global.route("/v1/chat/completions", 1, 0);
+        let ip = "10.0.0.1".parse().unwrap();
+
+        assert_ne!(global.key(ip), route.key(ip));
+        assert!(global.acquire_at(ip, 100).unwrap().is_allowed());
+        assert!(route.acquire_at(ip, 100).unwrap().is_allowed());
+        assert!(!global.acquire_at(ip, 100).unwrap().is_allowed());
+        assert!(!route.acquire_at(ip, 100).unwrap().is_allowed());
+    }
+
+    /// The buckets of both backends survive a state export and import
+    #[test]
+    fn test_state_archive_round_trip() {
+        use crate::secret::Secret;
+        use crate::state::{self, ImportMode};
+
+        let temp_dir = |name: &str| {
+            let dir = std::env::temp_dir().join(format!(
+                "ninja-tb-{name}-{}-{}",
+                std::process::id(),
+                rand::random::<u32>()
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+            dir
+        };
+        let (src, dst) = (temp_dir("src"), temp_dir("dst"));
+        let ip = "10.0.0.1".parse().unwrap();
+        let redb = |dir: &std::path::Path| {
+            RedisTokenBucket::builder()
+                .enable(true)
+                .capacity(2)
+                .fill_rate(0)
+                .db(Arc::new(
+                    database_builder()
+                        .create(dir.join("token_bucket.db"))
+                        .unwrap(),
+                ))
+                .build()
+        };
+
+        // redb, the database file
+        let bucket = redb(&src);
+        assert!(bucket.acquire_at(ip, 100).unwrap().is_allowed());
+        drop(bucket);
+        // mem, the checkpoint
+        let bucket = MemTokenBucket::new(true, 2, 0, 60);
+        assert!(bucket.acquire(ip).unwrap().is_allowed());
+        std::fs::write(
+            src.join(crate::serve::checkpoint::CHECKPOINT_FILE),
+            serde_json::to_vec(&bucket.snapshot().unwrap()).unwrap(),
+        )
+        .unwrap();
+
+        let key = Secret::new("state-key");
+        let mut archive = Vec::new();
+        state::export(&src, &key, &mut archive).unwrap();
+        state::import(archive.as_slice(), &dst, &key, ImportMode::Replace).unwrap();
+
+        // One token left in each imported bucket
+        let bucket = redb(&dst);
+        assert!(bucket.acquire_at(ip, 100).unwrap().is_allowed());
+        assert!(!bucket.acquire_at(ip, 100).unwrap().is_allowed());
+
+        let saved = std::fs::read(dst.join(crate::serve::checkpoint::CHECKPOINT_FILE)).unwrap();
+        let bucket = MemTokenBucket::new(true, 2, 0, 60);
+        bucket
+            .restore(serde_json::from_slice(&saved).unwrap(), 0)
+            .unwrap();
+        assert!(bucket.acquire(ip).unwrap().is_allowed());
+        assert!(!bucket.acquire(ip).unwrap().is_allowed());
+    }
+
+    #[test]
+    fn test_take_without_fill_rate() {
+        let (mut tokens, mut last_time) = (1, 100);
+        assert_eq!(
+            take(&mut tokens, &mut last_time, 1, 0, 100),
+            Acquire::Allowed
+        );
+        assert_eq!(
+            take(&mut tokens, &mut last_time, 1, 0, 200),
+            Acquire::Limited { available_at: None }
+        );
+    }
+}
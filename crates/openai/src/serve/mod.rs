@@ -1,18 +1,79 @@
+//! HTTP server, usable from the `ninja` binary or embedded in another application.
+//!
+//! ```no_run
+//! # async fn embed() -> anyhow::Result<()> {
+//! use axum::{routing::get, Router};
+//! use openai::serve::{Args, Serve};
+//!
+//! let args = Args::builder().bind("127.0.0.1:0".parse::<std::net::SocketAddr>()?).build();
+//! let server = Serve::new(args)
+//!     .merge(Router::new().route("/healthz", get(|| async { "ok" })))
+//!     .run_async()
+//!     .await?;
+//! println!("listening on {}", server.bound_addr());
+//! server.shutdown().await
+//! # }
+//! ```
+//!
+//! Stability: [`Serve`], [`ServeHandle`] and [`Args`] (with its builder) follow the
+//! crate semver, breaking changes only land in a minor version bump while the crate is
+//! `0.x`. Other items of this module are public for the binary and are not covered.
+//! The server state (context, limits, metrics) is global, only one server can run per
+//! process.
+
+mod accept;
+mod access_log;
+mod admin;
+mod backup;
+mod budget;
+mod checkpoint;
 mod error;
+mod http2_downgrade;
+mod idempotency;
+mod ip_filter;
+mod keepalive;
+mod leader;
+mod lifecycle;
+mod listener;
+mod memory;
+mod metrics;
 mod middleware;
+mod model_policy;
 #[cfg(feature = "preauth")]
 mod preauth;
+mod priority;
+mod privacy;
 mod proxy;
 mod puid;
 #[cfg(feature = "template")]
 mod router;
+mod routes;
+mod schedule;
 mod signal;
+mod timing;
+pub mod tls;
 mod turnstile;
 mod whitelist;
 
+pub use crate::context::args::Args;
+
+use self::accept::{
+    ConnectionLimit, ConnectionLimitAcceptor, FailClosedAcceptor, HeaderReadTimeoutAcceptor,
+    Http2DowngradeAcceptor, TlsHandshakeAcceptor,
+};
+use self::proxy::adapt;
+use self::proxy::body_transform;
+use self::proxy::cookie;
+use self::proxy::egress;
 use self::proxy::ext::RequestExt;
-use self::proxy::ext::SendRequestExt;
-use self::proxy::resp::response_convert;
+use self::proxy::inflight;
+use self::proxy::mirror;
+use self::proxy::model_name;
+use self::proxy::redact;
+use self::proxy::retry;
+use self::proxy::sse;
+use self::proxy::tag;
+use self::routes::{any, get, post, RouteGroup, Routes};
 use crate::arkose;
 use crate::arkose::ArkoseContext;
 use crate::arkose::ArkoseToken;
@@ -20,12 +81,13 @@ use crate::auth::model::{AccessToken, AuthAccount, RefreshToken, SessionAccessTo
 use crate::auth::provide::AuthProvider;
 use crate::constant::API_AUTH_SESSION_COOKIE_KEY;
 use crate::context;
-use crate::context::args::Args;
+use crate::context::args::SseFlushMode;
 use crate::dns;
 use crate::proxy::{InnerProxy, Proxy};
 use crate::serve::error::ProxyError;
 use crate::serve::error::ResponseError;
-use crate::serve::middleware::tokenbucket::{Strategy, TokenBucketProvider};
+use crate::serve::middleware::tokenbucket::{RouteBuckets, Strategy, TokenBucketProvider};
+use crate::state;
 use crate::{info, warn, with_context};
 use crate::{URL_CHATGPT_API, URL_PLATFORM_API};
 use axum::body::Body;
@@ -34,22 +96,26 @@ use axum::extract::Query;
 use axum::headers::authorization::Bearer;
 use axum::headers::Authorization;
 use axum::http::header;
+use axum::http::Request;
 use axum::http::Response;
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
-use axum::routing::get;
-use axum::routing::{any, post};
+use axum::routing::Route;
 use axum::Router;
 use axum::{Json, TypedHeader};
 use axum_extra::extract::cookie;
-use axum_server::tls_rustls::RustlsConfig;
+use axum_server::accept::DefaultAcceptor;
+use axum_server::tls_rustls::RustlsAcceptor;
 use axum_server::HttpConfig;
 use axum_server::{AddrIncomingConfig, Handle};
+use std::convert::Infallible;
 use std::net::SocketAddr;
 use std::ops::Not;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tower::{Layer, Service};
 use tower_http::trace;
 use tracing::Level;
 use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
@@ -57,14 +123,72 @@ use tracing_subscriber::util::SubscriberInitExt;
 
 type ProxyResult<T> = std::result::Result<T, ProxyError>;
 
+/// Workers over this many per available CPU are warned about
+const WORKERS_PER_CPU_WARN: usize = 2;
+
+/// Set by the first [`Serve::run_async`] past the argument checks
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Runtime worker threads, `0` is one per available CPU
+fn worker_threads(workers: usize, cpus: usize) -> usize {
+    if workers == 0 {
+        cpus
+    } else {
+        workers
+    }
+}
+
 fn print_boot_message(inner: &Args) {
     info!("OS: {}", std::env::consts::OS);
     info!("Arch: {}", std::env::consts::ARCH);
     info!("Concurrent limit: {}", inner.concurrent_limit);
+    if inner.max_concurrent_streams_inflight > 0 {
+        info!(
+            "Concurrent streams limit: {}",
+            inner.max_concurrent_streams_inflight
+        );
+    }
+    if !inner.retry_on_body_patterns.is_empty() {
+        if inner.upstream_retries > 0 {
+            info!(
+                "Upstream retries: {} ({} body patterns)",
+                inner.upstream_retries,
+                inner.retry_on_body_patterns.len()
+            );
+        } else {
+            warn!("Retry body patterns are set, but upstream retries is 0");
+        }
+    }
+    if inner.sse_flush_mode == SseFlushMode::Batched {
+        info!(
+            "SSE flush mode: batched ({} bytes / {} ms)",
+            inner.sse_batch_size, inner.sse_batch_time
+        );
+    }
+    if inner.stream_chunk_size > 0 {
+        info!("SSE chunk size: {} bytes", inner.stream_chunk_size);
+    }
+    if inner.log_sample_rate < 1.0 {
+        info!("Access log sample rate: {}", inner.log_sample_rate);
+    }
     info!("Timeout {} seconds", inner.timeout);
     info!("Connect timeout {} seconds", inner.connect_timeout);
+    info!("Header read timeout {} seconds", inner.header_read_timeout);
+    if inner.tls_cert.is_some() {
+        info!(
+            "TLS handshake timeout {} seconds, concurrent handshakes limit: {}",
+            inner.tls_handshake_timeout, inner.max_concurrent_handshakes
+        );
+    }
     info!("Keepalive {} seconds", inner.tcp_keepalive);
     info!("TCP keepalive: {}", inner.no_keepalive.not());
+    info!("Upstream HTTP/2: {}", inner.upstream_http2);
+    if inner.upstream_http2 && inner.upstream_http2_max_streams_per_conn > 0 {
+        info!(
+            "Upstream HTTP/2 streams per connection: {}",
+            inner.upstream_http2_max_streams_per_conn
+        );
+    }
     info!("Cookie store: {}", inner.cookie_store);
     info!("Enable direct connection: {}", inner.enable_direct);
     info!("Enable WebUI: {}", inner.enable_webui);
@@ -105,81 +229,342 @@ fn print_boot_message(inner: &Args) {
     });
 }
 
-pub struct Serve(Args);
+/// Custom router layer registered by the embedder
+type RouterLayer = Box<dyn FnOnce(Routes) -> Routes + Send>;
+
+/// Callback invoked with the bound address once the server is ready
+type ReadyCallback = Box<dyn FnOnce(SocketAddr) + Send>;
+
+pub struct Serve {
+    args: Args,
+    layers: Vec<RouterLayer>,
+    ready: Option<ReadyCallback>,
+}
 
 impl Serve {
     pub fn new(inner: Args) -> Self {
-        Self(inner)
+        Self {
+            args: inner,
+            layers: Vec::new(),
+            ready: None,
+        }
     }
 
+    /// Register a callback invoked with the bound address once the server is about to serve.
+    /// Startup errors (e.g. the port is in use) are returned by `run` instead.
+    pub fn on_ready<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(SocketAddr) + Send + 'static,
+    {
+        self.ready = Some(Box::new(f));
+        self
+    }
+
+    /// Register a custom tower middleware (e.g. auth, logging) in the request pipeline.
+    ///
+    /// Layers run in registration order (the first registered is the outermost), after
+    /// the global layers (trace, concurrency limit, CORS, timeout, body limit) and before
+    /// the route layers (access token auth, token bucket limit) and the forwarding handlers.
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<Route> + Clone + Send + 'static,
+        L::Service: Service<Request<Body>> + Clone + Send + 'static,
+        <L::Service as Service<Request<Body>>>::Response: IntoResponse + 'static,
+        <L::Service as Service<Request<Body>>>::Error: Into<Infallible> + 'static,
+        <L::Service as Service<Request<Body>>>::Future: Send + 'static,
+    {
+        self.layers.push(Box::new(move |routes: Routes| {
+            routes.layer(&["custom"], |router| router.layer(layer))
+        }));
+        self
+    }
+
+    /// Register additional routes, merged next to the built-in routes.
+    ///
+    /// The routes are served behind the global layers and the custom layers, but not
+    /// behind the access token auth and token bucket route layers.
+    pub fn merge(mut self, routes: Router) -> Self {
+        self.layers
+            .push(Box::new(move |router: Routes| router.merge_router(routes)));
+        self
+    }
+
+    /// Run the server until a shutdown signal (SIGINT, SIGTERM, ...) is received.
+    ///
     /// from issue: https://github.com/hyperium/hyper/issues/3140
-    #[tokio::main]
-    pub async fn run(self) -> anyhow::Result<()> {
-        tracing_subscriber::registry()
-            .with(
-                tracing_subscriber::EnvFilter::try_from_default_env()
-                    .unwrap_or_else(|_| "RUST_LOG=warn".into()),
-            )
-            .with(tracing_subscriber::fmt::layer())
-            .init();
+    pub fn run(self) -> anyhow::Result<()> {
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let workers = worker_threads(self.args.workers, cpus);
 
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .worker_threads(workers)
+            .build()?
+            .block_on(async move {
+                tracing_subscriber::registry()
+                    .with(
+                        tracing_subscriber::EnvFilter::try_from_default_env()
+                            .unwrap_or_else(|_| "RUST_LOG=warn".into()),
+                    )
+                    .with(tracing_subscriber::fmt::layer())
+                    .init();
+
+                info!("Workers: {workers} ({cpus} CPUs)");
+                if workers > cpus * WORKERS_PER_CPU_WARN {
+                    warn!(
+                        "workers ({workers}) is more than {WORKERS_PER_CPU_WARN}x the available CPUs ({cpus}), the worker threads will contend for the cores"
+                    );
+                }
+
+                let server = self.run_async().await?;
+
+                // Spawn a task to gracefully shutdown server.
+                tokio::spawn(signal::graceful_shutdown(server.handle.clone()));
+
+                let result = server.wait().await;
+                let _ = tokio::task::spawn_blocking(leader::release_all).await;
+                result
+            })
+    }
+
+    /// Start the server on the current tokio runtime, returns once the server is serving.
+    ///
+    /// No signal handler or log subscriber is installed, the server runs until
+    /// [`ServeHandle::shutdown`] is called. Only one server can run per process.
+    pub async fn run_async(mut self) -> anyhow::Result<ServeHandle> {
         // print boot message
-        print_boot_message(&self.0);
+        print_boot_message(&self.args);
 
-        // init context
-        context::init(self.0.clone());
-
-        // init global layer provider
-        let global_layer = tower::ServiceBuilder::new()
-            .layer(
-                tower_http::trace::TraceLayer::new_for_http()
-                    .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
-                    .on_response(trace::DefaultOnResponse::new().level(Level::INFO))
-                    .on_request(trace::DefaultOnRequest::new().level(Level::INFO))
-                    .on_failure(trace::DefaultOnFailure::new().level(Level::WARN)),
-            )
-            .layer(tower::limit::ConcurrencyLimitLayer::new(
-                self.0.concurrent_limit,
-            ))
-            .layer(
-                tower_http::cors::CorsLayer::new()
-                    .allow_credentials(true)
-                    .allow_headers(tower_http::cors::AllowHeaders::mirror_request())
-                    .allow_methods(tower_http::cors::AllowMethods::mirror_request())
-                    .allow_origin(tower_http::cors::AllowOrigin::mirror_request()),
+        self.args.response_headers.validate()?;
+        if self.args.tls_cert.is_some() != self.args.tls_key.is_some() {
+            anyhow::bail!("tls_cert and tls_key must be configured together")
+        }
+
+        // The server state is global, a second server would silently share it
+        if STARTED.swap(true, Ordering::SeqCst) {
+            anyhow::bail!(
+                "A server was already started in this process, only one server can run per process"
             )
-            .layer(axum::error_handling::HandleErrorLayer::new(
-                |_: axum::BoxError| async { axum::http::StatusCode::REQUEST_TIMEOUT },
-            ))
-            .layer(tower::timeout::TimeoutLayer::new(Duration::from_secs(
-                self.0.timeout as u64,
-            )))
-            .layer(axum::extract::DefaultBodyLimit::max(200 * 1024 * 1024));
-
-        // init auth layer provider
-        let app_layer = {
-            let limit_context = TokenBucketProvider::from((
-                Strategy::from_str(self.0.tb_strategy.as_str())?,
-                self.0.tb_enable,
-                self.0.tb_capacity,
-                self.0.tb_fill_rate,
-                self.0.tb_expired,
+        }
+
+        // Migrate the persisted state before the stores are opened
+        state::migrate::run(
+            &crate::homedir::home_dir()
+                .unwrap_or_default()
+                .join(context::WORKER_DIR),
+        )?;
+
+        // init context
+        context::init(self.args.clone());
+
+        // Background jobs run on the instance holding their lease
+        leader::init(
+            &self.args.leader_store,
+            self.args.leader_dir.clone(),
+            self.args.leader_lease_ttl,
+        )?;
+
+        // Access schedules, hot-reloaded while the server runs
+        let schedule_watch = self
+            .args
+            .access_schedules
+            .clone()
+            .map(schedule::init)
+            .transpose()?;
+
+        // Client IP allow and deny lists, hot-reloaded while the server runs
+        let ip_filter_watch = ip_filter::init(
+            self.args.allow_ips_file.clone(),
+            self.args.deny_ips_file.clone(),
+        )?;
+
+        // Per-key model policies, hot-reloaded while the server runs
+        let model_policy_watch = self
+            .args
+            .model_policies
+            .clone()
+            .map(model_policy::init)
+            .transpose()?;
+        if model_policy_watch.is_some() {
+            budget::init(&self.args.budget_store, self.args.pricing.clone())?;
+        }
+
+        // Bind the listener before the slow startup checks, so bind errors are reported early
+        let listener = listener::bind(self.args.bind.unwrap(), self.args.port_retry)?;
+        let bind_addr = listener.local_addr()?;
+        if let Some(admin_listen) = self.args.admin_listen.as_ref() {
+            admin_listen.validate(self.args.auth_key.is_some())?;
+        }
+        let admin_listener = self
+            .args
+            .admin_listen
+            .as_ref()
+            .map(listener::bind_admin)
+            .transpose()?;
+
+        // Lifecycle events, checked before the server is spawned
+        lifecycle::init(self.args.lifecycle_webhook.as_deref(), bind_addr)?;
+
+        self.args.mirror.validate()?;
+        mirror::init(&self.args.mirror);
+
+        // Egress request signing
+        egress::init(self.args.egress_auth.as_ref())?;
+
+        // Response redaction
+        redact::init(&self.args.redact)?;
+
+        // Event stream flush mode
+        sse::init(
+            self.args.sse_flush_mode,
+            self.args.sse_batch_size,
+            self.args.sse_batch_time,
+            self.args.stream_chunk_size,
+        );
+
+        // Chat completion streaming adaptation
+        adapt::init(self.args.stream_adapt);
+
+        // Request body transforms
+        body_transform::init(&self.args.body_transforms, self.args.body_transform_limit)?;
+
+        // Requested model aliases and allowlist
+        model_name::init(&self.args.model_aliases, &self.args.model_allowlist)?;
+
+        // Upstream cookies relayed to the clients or kept per session
+        cookie::init(self.args.cookie_mode, self.args.cookie_store);
+
+        // Privacy mode, strip the client identifiers of the upstream requests
+        privacy::init(self.args.privacy_mode, &self.args.privacy_strip_headers)?;
+
+        // Upstream request tagging
+        tag::init(
+            self.args.upstream_tag_header.as_deref(),
+            &self.args.upstream_tag_rules,
+        )?;
+
+        // Buffered bodies memory budget, the idempotency mem store is bounded by it
+        memory::init(self.args.max_buffered_memory);
+
+        // Replay the responses of the retried requests with an idempotency key
+        idempotency::init(
+            self.args.idempotency_enable,
+            &self.args.idempotency_store,
+            self.args.idempotency_window,
+        )?;
+
+        // Access log sampling, server errors are always logged
+        access_log::init(self.args.log_sample_rate)?;
+
+        // Upstream response body retry patterns
+        retry::init(&self.args.retry_on_body_patterns)?;
+
+        // Streaming responses limit, distinct from the general concurrent limit
+        inflight::init(self.args.max_concurrent_streams_inflight);
+        metrics::init(
+            self.args.concurrent_limit,
+            self.args.max_concurrent_streams_inflight,
+        );
+
+        // Turnstile siteverify concurrency
+        turnstile::init(self.args.cf_verify_concurrency);
+
+        // Auth mechanisms of the proxied requests
+        middleware::auth::init(
+            &self.args.auth_chain,
+            self.args.auth_sign_secret.as_ref(),
+            self.args.cf_site_key.is_some() && self.args.cf_secret_key.is_some(),
+        )?;
+
+        // State backup endpoint
+        backup::init(self.args.state_key.as_ref());
+
+        // Server-Timing response header
+        timing::init(self.args.expose_server_timing);
+
+        // HTTP/2 downgrade of the problem clients
+        http2_downgrade::init(&self.args.http2_disable_for);
+
+        // Concurrent limit queue, admitted by priority class
+        priority::init(
+            self.args.concurrent_limit,
+            self.args.priority_header.as_deref(),
+            &self.args.priority_trusted_peers,
+            self.args.priority_aging,
+            self.args.priority_max_queue,
+            &self.args.priority_rules,
+        )?;
+
+        // Route limits, checked before their buckets are created or restored
+        for (route, limit) in &self.args.route_limits {
+            limit.validate(route)?;
+        }
+
+        // init token bucket layer state
+        let limit_state = {
+            let limit_context = Arc::new(TokenBucketProvider::from((
+                Strategy::from_str(self.args.tb_strategy.as_str())?,
+                self.args.tb_enable,
+                self.args.tb_capacity,
+                self.args.tb_fill_rate,
+                self.args.tb_expired,
+            )));
+            let route_limits = Arc::new(RouteBuckets::new(
+                &limit_context,
+                &self.args.route_limits,
+                self.args.tb_expired,
             ));
 
-            tower::ServiceBuilder::new()
-                .layer(axum::middleware::from_fn(middleware::auth::auth_middleware))
-                .layer(axum::middleware::from_fn_with_state(
-                    Arc::new(limit_context),
-                    middleware::limit::limit_middleware,
-                ))
+            // Restore the in-memory stores saved by the previous run
+            if self.args.checkpoint_enable {
+                checkpoint::init(
+                    &crate::homedir::home_dir()
+                        .unwrap_or_default()
+                        .join(context::WORKER_DIR),
+                    vec![
+                        (
+                            "token_bucket",
+                            limit_context.clone() as Arc<dyn checkpoint::Volatile>,
+                        ),
+                        (
+                            "route_token_buckets",
+                            route_limits.clone() as Arc<dyn checkpoint::Volatile>,
+                        ),
+                        ("budget_spend", Arc::new(budget::BudgetSpend)),
+                    ],
+                    self.args.checkpoint_interval,
+                    self.args.tb_expired as u64,
+                )?;
+            }
+
+            middleware::limit::LimitState::new(
+                limit_context,
+                route_limits,
+                self.args.tb_debug_key,
+                self.args.tb_redb_max_concurrency,
+            )
         };
 
-        let router = Router::new()
+        let router = Routes::new(RouteGroup::Proxy)
             .route("/dashboard/*path", any(official_proxy))
             .route("/v1/*path", any(official_proxy))
             .route("/backend-api/*path", any(unofficial_proxy))
-            .route_layer(app_layer)
+            .layer(&["idempotency"], |router| {
+                router.route_layer(axum::middleware::from_fn(idempotency::middleware))
+            })
+            .layer(&["token_bucket"], |router| {
+                router.route_layer(axum::middleware::from_fn_with_state(
+                    limit_state,
+                    middleware::limit::limit_middleware,
+                ))
+            })
+            .layer(&["auth"], |router| {
+                router.route_layer(axum::middleware::from_fn(middleware::auth::auth_middleware))
+            })
+            .route_limits(&self.args.route_limits)?
+            .group(RouteGroup::Public)
             .route("/public-api/*path", any(unofficial_proxy))
+            .group(RouteGroup::Auth)
             .route("/auth/token", post(post_access_token))
             .route("/auth/refresh_token", post(post_refresh_token))
             .route("/auth/revoke_token", post(post_revoke_token))
@@ -187,34 +572,150 @@ impl Serve {
             .route("/auth/sess_token", post(post_sess_token))
             .route("/auth/billing", post(post_billing));
 
+        // Admin endpoints, served on their own listener when `admin_listen` is set
+        let admin_router = Routes::new(RouteGroup::Admin)
+            .route("/metrics", get(metrics::metrics))
+            .route("/admin/mirror", get(mirror::admin).post(mirror::admin))
+            .route("/admin/usage/:id", get(budget::usage))
+            .route("/admin/routes", get(routes::admin))
+            .route("/admin/state", get(backup::state));
+        let (router, admin) = match admin_listener {
+            Some(listener) => {
+                let admin_router = admin_router.on_admin_listener();
+                // Reachable over the network, gated as on the public listener
+                let admin_router = match self.args.admin_listen.as_ref() {
+                    Some(listen) if !listen.is_local() => admin_router
+                        .layer(&["admin_gate"], |router| {
+                            router.layer(axum::middleware::from_fn(admin::gate))
+                        }),
+                    _ => admin_router,
+                };
+                (router, Some((listener, admin_router)))
+            }
+            // Shared with the public listener, gated
+            None => (
+                router.merge(admin_router.layer(&["admin_gate"], |router| {
+                    router.layer(axum::middleware::from_fn(admin::gate))
+                })),
+                None,
+            ),
+        };
+
         let router = router::config(
             // Enable arkose token endpoint proxy
-            if self.0.enable_arkose_proxy {
-                router.route("/auth/arkose_token/:path", get(get_arkose_token))
+            if self.args.enable_arkose_proxy {
+                router
+                    .group(RouteGroup::Auth)
+                    .route("/auth/arkose_token/:path", get(get_arkose_token))
             } else {
                 router
             },
-            &self.0,
-        )
-        .layer(global_layer);
+            &self.args,
+        );
+
+        // Custom layers, the first registered is the outermost
+        let router = self
+            .layers
+            .into_iter()
+            .rev()
+            .fold(router, |router, layer| layer(router));
+
+        // Global layers, applied innermost first, each wraps the ones before it
+        let router = router
+            .layer(&["body_limit"], |router| {
+                router.layer(axum::extract::DefaultBodyLimit::max(200 * 1024 * 1024))
+            })
+            .timeout(self.args.timeout as u64, |router| {
+                router.layer(
+                    tower::ServiceBuilder::new()
+                        .layer(axum::error_handling::HandleErrorLayer::new(
+                            |_: axum::BoxError| async { axum::http::StatusCode::REQUEST_TIMEOUT },
+                        ))
+                        .layer(tower::timeout::TimeoutLayer::new(Duration::from_secs(
+                            self.args.timeout as u64,
+                        ))),
+                )
+            })
+            .layer(&["inflight"], |router| {
+                router.layer(axum::middleware::from_fn(metrics::track_inflight))
+            })
+            .layer(&["cors"], |router| {
+                router.layer(
+                    tower_http::cors::CorsLayer::new()
+                        .allow_credentials(true)
+                        .allow_headers(tower_http::cors::AllowHeaders::mirror_request())
+                        .allow_methods(tower_http::cors::AllowMethods::mirror_request())
+                        .allow_origin(tower_http::cors::AllowOrigin::mirror_request()),
+                )
+            })
+            .layer(&["priority_limit"], |router| {
+                router.layer(axum::middleware::from_fn(priority::limit))
+            })
+            .layer(&["timing"], |router| {
+                router.layer(axum::middleware::from_fn(timing::server_timing))
+            })
+            .layer(&["http2_downgrade"], |router| {
+                router.layer(axum::middleware::from_fn(http2_downgrade::record))
+            })
+            .layer(&["ip_filter"], |router| {
+                router.layer(axum::middleware::from_fn(ip_filter::check))
+            })
+            .layer(&["access_log"], |router| {
+                router.layer(axum::middleware::from_fn(access_log::log))
+            })
+            .layer(&["trace"], |router| {
+                router.layer(
+                    tower_http::trace::TraceLayer::new_for_http()
+                        .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
+                        .on_request(())
+                        .on_response(())
+                        .on_failure(trace::DefaultOnFailure::new().level(Level::WARN)),
+                )
+            });
+
+        // Publish the route table, then serve the routers
+        let (router, mut table) = router.into_parts();
+        let admin = admin.map(|(listener, admin_router)| {
+            let (admin_router, admin_table) = admin_router.into_parts();
+            table.extend(admin_table);
+            (listener, admin_router)
+        });
+        routes::init(table);
 
         // Signal the server to shutdown using Handle.
         let handle = Handle::new();
 
-        // Spawn a task to gracefully shutdown server.
-        tokio::spawn(signal::graceful_shutdown(handle.clone()));
-
         // Fast dns test
-        dns::fast::load_fastest_dns(self.0.fastest_dns).await?;
+        dns::fast::load_fastest_dns(self.args.fastest_dns).await?;
 
         // check wan address.
-        check_wan_address().await;
+        tokio::spawn(check_wan_address());
 
         // upgrade arkose version.
-        tokio::spawn(with_context!(arkose_context).periodic_upgrade());
+        info!("Arkose Periodic task is running");
+        tokio::spawn(leader::every(
+            "arkose_upgrade",
+            context::arkose::UPGRADE_INTERVAL,
+            || with_context!(arkose_context).upgrade(),
+        ));
+
+        // upstream session keepalive
+        if self.args.upstream_keepalive_interval > 0 {
+            match self.args.upstream_keepalive_token.clone() {
+                Some(token) => {
+                    let interval =
+                        Duration::from_secs(self.args.upstream_keepalive_interval as u64);
+                    tokio::spawn(keepalive::upstream_keepalive(token, interval));
+                }
+                None => info!("Upstream keepalive skipped, no upstream credential configured"),
+            }
+        }
 
         // http server tcp keepalive
-        let tcp_keepalive = Duration::from_secs(self.0.tcp_keepalive as u64 + 1);
+        let tcp_keepalive = Duration::from_secs(self.args.tcp_keepalive as u64 + 1);
+
+        // http server header read timeout (slowloris defense)
+        let header_read_timeout = Duration::from_secs(self.args.header_read_timeout as u64);
 
         // http server config
         let http_config = HttpConfig::new()
@@ -234,12 +735,12 @@ impl Serve {
 
         // PreAuth mitm proxy
         #[cfg(feature = "preauth")]
-        if let Some(pbind) = self.0.pbind.clone() {
+        if let Some(pbind) = self.args.pbind.clone() {
             let builder = mitm::Builder::builder()
                 .bind(pbind)
-                .upstream_proxy(self.0.pupstream.clone())
-                .cert(self.0.pcert.clone())
-                .key(self.0.pkey.clone())
+                .upstream_proxy(self.args.pupstream.clone())
+                .cert(self.args.pcert.clone())
+                .key(self.args.pkey.clone())
                 .graceful_shutdown(rx)
                 .cerificate_cache_size(1_000)
                 .mitm_filters(vec![String::from("ios.chat.openai.com")])
@@ -250,44 +751,180 @@ impl Serve {
             }
         }
 
-        info!(
-            "Starting HTTP(S) server at http(s)://{:?}",
-            self.0.bind.unwrap()
-        );
-
-        // Run http server
-        let result = match (self.0.tls_cert, self.0.tls_key) {
+        // TLS keypair, hot-reloaded while serving. A TLS listener never serves plaintext:
+        // it does not start without a usable keypair, and fails closed once it is invalid.
+        let tls = match (self.args.tls_cert.clone(), self.args.tls_key.clone()) {
             (Some(cert), Some(key)) => {
-                let tls_config = RustlsConfig::from_pem_file(cert, key)
-                    .await
-                    .expect("Failed to load TLS keypair");
-
-                axum_server::bind_rustls(self.0.bind.unwrap(), tls_config)
-                    .handle(handle)
-                    .addr_incoming_config(incoming_config)
-                    .http_config(http_config)
-                    .serve(router.into_make_service_with_connect_info::<SocketAddr>())
-                    .await
+                let alpn_protocols = tls::alpn_protocols(&self.args.tls_alpn_protocols)?;
+                let (tls_config, keypair) = tls::load_serving_config(
+                    &cert,
+                    &key,
+                    self.args.tls_key_password.as_ref(),
+                    &alpn_protocols,
+                )?;
+                // Served to the clients downgraded to HTTP/1.1
+                let http1_config = self
+                    .args
+                    .http2_disable_for
+                    .is_empty()
+                    .not()
+                    .then(|| tls::http1_config(&tls_config, &alpn_protocols))
+                    .transpose()?;
+                let hotwatch = tls::watch_rustls_config(
+                    tls_config.clone(),
+                    http1_config.clone(),
+                    keypair.clone(),
+                    cert,
+                    key,
+                    self.args.tls_key_password.clone(),
+                    alpn_protocols,
+                )?;
+                Some((tls_config, http1_config, keypair, hotwatch))
             }
-            _ => {
-                axum_server::bind(self.0.bind.unwrap())
-                    .handle(handle)
-                    .addr_incoming_config(incoming_config)
-                    .http_config(http_config)
-                    .serve(router.into_make_service_with_connect_info::<SocketAddr>())
-                    .await
+            (None, None) => {
+                if !self.args.http2_disable_for.is_empty() {
+                    warn!("http2_disable_for only applies to the TLS listener, ignored");
+                }
+                None
             }
+            _ => unreachable!("tls_cert and tls_key are checked together on startup"),
         };
 
-        if let Some(err) = result.err() {
-            warn!("Http Server error: {}", err);
-        }
+        info!("Starting HTTP(S) server at http(s)://{:?}", bind_addr);
 
-        if let Some(err) = tx.send(()).await.err() {
-            warn!("Send shutdown signal error: {}", err);
+        // Notify the server is ready
+        if let Some(ready) = self.ready.take() {
+            ready(bind_addr);
         }
 
-        tokio::time::sleep(Duration::from_secs(1)).await;
+        // Run http server
+        let server = handle.clone();
+        let tls_handshake_timeout = Duration::from_secs(self.args.tls_handshake_timeout as u64);
+        let max_concurrent_handshakes = self.args.max_concurrent_handshakes;
+        let connection_limit =
+            ConnectionLimit::new(metrics::MAIN_LISTENER, self.args.max_connections);
+        let admin_connection_limit =
+            ConnectionLimit::new(metrics::ADMIN_LISTENER, self.args.admin_max_connections);
+        let task = tokio::spawn(async move {
+            // Keep the watchers alive while serving
+            let _schedule_watch = schedule_watch;
+            let _model_policy_watch = model_policy_watch;
+            let _ip_filter_watch = ip_filter_watch;
+
+            let admin = admin.map(|(listener, router)| {
+                tokio::spawn(async move {
+                    if let Err(err) =
+                        listener::serve_admin(listener, router, admin_connection_limit).await
+                    {
+                        warn!("Admin server error: {}", err);
+                    }
+                })
+            });
+
+            let result = match tls {
+                Some((tls_config, http1_config, keypair, _hotwatch)) => {
+                    // Bound the handshakes before the header read timeout applies
+                    let tls_acceptor = |config| {
+                        let acceptor = RustlsAcceptor::new(config);
+                        match tls_handshake_timeout.is_zero() {
+                            true => acceptor,
+                            false => acceptor.handshake_timeout(tls_handshake_timeout),
+                        }
+                    };
+                    let rustls_acceptor = Http2DowngradeAcceptor::new(
+                        tls_acceptor(tls_config),
+                        http1_config.map(tls_acceptor),
+                    );
+
+                    // Connections over the limit wait before the handshake
+                    axum_server::from_tcp(listener)
+                        .acceptor(ConnectionLimitAcceptor::new(
+                            HeaderReadTimeoutAcceptor::new(
+                                FailClosedAcceptor::new(
+                                    TlsHandshakeAcceptor::new(
+                                        rustls_acceptor,
+                                        tls_handshake_timeout,
+                                        max_concurrent_handshakes,
+                                    ),
+                                    keypair,
+                                ),
+                                header_read_timeout,
+                            ),
+                            connection_limit,
+                        ))
+                        .handle(server)
+                        .addr_incoming_config(incoming_config)
+                        .http_config(http_config)
+                        .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+                        .await
+                }
+                None => {
+                    axum_server::from_tcp(listener)
+                        .acceptor(ConnectionLimitAcceptor::new(
+                            HeaderReadTimeoutAcceptor::new(DefaultAcceptor, header_read_timeout),
+                            connection_limit,
+                        ))
+                        .handle(server)
+                        .addr_incoming_config(incoming_config)
+                        .http_config(http_config)
+                        .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+                        .await
+                }
+            };
+
+            if let Some(err) = result.err() {
+                warn!("Http Server error: {}", err);
+            }
+
+            if let Some(admin) = admin {
+                admin.abort();
+                let _ = admin.await;
+            }
+
+            // Keep the in-memory stores for the next run
+            if let Err(err) = checkpoint::save().await {
+                warn!("Failed to save the checkpoint: {err}");
+            }
+
+            if let Some(err) = tx.send(()).await.err() {
+                warn!("Send shutdown signal error: {}", err);
+            }
+        });
+
+        // Lifecycle events, the instance is serving
+        lifecycle::notify(lifecycle::Kind::Ready, None);
+
+        Ok(ServeHandle {
+            handle,
+            bound_addr: bind_addr,
+            task,
+        })
+    }
+}
+
+/// Handle of a running server, returned by [`Serve::run_async`]
+pub struct ServeHandle {
+    handle: Handle,
+    bound_addr: SocketAddr,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl ServeHandle {
+    /// Address the server is listening on, with the actual port when bound to port 0
+    pub fn bound_addr(&self) -> SocketAddr {
+        self.bound_addr
+    }
+
+    /// Gracefully shut down the server, waits for the open connections up to 3 seconds
+    pub async fn shutdown(self) -> anyhow::Result<()> {
+        lifecycle::shutdown(None).await;
+        self.handle.graceful_shutdown(Some(Duration::from_secs(3)));
+        self.wait().await
+    }
+
+    /// Wait until the server is shut down
+    pub async fn wait(self) -> anyhow::Result<()> {
+        self.task.await?;
         Ok(())
     }
 }
@@ -436,19 +1073,55 @@ async fn get_arkose_token(
 ///
 /// platform API match path /v1/{tail.*}
 /// reference: https://platform.openai.com/docs/api-reference
-async fn official_proxy(req: RequestExt) -> Result<impl IntoResponse, ResponseError> {
-    let resp = with_context!(api_client)
-        .send_request(URL_PLATFORM_API, req)
-        .await?;
-    response_convert(resp).await
+async fn official_proxy(
+    mut req: RequestExt,
+) -> Result<Response<axum::body::BoxBody>, ResponseError> {
+    body_transform::apply(&mut req)?;
+    if let Err(resp) = model_name::normalize(&mut req) {
+        return Ok(resp);
+    }
+    let key_policy = match model_policy::check(&req) {
+        Ok(key_policy) => key_policy,
+        Err(resp) => return Ok(resp),
+    };
+    let model = model_policy::requested_model(&req);
+    let budget = match budget::admit(key_policy.as_ref(), model.as_deref(), req.body.as_deref()) {
+        Ok(budget) => budget,
+        Err(resp) => return Ok(resp),
+    };
+    let stream = inflight::acquire(&req)?;
+    mirror::spawn(&req);
+    let adapt = adapt::request(&mut req);
+    let resp = redact::apply(retry::send(URL_PLATFORM_API, req).await?).await?;
+    let resp = sse::flush(adapt::response(resp, adapt).await?);
+    let resp = model_policy::filter_listing(resp, key_policy.as_ref()).await?;
+    let resp = budget::account(resp, budget, model).await?;
+    Ok(inflight::hold(resp, stream))
 }
 
 /// reference: doc/http.rest
-async fn unofficial_proxy(req: RequestExt) -> Result<impl IntoResponse, ResponseError> {
-    let resp = with_context!(api_client)
-        .send_request(URL_CHATGPT_API, req)
-        .await?;
-    response_convert(resp).await
+async fn unofficial_proxy(
+    mut req: RequestExt,
+) -> Result<Response<axum::body::BoxBody>, ResponseError> {
+    body_transform::apply(&mut req)?;
+    if let Err(resp) = model_name::normalize(&mut req) {
+        return Ok(resp);
+    }
+    let key_policy = match model_policy::check(&req) {
+        Ok(key_policy) => key_policy,
+        Err(resp) => return Ok(resp),
+    };
+    let model = model_policy::requested_model(&req);
+    let budget = match budget::admit(key_policy.as_ref(), model.as_deref(), req.body.as_deref()) {
+        Ok(budget) => budget,
+        Err(resp) => return Ok(resp),
+    };
+    let stream = inflight::acquire(&req)?;
+    mirror::spawn(&req);
+    let resp = sse::flush(redact::apply(retry::send(URL_CHATGPT_API, req).await?).await?);
+    let resp = model_policy::filter_listing(resp, key_policy.as_ref()).await?;
+    let resp = budget::account(resp, budget, model).await?;
+    Ok(inflight::hold(resp, stream))
 }
 
 impl TryInto<Response<Body>> for SessionAccessToken {
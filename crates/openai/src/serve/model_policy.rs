@@ -0,0 +1,450 @@
+use crate::context::args::PriorityClass;
+use crate::serve::error::ResponseError;
+use crate::serve::lifecycle;
+use crate::serve::memory;
+use crate::serve::proxy::ext::RequestExt;
+use crate::token;
+use crate::{debug, error, info};
+use anyhow::Context;
+use axum::http::{header, Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use hotwatch::{Event, EventKind, Hotwatch};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// Loaded model policies, replaced on reload
+static POLICIES: RwLock<Vec<Arc<ModelPolicy>>> = RwLock::new(Vec::new());
+
+/// Model policies file (toml format file)
+#[derive(Deserialize)]
+struct PolicyFile {
+    #[serde(default)]
+    policy: Vec<ModelPolicy>,
+}
+
+/// A group of keys (token emails or `sk-`/`sess-` keys), the models it may use
+/// and the spend limits of each key
+#[derive(Deserialize)]
+pub(super) struct ModelPolicy {
+    name: String,
+    #[serde(default)]
+    emails: Vec<String>,
+    #[serde(default)]
+    keys: Vec<String>,
+    /// Model names, `*` matches any characters and `?` a single one, all models if unset
+    allowed_models: Option<Vec<String>>,
+    /// Daily spend limit of each key (USD, UTC day)
+    pub(super) budget_daily: Option<f64>,
+    /// Monthly spend limit of each key (USD, UTC month)
+    pub(super) budget_monthly: Option<f64>,
+    /// Priority class of the keys in the concurrent limit queue
+    pub(super) priority: Option<PriorityClass>,
+}
+
+impl ModelPolicy {
+    fn matches(&self, key: &Key) -> bool {
+        match key {
+            Key::Email(email) => self.emails.iter().any(|e| e.eq_ignore_ascii_case(email)),
+            Key::Secret(secret) => self.keys.iter().any(|k| k == secret),
+        }
+    }
+
+    /// Whether the policy covers the key of an identifier, see [`Key::id`]
+    fn matches_id(&self, id: &str) -> bool {
+        self.emails.iter().any(|e| e.eq_ignore_ascii_case(id))
+            || self.keys.iter().any(|k| Key::Secret(k.clone()).id() == id)
+    }
+
+    pub(super) fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn allows(&self, model: &str) -> bool {
+        self.allowed_models.as_ref().map_or(true, |models| {
+            models.iter().any(|pattern| glob_match(pattern, model))
+        })
+    }
+}
+
+/// Identity of the request key
+pub(super) enum Key {
+    Email(String),
+    Secret(String),
+}
+
+impl Key {
+    fn of(req: &RequestExt) -> Option<Self> {
+        Self::of_bearer(req.bearer_auth()?)
+    }
+
+    /// Identity of a bearer key, access tokens are decoded for their email
+    pub(super) fn of_bearer(bearer: &str) -> Option<Self> {
+        match Self::parse(bearer) {
+            // Access token, identified by its email
+            Self::Email(_) => {
+                let profile = token::check(bearer).ok().flatten()?;
+                Some(Self::Email(profile.email().to_owned()))
+            }
+            secret => Some(secret),
+        }
+    }
+
+    /// `sk-`/`sess-` keys are secrets, anything else is an email
+    pub(super) fn parse(key: &str) -> Self {
+        if token::check_sk_or_sess(key) {
+            Self::Secret(key.to_owned())
+        } else {
+            Self::Email(key.to_owned())
+        }
+    }
+
+    /// Stable identifier of the key, secrets are hashed so they are never stored
+    pub(super) fn id(&self) -> String {
+        use sha2::{Digest, Sha256};
+        match self {
+            Self::Email(email) => email.to_ascii_lowercase(),
+            Self::Secret(secret) => {
+                let digest = Sha256::digest(secret.as_bytes());
+                let hex = digest[..16]
+                    .iter()
+                    .map(|b| format!("{b:02x}"))
+                    .collect::<String>();
+                format!("key:{hex}")
+            }
+        }
+    }
+}
+
+/// Model listing response fields: the array and the model name of its entries
+#[derive(Clone, Copy)]
+enum ListingKind {
+    /// `GET /v1/models`, `{"data": [{"id": ..}]}`
+    Platform,
+    /// `GET /backend-api/models`, `{"models": [{"slug": ..}]}`
+    ChatGPT,
+}
+
+impl ListingKind {
+    fn of(req: &RequestExt) -> Option<Self> {
+        if req.method != Method::GET {
+            return None;
+        }
+        let path = req.uri.path();
+        if path.ends_with("/v1/models") {
+            Some(Self::Platform)
+        } else if path.ends_with("/backend-api/models") {
+            Some(Self::ChatGPT)
+        } else {
+            None
+        }
+    }
+
+    fn fields(self) -> (&'static str, &'static str) {
+        match self {
+            Self::Platform => ("data", "id"),
+            Self::ChatGPT => ("models", "slug"),
+        }
+    }
+}
+
+/// Request key and its policy, the model listing is filtered once the upstream answered
+pub(super) struct KeyPolicy {
+    pub(super) key: Key,
+    pub(super) policy: Arc<ModelPolicy>,
+    listing: Option<ListingKind>,
+}
+
+/// Glob match, `*` matches any characters (including none) and `?` a single one
+pub(super) fn glob_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name) = (pattern.as_bytes(), name.as_bytes());
+    let (mut p, mut n) = (0, 0);
+    // Position after the last `*` and the name position it matched up to
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some(c) if *c == b'?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((after, matched)) => {
+                    p = after;
+                    n = matched + 1;
+                    star = Some((after, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+fn load(path: &Path) -> anyhow::Result<Vec<Arc<ModelPolicy>>> {
+    let data = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read model policies {}", path.display()))?;
+    let file = toml::from_str::<PolicyFile>(&data)
+        .with_context(|| format!("Failed to parse model policies {}", path.display()))?;
+    Ok(file.policy.into_iter().map(Arc::new).collect())
+}
+
+/// Load the model policies file and hot-reload it when it changes.
+/// The previous policies stay in use if the reload fails.
+pub(super) fn init(path: PathBuf) -> anyhow::Result<Hotwatch> {
+    let policies = load(&path)?;
+    info!("Loaded {} model policies", policies.len());
+    *POLICIES.write().expect("model policies lock poisoned") = policies;
+
+    let mut hotwatch = Hotwatch::new()?;
+    info!("Start watching model policies file: {}", path.display());
+    hotwatch.watch(&path.clone(), move |event: Event| {
+        if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+            return;
+        }
+        match load(&path) {
+            Ok(policies) => {
+                info!("Reloaded {} model policies", policies.len());
+                if let Ok(mut lock) = POLICIES.write() {
+                    *lock = policies;
+                }
+                lifecycle::notify(lifecycle::Kind::Reload, Some("model_policies"));
+            }
+            Err(err) => error!("Failed to reload model policies: {err}"),
+        }
+    })?;
+    Ok(hotwatch)
+}
+
+fn policy_of(req: &RequestExt) -> Option<(Key, Arc<ModelPolicy>)> {
+    let policies = POLICIES.read().ok()?;
+    if policies.is_empty() {
+        return None;
+    }
+    let key = Key::of(req)?;
+    let policy = policies.iter().find(|p| p.matches(&key)).cloned()?;
+    Some((key, policy))
+}
+
+/// Policy of a key given by its email or `sk-`/`sess-` key
+pub(super) fn find(key: &Key) -> Option<Arc<ModelPolicy>> {
+    let policies = POLICIES.read().ok()?;
+    policies.iter().find(|p| p.matches(key)).cloned()
+}
+
+/// Policy of a key given by its identifier, the email or the `key:` digest
+pub(super) fn find_by_id(id: &str) -> Option<Arc<ModelPolicy>> {
+    let policies = POLICIES.read().ok()?;
+    policies.iter().find(|p| p.matches_id(id)).cloned()
+}
+
+/// Model named by the request body
+pub(super) fn requested_model(req: &RequestExt) -> Option<String> {
+    #[derive(Deserialize)]
+    struct Model {
+        model: Option<String>,
+    }
+
+    req.body
+        .as_ref()
+        .and_then(|body| serde_json::from_slice::<Model>(body).ok())
+        .and_then(|body| body.model)
+}
+
+/// Check the requested model against the key's policy before the request is sent
+/// upstream. Returns the key's policy, or the 403 response of a disallowed model.
+pub(super) fn check(req: &RequestExt) -> Result<Option<KeyPolicy>, Response> {
+    let (key, policy) = match policy_of(req) {
+        Some(policy) => policy,
+        None => return Ok(None),
+    };
+
+    if let Some(model) = requested_model(req) {
+        if !policy.allows(&model) {
+            debug!(
+                "Model `{model}` is not allowed by model policy `{}`",
+                policy.name
+            );
+            return Err(model_not_allowed_response(&model));
+        }
+    }
+
+    Ok(Some(KeyPolicy {
+        key,
+        policy,
+        listing: ListingKind::of(req),
+    }))
+}
+
+/// Remove the models the key may not use from a model listing response
+pub(super) async fn filter_listing(
+    resp: Response,
+    key_policy: Option<&KeyPolicy>,
+) -> Result<Response, ResponseError> {
+    let (policy, kind) = match key_policy {
+        Some(KeyPolicy {
+            policy,
+            listing: Some(kind),
+            ..
+        }) if resp.status().is_success() => (policy, *kind),
+        _ => return Ok(resp),
+    };
+
+    let (mut parts, body) = resp.into_parts();
+    let (data, reservation) = memory::read(body).await?;
+
+    let data = match serde_json::from_slice::<serde_json::Value>(&data) {
+        Ok(mut value) => {
+            filter_models(&mut value, policy, kind);
+            serde_json::to_vec(&value).map_err(ResponseError::InternalServerError)?
+        }
+        Err(_) => data,
+    };
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Ok(Response::from_parts(parts, memory::body(data, reservation)))
+}
+
+fn filter_models(value: &mut serde_json::Value, policy: &ModelPolicy, kind: ListingKind) {
+    let (array, id) = kind.fields();
+    if let Some(models) = value.get_mut(array).and_then(|v| v.as_array_mut()) {
+        models.retain(|model| {
+            model
+                .get(id)
+                .and_then(|v| v.as_str())
+                .map_or(false, |name| policy.allows(name))
+        });
+    }
+}
+
+/// 403 response for a model the key may not use
+fn model_not_allowed_response(model: &str) -> Response {
+    let body = serde_json::json!({
+        "error": {
+            "message": format!("The model `{model}` is not allowed for this key"),
+            "type": "invalid_request_error",
+            "param": "model",
+            "code": "model_not_allowed"
+        }
+    });
+    (StatusCode::FORBIDDEN, Json(body)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allowed_models: &[&str]) -> ModelPolicy {
+        ModelPolicy {
+            name: "test".to_owned(),
+            emails: vec!["user@example.com".to_owned()],
+            keys: vec!["sk-test".to_owned()],
+            allowed_models: Some(allowed_models.iter().map(|m| m.to_string()).collect()),
+            budget_daily: None,
+            budget_monthly: None,
+            priority: None,
+        }
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("gpt-4o-mini*", "gpt-4o-mini"));
+        assert!(glob_match("gpt-4o-mini*", "gpt-4o-mini-2024-07-18"));
+        assert!(!glob_match("gpt-4o-mini*", "gpt-4o"));
+        assert!(glob_match("gpt-3.5-turbo", "gpt-3.5-turbo"));
+        assert!(!glob_match("gpt-3.5-turbo", "gpt-3.5-turbo-16k"));
+        assert!(glob_match("gpt-?o", "gpt-4o"));
+        assert!(glob_match("*-mini", "o1-mini"));
+        assert!(glob_match("gpt-*-turbo*", "gpt-4-turbo-preview"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("gpt-4*", "text-gpt-4"));
+    }
+
+    #[test]
+    fn test_policy_matches_key() {
+        let policy = policy(&["gpt-4o-mini*"]);
+        assert!(policy.matches(&Key::Email("USER@example.com".to_owned())));
+        assert!(policy.matches(&Key::Secret("sk-test".to_owned())));
+        assert!(!policy.matches(&Key::Secret("sk-other".to_owned())));
+        assert!(policy.allows("gpt-4o-mini"));
+        assert!(!policy.allows("gpt-4o"));
+
+        // Budget-only policies do not restrict the models
+        let unrestricted = ModelPolicy {
+            allowed_models: None,
+            ..self::policy(&[])
+        };
+        assert!(unrestricted.allows("gpt-4o"));
+    }
+
+    #[test]
+    fn test_key_id() {
+        assert_eq!(
+            Key::parse("User@Example.com").id(),
+            Key::parse("user@example.com").id()
+        );
+        let id = Key::parse("sk-test").id();
+        assert!(id.starts_with("key:"), "{id}");
+        assert!(!id.contains("sk-test"));
+
+        // The policy of a key is found by its identifier
+        let policy = policy(&[]);
+        assert!(policy.matches_id(&id));
+        assert!(policy.matches_id("user@example.com"));
+        assert!(!policy.matches_id(&Key::parse("sk-other").id()));
+        assert!(!policy.matches_id("sk-test"));
+    }
+
+    #[test]
+    fn test_filter_platform_models() {
+        let mut value = serde_json::json!({
+            "object": "list",
+            "data": [
+                {"id": "gpt-4o", "object": "model"},
+                {"id": "gpt-4o-mini", "object": "model"},
+                {"id": "gpt-4o-mini-2024-07-18", "object": "model"},
+            ]
+        });
+        filter_models(
+            &mut value,
+            &policy(&["gpt-4o-mini*"]),
+            ListingKind::Platform,
+        );
+        let ids = value["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["id"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["gpt-4o-mini", "gpt-4o-mini-2024-07-18"]);
+        assert_eq!(value["object"], "list");
+    }
+
+    #[test]
+    fn test_filter_chatgpt_models() {
+        let mut value = serde_json::json!({
+            "models": [
+                {"slug": "text-davinci-002-render-sha"},
+                {"slug": "gpt-4"},
+            ],
+            "categories": []
+        });
+        filter_models(
+            &mut value,
+            &policy(&["text-davinci-*"]),
+            ListingKind::ChatGPT,
+        );
+        assert_eq!(value["models"].as_array().unwrap().len(), 1);
+        assert_eq!(value["models"][0]["slug"], "text-davinci-002-render-sha");
+    }
+
+    #[test]
+    fn test_model_not_allowed_response() {
+        let resp = model_not_allowed_response("gpt-4o");
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+}
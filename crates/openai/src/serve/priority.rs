@@ -0,0 +1,548 @@
+use crate::context::args::{PriorityClass, PriorityRule};
+use crate::serve::error::{ProxyError, ResponseError};
+use crate::serve::model_policy::{self, glob_match, Key};
+use crate::serve::proxy::tag::{bearer, Source};
+use crate::serve::timing;
+use crate::{debug, info};
+use axum::extract::ConnectInfo;
+use axum::http::{HeaderName, Request};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+/// Requests admitted by the concurrent limit, by class (high/normal/low)
+pub(super) static ADMITTED: [AtomicU64; 3] = [ZERO; 3];
+/// Requests shed from the concurrent limit queue, by class (high/normal/low)
+pub(super) static SHED: [AtomicU64; 3] = [ZERO; 3];
+
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+static PRIORITY: OnceLock<Priority> = OnceLock::new();
+
+struct Priority {
+    limiter: Arc<Limiter>,
+    header: Option<HeaderName>,
+    trusted_peers: Vec<cidr::IpCidr>,
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    routes: Vec<String>,
+    source: Option<Source>,
+    pattern: String,
+    class: PriorityClass,
+}
+
+pub(super) fn init(
+    concurrent_limit: usize,
+    header: Option<&str>,
+    trusted_peers: &[cidr::IpCidr],
+    aging: u64,
+    max_queue: usize,
+    rules: &[PriorityRule],
+) -> anyhow::Result<()> {
+    let header = header
+        .map(HeaderName::from_str)
+        .transpose()
+        .map_err(|_| anyhow::anyhow!("Invalid priority header name"))?;
+    if let Some(header) = header.as_ref() {
+        info!("Priority header: {header}");
+    }
+    if max_queue > 0 {
+        info!("Priority queue limit: {max_queue}");
+    }
+    if !rules.is_empty() {
+        info!("Priority rules: {}", rules.len());
+    }
+    let rules = rules
+        .iter()
+        .map(|rule| {
+            rule.validate()?;
+            Ok(Rule {
+                routes: rule.routes.clone(),
+                source: rule.source.as_deref().map(Source::parse).transpose()?,
+                pattern: rule.pattern.clone(),
+                class: rule.priority,
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let _ = PRIORITY.set(Priority {
+        limiter: Arc::new(Limiter::new(
+            concurrent_limit,
+            Duration::from_secs(aging),
+            max_queue,
+        )),
+        header,
+        trusted_peers: trusted_peers.to_vec(),
+        rules,
+    });
+    Ok(())
+}
+
+/// Index of a class in the metrics counters
+pub(super) fn index(class: PriorityClass) -> usize {
+    match class {
+        PriorityClass::High => 0,
+        PriorityClass::Normal => 1,
+        PriorityClass::Low => 2,
+    }
+}
+
+/// Requests waiting for a concurrent limit slot
+pub(super) fn queued() -> usize {
+    PRIORITY.get().map_or(0, |p| p.limiter.queued())
+}
+
+/// Concurrent limit, a request waits for a slot in its priority class
+pub(super) async fn limit<B>(request: Request<B>, next: Next<B>) -> Response {
+    let priority = match PRIORITY.get() {
+        Some(priority) => priority,
+        None => return next.run(request).await,
+    };
+
+    let class = priority.class_of(&request);
+    let start = Instant::now();
+    let permit = priority.limiter.clone().acquire(class).await;
+    timing::record_queue(start.elapsed());
+    match permit {
+        Ok(_permit) => next.run(request).await,
+        Err(()) => {
+            debug!("Request of class {} shed from the queue", class.as_str());
+            ResponseError::ServiceUnavailable(ProxyError::RequestShed).into_response()
+        }
+    }
+}
+
+impl Priority {
+    /// Class of the trusted priority header, then of the first matching priority rule,
+    /// then of the key group, `normal` otherwise. The headers of the untrusted peers are
+    /// ignored, by the priority header and the `header:<name>` rules alike.
+    fn class_of<B>(&self, request: &Request<B>) -> PriorityClass {
+        let trusted = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map_or(false, |ConnectInfo(addr)| self.trusts(addr.ip()));
+        if let Some(header) = self.header.as_ref() {
+            if trusted {
+                if let Some(class) = request
+                    .headers()
+                    .get(header)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| PriorityClass::from_str(v.trim()).ok())
+                {
+                    return class;
+                }
+            }
+        }
+
+        // The key is only decoded once, if needed
+        let headers = request.headers();
+        let path = request.uri().path();
+        let mut key = None;
+        for rule in &self.rules {
+            if !rule.routes.is_empty() && !rule.routes.iter().any(|r| path.starts_with(r.as_str()))
+            {
+                continue;
+            }
+            let value = match rule.source.as_ref() {
+                Some(Source::Header(_)) if !trusted => continue,
+                Some(source) => {
+                    if source.needs_key() && key.is_none() {
+                        key = Some(bearer(headers).and_then(Key::of_bearer));
+                    }
+                    match source.value(headers, key.as_ref().and_then(Option::as_ref)) {
+                        Some(value) => value,
+                        None => continue,
+                    }
+                }
+                None => String::new(),
+            };
+            if glob_match(&rule.pattern, &value) {
+                return rule.class;
+            }
+        }
+
+        key.unwrap_or_else(|| bearer(headers).and_then(Key::of_bearer))
+            .and_then(|key| model_policy::find(&key))
+            .and_then(|policy| policy.priority)
+            .unwrap_or_default()
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        if self.trusted_peers.is_empty() {
+            return ip.is_loopback();
+        }
+        self.trusted_peers.iter().any(|cidr| cidr.contains(&ip))
+    }
+}
+
+/// Concurrency limiter with a priority-aware wait queue.
+///
+/// A released slot goes to the waiter of the highest class, the longest waiting
+/// first within a class. A waiter rises one class each `aging`, so low priority
+/// requests are admitted under a steady high priority load.
+struct Limiter {
+    aging: Duration,
+    max_queue: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    available: usize,
+    next_id: u64,
+    waiters: Vec<Waiter>,
+}
+
+struct Waiter {
+    id: u64,
+    class: PriorityClass,
+    since: Instant,
+    /// `true` when admitted, `false` when shed
+    tx: oneshot::Sender<bool>,
+}
+
+/// Slot of an admitted request, handed to the next waiter when dropped
+struct Permit {
+    limiter: Arc<Limiter>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limiter.release();
+    }
+}
+
+/// Queued request, leaves the queue (or gives back its slot) when cancelled
+struct Pending {
+    limiter: Arc<Limiter>,
+    id: u64,
+    rx: oneshot::Receiver<bool>,
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        let mut state = self.limiter.lock();
+        if let Some(i) = state.waiters.iter().position(|w| w.id == self.id) {
+            state.waiters.remove(i);
+            return;
+        }
+        drop(state);
+        // Admitted after the request was cancelled
+        if let Ok(true) = self.rx.try_recv() {
+            self.limiter.release();
+        }
+    }
+}
+
+impl Limiter {
+    fn new(limit: usize, aging: Duration, max_queue: usize) -> Self {
+        Self {
+            aging,
+            max_queue,
+            state: Mutex::new(State {
+                available: limit,
+                next_id: 0,
+                waiters: Vec::new(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn queued(&self) -> usize {
+        self.lock().waiters.len()
+    }
+
+    /// Class a waiter competes with, raised one class per `aging` waited
+    fn rank(&self, waiter: &Waiter, now: Instant) -> usize {
+        let rank = 2 - index(waiter.class);
+        if self.aging.is_zero() {
+            return rank;
+        }
+        let aged = (now.duration_since(waiter.since).as_nanos() / self.aging.as_nanos()) as usize;
+        (rank + aged).min(2)
+    }
+
+    /// Wait for a slot, `Err` when the request is shed from the queue
+    async fn acquire(self: Arc<Self>, class: PriorityClass) -> Result<Permit, ()> {
+        let (id, rx) = {
+            let mut state = self.lock();
+            if state.available > 0 && state.waiters.is_empty() {
+                state.available -= 1;
+                drop(state);
+                ADMITTED[index(class)].fetch_add(1, Ordering::Relaxed);
+                return Ok(Permit { limiter: self });
+            }
+
+            if self.max_queue > 0 {
+                let queued = state.waiters.len();
+                // Low priority requests are shed from half of the queue
+                if class == PriorityClass::Low && queued >= self.max_queue / 2 {
+                    SHED[index(class)].fetch_add(1, Ordering::Relaxed);
+                    return Err(());
+                }
+                if queued >= self.max_queue && !self.preempt(&mut state, class) {
+                    SHED[index(class)].fetch_add(1, Ordering::Relaxed);
+                    return Err(());
+                }
+            }
+
+            let (tx, rx) = oneshot::channel();
+            let id = state.next_id;
+            state.next_id += 1;
+            state.waiters.push(Waiter {
+                id,
+                class,
+                since: Instant::now(),
+                tx,
+            });
+            (id, rx)
+        };
+
+        let mut pending = Pending {
+            limiter: self,
+            id,
+            rx,
+        };
+        let admitted = (&mut pending.rx).await.unwrap_or(false);
+        if !admitted {
+            return Err(());
+        }
+        ADMITTED[index(class)].fetch_add(1, Ordering::Relaxed);
+        // The slot now belongs to the permit
+        pending.rx = oneshot::channel().1;
+        Ok(Permit {
+            limiter: pending.limiter.clone(),
+        })
+    }
+
+    /// Shed the most recent waiter of the lowest class below `class`
+    fn preempt(&self, state: &mut State, class: PriorityClass) -> bool {
+        let now = Instant::now();
+        let rank = 2 - index(class);
+        let victim = state
+            .waiters
+            .iter()
+            .enumerate()
+            .filter(|(_, w)| self.rank(w, now) < rank)
+            .min_by_key(|(_, w)| (self.rank(w, now), std::cmp::Reverse(w.since)))
+            .map(|(i, _)| i);
+        match victim {
+            Some(i) => {
+                let waiter = state.waiters.remove(i);
+                SHED[index(waiter.class)].fetch_add(1, Ordering::Relaxed);
+                let _ = waiter.tx.send(false);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Hand the slot to the best waiter, or return it to the pool
+    fn release(&self) {
+        let mut state = self.lock();
+        let now = Instant::now();
+        while let Some(i) = state
+            .waiters
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, w)| (self.rank(w, now), std::cmp::Reverse(w.since)))
+            .map(|(i, _)| i)
+        {
+            let waiter = state.waiters.remove(i);
+            if waiter.tx.send(true).is_ok() {
+                return;
+            }
+        }
+        state.available += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Queue `n` waiters of `class`, each holding its slot until dropped
+    fn spawn_waiters(
+        limiter: &Arc<Limiter>,
+        class: PriorityClass,
+        n: usize,
+    ) -> Vec<tokio::task::JoinHandle<Result<Permit, ()>>> {
+        (0..n)
+            .map(|_| tokio::spawn(limiter.clone().acquire(class)))
+            .collect()
+    }
+
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    fn rule(routes: &[&str], source: Option<&str>, pattern: &str, class: PriorityClass) -> Rule {
+        Rule {
+            routes: routes.iter().map(|route| route.to_string()).collect(),
+            source: source.map(|source| Source::parse(source).unwrap()),
+            pattern: pattern.to_owned(),
+            class,
+        }
+    }
+
+    fn request(path: &str, tenant: Option<&str>, peer: &str) -> Request<()> {
+        let mut request = Request::builder()
+            .uri(path)
+            .extension(ConnectInfo(SocketAddr::new(peer.parse().unwrap(), 40000)));
+        if let Some(tenant) = tenant {
+            request = request.header("x-tenant", tenant);
+        }
+        request.body(()).unwrap()
+    }
+
+    #[test]
+    fn test_priority_rules() {
+        let priority = Priority {
+            limiter: Arc::new(Limiter::new(1, Duration::ZERO, 0)),
+            header: None,
+            trusted_peers: Vec::new(),
+            rules: vec![
+                rule(
+                    &["/v1/chat/completions"],
+                    Some("header:x-tenant"),
+                    "gold-*",
+                    PriorityClass::High,
+                ),
+                rule(&["/v1/files", "/v1/batches"], None, "*", PriorityClass::Low),
+            ],
+        };
+
+        let class = |path, tenant| priority.class_of(&request(path, tenant, "127.0.0.1"));
+        assert_eq!(
+            class("/v1/chat/completions", Some("gold-eu")),
+            PriorityClass::High
+        );
+        // The identity does not match
+        assert_eq!(
+            class("/v1/chat/completions", Some("free")),
+            PriorityClass::Normal
+        );
+        assert_eq!(class("/v1/chat/completions", None), PriorityClass::Normal);
+        // The route does not match
+        assert_eq!(class("/v1/models", Some("gold-eu")), PriorityClass::Normal);
+        assert_eq!(
+            class("/v1/batches/b-1", Some("gold-eu")),
+            PriorityClass::Low
+        );
+        // The header of an untrusted peer is ignored
+        let untrusted = request("/v1/chat/completions", Some("gold-eu"), "203.0.113.7");
+        assert_eq!(priority.class_of(&untrusted), PriorityClass::Normal);
+        assert_eq!(
+            priority.class_of(&request("/v1/files", None, "203.0.113.7")),
+            PriorityClass::Low
+        );
+    }
+
+    #[tokio::test]
+    async fn test_high_priority_admitted_first() {
+        let limiter = Arc::new(Limiter::new(2, Duration::ZERO, 0));
+        let held = [
+            limiter.clone().acquire(PriorityClass::Low).await.unwrap(),
+            limiter.clone().acquire(PriorityClass::Low).await.unwrap(),
+        ];
+        let _low = spawn_waiters(&limiter, PriorityClass::Low, 32);
+        settle().await;
+
+        let high = tokio::spawn(limiter.clone().acquire(PriorityClass::High));
+        settle().await;
+        assert_eq!(limiter.queued(), 33);
+
+        let [first, _second] = held;
+        drop(first);
+        let permit = tokio::time::timeout(Duration::from_millis(500), high)
+            .await
+            .expect("high priority request was not admitted promptly")
+            .unwrap();
+        assert!(permit.is_ok());
+        assert_eq!(limiter.queued(), 32);
+    }
+
+    #[tokio::test]
+    async fn test_aging_admits_low_priority() {
+        let limiter = Arc::new(Limiter::new(1, Duration::from_millis(50), 0));
+        let held = limiter.clone().acquire(PriorityClass::High).await.unwrap();
+
+        let low = tokio::spawn(limiter.clone().acquire(PriorityClass::Low));
+        // Two classes of aging, the low waiter now competes as high
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        let normal = tokio::spawn(limiter.clone().acquire(PriorityClass::Normal));
+        settle().await;
+
+        drop(held);
+        let permit = tokio::time::timeout(Duration::from_millis(500), low)
+            .await
+            .expect("aged low priority request was not admitted")
+            .unwrap();
+        assert!(permit.is_ok());
+        assert!(!normal.is_finished());
+    }
+
+    #[tokio::test]
+    async fn test_queue_sheds_low_priority() {
+        let limiter = Arc::new(Limiter::new(1, Duration::ZERO, 4));
+        let _held = limiter
+            .clone()
+            .acquire(PriorityClass::Normal)
+            .await
+            .unwrap();
+
+        let low = spawn_waiters(&limiter, PriorityClass::Low, 2);
+        settle().await;
+        // Half of the queue is taken, further low priority requests are shed
+        assert!(limiter.clone().acquire(PriorityClass::Low).await.is_err());
+
+        let _normal = spawn_waiters(&limiter, PriorityClass::Normal, 2);
+        settle().await;
+        assert_eq!(limiter.queued(), 4);
+
+        // The queue is full, a high priority request preempts the latest low waiter
+        let _high = tokio::spawn(limiter.clone().acquire(PriorityClass::High));
+        settle().await;
+        assert_eq!(limiter.queued(), 4);
+        let mut shed = 0;
+        for waiter in low {
+            if waiter.is_finished() {
+                assert!(waiter.await.unwrap().is_err());
+                shed += 1;
+            }
+        }
+        assert_eq!(shed, 1);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_waiter_leaves_queue() {
+        let limiter = Arc::new(Limiter::new(1, Duration::ZERO, 0));
+        let held = limiter
+            .clone()
+            .acquire(PriorityClass::Normal)
+            .await
+            .unwrap();
+
+        let waiter = tokio::spawn(limiter.clone().acquire(PriorityClass::Normal));
+        settle().await;
+        waiter.abort();
+        settle().await;
+        assert_eq!(limiter.queued(), 0);
+
+        drop(held);
+        assert!(tokio::time::timeout(
+            Duration::from_millis(100),
+            limiter.clone().acquire(PriorityClass::Normal)
+        )
+        .await
+        .is_ok());
+    }
+}
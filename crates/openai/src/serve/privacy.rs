@@ -0,0 +1,125 @@
+use crate::info;
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue};
+use std::sync::OnceLock;
+
+/// Client address headers, never sent upstream in privacy mode
+const CLIENT_ADDRESS_HEADERS: [&str; 8] = [
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-real-ip",
+    "true-client-ip",
+    "cf-connecting-ip",
+    "x-client-ip",
+    "x-cluster-client-ip",
+];
+
+/// Outbound `Accept-Language` in privacy mode
+const ACCEPT_LANGUAGE: &str = "en-US,en;q=0.9";
+
+/// Privacy mode, `None` when disabled
+static PRIVACY: OnceLock<Option<Privacy>> = OnceLock::new();
+
+struct Privacy {
+    strip: Vec<HeaderName>,
+}
+
+impl Privacy {
+    fn new(strip_headers: &[String]) -> anyhow::Result<Self> {
+        let strip = CLIENT_ADDRESS_HEADERS
+            .into_iter()
+            .map(HeaderName::from_static)
+            .chain(
+                strip_headers
+                    .iter()
+                    .map(|name| HeaderName::try_from(name.as_str()))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|err| anyhow::anyhow!("privacy strip header: {err}"))?,
+            )
+            .collect();
+        Ok(Self { strip })
+    }
+
+    /// Remove the client identifiers of the outbound headers. The client `User-Agent`
+    /// is removed so the upstream client's own applies.
+    fn scrub(&self, headers: &mut HeaderMap) {
+        for name in &self.strip {
+            headers.remove(name);
+        }
+        headers.remove(header::USER_AGENT);
+        if headers.contains_key(header::ACCEPT_LANGUAGE) {
+            headers.insert(
+                header::ACCEPT_LANGUAGE,
+                HeaderValue::from_static(ACCEPT_LANGUAGE),
+            );
+        }
+    }
+}
+
+pub(super) fn init(enabled: bool, strip_headers: &[String]) -> anyhow::Result<()> {
+    let privacy = if enabled {
+        info!("Privacy mode: client identifiers are not sent upstream");
+        Some(Privacy::new(strip_headers)?)
+    } else {
+        None
+    };
+    let _ = PRIVACY.set(privacy);
+    Ok(())
+}
+
+/// Whether client identifiers are withheld from the upstream
+pub(super) fn enabled() -> bool {
+    matches!(PRIVACY.get(), Some(Some(_)))
+}
+
+/// Scrub the headers of an upstream request in privacy mode
+pub(super) fn scrub(headers: &mut HeaderMap) {
+    if let Some(Some(privacy)) = PRIVACY.get() {
+        privacy.scrub(headers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("x-forwarded-for"),
+            HeaderValue::from_static("203.0.113.77"),
+        );
+        headers.insert(
+            header::USER_AGENT,
+            HeaderValue::from_static("sentinel-agent/1.0"),
+        );
+        headers.insert(
+            HeaderName::from_static("x-client-id"),
+            HeaderValue::from_static("sentinel-client-id"),
+        );
+        headers.insert(
+            header::ACCEPT_LANGUAGE,
+            HeaderValue::from_static("x-sentinel-lang"),
+        );
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer sk-test"),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_scrub_keeps_credentials() {
+        let privacy = Privacy::new(&[]).unwrap();
+        let mut headers = client_headers();
+        privacy.scrub(&mut headers);
+        assert!(headers.get("x-forwarded-for").is_none());
+        assert!(headers.get(header::USER_AGENT).is_none());
+        assert_eq!(headers[header::ACCEPT_LANGUAGE], ACCEPT_LANGUAGE);
+        assert_eq!(headers[header::AUTHORIZATION], "Bearer sk-test");
+        // Not configured to be stripped
+        assert_eq!(headers["x-client-id"], "sentinel-client-id");
+
+        assert!(Privacy::new(&["bad header".to_owned()]).is_err());
+    }
+}
@@ -0,0 +1,536 @@
+//! Streaming adaptation of the chat completions (`stream_adapt`). The upstream is
+//! requested in the configured streaming mode and the response is converted to the
+//! mode of the client: an event stream is aggregated into a `chat.completion` object,
+//! a completion object is replayed as `chat.completion.chunk` events.
+
+use super::ext::RequestExt;
+use crate::context::args::StreamAdapt;
+use crate::serve::error::ResponseError;
+use crate::serve::memory;
+use crate::{info, warn};
+use axum::body::Bytes;
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use axum::response::Response;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+const CHAT_COMPLETIONS: &str = "/v1/chat/completions";
+
+/// End of an OpenAI event stream
+const DONE: &str = "[DONE]";
+
+static MODE: OnceLock<StreamAdapt> = OnceLock::new();
+
+pub(crate) fn init(mode: StreamAdapt) {
+    if mode != StreamAdapt::Off {
+        info!("Stream adapt: upstream {}", mode.to_string());
+    }
+    let _ = MODE.set(mode);
+}
+
+/// Streaming mode of the client, when the upstream is requested in the other mode
+#[derive(Debug, PartialEq)]
+pub(crate) struct Adapt {
+    /// The client requested an event stream
+    stream: bool,
+    /// The client requested the usage chunk (`stream_options.include_usage`)
+    include_usage: bool,
+}
+
+/// Switch a chat completion request to the upstream streaming mode
+pub(crate) fn request(req: &mut RequestExt) -> Option<Adapt> {
+    let upstream_stream = match MODE.get()? {
+        StreamAdapt::Off => return None,
+        StreamAdapt::Stream => true,
+        StreamAdapt::Json => false,
+    };
+    if req.method != Method::POST || !req.uri.path().ends_with(CHAT_COMPLETIONS) {
+        return None;
+    }
+
+    let body = req
+        .body
+        .as_ref()
+        .and_then(|body| serde_json::from_slice::<Map<String, Value>>(body).ok())?;
+    let (adapt, body) = adapt_body(body, upstream_stream)?;
+    req.body = Some(Bytes::from(serde_json::to_vec(&body).ok()?));
+    req.headers.remove(header::CONTENT_LENGTH);
+    Some(adapt)
+}
+
+fn adapt_body(
+    mut body: Map<String, Value>,
+    upstream_stream: bool,
+) -> Option<(Adapt, Map<String, Value>)> {
+    let stream = body.get("stream").and_then(Value::as_bool).unwrap_or(false);
+    if stream == upstream_stream {
+        return None;
+    }
+    let include_usage = body
+        .get("stream_options")
+        .and_then(|options| options.get("include_usage"))
+        .and_then(Value::as_bool)
+        .unwrap_or(false);
+
+    body.insert("stream".to_owned(), Value::Bool(upstream_stream));
+    if upstream_stream {
+        // Usage of the aggregated completion
+        body.insert(
+            "stream_options".to_owned(),
+            json!({ "include_usage": true }),
+        );
+    } else {
+        // Rejected by the upstream on a non-streaming request
+        body.remove("stream_options");
+    }
+    Some((
+        Adapt {
+            stream,
+            include_usage,
+        },
+        body,
+    ))
+}
+
+/// Convert the upstream response to the streaming mode of the client. The whole
+/// upstream body is buffered before the converted response is sent.
+pub(crate) async fn response(
+    resp: Response,
+    adapt: Option<Adapt>,
+) -> Result<Response, ResponseError> {
+    let adapt = match adapt {
+        Some(adapt) if resp.status().is_success() => adapt,
+        _ => return Ok(resp),
+    };
+    let event_stream = is_content_type(resp.headers(), mime::TEXT_EVENT_STREAM.as_ref());
+    // The upstream answered in the mode of the client anyway
+    if adapt.stream == event_stream {
+        return Ok(resp);
+    }
+    if !event_stream && !is_content_type(resp.headers(), mime::APPLICATION_JSON.as_ref()) {
+        return Ok(resp);
+    }
+    if is_encoded(resp.headers()) {
+        warn!("Stream adapt skipped, the upstream body is encoded");
+        return Ok(resp);
+    }
+
+    let (mut parts, body) = resp.into_parts();
+    let (data, mut reservation) = memory::read(body).await?;
+
+    let (content_type, data) = if event_stream {
+        match aggregate(&data) {
+            Ok(completion) => (mime::APPLICATION_JSON, serde_json::to_vec(&completion)?),
+            // The stream failed after the response headers were sent
+            Err(error) => {
+                parts.status = StatusCode::BAD_GATEWAY;
+                (mime::APPLICATION_JSON, serde_json::to_vec(&error)?)
+            }
+        }
+    } else {
+        let completion =
+            serde_json::from_slice::<Value>(&data).map_err(ResponseError::BadGateway)?;
+        (
+            mime::TEXT_EVENT_STREAM,
+            replay(&completion, adapt.include_usage),
+        )
+    };
+
+    if let Ok(value) = HeaderValue::from_str(content_type.as_ref()) {
+        parts.headers.insert(header::CONTENT_TYPE, value);
+    }
+    parts.headers.remove(header::CONTENT_LENGTH);
+    if !reservation.fit(data.len()) {
+        return Err(memory::exceeded());
+    }
+    Ok(Response::from_parts(parts, memory::body(data, reservation)))
+}
+
+fn is_content_type(headers: &HeaderMap, content_type: &str) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.starts_with(content_type))
+}
+
+fn is_encoded(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| !v.eq_ignore_ascii_case("identity"))
+}
+
+/// Data of the events of an event stream, the `data` lines of an event joined
+fn events(data: &[u8]) -> Vec<String> {
+    let text = String::from_utf8_lossy(data);
+    let mut events = Vec::new();
+    let mut event: Option<String> = None;
+    for line in text.lines() {
+        if line.is_empty() {
+            events.extend(event.take());
+            continue;
+        }
+        // Comments and the `event`, `id` and `retry` fields carry no completion data
+        if let Some(value) = line.strip_prefix("data:") {
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match &mut event {
+                Some(event) => {
+                    event.push('\n');
+                    event.push_str(value);
+                }
+                None => event = Some(value.to_owned()),
+            }
+        }
+    }
+    events.extend(event);
+    events
+}
+
+/// Choice reassembled from the deltas
+#[derive(Default)]
+struct Choice {
+    role: Option<Value>,
+    content: Option<String>,
+    refusal: Option<String>,
+    tool_calls: BTreeMap<u64, ToolCall>,
+    logprobs: Vec<Value>,
+    finish_reason: Value,
+}
+
+#[derive(Default)]
+struct ToolCall {
+    id: Value,
+    kind: Value,
+    name: String,
+    arguments: String,
+}
+
+impl Choice {
+    fn merge(&mut self, choice: &Value) {
+        let delta = &choice["delta"];
+        if let Some(role) = delta.get("role").filter(|role| !role.is_null()) {
+            self.role = Some(role.clone());
+        }
+        if let Some(content) = delta["content"].as_str() {
+            self.content
+                .get_or_insert_with(String::new)
+                .push_str(content);
+        }
+        if let Some(refusal) = delta["refusal"].as_str() {
+            self.refusal
+                .get_or_insert_with(String::new)
+                .push_str(refusal);
+        }
+        for call in delta["tool_calls"].as_array().into_iter().flatten() {
+            let tool_call = self
+                .tool_calls
+                .entry(call["index"].as_u64().unwrap_or(0))
+                .or_default();
+            if !call["id"].is_null() {
+                tool_call.id = call["id"].clone();
+            }
+            if !call["type"].is_null() {
+                tool_call.kind = call["type"].clone();
+            }
+            if let Some(name) = call["function"]["name"].as_str() {
+                tool_call.name.push_str(name);
+            }
+            if let Some(arguments) = call["function"]["arguments"].as_str() {
+                tool_call.arguments.push_str(arguments);
+            }
+        }
+        if let Some(content) = choice["logprobs"]["content"].as_array() {
+            self.logprobs.extend(content.iter().cloned());
+        }
+        if !choice["finish_reason"].is_null() {
+            self.finish_reason = choice["finish_reason"].clone();
+        }
+    }
+
+    fn into_value(self, index: u64) -> Value {
+        let mut message = json!({
+            "role": self.role.unwrap_or_else(|| json!("assistant")),
+            "content": self.content,
+        });
+        if let Some(refusal) = self.refusal {
+            message["refusal"] = json!(refusal);
+        }
+        if !self.tool_calls.is_empty() {
+            message["tool_calls"] = self
+                .tool_calls
+                .into_values()
+                .map(|call| {
+                    let kind = match call.kind {
+                        Value::Null => json!("function"),
+                        kind => kind,
+                    };
+                    json!({
+                        "id": call.id,
+                        "type": kind,
+                        "function": { "name": call.name, "arguments": call.arguments },
+                    })
+                })
+                .collect();
+        }
+        let logprobs = match self.logprobs.is_empty() {
+            true => Value::Null,
+            false => json!({ "content": self.logprobs }),
+        };
+        json!({
+            "index": index,
+            "message": message,
+            "logprobs": logprobs,
+            "finish_reason": self.finish_reason,
+        })
+    }
+}
+
+/// Reassemble the completion of a `chat.completion.chunk` event stream, the error
+/// object when the stream carries one
+fn aggregate(data: &[u8]) -> Result<Value, Value> {
+    let mut completion = Map::new();
+    let mut choices = BTreeMap::<u64, Choice>::new();
+    let mut usage = Value::Null;
+
+    for event in events(data) {
+        if event == DONE {
+            break;
+        }
+        let chunk = match serde_json::from_str::<Value>(&event) {
+            Ok(chunk) => chunk,
+            Err(_) => continue,
+        };
+        if chunk.get("error").is_some() {
+            return Err(chunk);
+        }
+        for key in [
+            "id",
+            "created",
+            "model",
+            "system_fingerprint",
+            "service_tier",
+        ] {
+            if let Some(value) = chunk.get(key).filter(|value| !value.is_null()) {
+                completion
+                    .entry(key.to_owned())
+                    .or_insert_with(|| value.clone());
+            }
+        }
+        if let Some(value) = chunk.get("usage").filter(|value| !value.is_null()) {
+            usage = value.clone();
+        }
+        for choice in chunk["choices"].as_array().into_iter().flatten() {
+            choices
+                .entry(choice["index"].as_u64().unwrap_or(0))
+                .or_default()
+                .merge(choice);
+        }
+    }
+
+    completion.insert("object".to_owned(), json!("chat.completion"));
+    completion.insert(
+        "choices".to_owned(),
+        choices
+            .into_iter()
+            .map(|(index, choice)| choice.into_value(index))
+            .collect(),
+    );
+    completion.insert("usage".to_owned(), usage);
+    Ok(Value::Object(completion))
+}
+
+/// Replay a `chat.completion` object as an event stream: a delta and a finish chunk
+/// per choice, the usage chunk when requested, then `[DONE]`
+fn replay(completion: &Value, include_usage: bool) -> Vec<u8> {
+    let chunk = |choices: Value| {
+        let mut chunk = json!({
+            "id": completion["id"],
+            "object": "chat.completion.chunk",
+            "created": completion["created"],
+            "model": completion["model"],
+            "choices": choices,
+        });
+        if let Some(fingerprint) = completion.get("system_fingerprint") {
+            chunk["system_fingerprint"] = fingerprint.clone();
+        }
+        chunk
+    };
+
+    let mut chunks = Vec::new();
+    for choice in completion["choices"].as_array().into_iter().flatten() {
+        let message = &choice["message"];
+        let mut delta = json!({ "role": message["role"], "content": message["content"] });
+        if let Some(refusal) = message.get("refusal").filter(|v| !v.is_null()) {
+            delta["refusal"] = refusal.clone();
+        }
+        if let Some(calls) = message["tool_calls"].as_array() {
+            delta["tool_calls"] = calls
+                .iter()
+                .enumerate()
+                .map(|(index, call)| {
+                    let mut call = call.clone();
+                    call["index"] = json!(index);
+                    call
+                })
+                .collect();
+        }
+        chunks.push(chunk(json!([{
+            "index": choice["index"],
+            "delta": delta,
+            "logprobs": choice["logprobs"],
+            "finish_reason": null,
+        }])));
+        chunks.push(chunk(json!([{
+            "index": choice["index"],
+            "delta": {},
+            "logprobs": null,
+            "finish_reason": choice["finish_reason"],
+        }])));
+    }
+    if include_usage {
+        let mut usage = chunk(json!([]));
+        usage["usage"] = completion["usage"].clone();
+        chunks.push(usage);
+    }
+
+    let mut data = Vec::new();
+    for chunk in chunks {
+        data.extend_from_slice(b"data: ");
+        data.extend_from_slice(chunk.to_string().as_bytes());
+        data.extend_from_slice(b"\n\n");
+    }
+    data.extend_from_slice(format!("data: {DONE}\n\n").as_bytes());
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::response::IntoResponse;
+
+    const STREAM: &str = concat!(
+        "data: {\"id\":\"chatcmpl-1\",\"object\":\"chat.completion.chunk\",\"created\":1700000000,\"model\":\"gpt-4\",\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"content\":\"\"},\"finish_reason\":null}]}\n\n",
+        ": keep-alive\n\n",
+        "data: {\"id\":\"chatcmpl-1\",\"object\":\"chat.completion.chunk\",\"created\":1700000000,\"model\":\"gpt-4\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hel\"},\"finish_reason\":null}]}\r\n\r\n",
+        "data: {\"id\":\"chatcmpl-1\",\"object\":\"chat.completion.chunk\",\"created\":1700000000,\"model\":\"gpt-4\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\"lo\"},\"finish_reason\":null}]}\n\n",
+        "data: {\"id\":\"chatcmpl-1\",\"object\":\"chat.completion.chunk\",\"created\":1700000000,\"model\":\"gpt-4\",\"choices\":[{\"index\":0,\"delta\":{},\"finish_reason\":\"stop\"}]}\n\n",
+        "data: {\"id\":\"chatcmpl-1\",\"object\":\"chat.completion.chunk\",\"created\":1700000000,\"model\":\"gpt-4\",\"choices\":[],\"usage\":{\"prompt_tokens\":5,\"completion_tokens\":2,\"total_tokens\":7}}\n\n",
+        "data: [DONE]\n\n",
+    );
+
+    #[test]
+    fn test_aggregate() {
+        let completion = aggregate(STREAM.as_bytes()).unwrap();
+        assert_eq!(
+            completion,
+            json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion",
+                "created": 1700000000,
+                "model": "gpt-4",
+                "choices": [{
+                    "index": 0,
+                    "message": { "role": "assistant", "content": "Hello" },
+                    "logprobs": null,
+                    "finish_reason": "stop",
+                }],
+                "usage": { "prompt_tokens": 5, "completion_tokens": 2, "total_tokens": 7 },
+            })
+        );
+    }
+
+    #[test]
+    fn test_aggregate_tool_calls() {
+        let stream = concat!(
+            "data: {\"id\":\"c\",\"created\":1,\"model\":\"m\",\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"content\":null,\"tool_calls\":[{\"index\":0,\"id\":\"call_1\",\"type\":\"function\",\"function\":{\"name\":\"get_weather\",\"arguments\":\"\"}}]},\"finish_reason\":null}]}\n\n",
+            "data: {\"id\":\"c\",\"created\":1,\"model\":\"m\",\"choices\":[{\"index\":0,\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"{\\\"city\\\":\"}}]},\"finish_reason\":null}]}\n\n",
+            "data: {\"id\":\"c\",\"created\":1,\"model\":\"m\",\"choices\":[{\"index\":0,\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"\\\"Paris\\\"}\"}}]},\"finish_reason\":\"tool_calls\"}]}\n\n",
+            "data: [DONE]\n\n",
+        );
+        let completion = aggregate(stream.as_bytes()).unwrap();
+        assert_eq!(
+            completion["choices"][0]["message"],
+            json!({
+                "role": "assistant",
+                "content": null,
+                "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": { "name": "get_weather", "arguments": "{\"city\":\"Paris\"}" },
+                }],
+            })
+        );
+        assert_eq!(completion["choices"][0]["finish_reason"], "tool_calls");
+    }
+
+    #[test]
+    fn test_aggregate_error() {
+        let stream = concat!(
+            "data: {\"id\":\"c\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hi\"}}]}\n\n",
+            "data: {\"error\":{\"message\":\"The server had an error\",\"type\":\"server_error\"}}\n\n",
+        );
+        let error = aggregate(stream.as_bytes()).unwrap_err();
+        assert_eq!(error["error"]["type"], "server_error");
+    }
+
+    #[test]
+    fn test_replay_round_trip() {
+        let completion = aggregate(STREAM.as_bytes()).unwrap();
+        let replayed = replay(&completion, true);
+        assert!(String::from_utf8_lossy(&replayed).ends_with("data: [DONE]\n\n"));
+        assert_eq!(aggregate(&replayed).unwrap(), completion);
+
+        // The usage chunk only when the client asked for it
+        let replayed = replay(&completion, false);
+        assert!(aggregate(&replayed).unwrap()["usage"].is_null());
+    }
+
+    #[test]
+    fn test_adapt_body() {
+        let body = |value: Value| value.as_object().unwrap().clone();
+
+        let (adapt, upstream) = adapt_body(body(json!({ "model": "gpt-4" })), true).unwrap();
+        assert_eq!(
+            adapt,
+            Adapt {
+                stream: false,
+                include_usage: false
+            }
+        );
+        assert_eq!(upstream["stream"], true);
+        assert_eq!(upstream["stream_options"]["include_usage"], true);
+
+        let (adapt, upstream) = adapt_body(
+            body(json!({ "stream": true, "stream_options": { "include_usage": true } })),
+            false,
+        )
+        .unwrap();
+        assert!(adapt.stream && adapt.include_usage);
+        assert_eq!(upstream["stream"], false);
+        assert!(upstream.get("stream_options").is_none());
+
+        // Already in the upstream mode
+        assert!(adapt_body(body(json!({ "stream": true })), true).is_none());
+        assert!(adapt_body(body(json!({})), false).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_response_aggregated() {
+        let mut resp = STREAM.into_response();
+        resp.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/event-stream"),
+        );
+        let adapt = Adapt {
+            stream: false,
+            include_usage: false,
+        };
+        let resp = response(resp, Some(adapt)).await.unwrap();
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+
+        let data = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let completion: Value = serde_json::from_slice(&data).unwrap();
+        assert_eq!(completion["choices"][0]["message"]["content"], "Hello");
+    }
+}
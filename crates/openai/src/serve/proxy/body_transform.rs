@@ -0,0 +1,373 @@
+//! Request body transforms (`body_transforms`). The rules set, default, clamp or remove
+//! the fields of the JSON request bodies before they are forwarded, in order, so the
+//! operators enforce their defaults and bounds without a separate gateway. Bodies that
+//! are not JSON are forwarded untouched. Bodies larger than `body_transform_limit` are
+//! rejected, they would bypass the rules, except the uploads (forms, raw bytes).
+
+use super::ext::RequestExt;
+use crate::context::args::{BodyTransform, BodyTransformOp, JsonPathSegment};
+use crate::serve::error::ResponseError;
+use crate::{debug, info};
+use axum::body::Bytes;
+use axum::http::{header, HeaderMap};
+use serde_json::Value;
+use std::sync::OnceLock;
+
+static TRANSFORMS: OnceLock<Transforms> = OnceLock::new();
+
+struct Transforms {
+    rules: Vec<BodyTransform>,
+    /// Largest transformed body (byte)
+    limit: usize,
+}
+
+pub(crate) fn init(rules: &[BodyTransform], limit: usize) -> anyhow::Result<()> {
+    if rules.is_empty() {
+        return Ok(());
+    }
+    for rule in rules {
+        rule.validate()?;
+    }
+    info!(
+        "Body transforms: {} rules, bodies up to {limit} bytes",
+        rules.len()
+    );
+    let _ = TRANSFORMS.set(Transforms {
+        rules: rules.to_vec(),
+        limit,
+    });
+    Ok(())
+}
+
+/// Apply the body transforms of the request route
+pub(crate) fn apply(req: &mut RequestExt) -> Result<(), ResponseError> {
+    match TRANSFORMS.get() {
+        Some(transforms) => transforms.apply(req),
+        None => Ok(()),
+    }
+}
+
+/// Whether the body is an upload the upstream does not read as JSON: a multipart or
+/// url-encoded form, or raw bytes. Any other type, or none, may carry JSON.
+fn upload_content(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<mime::Mime>().ok())
+        .map_or(false, |content_type| {
+            content_type.type_() == mime::MULTIPART
+                || matches!(
+                    content_type.essence_str(),
+                    "application/x-www-form-urlencoded" | "application/octet-stream"
+                )
+        })
+}
+
+impl Transforms {
+    fn apply(&self, req: &mut RequestExt) -> Result<(), ResponseError> {
+        let path = req.uri.path();
+        let rules = self
+            .rules
+            .iter()
+            .filter(|rule| {
+                rule.routes.is_empty() || rule.routes.iter().any(|route| path.starts_with(route))
+            })
+            .collect::<Vec<_>>();
+        if rules.is_empty() {
+            return Ok(());
+        }
+
+        let mut body = match req.body.as_ref() {
+            Some(body) if body.len() > self.limit && upload_content(&req.headers) => {
+                debug!(
+                    "Request upload of {} bytes is above body_transform_limit, not transformed",
+                    body.len()
+                );
+                return Ok(());
+            }
+            Some(body) if body.len() > self.limit => {
+                return Err(ResponseError::PayloadTooLarge(anyhow::anyhow!(
+                    "Request body of {} bytes is above the body_transform_limit of {} bytes",
+                    body.len(),
+                    self.limit
+                )));
+            }
+            Some(body) => match serde_json::from_slice::<Value>(body) {
+                Ok(body) => body,
+                Err(_) => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+
+        let mut changed = false;
+        for rule in rules {
+            changed |= transform(&mut body, rule.path.segments(), rule);
+        }
+        if !changed {
+            return Ok(());
+        }
+        if let Ok(body) = serde_json::to_vec(&body) {
+            req.body = Some(Bytes::from(body));
+            req.headers.remove(header::CONTENT_LENGTH);
+        }
+        Ok(())
+    }
+}
+
+/// Apply a rule to the fields selected by `segments` under `value`, whether the
+/// body changed. Missing parents are not created.
+fn transform(value: &mut Value, segments: &[JsonPathSegment], rule: &BodyTransform) -> bool {
+    match segments {
+        [] => false,
+        [last] => apply_last(value, last, rule),
+        [segment, rest @ ..] => children(value, segment)
+            .into_iter()
+            .fold(false, |changed, child| {
+                transform(child, rest, rule) | changed
+            }),
+    }
+}
+
+/// Values selected by a segment
+fn children<'a>(value: &'a mut Value, segment: &JsonPathSegment) -> Vec<&'a mut Value> {
+    match (segment, value) {
+        (JsonPathSegment::Key(key), Value::Object(object)) => {
+            object.get_mut(key).into_iter().collect()
+        }
+        (JsonPathSegment::Index(index), Value::Array(array)) => {
+            array.get_mut(*index).into_iter().collect()
+        }
+        (JsonPathSegment::Wildcard, Value::Array(array)) => array.iter_mut().collect(),
+        (JsonPathSegment::Wildcard, Value::Object(object)) => object.values_mut().collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Apply a rule to the field selected by the last segment of its path in `parent`
+fn apply_last(parent: &mut Value, segment: &JsonPathSegment, rule: &BodyTransform) -> bool {
+    match rule.op {
+        BodyTransformOp::Set | BodyTransformOp::Default => {
+            let value = rule.value.clone().unwrap_or(Value::Null);
+            let default = rule.op == BodyTransformOp::Default;
+            match (segment, parent) {
+                (JsonPathSegment::Key(key), Value::Object(object)) => {
+                    if default && object.contains_key(key) {
+                        return false;
+                    }
+                    object.insert(key.clone(), value.clone()).as_ref() != Some(&value)
+                }
+                (segment, parent) if !default => {
+                    children(parent, segment)
+                        .into_iter()
+                        .fold(false, |changed, field| {
+                            let old = std::mem::replace(field, value.clone());
+                            old != value || changed
+                        })
+                }
+                // An array element is never absent, but out of bounds
+                _ => false,
+            }
+        }
+        BodyTransformOp::Clamp => children(parent, segment)
+            .into_iter()
+            .fold(false, |changed, field| {
+                clamp(field, rule.min, rule.max) | changed
+            }),
+        BodyTransformOp::Remove => match (segment, parent) {
+            (JsonPathSegment::Key(key), Value::Object(object)) => object.remove(key).is_some(),
+            (JsonPathSegment::Index(index), Value::Array(array)) if *index < array.len() => {
+                array.remove(*index);
+                true
+            }
+            (JsonPathSegment::Wildcard, Value::Array(array)) => {
+                let changed = !array.is_empty();
+                array.clear();
+                changed
+            }
+            (JsonPathSegment::Wildcard, Value::Object(object)) => {
+                let changed = !object.is_empty();
+                object.clear();
+                changed
+            }
+            _ => false,
+        },
+    }
+}
+
+/// Bound a number, an integer stays an integer when the bound is one. Other values are
+/// left as they are.
+fn clamp(value: &mut Value, min: Option<f64>, max: Option<f64>) -> bool {
+    let Some(number) = value.as_f64() else {
+        return false;
+    };
+    let clamped = number
+        .max(min.unwrap_or(f64::NEG_INFINITY))
+        .min(max.unwrap_or(f64::INFINITY));
+    if clamped == number {
+        return false;
+    }
+    *value = match (value.is_i64() || value.is_u64()) && clamped.fract() == 0.0 {
+        true if clamped >= 0.0 => Value::from(clamped as u64),
+        true => Value::from(clamped as i64),
+        false => Value::from(clamped),
+    };
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::args::JsonPath;
+    use axum::http::{Method, Uri};
+    use axum::response::IntoResponse;
+    use axum_extra::extract::CookieJar;
+
+    fn rules(toml: &str) -> Vec<BodyTransform> {
+        #[derive(serde::Deserialize)]
+        struct Config {
+            body_transforms: Vec<BodyTransform>,
+        }
+        let rules = toml::from_str::<Config>(toml).unwrap().body_transforms;
+        for rule in &rules {
+            rule.validate().unwrap();
+        }
+        rules
+    }
+
+    fn request(uri: &'static str, body: &str) -> RequestExt {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_LENGTH, body.len().into());
+        RequestExt {
+            uri: Uri::from_static(uri),
+            method: Method::POST,
+            headers,
+            jar: CookieJar::new(),
+            body: Some(Bytes::from(body.to_owned())),
+            reservation: None,
+        }
+    }
+
+    fn body(req: &RequestExt) -> Value {
+        serde_json::from_slice(req.body.as_ref().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_rules_in_order() {
+        let transforms = Transforms {
+            rules: rules(
+                r#"
+                [[body_transforms]]
+                path = "$.temperature"
+                op = "default"
+                value = 0.7
+
+                [[body_transforms]]
+                routes = ["/v1/chat/completions"]
+                path = "$.max_tokens"
+                op = "clamp"
+                max = 4096
+
+                [[body_transforms]]
+                path = "$.messages[*].name"
+                op = "remove"
+
+                [[body_transforms]]
+                path = "$['user']"
+                op = "set"
+                value = "gateway"
+                "#,
+            ),
+            limit: 1024,
+        };
+
+        let mut req = request(
+            "/v1/chat/completions",
+            r#"{"model":"gpt-4o","max_tokens":10000,"messages":[{"role":"user","name":"a","content":"hi"}]}"#,
+        );
+        transforms.apply(&mut req).unwrap();
+        assert_eq!(
+            body(&req),
+            serde_json::json!({
+                "model": "gpt-4o",
+                "temperature": 0.7,
+                "max_tokens": 4096,
+                "messages": [{"role": "user", "content": "hi"}],
+                "user": "gateway"
+            })
+        );
+        assert!(req.headers.get(header::CONTENT_LENGTH).is_none());
+
+        // The route of the clamp rule does not match, the present temperature is kept
+        let mut req = request(
+            "/v1/embeddings",
+            r#"{"temperature":1.2,"max_tokens":10000,"user":"gateway"}"#,
+        );
+        transforms.apply(&mut req).unwrap();
+        assert_eq!(body(&req)["temperature"], 1.2);
+        assert_eq!(body(&req)["max_tokens"], 10000);
+    }
+
+    #[test]
+    fn test_untouched_bodies() {
+        let transforms = Transforms {
+            rules: rules(
+                r#"
+                [[body_transforms]]
+                path = "$.temperature"
+                op = "set"
+                value = 0
+                "#,
+            ),
+            limit: 32,
+        };
+
+        // Not JSON
+        let mut req = request("/v1/chat/completions", "temperature=1");
+        transforms.apply(&mut req).unwrap();
+        assert_eq!(req.body.as_deref(), Some(b"temperature=1".as_slice()));
+
+        // An upload, at any size
+        let body = r#"{"temperature":1,"input":"a long enough input"}"#;
+        let mut req = request("/v1/audio/transcriptions", body);
+        req.headers.insert(
+            header::CONTENT_TYPE,
+            "multipart/form-data; boundary=x".parse().unwrap(),
+        );
+        transforms.apply(&mut req).unwrap();
+        assert_eq!(req.body.as_deref(), Some(body.as_bytes()));
+
+        // Above the limit, rejected rather than forwarded without the rules
+        let mut req = request("/v1/chat/completions", body);
+        req.headers.insert(
+            header::CONTENT_TYPE,
+            "application/json; charset=utf-8".parse().unwrap(),
+        );
+        let err = transforms.apply(&mut req).unwrap_err();
+        assert_eq!(err.into_response().status(), 413);
+
+        // Already compliant, forwarded as sent
+        let body = r#"{"temperature":0}"#;
+        let mut req = request("/v1/chat/completions", body);
+        transforms.apply(&mut req).unwrap();
+        assert_eq!(req.body.as_deref(), Some(body.as_bytes()));
+        assert!(req.headers.get(header::CONTENT_LENGTH).is_some());
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        assert!("temperature".parse::<JsonPath>().is_err());
+        assert!("$..name".parse::<JsonPath>().is_err());
+        assert!("$.messages[?(@.role)]".parse::<JsonPath>().is_err());
+
+        let rule = |toml: &str| {
+            toml::from_str::<BodyTransform>(toml)
+                .map_err(anyhow::Error::from)
+                .and_then(|rule| rule.validate())
+        };
+        assert!(rule("path = \"$.max_tokens\"\nop = \"clamp\"").is_err());
+        assert!(rule("path = \"$.max_tokens\"\nop = \"clamp\"\nmin = 10\nmax = 1").is_err());
+        assert!(rule("path = \"$.user\"\nop = \"set\"").is_err());
+        assert!(rule("path = \"$.user\"\nop = \"remove\"\nvalue = 1").is_err());
+        assert!(rule("path = \"$.user\"\nop = \"remove\"").is_ok());
+    }
+}
@@ -0,0 +1,284 @@
+//! Upstream cookies of the proxied requests (`cookie_mode`).
+//!
+//! `passthrough` relays the `_puid` and `cf_clearance` cookies between the client and the
+//! upstream, `strip` relays none. `jar` keeps the cookies set by the upstream in a jar per
+//! session and sends them back on the next requests of the session, the client never sees
+//! them. A session is the bearer token of the request: the requests of an access token or
+//! API key share a jar, requests without one get no cookies.
+
+use crate::context::args::CookieMode;
+use crate::{now_duration, warn};
+use axum::http::{header, HeaderMap};
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Jars of the sessions without a request for this long are dropped
+const SESSION_IDLE: Duration = Duration::from_secs(24 * 3600);
+/// Jars kept, the least recently used one is dropped for a new session over it
+const MAX_SESSIONS: usize = 10_000;
+/// Cookies kept in a jar, new cookies over it are dropped
+const MAX_COOKIES: usize = 64;
+
+static MODE: OnceLock<CookieMode> = OnceLock::new();
+static JARS: OnceLock<Mutex<HashMap<String, Jar>>> = OnceLock::new();
+
+pub(crate) fn init(mode: CookieMode, cookie_store: bool) {
+    if mode == CookieMode::Jar && cookie_store {
+        warn!("cookie_store is one client cookie jar shared by every session, disable it in jar cookie mode");
+    }
+    let _ = MODE.set(mode);
+}
+
+pub(crate) fn mode() -> CookieMode {
+    MODE.get().copied().unwrap_or_default()
+}
+
+fn jars() -> MutexGuard<'static, HashMap<String, Jar>> {
+    JARS.get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+}
+
+fn now() -> u64 {
+    now_duration().map_or(0, |now| now.as_secs())
+}
+
+/// Session of the request in `jar` mode, the digest of its bearer token
+pub(crate) fn session(headers: &HeaderMap) -> Option<String> {
+    if mode() != CookieMode::Jar {
+        return None;
+    }
+    let token = headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")?
+        .trim();
+    (!token.is_empty()).then(|| crate::sign::sha256_hex(token.as_bytes()))
+}
+
+/// Cookies of a session, by upstream origin and name
+#[derive(Default)]
+struct Jar {
+    cookies: HashMap<(String, String), Stored>,
+    last_used: u64,
+}
+
+struct Stored {
+    value: String,
+    /// Unix seconds, `None` lasts as long as the session
+    expires_at: Option<u64>,
+}
+
+/// Keep the cookies set by an upstream response in the session jar
+pub(crate) fn store<'a>(
+    session: &str,
+    origin: &str,
+    cookies: impl Iterator<Item = reqwest::cookie::Cookie<'a>>,
+) {
+    let now = now();
+    let cookies = cookies
+        .map(|cookie| {
+            let expires_at = match (cookie.max_age(), cookie.expires()) {
+                (Some(max_age), _) => Some(now + max_age.as_secs()),
+                (None, Some(expires)) => Some(
+                    expires
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |expires| expires.as_secs()),
+                ),
+                (None, None) => None,
+            };
+            (
+                cookie.name().to_owned(),
+                cookie.value().to_owned(),
+                expires_at,
+            )
+        })
+        .collect::<Vec<_>>();
+    if !cookies.is_empty() {
+        set(&mut jars(), session, origin, cookies, now);
+    }
+}
+
+fn set(
+    jars: &mut HashMap<String, Jar>,
+    session: &str,
+    origin: &str,
+    cookies: Vec<(String, String, Option<u64>)>,
+    now: u64,
+) {
+    jars.retain(|_, jar| jar.last_used + SESSION_IDLE.as_secs() > now);
+    if jars.len() >= MAX_SESSIONS && !jars.contains_key(session) {
+        let oldest = jars
+            .iter()
+            .min_by_key(|(_, jar)| jar.last_used)
+            .map(|(session, _)| session.clone());
+        if let Some(oldest) = oldest {
+            jars.remove(&oldest);
+        }
+    }
+
+    let jar = jars.entry(session.to_owned()).or_default();
+    jar.last_used = now;
+    for (name, value, expires_at) in cookies {
+        let key = (origin.to_owned(), name);
+        // An expired cookie deletes it
+        if expires_at.map_or(false, |expires_at| expires_at <= now) {
+            jar.cookies.remove(&key);
+        } else if jar.cookies.len() < MAX_COOKIES || jar.cookies.contains_key(&key) {
+            jar.cookies.insert(key, Stored { value, expires_at });
+        }
+    }
+}
+
+/// `name=value` cookies of the session jar for an upstream origin
+pub(crate) fn cookies(session: &str, origin: &str) -> Vec<String> {
+    cookies_at(&mut jars(), session, origin, now())
+}
+
+fn cookies_at(
+    jars: &mut HashMap<String, Jar>,
+    session: &str,
+    origin: &str,
+    now: u64,
+) -> Vec<String> {
+    let Some(jar) = jars.get_mut(session) else {
+        return Vec::new();
+    };
+    jar.last_used = now;
+    jar.cookies.retain(|_, cookie| {
+        cookie
+            .expires_at
+            .map_or(true, |expires_at| expires_at > now)
+    });
+    jar.cookies
+        .iter()
+        .filter(|((cookie_origin, _), _)| cookie_origin == origin)
+        .map(|((_, name), cookie)| format!("{name}={}", cookie.value))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGIN: &str = "https://chatgpt.com";
+
+    fn cookie(name: &str, value: &str, expires_at: Option<u64>) -> (String, String, Option<u64>) {
+        (name.to_owned(), value.to_owned(), expires_at)
+    }
+
+    #[test]
+    fn test_jar_per_session() {
+        let mut jars = HashMap::new();
+        set(
+            &mut jars,
+            "session-a",
+            ORIGIN,
+            vec![
+                cookie("__cf_bm", "a", None),
+                cookie("oai-did", "1", Some(200)),
+            ],
+            100,
+        );
+        set(
+            &mut jars,
+            "session-b",
+            ORIGIN,
+            vec![cookie("__cf_bm", "b", None)],
+            100,
+        );
+
+        let mut a = cookies_at(&mut jars, "session-a", ORIGIN, 150);
+        a.sort();
+        assert_eq!(a, ["__cf_bm=a", "oai-did=1"]);
+        assert_eq!(
+            cookies_at(&mut jars, "session-b", ORIGIN, 150),
+            ["__cf_bm=b"]
+        );
+        assert!(cookies_at(&mut jars, "session-a", "https://api.openai.com", 150).is_empty());
+        assert!(cookies_at(&mut jars, "session-c", ORIGIN, 150).is_empty());
+
+        // Expired, then deleted by the upstream
+        assert_eq!(
+            cookies_at(&mut jars, "session-a", ORIGIN, 250),
+            ["__cf_bm=a"]
+        );
+        set(
+            &mut jars,
+            "session-a",
+            ORIGIN,
+            vec![cookie("__cf_bm", "", Some(0))],
+            300,
+        );
+        assert!(cookies_at(&mut jars, "session-a", ORIGIN, 300).is_empty());
+    }
+
+    #[test]
+    fn test_idle_sessions_dropped() {
+        let mut jars = HashMap::new();
+        set(
+            &mut jars,
+            "session-idle",
+            ORIGIN,
+            vec![cookie("a", "1", None)],
+            1_000,
+        );
+        set(
+            &mut jars,
+            "session-active",
+            ORIGIN,
+            vec![cookie("b", "2", None)],
+            1_000 + SESSION_IDLE.as_secs(),
+        );
+        assert!(cookies_at(
+            &mut jars,
+            "session-idle",
+            ORIGIN,
+            1_000 + SESSION_IDLE.as_secs()
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_jars_bounded() {
+        let mut jars = HashMap::new();
+        for i in 0..MAX_SESSIONS as u64 {
+            set(
+                &mut jars,
+                &format!("session-{i}"),
+                ORIGIN,
+                vec![cookie("a", "1", None)],
+                1_000 + i,
+            );
+        }
+        // session-0 is touched, session-1 is the least recently used
+        cookies_at(&mut jars, "session-0", ORIGIN, 20_000);
+        set(
+            &mut jars,
+            "session-new",
+            ORIGIN,
+            vec![cookie("a", "1", None)],
+            20_000,
+        );
+        assert_eq!(jars.len(), MAX_SESSIONS);
+        assert!(jars.contains_key("session-0"));
+        assert!(!jars.contains_key("session-1"));
+
+        let cookies = (0..MAX_COOKIES + 10)
+            .map(|i| cookie(&format!("c{i}"), "1", None))
+            .collect();
+        set(&mut jars, "session-0", ORIGIN, cookies, 20_000);
+        assert_eq!(jars["session-0"].cookies.len(), MAX_COOKIES);
+        // A kept cookie is still updated
+        set(
+            &mut jars,
+            "session-0",
+            ORIGIN,
+            vec![cookie("a", "2", None)],
+            20_000,
+        );
+        assert!(cookies_at(&mut jars, "session-0", ORIGIN, 20_000).contains(&"a=2".to_owned()));
+    }
+}
@@ -0,0 +1,409 @@
+use crate::context::args::EgressAuthConfig;
+use crate::info;
+use crate::serve::error::ResponseError;
+use crate::serve::timing;
+use crate::sign::{hex, hmac_sha256, sha256_hex};
+use chrono::{DateTime, Utc};
+use reqwest::header::{HeaderName, HeaderValue};
+use reqwest::RequestBuilder;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+/// Egress request signer, `None` when `[egress_auth]` is not configured
+static EGRESS_AUTH: OnceLock<Option<EgressAuth>> = OnceLock::new();
+
+pub(crate) fn init(config: Option<&EgressAuthConfig>) -> anyhow::Result<()> {
+    let egress_auth = match config {
+        Some(config) => {
+            info!(
+                "Egress auth: upstream requests are signed with key `{}`",
+                config.key_id
+            );
+            Some(EgressAuth::new(config)?)
+        }
+        None => None,
+    };
+    let _ = EGRESS_AUTH.set(egress_auth);
+    Ok(())
+}
+
+/// Send an upstream request, signed just before dispatch when egress auth is enabled.
+/// Every call signs again, so a retried request carries a fresh date.
+pub(crate) async fn send(builder: RequestBuilder) -> Result<reqwest::Response, ResponseError> {
+    let start = Instant::now();
+    let result = dispatch(builder).await;
+    timing::record_upstream(start.elapsed());
+    result
+}
+
+async fn dispatch(builder: RequestBuilder) -> Result<reqwest::Response, ResponseError> {
+    let egress_auth = match EGRESS_AUTH.get() {
+        Some(Some(egress_auth)) => egress_auth,
+        _ => {
+            return builder
+                .send()
+                .await
+                .map_err(ResponseError::InternalServerError)
+        }
+    };
+
+    let (client, req) = builder.build_split();
+    let mut req = req.map_err(ResponseError::InternalServerError)?;
+    egress_auth
+        .sign(&mut req, Utc::now())
+        .map_err(ResponseError::InternalServerError)?;
+    client
+        .execute(req)
+        .await
+        .map_err(ResponseError::InternalServerError)
+}
+
+/// Field of the signed string
+enum Field {
+    Method,
+    /// Path and query
+    Path,
+    /// Host, with the port unless it is the scheme default
+    Host,
+    Date,
+    BodySha256,
+    /// Header values joined by `,`, empty when the header is not set
+    Header(HeaderName),
+}
+
+impl Field {
+    fn parse(field: &str) -> anyhow::Result<Self> {
+        Ok(match field {
+            "method" => Self::Method,
+            "path" => Self::Path,
+            "host" => Self::Host,
+            "date" => Self::Date,
+            "body_sha256" => Self::BodySha256,
+            _ => match field.strip_prefix("header:") {
+                Some(name) => Self::Header(HeaderName::try_from(name)?),
+                None => anyhow::bail!("Invalid egress_auth canonical field `{field}`"),
+            },
+        })
+    }
+}
+
+struct EgressAuth {
+    key_id: String,
+    secret: Vec<u8>,
+    header: HeaderName,
+    format: String,
+    date_header: HeaderName,
+    body_hash_header: Option<HeaderName>,
+    canonical: Vec<Field>,
+}
+
+impl EgressAuth {
+    fn new(config: &EgressAuthConfig) -> anyhow::Result<Self> {
+        config.validate()?;
+        Ok(Self {
+            key_id: config.key_id.clone(),
+            secret: config.secret.expose().as_bytes().to_vec(),
+            header: HeaderName::try_from(config.header.as_str())?,
+            format: config.format.clone(),
+            date_header: HeaderName::try_from(config.date_header.as_str())?,
+            body_hash_header: config
+                .body_hash_header
+                .as_deref()
+                .map(HeaderName::try_from)
+                .transpose()?,
+            canonical: config
+                .canonical
+                .iter()
+                .map(|field| Field::parse(field))
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+
+    /// The signed string, the canonical fields of the request one per line
+    fn string_to_sign(&self, req: &reqwest::Request, date: &str, body_sha256: &str) -> String {
+        let url = req.url();
+        self.canonical
+            .iter()
+            .map(|field| match field {
+                Field::Method => req.method().as_str().to_owned(),
+                Field::Path => match url.query() {
+                    Some(query) => format!("{}?{query}", url.path()),
+                    None => url.path().to_owned(),
+                },
+                Field::Host => match (url.host_str(), url.port()) {
+                    (Some(host), Some(port)) => format!("{host}:{port}"),
+                    (host, _) => host.unwrap_or_default().to_owned(),
+                },
+                Field::Date => date.to_owned(),
+                Field::BodySha256 => body_sha256.to_owned(),
+                Field::Header(name) => req
+                    .headers()
+                    .get_all(name)
+                    .iter()
+                    .filter_map(|v| v.to_str().ok())
+                    .map(str::trim)
+                    .collect::<Vec<_>>()
+                    .join(","),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Sign the request as it is sent, after every header and body transform.
+    /// The body must be buffered, streaming bodies cannot be pre-hashed.
+    fn sign(&self, req: &mut reqwest::Request, now: DateTime<Utc>) -> anyhow::Result<()> {
+        let body = match req.body() {
+            Some(body) => body.as_bytes().ok_or_else(|| {
+                anyhow::anyhow!(
+                    "egress_auth cannot sign a streaming request body, signed trailers are not supported"
+                )
+            })?,
+            None => &[],
+        };
+        let body_sha256 = sha256_hex(body);
+
+        let date = now.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+
+        // Set before signing, so they can be signed as headers
+        let headers = req.headers_mut();
+        headers.insert(self.date_header.clone(), HeaderValue::from_str(&date)?);
+        if let Some(name) = self.body_hash_header.as_ref() {
+            headers.insert(name.clone(), HeaderValue::from_str(&body_sha256)?);
+        }
+
+        let signature = hex(&hmac_sha256(
+            &self.secret,
+            self.string_to_sign(req, &date, &body_sha256).as_bytes(),
+        ));
+        let value = self
+            .format
+            .replace("{key_id}", &self.key_id)
+            .replace("{signature}", &signature)
+            .replace("{date}", &date)
+            .replace("{body_sha256}", &body_sha256);
+        req.headers_mut()
+            .insert(self.header.clone(), HeaderValue::from_str(&value)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secret::Secret;
+    use chrono::TimeZone;
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const SECRET: &str = "gateway-secret";
+
+    fn egress_auth() -> EgressAuth {
+        EgressAuth::new(&EgressAuthConfig {
+            key_id: "ninja-1".to_owned(),
+            secret: Secret::new(SECRET),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    /// Mock gateway, answers 200 with the signed date when the signature verifies,
+    /// 401 otherwise
+    async fn gateway(requests: usize) -> (String, tokio::task::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            for _ in 0..requests {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut received = Vec::new();
+                let mut buf = [0u8; 4096];
+                let head_end = loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    received.extend_from_slice(&buf[..n]);
+                    if let Some(pos) = received.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break pos + 4;
+                    }
+                };
+
+                let head = String::from_utf8(received[..head_end].to_vec()).unwrap();
+                let mut lines = head.split("\r\n");
+                let mut request_line = lines.next().unwrap().split(' ');
+                let (method, path) = (request_line.next().unwrap(), request_line.next().unwrap());
+                let headers: HashMap<String, String> = lines
+                    .filter_map(|line| line.split_once(':'))
+                    .map(|(name, value)| (name.to_lowercase(), value.trim().to_owned()))
+                    .collect();
+                let len = headers
+                    .get("content-length")
+                    .map_or(0, |len| len.parse::<usize>().unwrap());
+                while received.len() < head_end + len {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    received.extend_from_slice(&buf[..n]);
+                }
+                let body = &received[head_end..head_end + len];
+
+                let date = headers.get("date").cloned().unwrap_or_default();
+                let string_to_sign = format!("{method}\n{path}\n{date}\n{}", sha256_hex(body));
+                let signature = hex(&hmac_sha256(SECRET.as_bytes(), string_to_sign.as_bytes()));
+                let verified = headers.get("authorization")
+                    == Some(&format!("HMAC-SHA256 KeyId=ninja-1, Signature={signature}"))
+                    && headers.get("x-content-sha256") == Some(&sha256_hex(body));
+
+                let status = if verified {
+                    "200 OK"
+                } else {
+                    "401 Unauthorized"
+                };
+                let resp = format!(
+                    "HTTP/1.1 {status}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{date}",
+                    date.len()
+                );
+                stream.write_all(resp.as_bytes()).await.unwrap();
+            }
+        });
+        (format!("http://{addr}"), handle)
+    }
+
+    async fn send_signed(
+        egress_auth: &EgressAuth,
+        builder: RequestBuilder,
+        now: DateTime<Utc>,
+    ) -> (u16, String) {
+        let (client, req) = builder.build_split();
+        let mut req = req.unwrap();
+        egress_auth.sign(&mut req, now).unwrap();
+        let resp = client.execute(req).await.unwrap();
+        (resp.status().as_u16(), resp.text().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_gateway_verifies_signature() {
+        let egress_auth = egress_auth();
+        let (origin, gateway) = gateway(2).await;
+        let client = reqwest::Client::new();
+
+        let post = client
+            .post(format!("{origin}/v1/chat/completions?api-version=1"))
+            .header("content-type", "application/json")
+            .body(r#"{"model":"gpt-4","messages":[]}"#);
+        assert_eq!(send_signed(&egress_auth, post, Utc::now()).await.0, 200);
+
+        let get = client.get(format!("{origin}/v1/models"));
+        assert_eq!(send_signed(&egress_auth, get, Utc::now()).await.0, 200);
+        gateway.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tampered_request_rejected() {
+        let egress_auth = egress_auth();
+        let (origin, gateway) = gateway(1).await;
+        let (client, req) = reqwest::Client::new()
+            .post(format!("{origin}/v1/chat/completions"))
+            .body("signed")
+            .build_split();
+        let mut req = req.unwrap();
+        egress_auth.sign(&mut req, Utc::now()).unwrap();
+        *req.body_mut() = Some("tampered".into());
+
+        let resp = client.execute(req).await.unwrap();
+        assert_eq!(resp.status().as_u16(), 401);
+        gateway.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_retry_signs_fresh_date() {
+        let egress_auth = egress_auth();
+        let (origin, gateway) = gateway(2).await;
+        let builder = reqwest::Client::new()
+            .post(format!("{origin}/v1/chat/completions"))
+            .body(r#"{"model":"gpt-4"}"#);
+
+        let now = Utc::now();
+        let retry = builder.try_clone().unwrap();
+        let (status, first) = send_signed(&egress_auth, builder, now).await;
+        assert_eq!(status, 200);
+        let later = now + chrono::Duration::from_std(Duration::from_secs(5)).unwrap();
+        let (status, second) = send_signed(&egress_auth, retry, later).await;
+        assert_eq!(status, 200);
+        assert_ne!(first, second);
+        gateway.await.unwrap();
+    }
+
+    fn signature_of(egress_auth: &EgressAuth, req: &mut reqwest::Request) -> String {
+        let now = Utc.with_ymd_and_hms(2026, 10, 14, 8, 0, 0).unwrap();
+        egress_auth.sign(req, now).unwrap();
+        let value = req.headers()["authorization"].to_str().unwrap();
+        value.rsplit("Signature=").next().unwrap().to_owned()
+    }
+
+    #[test]
+    fn test_signature_vectors() {
+        // Default canonical string:
+        // POST\n/v1/chat/completions?api-version=1\nWed, 14 Oct 2026 08:00:00 GMT\n03a30e..d11e
+        let mut req = reqwest::Client::new()
+            .post("http://upstream.example/v1/chat/completions?api-version=1")
+            .body(r#"{"model":"gpt-4"}"#)
+            .build()
+            .unwrap();
+        assert_eq!(
+            signature_of(&egress_auth(), &mut req),
+            "0f5099f7868b6dd551fbe92e6becfb9d8d5442fc9b54af28387d99d709af82e6"
+        );
+        assert_eq!(req.headers()["date"], "Wed, 14 Oct 2026 08:00:00 GMT");
+        assert_eq!(
+            req.headers()["x-content-sha256"],
+            "03a30ef116dc2b78a4b58b6501a61bb422d4082d3262bed0d365418567fbd11e"
+        );
+
+        // GET\nupstream.example:8443\n/v1/models\nreq-1\ne3b0c4..b855
+        let egress_auth = EgressAuth::new(&EgressAuthConfig {
+            key_id: "ninja-1".to_owned(),
+            secret: Secret::new(SECRET),
+            canonical: [
+                "method",
+                "host",
+                "path",
+                "header:x-request-id",
+                "body_sha256",
+            ]
+            .map(str::to_owned)
+            .to_vec(),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut req = reqwest::Client::new()
+            .get("http://upstream.example:8443/v1/models")
+            .header("x-request-id", "req-1")
+            .build()
+            .unwrap();
+        assert_eq!(
+            signature_of(&egress_auth, &mut req),
+            "9e9c61e183d9a5ee1334b7423b0a05725dd6187857100f5dff0264cb5aa7e634"
+        );
+    }
+
+    #[test]
+    fn test_invalid_canonical_field() {
+        let config = EgressAuthConfig {
+            key_id: "ninja-1".to_owned(),
+            secret: Secret::new(SECRET),
+            canonical: vec!["method".to_owned(), "query".to_owned()],
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        assert!(EgressAuth::new(&config).is_err());
+    }
+
+    #[test]
+    fn test_streaming_body_rejected() {
+        let stream = futures::stream::iter([Ok::<_, std::io::Error>("chunk")]);
+        let mut req = reqwest::Client::new()
+            .post("http://127.0.0.1/v1/files")
+            .body(reqwest::Body::wrap_stream(stream))
+            .build()
+            .unwrap();
+        let err = egress_auth().sign(&mut req, Utc::now()).unwrap_err();
+        assert!(err.to_string().contains("streaming request body"));
+    }
+}
@@ -1,6 +1,7 @@
 use std::str::FromStr;
+use std::sync::Arc;
 
-use axum::body::Bytes;
+use axum::body::{Body, Bytes};
 use axum::response::{IntoResponse, Response};
 use axum::{
     async_trait,
@@ -13,6 +14,8 @@ use http::{header, Uri};
 use typed_builder::TypedBuilder;
 
 use crate::serve::error::ResponseError;
+use crate::serve::idempotency::{read_body, MAX_REQUEST_BODY};
+use crate::serve::memory::Reservation;
 
 /// Context extension.
 #[derive(TypedBuilder)]
@@ -32,12 +35,15 @@ pub struct ResponseExt {
 }
 
 /// Extractor for request parts.
+#[derive(Clone)]
 pub struct RequestExt {
     pub uri: Uri,
     pub method: http::Method,
     pub headers: http::HeaderMap,
     pub jar: CookieJar,
     pub body: Option<Bytes>,
+    /// Size of the body in the buffered memory budget, shared by the clones
+    pub(crate) reservation: Option<Arc<Reservation>>,
 }
 
 impl RequestExt {
@@ -96,26 +102,30 @@ pub(crate) trait SendRequestExt {
 }
 
 #[async_trait]
-impl<S, B> FromRequest<S, B> for RequestExt
+impl<S> FromRequest<S, Body> for RequestExt
 where
-    Bytes: FromRequest<S, B>,
-    B: Send + 'static,
     S: Send + Sync,
 {
     type Rejection = Response;
 
-    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
-        let (parts, body) = req.into_parts();
+    async fn from_request(req: Request<Body>, state: &S) -> Result<Self, Self::Rejection> {
+        let (mut parts, body) = req.into_parts();
 
-        let body = if parts.headers.get(CONTENT_TYPE).is_some() {
-            Some(
-                Bytes::from_request(Request::new(body), state)
+        let (body, reservation) =
+            if let Some(reservation) = parts.extensions.remove::<Arc<Reservation>>() {
+                // Already read and reserved by a middleware
+                let body = Bytes::from_request(Request::new(body), state)
                     .await
-                    .map_err(IntoResponse::into_response)?,
-            )
-        } else {
-            None
-        };
+                    .map_err(IntoResponse::into_response)?;
+                (Some(body), Some(reservation))
+            } else if parts.headers.get(CONTENT_TYPE).is_some() {
+                // Reserved frame by frame, a chunked body is refused as soon as it
+                // exceeds the buffered memory limit
+                let (body, reservation) = read_body(&parts.headers, body, MAX_REQUEST_BODY).await?;
+                (Some(body), Some(Arc::new(reservation)))
+            } else {
+                (None, None)
+            };
 
         Ok(RequestExt {
             uri: parts.uri,
@@ -123,6 +133,7 @@ where
             method: parts.method,
             headers: parts.headers,
             body,
+            reservation,
         })
     }
 }
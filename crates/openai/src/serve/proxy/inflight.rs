@@ -0,0 +1,202 @@
+use crate::serve::error::{ProxyError, ResponseError};
+use crate::serve::metrics::{STREAMS_INFLIGHT, STREAMS_REJECTED};
+use axum::body::{Bytes, HttpBody};
+use axum::http::{HeaderMap, Method};
+use axum::response::Response;
+use pin_project_lite::pin_project;
+use serde::Deserialize;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::ext::RequestExt;
+
+/// Streaming responses limit, `None` is unlimited
+static STREAMS: OnceLock<Option<Arc<Semaphore>>> = OnceLock::new();
+
+pub(crate) fn init(max_concurrent_streams_inflight: usize) {
+    let _ = STREAMS.set(
+        (max_concurrent_streams_inflight > 0)
+            .then(|| Arc::new(Semaphore::new(max_concurrent_streams_inflight))),
+    );
+}
+
+/// Slot of an active streaming response, released when the response body is dropped
+pub(crate) struct StreamGuard {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        STREAMS_INFLIGHT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Admit a streaming request, `None` for a non-streaming request.
+/// Streaming requests over the limit are rejected with 429.
+pub(crate) fn acquire(req: &RequestExt) -> Result<Option<StreamGuard>, ResponseError> {
+    acquire_from(STREAMS.get().and_then(Option::as_ref), req)
+}
+
+fn acquire_from(
+    streams: Option<&Arc<Semaphore>>,
+    req: &RequestExt,
+) -> Result<Option<StreamGuard>, ResponseError> {
+    if !is_stream_request(req) {
+        return Ok(None);
+    }
+
+    let permit = match streams {
+        Some(streams) => match streams.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                STREAMS_REJECTED.fetch_add(1, Ordering::Relaxed);
+                return Err(ResponseError::TooManyRequests(
+                    ProxyError::TooManyConcurrentStreams,
+                ));
+            }
+        },
+        None => None,
+    };
+
+    STREAMS_INFLIGHT.fetch_add(1, Ordering::Relaxed);
+    Ok(Some(StreamGuard { _permit: permit }))
+}
+
+/// Keep the stream slot until the response body is finished
+pub(crate) fn hold(resp: Response, guard: Option<StreamGuard>) -> Response {
+    self::guard(resp, guard)
+}
+
+/// Keep a guard until the response body is finished
+pub(crate) fn guard<G: Send + 'static>(resp: Response, guard: Option<G>) -> Response {
+    match guard {
+        Some(guard) => resp.map(|body| {
+            axum::body::boxed(GuardedBody {
+                inner: body,
+                _guard: guard,
+            })
+        }),
+        None => resp,
+    }
+}
+
+pub(super) fn is_stream_request(req: &RequestExt) -> bool {
+    #[derive(Deserialize)]
+    struct Stream {
+        #[serde(default)]
+        stream: bool,
+    }
+
+    // ChatGPT conversation responses are always streamed
+    if req.method == Method::POST && req.uri.path().ends_with("/backend-api/conversation") {
+        return true;
+    }
+
+    req.body
+        .as_ref()
+        .and_then(|body| serde_json::from_slice::<Stream>(body).ok())
+        .map_or(false, |body| body.stream)
+}
+
+pin_project! {
+    struct GuardedBody<B, G> {
+        #[pin]
+        inner: B,
+        _guard: G,
+    }
+}
+
+impl<B: HttpBody<Data = Bytes>, G> HttpBody for GuardedBody<B, G> {
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        self.project().inner.poll_data(cx)
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        self.project().inner.poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> axum::body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Uri;
+    use axum_extra::extract::CookieJar;
+
+    fn request(method: Method, path: &'static str, body: Option<&'static str>) -> RequestExt {
+        RequestExt {
+            uri: Uri::from_static(path),
+            method,
+            headers: HeaderMap::new(),
+            jar: CookieJar::new(),
+            body: body.map(Bytes::from_static),
+            reservation: None,
+        }
+    }
+
+    #[test]
+    fn test_is_stream_request() {
+        assert!(is_stream_request(&request(
+            Method::POST,
+            "/v1/chat/completions",
+            Some(r#"{"model":"gpt-3.5-turbo","stream":true}"#)
+        )));
+        assert!(!is_stream_request(&request(
+            Method::POST,
+            "/v1/chat/completions",
+            Some(r#"{"model":"gpt-3.5-turbo"}"#)
+        )));
+        assert!(is_stream_request(&request(
+            Method::POST,
+            "/backend-api/conversation",
+            None
+        )));
+        assert!(!is_stream_request(&request(
+            Method::GET,
+            "/backend-api/conversation/1",
+            None
+        )));
+    }
+
+    #[test]
+    fn test_streams_limit() {
+        let streams = Arc::new(Semaphore::new(1));
+        let stream = || {
+            request(
+                Method::POST,
+                "/v1/chat/completions",
+                Some(r#"{"stream":true}"#),
+            )
+        };
+        let quick = request(Method::GET, "/v1/models", None);
+
+        let first = acquire_from(Some(&streams), &stream()).unwrap();
+        assert!(first.is_some());
+        assert!(acquire_from(Some(&streams), &stream()).is_err());
+
+        // Non-streaming requests are not bound by the streams limit
+        assert!(acquire_from(Some(&streams), &quick).unwrap().is_none());
+
+        drop(first);
+        assert!(acquire_from(Some(&streams), &stream()).unwrap().is_some());
+    }
+}
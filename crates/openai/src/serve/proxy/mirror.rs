@@ -0,0 +1,306 @@
+use crate::context::args::MirrorConfig;
+use crate::secret::Secret;
+use crate::serve::error::{ProxyError, ResponseError};
+use crate::serve::metrics::{
+    MIRROR_DROPPED, MIRROR_ERRORS, MIRROR_LATENCY_MS, MIRROR_REQUESTS, MIRROR_RESPONSES,
+};
+use crate::serve::privacy;
+use crate::{debug, info, with_context};
+use axum::body::Bytes;
+use axum::headers::authorization::Bearer;
+use axum::headers::Authorization;
+use axum::http::{header, HeaderMap, HeaderValue, Method};
+use axum::{Json, TypedHeader};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+use tokio::sync::Semaphore;
+
+use super::ext::RequestExt;
+
+/// Request headers that are never copied to the mirror upstream
+const MIRROR_SKIP_HEADERS: [header::HeaderName; 6] = [
+    header::HOST,
+    header::AUTHORIZATION,
+    header::COOKIE,
+    header::CONTENT_LENGTH,
+    header::CONNECTION,
+    header::TRANSFER_ENCODING,
+];
+
+struct Mirror {
+    config: MirrorConfig,
+    origin: String,
+    inflight: Arc<Semaphore>,
+    /// Kill switch, toggled through `/admin/mirror`
+    enabled: AtomicBool,
+}
+
+/// Mirror upstream, `None` when mirroring is not configured
+static MIRROR: OnceLock<Option<Mirror>> = OnceLock::new();
+
+pub(crate) fn init(config: &MirrorConfig) {
+    let mirror = config.origin.as_ref().map(|origin| {
+        info!("Mirroring {}% of the requests to {origin}", config.percent);
+        Mirror {
+            origin: origin.as_str().trim_end_matches('/').to_owned(),
+            inflight: Arc::new(Semaphore::new(config.max_inflight)),
+            enabled: AtomicBool::new(true),
+            config: config.clone(),
+        }
+    });
+    let _ = MIRROR.set(mirror);
+}
+
+/// Copy a sample of the proxied requests to the mirror upstream.
+/// The copy is fire-and-forget, it never delays or fails the primary request.
+pub(crate) fn spawn(req: &RequestExt) {
+    let mirror = match MIRROR.get().and_then(Option::as_ref) {
+        Some(mirror) if mirror.enabled.load(Ordering::Relaxed) => mirror,
+        _ => return,
+    };
+
+    if !matches(&mirror.config, req) || !sampled(mirror.config.percent, &mut rand::thread_rng()) {
+        return;
+    }
+
+    // Drop the mirror instead of queueing it when the mirror upstream is slow
+    let permit = match mirror.inflight.clone().try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_) => {
+            MIRROR_DROPPED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    };
+
+    let path_and_query = req
+        .uri
+        .path_and_query()
+        .map(|v| v.as_str())
+        .unwrap_or(req.uri.path());
+    let url = format!("{}{path_and_query}", mirror.origin);
+    let headers = mirror_headers(&req.headers, mirror.config.authorization.as_ref());
+    let (method, body) = (req.method.clone(), req.body.clone());
+    let client = with_context!(api_client);
+
+    tokio::spawn(async move {
+        send(client, method, url, headers, body).await;
+        drop(permit);
+    });
+}
+
+/// Send the mirrored request, only the status and latency of the response are recorded
+async fn send(
+    client: reqwest::Client,
+    method: Method,
+    url: String,
+    headers: HeaderMap,
+    body: Option<Bytes>,
+) {
+    MIRROR_REQUESTS.fetch_add(1, Ordering::Relaxed);
+    let mut builder = client.request(method, &url).headers(headers);
+    if let Some(body) = body {
+        builder = builder.body(body);
+    }
+
+    let start = Instant::now();
+    match builder.send().await {
+        Ok(resp) => {
+            MIRROR_LATENCY_MS.fetch_add(start.elapsed().as_millis() as u64, Ordering::Relaxed);
+            let class = (resp.status().as_u16() / 100).clamp(1, 5) as usize;
+            MIRROR_RESPONSES[class - 1].fetch_add(1, Ordering::Relaxed);
+        }
+        Err(err) => {
+            MIRROR_ERRORS.fetch_add(1, Ordering::Relaxed);
+            debug!("Mirror request to {url} failed: {err}");
+        }
+    }
+}
+
+/// Whether the request is on a mirrored route, streaming uploads are never mirrored
+fn matches(config: &MirrorConfig, req: &RequestExt) -> bool {
+    let upload = req
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.starts_with("multipart/"));
+    let path = req.uri.path();
+    !upload
+        && (config.routes.is_empty()
+            || config
+                .routes
+                .iter()
+                .any(|route| path.starts_with(route.as_str())))
+}
+
+fn sampled(percent: f64, rng: &mut impl Rng) -> bool {
+    percent > 0.0 && rng.gen_range(0.0..100.0) < percent
+}
+
+/// Request headers for the mirror upstream, signed with the mirror token
+fn mirror_headers(headers: &HeaderMap, authorization: Option<&Secret>) -> HeaderMap {
+    let mut mirror = headers
+        .iter()
+        .filter(|(name, _)| !MIRROR_SKIP_HEADERS.contains(name))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect::<HeaderMap>();
+    privacy::scrub(&mut mirror);
+    if let Some(token) = authorization {
+        if let Ok(value) = HeaderValue::from_str(&format!("Bearer {}", token.expose())) {
+            mirror.insert(header::AUTHORIZATION, value);
+        }
+    }
+    mirror
+}
+
+#[derive(Serialize)]
+pub(crate) struct MirrorState {
+    configured: bool,
+    enabled: bool,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct MirrorSwitch {
+    enabled: bool,
+}
+
+/// GET /admin/mirror, POST /admin/mirror `{"enabled": false}`
+/// The mirroring kill switch
+pub(crate) async fn admin(
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    switch: Option<Json<MirrorSwitch>>,
+) -> Result<Json<MirrorState>, ResponseError> {
+    // Require auth key
+    if let Some(auth_key) = with_context!(auth_key) {
+        let bearer =
+            bearer.ok_or_else(|| ResponseError::Unauthorized(ProxyError::AuthKeyRequired))?;
+        if auth_key.ne(bearer.token()) {
+            return Err(ResponseError::Forbidden(ProxyError::AuthKeyError));
+        }
+    }
+
+    let mirror = MIRROR.get().and_then(Option::as_ref);
+    if let (Some(mirror), Some(Json(switch))) = (mirror, switch) {
+        mirror.enabled.store(switch.enabled, Ordering::Relaxed);
+        info!(
+            "Mirroring {}",
+            if switch.enabled {
+                "enabled"
+            } else {
+                "disabled"
+            }
+        );
+    }
+
+    Ok(Json(MirrorState {
+        configured: mirror.is_some(),
+        enabled: mirror.map_or(false, |mirror| mirror.enabled.load(Ordering::Relaxed)),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Uri;
+    use axum_extra::extract::CookieJar;
+    use rand::SeedableRng;
+    use std::str::FromStr;
+
+    fn request(path: &str, content_type: &str) -> RequestExt {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_str(content_type).unwrap(),
+        );
+        RequestExt {
+            uri: Uri::from_str(path).unwrap(),
+            method: Method::POST,
+            headers,
+            jar: CookieJar::new(),
+            body: Some(Bytes::from_static(b"{}")),
+            reservation: None,
+        }
+    }
+
+    #[test]
+    fn test_sampling_rate() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        for percent in [1.0, 10.0, 50.0] {
+            let hits = (0..100_000).filter(|_| sampled(percent, &mut rng)).count();
+            let rate = hits as f64 / 1000.0;
+            assert!((rate - percent).abs() < 0.5, "{percent}%: {rate}%");
+        }
+        assert!(!(0..10_000).any(|_| sampled(0.0, &mut rng)));
+        assert!((0..10_000).all(|_| sampled(100.0, &mut rng)));
+    }
+
+    #[test]
+    fn test_route_filter() {
+        let config = MirrorConfig {
+            routes: vec!["/v1/chat/".to_owned()],
+            ..Default::default()
+        };
+        assert!(matches(
+            &config,
+            &request("/v1/chat/completions", "application/json")
+        ));
+        assert!(!matches(
+            &config,
+            &request("/v1/models", "application/json")
+        ));
+        assert!(!matches(
+            &config,
+            &request("/v1/chat/completions", "multipart/form-data; boundary=x")
+        ));
+        assert!(matches(
+            &MirrorConfig::default(),
+            &request("/backend-api/models", "application/json")
+        ));
+    }
+
+    #[test]
+    fn test_mirror_headers_resigned() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer primary"),
+        );
+        headers.insert(header::COOKIE, HeaderValue::from_static("_puid=primary"));
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+
+        let mirror = mirror_headers(&headers, Some(&Secret::new("mirror")));
+        assert_eq!(mirror[header::AUTHORIZATION], "Bearer mirror");
+        assert!(mirror.get(header::COOKIE).is_none());
+        assert_eq!(mirror[header::CONTENT_TYPE], "application/json");
+
+        // The primary credentials never reach the mirror upstream
+        let mirror = mirror_headers(&headers, None);
+        assert!(mirror.get(header::AUTHORIZATION).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_mirror_failure_is_recorded() {
+        // Nothing listens on the port once the listener is dropped
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let errors = MIRROR_ERRORS.load(Ordering::Relaxed);
+
+        send(
+            reqwest::Client::new(),
+            Method::POST,
+            format!("http://{addr}/v1/chat/completions"),
+            HeaderMap::new(),
+            Some(Bytes::from_static(b"{}")),
+        )
+        .await;
+
+        assert_eq!(MIRROR_ERRORS.load(Ordering::Relaxed), errors + 1);
+    }
+}
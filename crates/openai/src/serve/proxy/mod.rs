@@ -1,11 +1,24 @@
+pub(crate) mod adapt;
+pub(crate) mod body_transform;
+pub(crate) mod cookie;
+pub(crate) mod egress;
 pub mod ext;
+pub(crate) mod inflight;
+pub(crate) mod mirror;
+pub(crate) mod model_name;
+pub(crate) mod redact;
 pub mod req;
 pub mod resp;
+pub(crate) mod retry;
+pub(crate) mod sse;
+pub(crate) mod tag;
 mod toapi;
 
 use super::error::ResponseError;
+use super::privacy;
 use crate::constant::CF_CLEARANCE;
 use crate::constant::PUID;
+use crate::context::args::CookieMode;
 use crate::debug;
 use axum::http::header;
 use axum::http::HeaderMap;
@@ -85,16 +98,25 @@ pub(crate) fn header_convert(
     headers.insert(header::ORIGIN, header::HeaderValue::from_static(origin));
     headers.insert(header::REFERER, header::HeaderValue::from_static(origin));
 
-    jar.iter()
-        .filter(|c| {
-            let name = c.name().to_lowercase();
-            name.eq(PUID) || name.eq(CF_CLEARANCE)
-        })
-        .for_each(|c| {
-            let c = format!("{}={}", c.name(), cookie_encoded(c.value()));
-            debug!("cookie: {}", c);
-            cookies.push(c);
-        });
+    match cookie::mode() {
+        CookieMode::Passthrough => jar
+            .iter()
+            .filter(|c| {
+                let name = c.name().to_lowercase();
+                name.eq(PUID) || name.eq(CF_CLEARANCE)
+            })
+            .for_each(|c| {
+                let c = format!("{}={}", c.name(), cookie_encoded(c.value()));
+                debug!("cookie: {}", c);
+                cookies.push(c);
+            }),
+        CookieMode::Jar => {
+            if let Some(session) = cookie::session(h) {
+                cookies.extend(cookie::cookies(&session, origin));
+            }
+        }
+        CookieMode::Strip => {}
+    }
 
     // setting cookie
     if !cookies.is_empty() {
@@ -104,6 +126,17 @@ pub(crate) fn header_convert(
                 .map_err(ResponseError::InternalServerError)?,
         );
     }
+
+    // A response to redact must come unencoded
+    if redact::enabled() {
+        headers.insert(
+            header::ACCEPT_ENCODING,
+            header::HeaderValue::from_static("identity"),
+        );
+    }
+
+    privacy::scrub(&mut headers);
+    tag::apply(h, &mut headers);
     Ok(headers)
 }
 
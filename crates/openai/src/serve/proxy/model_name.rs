@@ -0,0 +1,341 @@
+//! Requested model normalization (`model_aliases`, `model_allowlist`). The model named
+//! by a request body, the `model` field of a JSON body or of a multipart form (audio
+//! transcriptions and translations), is rewritten from an alias to its canonical name,
+//! then checked against the allowlist before the request is forwarded, so the model
+//! policies and budgets see the canonical name.
+
+use super::ext::RequestExt;
+use crate::serve::model_policy::glob_match;
+use crate::{debug, info};
+use axum::body::Bytes;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::OnceLock;
+
+static MODEL_NAMES: OnceLock<ModelNames> = OnceLock::new();
+
+struct ModelNames {
+    /// Alias -> canonical name
+    aliases: BTreeMap<String, String>,
+    /// Model name patterns, `*` matches any characters and `?` a single one, all models if empty
+    allowlist: Vec<String>,
+}
+
+pub(crate) fn init(aliases: &BTreeMap<String, String>, allowlist: &[String]) -> anyhow::Result<()> {
+    if aliases.is_empty() && allowlist.is_empty() {
+        return Ok(());
+    }
+    let names = ModelNames {
+        aliases: aliases.clone(),
+        allowlist: allowlist.to_vec(),
+    };
+    names.validate()?;
+    info!(
+        "Model names: {} aliases, {} allowed patterns",
+        names.aliases.len(),
+        names.allowlist.len()
+    );
+    let _ = MODEL_NAMES.set(names);
+    Ok(())
+}
+
+/// Rewrite the requested model alias to its canonical name. Returns the 400 response
+/// of a model the allowlist does not allow.
+pub(crate) fn normalize(req: &mut RequestExt) -> Result<(), Response> {
+    match MODEL_NAMES.get() {
+        Some(names) => names.normalize(req),
+        None => Ok(()),
+    }
+}
+
+impl ModelNames {
+    /// Every alias must resolve to an allowed model
+    fn validate(&self) -> anyhow::Result<()> {
+        for (alias, canonical) in &self.aliases {
+            if alias.is_empty() || canonical.is_empty() {
+                anyhow::bail!(
+                    "Invalid model alias `{alias}` = `{canonical}`, names must not be empty"
+                )
+            }
+            if !self.allows(canonical) {
+                anyhow::bail!(
+                    "Model alias `{alias}` maps to `{canonical}`, which model_allowlist does not allow"
+                )
+            }
+        }
+        Ok(())
+    }
+
+    fn allows(&self, model: &str) -> bool {
+        self.allowlist.is_empty()
+            || self
+                .allowlist
+                .iter()
+                .any(|pattern| glob_match(pattern, model))
+    }
+
+    /// Canonical name of a model, `Err` when it is not allowed
+    fn resolve<'a>(&'a self, model: &'a str) -> Result<&'a str, ()> {
+        let canonical = self.aliases.get(model).map_or(model, String::as_str);
+        match self.allows(canonical) {
+            true => Ok(canonical),
+            false => Err(()),
+        }
+    }
+
+    fn normalize(&self, req: &mut RequestExt) -> Result<(), Response> {
+        match multipart_boundary(&req.headers) {
+            Some(boundary) => self.normalize_multipart(req, &boundary),
+            None => self.normalize_json(req),
+        }
+    }
+
+    fn normalize_json(&self, req: &mut RequestExt) -> Result<(), Response> {
+        let mut body = match req
+            .body
+            .as_ref()
+            .and_then(|body| serde_json::from_slice::<Map<String, Value>>(body).ok())
+        {
+            Some(body) => body,
+            None => return Ok(()),
+        };
+        let model = match body.get("model").and_then(Value::as_str) {
+            Some(model) => model,
+            None => return Ok(()),
+        };
+
+        let canonical = match self.resolve(model) {
+            Ok(canonical) if canonical == model => return Ok(()),
+            Ok(canonical) => canonical.to_owned(),
+            Err(()) => {
+                debug!("Model `{model}` is not in the model allowlist");
+                return Err(unknown_model_response(model));
+            }
+        };
+        debug!("Model alias `{model}` rewritten to `{canonical}`");
+        body.insert("model".to_owned(), Value::String(canonical));
+        if let Ok(body) = serde_json::to_vec(&body) {
+            req.body = Some(Bytes::from(body));
+            req.headers.remove(header::CONTENT_LENGTH);
+        }
+        Ok(())
+    }
+
+    /// Every `model` field of a multipart form body is checked and rewritten
+    fn normalize_multipart(&self, req: &mut RequestExt, boundary: &str) -> Result<(), Response> {
+        let body = match req.body.clone() {
+            Some(body) => body,
+            None => return Ok(()),
+        };
+        let mut rewritten = None;
+        // The last field first, the ranges before it stay valid
+        for range in form_fields(&body, boundary, "model").into_iter().rev() {
+            let model = String::from_utf8_lossy(&body[range.clone()]);
+            match self.resolve(&model) {
+                Ok(canonical) if canonical == model => {}
+                Ok(canonical) => {
+                    debug!("Model alias `{model}` rewritten to `{canonical}`");
+                    rewritten
+                        .get_or_insert_with(|| body.to_vec())
+                        .splice(range, canonical.bytes());
+                }
+                Err(()) => {
+                    debug!("Model `{model}` is not in the model allowlist");
+                    return Err(unknown_model_response(&model));
+                }
+            }
+        }
+        if let Some(body) = rewritten {
+            req.body = Some(Bytes::from(body));
+            req.headers.remove(header::CONTENT_LENGTH);
+        }
+        Ok(())
+    }
+}
+
+/// Boundary of a `multipart/form-data` request body
+fn multipart_boundary(headers: &HeaderMap) -> Option<String> {
+    let mime = headers
+        .get(header::CONTENT_TYPE)?
+        .to_str()
+        .ok()?
+        .parse::<mime::Mime>()
+        .ok()?;
+    if mime.type_() != mime::MULTIPART || mime.subtype() != mime::FORM_DATA {
+        return None;
+    }
+    mime.get_param(mime::BOUNDARY)
+        .map(|boundary| boundary.as_str().to_owned())
+}
+
+/// Value ranges of the fields named `name` in a multipart form body
+fn form_fields(body: &[u8], boundary: &str, name: &str) -> Vec<Range<usize>> {
+    let delimiter = format!("--{boundary}");
+    let delimiter = delimiter.as_bytes();
+    let starts = body
+        .windows(delimiter.len())
+        .enumerate()
+        .filter(|(_, window)| *window == delimiter)
+        .map(|(start, _)| start)
+        .collect::<Vec<_>>();
+
+    let mut fields = Vec::new();
+    for part in starts.windows(2) {
+        let (start, end) = (part[0] + delimiter.len(), part[1]);
+        // Headers, a blank line, then the value up to the CRLF of the next delimiter
+        let head_len = match body[start..end].windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(head_len) => head_len,
+            None => continue,
+        };
+        let head = String::from_utf8_lossy(&body[start..start + head_len]);
+        if !head.lines().any(|line| names_field(line, name)) {
+            continue;
+        }
+        let value_start = start + head_len + 4;
+        let value_end = match body[..end].ends_with(b"\r\n") {
+            true => end - 2,
+            false => end,
+        };
+        fields.push(value_start..value_end.max(value_start));
+    }
+    fields
+}
+
+/// Whether a part header line is the `Content-Disposition` of the field `name`
+fn names_field(line: &str, name: &str) -> bool {
+    let (header, value) = match line.split_once(':') {
+        Some(header) => header,
+        None => return false,
+    };
+    header.trim().eq_ignore_ascii_case("content-disposition")
+        && value.split(';').skip(1).any(|param| {
+            param.split_once('=').map_or(false, |(key, value)| {
+                key.trim().eq_ignore_ascii_case("name") && value.trim().trim_matches('"') == name
+            })
+        })
+}
+
+/// 400 response for a model the allowlist does not allow
+fn unknown_model_response(model: &str) -> Response {
+    let body = serde_json::json!({
+        "error": {
+            "message": format!("The model `{model}` does not exist or is not available"),
+            "type": "invalid_request_error",
+            "param": "model",
+            "code": "model_not_available"
+        }
+    });
+    (StatusCode::BAD_REQUEST, Json(body)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{HeaderMap, Method, Uri};
+    use axum_extra::extract::CookieJar;
+
+    fn names() -> ModelNames {
+        ModelNames {
+            aliases: BTreeMap::from([
+                ("gpt-4-0314".to_owned(), "gpt-4o".to_owned()),
+                ("fast".to_owned(), "gpt-4o-mini".to_owned()),
+            ]),
+            allowlist: vec!["gpt-4o*".to_owned(), "o1".to_owned()],
+        }
+    }
+
+    fn request(body: &str) -> RequestExt {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_LENGTH, body.len().into());
+        RequestExt {
+            uri: Uri::from_static("/v1/chat/completions"),
+            method: Method::POST,
+            headers,
+            jar: CookieJar::new(),
+            body: Some(Bytes::from(body.to_owned())),
+            reservation: None,
+        }
+    }
+
+    fn model(req: &RequestExt) -> Value {
+        serde_json::from_slice::<Value>(req.body.as_ref().unwrap()).unwrap()["model"].clone()
+    }
+
+    #[test]
+    fn test_alias_rewritten() {
+        let mut req = request(r#"{"model":"fast","stream":true}"#);
+        assert!(names().normalize(&mut req).is_ok());
+        assert_eq!(model(&req), "gpt-4o-mini");
+        assert!(req.headers.get(header::CONTENT_LENGTH).is_none());
+
+        // Canonical names are forwarded untouched
+        let body = r#"{"model":"o1","stream":true}"#;
+        let mut req = request(body);
+        assert!(names().normalize(&mut req).is_ok());
+        assert_eq!(req.body.as_deref(), Some(body.as_bytes()));
+        assert!(req.headers.get(header::CONTENT_LENGTH).is_some());
+    }
+
+    #[test]
+    fn test_unknown_model_rejected() {
+        let mut req = request(r#"{"model":"gpt-3.5-turbo"}"#);
+        let resp = names().normalize(&mut req).unwrap_err();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // Requests without a model name are left to the upstream
+        let mut req = request(r#"{"input":"text"}"#);
+        assert!(names().normalize(&mut req).is_ok());
+    }
+
+    fn form(model: &str) -> RequestExt {
+        let body = format!(
+            "--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.mp3\"\r\n\
+             Content-Type: audio/mpeg\r\n\r\nname=\"model\"\r\n\
+             --XyZ\r\ncontent-disposition: form-data; name=\"model\"\r\n\r\n{model}\r\n\
+             --XyZ--\r\n"
+        );
+        let mut req = request(&body);
+        req.headers.insert(
+            header::CONTENT_TYPE,
+            "multipart/form-data; boundary=XyZ".parse().unwrap(),
+        );
+        req
+    }
+
+    #[test]
+    fn test_multipart_model() {
+        let mut names = names();
+        names.allowlist.push("whisper-1".to_owned());
+        names
+            .aliases
+            .insert("stt".to_owned(), "whisper-1".to_owned());
+
+        let mut req = form("whisper-1");
+        let body = req.body.clone();
+        assert!(names.normalize(&mut req).is_ok());
+        assert_eq!(req.body, body);
+
+        // The alias is rewritten in place, the file part is untouched
+        let mut req = form("stt");
+        assert!(names.normalize(&mut req).is_ok());
+        assert_eq!(req.body, form("whisper-1").body);
+        assert!(req.headers.get(header::CONTENT_LENGTH).is_none());
+
+        let mut req = form("gpt-3.5-turbo");
+        let resp = names.normalize(&mut req).unwrap_err();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_alias_to_disallowed_model() {
+        let mut names = names();
+        names
+            .aliases
+            .insert("legacy".to_owned(), "gpt-3.5-turbo".to_owned());
+        assert!(names.validate().is_err());
+    }
+}
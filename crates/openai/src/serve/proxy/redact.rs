@@ -0,0 +1,677 @@
+use crate::context::args::RedactConfig;
+use crate::serve::error::ResponseError;
+use crate::serve::memory::{self, Reservation};
+use crate::{debug, info, warn};
+use aho_corasick::{AhoCorasick, MatchKind};
+use axum::body::{Bytes, HttpBody};
+use axum::http::{header, HeaderMap};
+use axum::response::Response;
+use pin_project_lite::pin_project;
+use regex::bytes::Regex;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+
+/// Completion text fields of a choice of an event, a chat delta or a completion text
+const TEXT_FIELDS: [&str; 2] = ["/delta/content", "/text"];
+
+/// Events longer than this are released without their text joined to the next events
+const MAX_EVENT: usize = 1024 * 1024;
+
+/// Response redactor, `None` when nothing is configured
+static REDACTOR: OnceLock<Option<Redactor>> = OnceLock::new();
+
+pub(crate) fn init(config: &RedactConfig) -> anyhow::Result<()> {
+    let redactor = if config.is_empty() {
+        None
+    } else {
+        info!(
+            "Response redaction: {} literals, {} patterns",
+            config.literals.len(),
+            config.patterns.len()
+        );
+        Some(Redactor::new(config)?)
+    };
+    let _ = REDACTOR.set(redactor);
+    Ok(())
+}
+
+/// Whether the responses are redacted, the upstream is then asked for unencoded bodies
+pub(crate) fn enabled() -> bool {
+    matches!(REDACTOR.get(), Some(Some(_)))
+}
+
+struct Redactor {
+    literals: Option<AhoCorasick>,
+    patterns: Option<Regex>,
+    mask: Vec<u8>,
+    /// Bytes of the last line held back by the stream scanner
+    window: usize,
+}
+
+impl Redactor {
+    fn new(config: &RedactConfig) -> anyhow::Result<Self> {
+        config.validate()?;
+        let literals = if config.literals.is_empty() {
+            None
+        } else {
+            Some(
+                AhoCorasick::builder()
+                    .match_kind(MatchKind::LeftmostLongest)
+                    .build(&config.literals)?,
+            )
+        };
+        let patterns = if config.patterns.is_empty() {
+            None
+        } else {
+            let combined = config
+                .patterns
+                .iter()
+                .map(|pattern| format!("(?:{pattern})"))
+                .collect::<Vec<_>>()
+                .join("|");
+            Some(Regex::new(&combined)?)
+        };
+        let window = config
+            .literals
+            .iter()
+            .map(String::len)
+            .max()
+            .unwrap_or_default()
+            .max(config.max_pattern_len);
+        Ok(Self {
+            literals,
+            patterns,
+            mask: config.mask.as_bytes().to_vec(),
+            window,
+        })
+    }
+
+    /// Non-overlapping match spans, sorted. Matches never span a line.
+    fn spans(&self, haystack: &[u8]) -> Vec<(usize, usize)> {
+        let mut matches = Vec::new();
+        if let Some(literals) = &self.literals {
+            matches.extend(literals.find_iter(haystack).map(|m| (m.start(), m.end())));
+        }
+        if let Some(patterns) = &self.patterns {
+            matches.extend(patterns.find_iter(haystack).map(|m| (m.start(), m.end())));
+        }
+        matches.retain(|&(start, end)| start < end && !haystack[start..end].contains(&b'\n'));
+        matches.sort_unstable_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+
+        let mut spans: Vec<(usize, usize)> = Vec::with_capacity(matches.len());
+        for (start, end) in matches {
+            match spans.last_mut() {
+                Some(last) if start < last.1 => last.1 = last.1.max(end),
+                _ => spans.push((start, end)),
+            }
+        }
+        spans
+    }
+
+    /// Replace the spans ending before `cut`
+    fn replace(&self, data: &[u8], spans: &[(usize, usize)], cut: usize) -> (Vec<u8>, usize) {
+        let mut out = Vec::with_capacity(cut);
+        let mut pos = 0;
+        let mut count = 0;
+        for &(start, end) in spans.iter().take_while(|span| span.1 <= cut) {
+            out.extend_from_slice(&data[pos..start]);
+            out.extend_from_slice(&self.mask);
+            pos = end;
+            count += 1;
+        }
+        out.extend_from_slice(&data[pos..cut]);
+        (out, count)
+    }
+
+    /// Redact a whole body
+    fn redact(&self, data: &[u8]) -> (Vec<u8>, usize) {
+        self.replace(data, &self.spans(data), data.len())
+    }
+
+    /// Redact the part of a stream that no later chunk can complete a match of, and
+    /// remove it from `pending`. The complete lines are released, the last line is
+    /// held back by up to `window` bytes. Everything is released at the end.
+    fn scan(&self, pending: &mut Vec<u8>, eof: bool) -> (Vec<u8>, usize) {
+        let len = pending.len();
+        let mut cut = if eof {
+            len
+        } else {
+            let line = pending
+                .iter()
+                .rposition(|&b| b == b'\n')
+                .map_or(0, |pos| pos + 1);
+            let mut cut = line.max(len.saturating_sub(self.window));
+            // Never split a UTF-8 character
+            while cut > 0 && cut < len && (pending[cut] & 0xC0) == 0x80 {
+                cut -= 1;
+            }
+            cut
+        };
+
+        let spans = self.spans(pending);
+        if let Some(&(_, end)) = spans.iter().take_while(|span| span.0 < cut).last() {
+            cut = cut.max(end);
+        }
+
+        let redacted = self.replace(pending, &spans, cut);
+        pending.drain(..cut);
+        redacted
+    }
+}
+
+/// Redact the body of an upstream response. Event streams are redacted as they are
+/// forwarded, JSON and text bodies are buffered.
+pub(crate) async fn apply(resp: Response) -> Result<Response, ResponseError> {
+    let redactor = match REDACTOR.get() {
+        Some(Some(redactor)) => redactor,
+        _ => return Ok(resp),
+    };
+
+    let content_type = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let event_stream = content_type.starts_with(mime::TEXT_EVENT_STREAM.as_ref());
+    if !event_stream
+        && !content_type.starts_with(mime::APPLICATION_JSON.as_ref())
+        && !content_type.starts_with("text/")
+    {
+        return Ok(resp);
+    }
+
+    // An encoded body cannot be matched, it is never forwarded unredacted
+    if is_encoded(resp.headers()) {
+        warn!("Response refused, the upstream body to redact is encoded");
+        return Err(ResponseError::BadGateway(anyhow::anyhow!(
+            "The upstream response is encoded and cannot be redacted"
+        )));
+    }
+
+    if event_stream {
+        return Ok(resp.map(|body| {
+            axum::body::boxed(RedactedBody {
+                inner: body,
+                redactor,
+                events: Events::new(),
+                pending: Vec::new(),
+                count: 0,
+                done: false,
+            })
+        }));
+    }
+
+    let (mut parts, body) = resp.into_parts();
+    let (data, reservation) = memory::read(body).await?;
+    let (data, count) = redactor.redact(&data);
+    if count > 0 {
+        info!("Redacted {count} matches in the response");
+        parts.headers.remove(header::CONTENT_LENGTH);
+    }
+
+    Ok(Response::from_parts(parts, memory::body(data, reservation)))
+}
+
+fn is_encoded(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| !v.eq_ignore_ascii_case("identity"))
+}
+
+/// Events of a stream held back while their completion text could start a match
+/// that a later event completes. The text of the choices is joined across the events,
+/// so a match split over several deltas is masked too.
+struct Events {
+    /// Bytes of the incomplete event
+    partial: Vec<u8>,
+    held: VecDeque<Event>,
+    /// Bytes of the incomplete and held back events in the buffered memory budget
+    reservation: Reservation,
+}
+
+impl Events {
+    fn new() -> Self {
+        Self {
+            partial: Vec::new(),
+            held: VecDeque::new(),
+            reservation: Reservation::new(),
+        }
+    }
+
+    /// Add a chunk of the stream, returns the released events and the redactions.
+    /// Over the buffered memory limit the held back events are released as at the
+    /// end, without their text joined to the next events.
+    fn push(&mut self, redactor: &Redactor, chunk: &[u8], eof: bool) -> (Vec<u8>, usize) {
+        self.partial.extend_from_slice(chunk);
+        while let Some(end) = event_end(&self.partial) {
+            let raw = self.partial.drain(..end).collect();
+            self.held.push_back(Event::parse(raw));
+        }
+        if (eof && !self.partial.is_empty()) || self.partial.len() > MAX_EVENT {
+            self.held
+                .push_back(Event::parse(std::mem::take(&mut self.partial)));
+        }
+        let (mut out, mut count) = self.release(redactor, eof);
+        if !self.reserve() {
+            debug!("Redaction: held back events over the buffered memory limit, released");
+            let (rest, more) = self.release(redactor, true);
+            out.extend_from_slice(&rest);
+            count += more;
+            self.reserve();
+        }
+        (out, count)
+    }
+
+    /// Reserve the bytes of the incomplete and held back events, `false` when they
+    /// do not fit the budget
+    fn reserve(&mut self) -> bool {
+        let len = self.partial.len() + self.held.iter().map(|event| event.raw.len()).sum::<usize>();
+        self.reservation.shrink(len);
+        self.reservation.fit(len)
+    }
+
+    /// Mask the matches of the joined text that start before the held back window,
+    /// and release the events ending before it. Everything is released at the end.
+    fn release(&mut self, redactor: &Redactor, eof: bool) -> (Vec<u8>, usize) {
+        let mut text = Vec::new();
+        let mut ends = Vec::with_capacity(self.held.len());
+        for event in &self.held {
+            for (_, field) in &event.texts {
+                text.extend_from_slice(field.as_bytes());
+            }
+            ends.push(text.len());
+        }
+
+        let mut cut = if eof {
+            text.len()
+        } else {
+            text.len().saturating_sub(redactor.window)
+        };
+        let spans = redactor
+            .spans(&text)
+            .into_iter()
+            .take_while(|span| span.0 < cut)
+            .collect::<Vec<_>>();
+        if let Some(&(_, end)) = spans.last() {
+            cut = cut.max(end);
+            self.mask(&spans, &redactor.mask);
+        }
+
+        let released = ends.iter().take_while(|&&end| end <= cut).count();
+        let out = self
+            .held
+            .drain(..released)
+            .flat_map(Event::into_bytes)
+            .collect();
+        (out, spans.len())
+    }
+
+    /// Replace the spans of the joined text, the mask goes to the field a span starts in
+    fn mask(&mut self, spans: &[(usize, usize)], mask: &[u8]) {
+        let mut offset = 0;
+        for event in &mut self.held {
+            for (_, field) in &mut event.texts {
+                let (start, end) = (offset, offset + field.len());
+                offset = end;
+                let bytes = field.as_bytes();
+                let mut out = Vec::with_capacity(bytes.len());
+                let mut pos = 0;
+                for &(span_start, span_end) in
+                    spans.iter().filter(|span| span.0 < end && span.1 > start)
+                {
+                    let from = span_start.max(start) - start;
+                    out.extend_from_slice(&bytes[pos..from]);
+                    if span_start >= start {
+                        out.extend_from_slice(mask);
+                    }
+                    pos = span_end.min(end) - start;
+                }
+                if pos > 0 {
+                    out.extend_from_slice(&bytes[pos..]);
+                    *field = String::from_utf8_lossy(&out).into_owned();
+                    event.modified = true;
+                }
+            }
+        }
+    }
+}
+
+/// End of the first complete event, after its blank line
+fn event_end(data: &[u8]) -> Option<usize> {
+    data.windows(2).enumerate().find_map(|(i, w)| match w {
+        b"\n\n" => Some(i + 2),
+        b"\n\r" if data.get(i + 2) == Some(&b'\n') => Some(i + 3),
+        _ => None,
+    })
+}
+
+struct Event {
+    raw: Vec<u8>,
+    /// Range of the `data:` JSON in `raw`, and the JSON
+    data: Option<(Range<usize>, Value)>,
+    /// Completion text fields, by JSON pointer
+    texts: Vec<(String, String)>,
+    modified: bool,
+}
+
+impl Event {
+    fn parse(raw: Vec<u8>) -> Self {
+        let mut event = Self {
+            raw,
+            data: None,
+            texts: Vec::new(),
+            modified: false,
+        };
+        let mut start = 0;
+        for line in event.raw.split(|&b| b == b'\n') {
+            if let Some(payload) = line.strip_prefix(b"data:") {
+                let offset = start + 5 + usize::from(payload.first() == Some(&b' '));
+                let end = start + line.len() - usize::from(line.last() == Some(&b'\r'));
+                let range = offset.min(end)..end;
+                if let Ok(json) = serde_json::from_slice::<Value>(&event.raw[range.clone()]) {
+                    let choices = json
+                        .get("choices")
+                        .and_then(Value::as_array)
+                        .map_or(0, Vec::len);
+                    for choice in 0..choices {
+                        for field in TEXT_FIELDS {
+                            let pointer = format!("/choices/{choice}{field}");
+                            if let Some(text) = json.pointer(&pointer).and_then(Value::as_str) {
+                                event.texts.push((pointer, text.to_owned()));
+                            }
+                        }
+                    }
+                    event.data = Some((range, json));
+                }
+                break;
+            }
+            start += line.len() + 1;
+        }
+        event
+    }
+
+    /// The event as received, or with its masked text
+    fn into_bytes(self) -> Vec<u8> {
+        match self.data {
+            Some((range, mut json)) if self.modified => {
+                for (pointer, text) in self.texts {
+                    if let Some(value) = json.pointer_mut(&pointer) {
+                        *value = Value::String(text);
+                    }
+                }
+                let mut out = self.raw[..range.start].to_vec();
+                out.extend_from_slice(json.to_string().as_bytes());
+                out.extend_from_slice(&self.raw[range.end..]);
+                out
+            }
+            _ => self.raw,
+        }
+    }
+}
+
+pin_project! {
+    /// Event stream body with the matches redacted, a match split across chunks
+    /// or across the completion deltas of several events included
+    struct RedactedBody<B> {
+        #[pin]
+        inner: B,
+        redactor: &'static Redactor,
+        events: Events,
+        pending: Vec<u8>,
+        count: usize,
+        done: bool,
+    }
+}
+
+impl<B: HttpBody<Data = Bytes>> HttpBody for RedactedBody<B> {
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let mut this = self.project();
+        if *this.done {
+            return Poll::Ready(None);
+        }
+
+        loop {
+            match this.inner.as_mut().poll_data(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    let (events, count) = this.events.push(this.redactor, &chunk, false);
+                    *this.count += count;
+                    this.pending.extend_from_slice(&events);
+                    let (out, count) = this.redactor.scan(this.pending, false);
+                    *this.count += count;
+                    if !out.is_empty() {
+                        return Poll::Ready(Some(Ok(Bytes::from(out))));
+                    }
+                }
+                // The held back bytes are dropped, never sent unredacted
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                Poll::Ready(None) => {
+                    *this.done = true;
+                    let (events, count) = this.events.push(this.redactor, &[], true);
+                    *this.count += count;
+                    this.pending.extend_from_slice(&events);
+                    let (out, count) = this.redactor.scan(this.pending, true);
+                    *this.count += count;
+                    if *this.count > 0 {
+                        info!("Redacted {} matches in the response stream", this.count);
+                    }
+                    if out.is_empty() {
+                        return Poll::Ready(None);
+                    }
+                    return Poll::Ready(Some(Ok(Bytes::from(out))));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        self.project().inner.poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::StreamBody;
+    use std::convert::Infallible;
+
+    fn redactor(literals: &[&str], patterns: &[&str]) -> &'static Redactor {
+        let config = RedactConfig {
+            literals: literals.iter().map(|s| s.to_string()).collect(),
+            patterns: patterns.iter().map(|s| s.to_string()).collect(),
+            max_pattern_len: 16,
+            ..Default::default()
+        };
+        Box::leak(Box::new(Redactor::new(&config).unwrap()))
+    }
+
+    async fn stream(redactor: &'static Redactor, chunks: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        let chunks = futures::stream::iter(
+            chunks
+                .into_iter()
+                .map(|chunk| Ok::<_, Infallible>(Bytes::from(chunk))),
+        );
+        let mut body = Box::pin(RedactedBody {
+            inner: StreamBody::new(chunks),
+            redactor,
+            events: Events::new(),
+            pending: Vec::new(),
+            count: 0,
+            done: false,
+        });
+        let mut out = Vec::new();
+        while let Some(chunk) = body.data().await {
+            out.push(chunk.unwrap().to_vec());
+        }
+        out
+    }
+
+    #[test]
+    fn test_redact_body() {
+        let redactor = redactor(&["internal-host", "secret"], &[r"sk-[a-z0-9]{6}"]);
+        let body = br#"{"content":"the secret of internal-host is sk-abc123, no secrets"}"#;
+        let (out, count) = redactor.redact(body);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"{"content":"the [REDACTED] of [REDACTED] is [REDACTED], no [REDACTED]s"}"#
+        );
+        assert_eq!(count, 4);
+
+        // Overlapping matches are masked once
+        let redactor = self::redactor(&["secret"], &["cret-key"]);
+        let (out, count) = redactor.redact(b"a secret-key");
+        assert_eq!(out, b"a [REDACTED]");
+        assert_eq!(count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_match_split_across_chunks() {
+        let redactor = redactor(&["secret"], &[r"sk-[a-z0-9]{6}"]);
+        let event = b"data: {\"content\":\"my secret is sk-abc123 ok\"}\n\n".repeat(2);
+        let (expected, _) = redactor.redact(&event);
+
+        for at in 0..=event.len() {
+            let chunks = vec![event[..at].to_vec(), event[at..].to_vec()];
+            let out = stream(redactor, chunks).await.concat();
+            assert_eq!(out, expected, "split at {at}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_utf8_boundaries() {
+        let redactor = redactor(&["秘密"], &[]);
+        let event = "data: {\"content\":\"这是秘密，别说\"}\n\n"
+            .as_bytes()
+            .to_vec();
+
+        for at in 0..=event.len() {
+            let chunks = vec![event[..at].to_vec(), event[at..].to_vec()];
+            let out = stream(redactor, chunks).await;
+            for chunk in &out {
+                assert!(std::str::from_utf8(chunk).is_ok(), "split at {at}");
+            }
+            assert_eq!(
+                String::from_utf8(out.concat()).unwrap(),
+                "data: {\"content\":\"这是[REDACTED]，别说\"}\n\n"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_event_framing() {
+        let redactor = redactor(&["secret"], &[r"\d{4}-\d{4}"]);
+        let events: Vec<Vec<u8>> = [
+            "data: {\"delta\":\"a secret\"}\n\n",
+            "data: {\"delta\":\"1234-5678\"}\n\n",
+            "data: {\"delta\":\"se",
+            "cret\"}\n\ndata: [DONE]\n\n",
+        ]
+        .iter()
+        .map(|event| event.as_bytes().to_vec())
+        .collect();
+        let input = events.concat();
+
+        let out = String::from_utf8(stream(redactor, events).await.concat()).unwrap();
+        assert_eq!(
+            out.matches("\n\n").count(),
+            String::from_utf8(input).unwrap().matches("\n\n").count()
+        );
+        assert!(!out.contains("secret") && !out.contains("1234-5678"));
+        assert!(out.ends_with("data: [DONE]\n\n"));
+    }
+
+    #[tokio::test]
+    async fn test_textless_events_not_held() {
+        let redactor = redactor(&["secret"], &[]);
+        let events: Vec<Vec<u8>> = (0..64)
+            .map(|i| format!("event: ping\ndata: {{\"id\":{i},\"choices\":[]}}\n\n").into_bytes())
+            .collect();
+
+        // Each event is released as it arrives, nothing is held back
+        let mut held = Events::new();
+        for event in &events {
+            let (out, count) = held.push(redactor, event, false);
+            assert_eq!(&out, event);
+            assert_eq!(count, 0);
+            assert!(held.held.is_empty() && held.partial.is_empty());
+        }
+
+        assert_eq!(stream(redactor, events.clone()).await, events);
+    }
+
+    #[tokio::test]
+    async fn test_match_split_across_events() {
+        let redactor = redactor(&["secret"], &[r"sk-[a-z0-9]{6}"]);
+        let events: Vec<Vec<u8>> = [
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"my se\"}}]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"cret is sk-ab\"}}]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"c123\"}}]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\" ok\"}}]}\n\n",
+            "data: [DONE]\n\n",
+        ]
+        .iter()
+        .map(|event| event.as_bytes().to_vec())
+        .collect();
+
+        let out = stream(redactor, events).await.concat();
+        let text = String::from_utf8(out)
+            .unwrap()
+            .split("\n\n")
+            .filter_map(|event| event.strip_prefix("data: "))
+            .filter_map(|data| serde_json::from_str::<Value>(data).ok())
+            .filter_map(|json| {
+                json.pointer("/choices/0/delta/content")
+                    .and_then(Value::as_str)
+                    .map(str::to_owned)
+            })
+            .collect::<String>();
+        assert_eq!(text, "my [REDACTED] is [REDACTED] ok");
+    }
+
+    #[test]
+    fn test_validate() {
+        let valid = RedactConfig {
+            literals: vec!["secret".to_owned()],
+            ..Default::default()
+        };
+        assert!(valid.validate().is_ok());
+        for invalid in [
+            RedactConfig {
+                literals: vec!["two\nlines".to_owned()],
+                ..Default::default()
+            },
+            RedactConfig {
+                patterns: vec!["(".to_owned()],
+                ..Default::default()
+            },
+            RedactConfig {
+                mask: "\"".to_owned(),
+                ..Default::default()
+            },
+            RedactConfig {
+                max_pattern_len: 0,
+                ..Default::default()
+            },
+        ] {
+            assert!(invalid.validate().is_err());
+        }
+    }
+}
@@ -14,6 +14,8 @@ use crate::constant::{ARKOSE_TOKEN, EMPTY, MODEL, NULL, PUID};
 use crate::gpt_model::GPTModel;
 use crate::{arkose, with_context};
 
+use super::cookie;
+use super::egress;
 use super::ext::{RequestExt, ResponseExt, SendRequestExt};
 use super::header_convert;
 use super::toapi;
@@ -29,7 +31,7 @@ impl SendRequestExt for reqwest::Client {
     ) -> Result<ResponseExt, ResponseError> {
         // If to_api is true, then send request to api
         if toapi::support(&req) {
-            return toapi::send_request(req).await;
+            return toapi::send_request(self.clone(), req).await;
         }
 
         // Build rqeuest path and query
@@ -40,7 +42,10 @@ impl SendRequestExt for reqwest::Client {
             .unwrap_or(req.uri.path());
 
         // Build url
-        let url = format!("{origin}{path_and_query}");
+        let url = format!(
+            "{}{path_and_query}",
+            with_context!(upstream_origin).unwrap_or(origin)
+        );
 
         // Handle conversation request
         handle_conv_request(&mut req).await?;
@@ -56,8 +61,13 @@ impl SendRequestExt for reqwest::Client {
             builder = builder.body(body);
         }
 
-        // Send request
-        Ok(ResponseExt::builder().inner(builder.send().await?).build())
+        // Send request, the upstream cookies of a session are kept in its jar
+        let session = cookie::session(&req.headers);
+        let resp = egress::send(builder).await?;
+        if let Some(session) = session {
+            cookie::store(&session, origin, resp.cookies());
+        }
+        Ok(ResponseExt::builder().inner(resp).build())
     }
 }
 
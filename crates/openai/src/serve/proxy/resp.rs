@@ -1,11 +1,12 @@
 use std::time::UNIX_EPOCH;
 
 use crate::constant::{CF_CLEARANCE, NINJA_VERSION, PUID};
+use crate::context::args::{CookieMode, ResponseHeaderPolicy};
 use crate::with_context;
 use crate::LIB_VERSION;
 use axum::body::Body;
 use axum::body::StreamBody;
-use axum::http::header;
+use axum::http::header::{self, HeaderMap, HeaderName, HeaderValue};
 use axum::response::{IntoResponse, Response};
 use axum_extra::extract::cookie;
 use axum_extra::extract::cookie::Cookie;
@@ -20,9 +21,27 @@ use super::toapi;
 pub(crate) async fn response_convert(
     resp: ResponseExt,
 ) -> Result<impl IntoResponse, ResponseError> {
+    let policy = with_context!(response_headers);
+
     // If to api is some, then convert to api response
     if resp.context.is_some() {
-        return Ok(toapi::response_convert(resp).await?.into_response());
+        let upstream = resp.inner.headers().clone();
+        let mut response = toapi::response_convert(resp).await?.into_response();
+        // The converted response sets its own content headers
+        for (name, value) in forward_headers(policy, &upstream) {
+            if !response.headers().contains_key(name) {
+                response.headers_mut().insert(name, value.clone());
+            }
+        }
+        for (name, value) in &policy.add {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::try_from(name.as_str()),
+                HeaderValue::from_str(value),
+            ) {
+                response.headers_mut().insert(name, value);
+            }
+        }
+        return Ok(response);
     }
 
     // Build new response
@@ -30,18 +49,22 @@ pub(crate) async fn response_convert(
         .status(resp.inner.status())
         .header(NINJA_VERSION, LIB_VERSION);
 
-    // Copy headers except for "set-cookie"
-    for kv in resp
-        .inner
-        .headers()
-        .into_iter()
-        .filter(|(k, _)| k.ne(&header::SET_COOKIE) && k.ne(&header::CONTENT_LENGTH))
-    {
-        builder = builder.header(kv.0, kv.1);
+    // Copy the upstream headers allowed by the header policy
+    for (name, value) in forward_headers(policy, resp.inner.headers()) {
+        builder = builder.header(name, value);
+    }
+
+    // Add the static headers
+    for (name, value) in &policy.add {
+        builder = builder.header(name.as_str(), value.as_str());
     }
 
-    // Filter and transform cookies
-    for cookie in resp.inner.cookies() {
+    // Filter and transform cookies, only relayed to the client in passthrough mode
+    let relayed = match super::cookie::mode() {
+        CookieMode::Passthrough => resp.inner.cookies().collect(),
+        CookieMode::Strip | CookieMode::Jar => Vec::new(),
+    };
+    for cookie in relayed {
         let name = cookie.name().to_lowercase();
         if name.eq(PUID) || name.eq(CF_CLEARANCE) {
             if let Some(expires) = cookie.expires() {
@@ -100,3 +123,95 @@ pub(crate) async fn response_convert(
             .into_response())
     }
 }
+
+/// Upstream headers forwarded by the header policy
+fn forward_headers<'a>(
+    policy: &'a ResponseHeaderPolicy,
+    headers: &'a HeaderMap,
+) -> impl Iterator<Item = (&'a HeaderName, &'a HeaderValue)> {
+    headers
+        .iter()
+        .filter(|(name, _)| policy.forward(name.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstream_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::SET_COOKIE, HeaderValue::from_static("__cf_bm=1"));
+        headers.insert(header::SERVER, HeaderValue::from_static("cloudflare"));
+        headers.insert("cf-ray", HeaderValue::from_static("1234-LAX"));
+        headers.insert(
+            header::TRANSFER_ENCODING,
+            HeaderValue::from_static("chunked"),
+        );
+        headers.insert("x-request-id", HeaderValue::from_static("req_1"));
+        headers.insert(
+            "x-ratelimit-remaining-requests",
+            HeaderValue::from_static("99"),
+        );
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/event-stream"),
+        );
+        headers
+    }
+
+    fn forwarded(policy: &ResponseHeaderPolicy) -> Vec<String> {
+        let headers = upstream_headers();
+        forward_headers(policy, &headers)
+            .map(|(name, _)| name.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_default_policy() {
+        let names = forwarded(&ResponseHeaderPolicy::default());
+        assert!(names.contains(&"x-request-id".to_owned()));
+        assert!(names.contains(&"content-type".to_owned()));
+        for name in ["set-cookie", "server", "cf-ray", "transfer-encoding"] {
+            assert!(!names.contains(&name.to_owned()), "{name} forwarded");
+        }
+    }
+
+    #[test]
+    fn test_scrub_extends_defaults() {
+        let policy = toml::from_str::<ResponseHeaderPolicy>("scrub = [\"X-Request-Id\"]").unwrap();
+        let names = forwarded(&policy);
+        assert!(names.contains(&"content-type".to_owned()));
+        for name in ["x-request-id", "server", "cf-ray"] {
+            assert!(!names.contains(&name.to_owned()), "{name} forwarded");
+        }
+    }
+
+    #[test]
+    fn test_set_cookie_never_forwarded() {
+        let policy = ResponseHeaderPolicy {
+            scrub: vec![],
+            allow: vec!["Set-Cookie".to_owned(), "x-request-id".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(forwarded(&policy), vec!["x-request-id".to_owned()]);
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let policy = ResponseHeaderPolicy {
+            allow: vec![
+                "X-Request-Id".to_owned(),
+                "x-ratelimit-remaining-requests".to_owned(),
+                "server".to_owned(),
+            ],
+            ..Default::default()
+        };
+        let mut names = forwarded(&policy);
+        names.sort();
+        // Scrubbed headers win over the allowlist
+        assert_eq!(
+            names,
+            vec!["x-ratelimit-remaining-requests", "x-request-id"]
+        );
+    }
+}
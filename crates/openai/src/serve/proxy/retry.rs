@@ -0,0 +1,140 @@
+use crate::serve::error::ResponseError;
+use crate::serve::memory::{self, Buffer};
+use crate::{warn, with_context};
+use axum::http::{header, HeaderMap};
+use axum::response::{IntoResponse, Response};
+use regex::bytes::RegexSet;
+use std::sync::OnceLock;
+
+use super::ext::{RequestExt, SendRequestExt};
+use super::inflight;
+use super::resp::response_convert;
+
+/// Larger response bodies are not matched against the retry patterns
+const MAX_INSPECT_BODY: u64 = 1024 * 1024;
+
+/// Response body patterns that trigger a retry, `None` disables it
+static PATTERNS: OnceLock<Option<RegexSet>> = OnceLock::new();
+
+pub(crate) fn init(patterns: &[String]) -> anyhow::Result<()> {
+    let patterns = compile(patterns)?;
+    let _ = PATTERNS.set(patterns);
+    Ok(())
+}
+
+fn compile(patterns: &[String]) -> anyhow::Result<Option<RegexSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    RegexSet::new(patterns)
+        .map(Some)
+        .map_err(|err| anyhow::anyhow!("Invalid retry_on_body_patterns: {err}"))
+}
+
+/// Send the request upstream and convert the response.
+/// Non-streaming responses whose body matches a retry pattern, whatever the status,
+/// are sent again up to `upstream_retries` times.
+pub(crate) async fn send(origin: &'static str, req: RequestExt) -> Result<Response, ResponseError> {
+    let retries = with_context!(upstream_retries);
+    let patterns = match PATTERNS.get().and_then(Option::as_ref) {
+        Some(patterns) if retries > 0 && !inflight::is_stream_request(&req) => patterns,
+        _ => return send_once(origin, req).await,
+    };
+
+    let mut attempt = 0;
+    loop {
+        let resp = send_once(origin, req.clone()).await?;
+        if attempt >= retries || !inspectable(resp.headers()) {
+            return Ok(resp);
+        }
+
+        let (parts, body) = resp.into_parts();
+        let (data, reservation) = match memory::buffer_at_most(body, MAX_INSPECT_BODY as usize)
+            .await
+            .map_err(ResponseError::BadGateway)?
+        {
+            Buffer::Full(data, reservation) => (data, reservation),
+            // Not inspected over the size cap or the buffered memory limit, forwarded as it is
+            Buffer::Exceeded(body) => return Ok(Response::from_parts(parts, body)),
+        };
+
+        if !patterns.is_match(&data) {
+            return Ok(Response::from_parts(parts, memory::body(data, reservation)));
+        }
+
+        attempt += 1;
+        warn!(
+            "Upstream response ({}) matched a retry body pattern, retrying {attempt}/{retries}",
+            parts.status
+        );
+    }
+}
+
+async fn send_once(origin: &'static str, req: RequestExt) -> Result<Response, ResponseError> {
+    let (client, stream) = with_context!(api_client_stream);
+    let resp = client.send_request(origin, req).await?;
+    Ok(inflight::guard(
+        response_convert(resp).await?.into_response(),
+        stream,
+    ))
+}
+
+/// Whether the response body is buffered and matched, event streams and bodies declared
+/// larger than the cap are not. Chunked bodies are capped while they are read.
+fn inspectable(headers: &HeaderMap) -> bool {
+    let event_stream = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.starts_with(mime::TEXT_EVENT_STREAM.as_ref()));
+    let too_large = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .map_or(false, |len| len > MAX_INSPECT_BODY);
+    !event_stream && !too_large
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_compile_patterns() {
+        assert!(compile(&[]).unwrap().is_none());
+        assert!(compile(&["(unclosed".to_owned()]).is_err());
+
+        let patterns = compile(&[
+            r#""code"\s*:\s*"rate_limit_exceeded""#.to_owned(),
+            "at capacity".to_owned(),
+        ])
+        .unwrap()
+        .unwrap();
+        assert!(patterns.is_match(br#"{"error":{"code": "rate_limit_exceeded"}}"#));
+        assert!(patterns.is_match(b"The server is at capacity"));
+        assert!(!patterns.is_match(br#"{"choices":[]}"#));
+    }
+
+    #[test]
+    fn test_inspectable() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        assert!(inspectable(&headers));
+
+        headers.insert(
+            header::CONTENT_LENGTH,
+            HeaderValue::from(2 * MAX_INSPECT_BODY),
+        );
+        assert!(!inspectable(&headers));
+
+        headers.remove(header::CONTENT_LENGTH);
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/event-stream; charset=utf-8"),
+        );
+        assert!(!inspectable(&headers));
+    }
+}
@@ -0,0 +1,313 @@
+use crate::context::args::SseFlushMode;
+use axum::body::{Bytes, HttpBody};
+use axum::http::{header, HeaderMap};
+use axum::response::Response;
+use bytes::BytesMut;
+use pin_project_lite::pin_project;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::Sleep;
+
+/// End of an OpenAI event stream, never held back by batching
+const DONE: &[u8] = b"[DONE]";
+
+static FLUSH: OnceLock<Option<Flush>> = OnceLock::new();
+
+/// Flush thresholds of the event streams
+#[derive(Clone, Copy)]
+struct Flush {
+    /// Bytes buffered before a flush, 0 flushes every upstream chunk
+    size: usize,
+    /// Longest wait of a buffered chunk before a flush
+    time: Duration,
+    /// Largest chunk written to the client, 0 for no limit. A cap on the write size
+    /// only, the chunks are split at any byte, within an event or a line
+    chunk_size: usize,
+}
+
+pub(crate) fn init(mode: SseFlushMode, batch_size: usize, batch_time: usize, chunk_size: usize) {
+    let _ = FLUSH.set(match (mode, chunk_size) {
+        // Upstream chunks are forwarded as they are
+        (SseFlushMode::Immediate, 0) => None,
+        (SseFlushMode::Immediate, _) => Some(Flush {
+            size: 0,
+            time: Duration::ZERO,
+            chunk_size,
+        }),
+        (SseFlushMode::Batched, _) => Some(Flush {
+            size: batch_size,
+            time: Duration::from_millis(batch_time as u64),
+            chunk_size,
+        }),
+    });
+}
+
+/// Batch the small events of an event stream response in the batched flush mode, and
+/// split the chunks larger than `stream_chunk_size` at the size, not at event boundaries
+pub(crate) fn flush(resp: Response) -> Response {
+    let flush = match FLUSH.get().copied().flatten() {
+        Some(flush) if is_event_stream(resp.headers()) => flush,
+        _ => return resp,
+    };
+    resp.map(|body| axum::body::boxed(BatchedBody::new(body, flush)))
+}
+
+fn is_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.starts_with(mime::TEXT_EVENT_STREAM.as_ref()))
+}
+
+pin_project! {
+    /// Body that coalesces chunks until `size` bytes are buffered or `time` has passed
+    /// since the first buffered chunk. `[DONE]` flushes immediately. A flush is written
+    /// in chunks of at most `chunk_size` bytes.
+    struct BatchedBody<B: HttpBody> {
+        #[pin]
+        inner: B,
+        buf: BytesMut,
+        flush: Flush,
+        deadline: Option<Pin<Box<Sleep>>>,
+        /// The buffer is being written out, the next chunks are taken without waiting
+        draining: bool,
+        /// The inner body has ended
+        ended: bool,
+        error: Option<B::Error>,
+    }
+}
+
+impl<B: HttpBody> BatchedBody<B> {
+    fn new(inner: B, flush: Flush) -> Self {
+        Self {
+            inner,
+            buf: BytesMut::new(),
+            flush,
+            deadline: None,
+            draining: false,
+            ended: false,
+            error: None,
+        }
+    }
+}
+
+/// Next chunk of a flushed buffer, at most `chunk_size` bytes
+fn take(buf: &mut BytesMut, chunk_size: usize) -> Bytes {
+    match chunk_size {
+        0 => buf.split().freeze(),
+        _ => buf.split_to(buf.len().min(chunk_size)).freeze(),
+    }
+}
+
+impl<B: HttpBody<Data = Bytes>> HttpBody for BatchedBody<B> {
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let mut this = self.project();
+        let chunk_size = this.flush.chunk_size;
+
+        if *this.draining {
+            if !this.buf.is_empty() {
+                return Poll::Ready(Some(Ok(take(this.buf, chunk_size))));
+            }
+            *this.draining = false;
+        }
+        if let Some(err) = this.error.take() {
+            return Poll::Ready(Some(Err(err)));
+        }
+        if *this.ended {
+            return Poll::Ready(None);
+        }
+
+        loop {
+            match this.inner.as_mut().poll_data(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    // `[DONE]` may be split across chunks
+                    let from = this.buf.len().saturating_sub(DONE.len() - 1);
+                    this.buf.extend_from_slice(&chunk);
+                    let done = this.buf[from..]
+                        .windows(DONE.len())
+                        .any(|window| window == DONE);
+
+                    if done || this.buf.len() >= this.flush.size {
+                        *this.deadline = None;
+                        *this.draining = true;
+                        return Poll::Ready(Some(Ok(take(this.buf, chunk_size))));
+                    }
+                    if this.deadline.is_none() {
+                        *this.deadline = Some(Box::pin(tokio::time::sleep(this.flush.time)));
+                    }
+                }
+                Poll::Ready(Some(Err(err))) => {
+                    // Flush the buffered events before the error
+                    if this.buf.is_empty() {
+                        return Poll::Ready(Some(Err(err)));
+                    }
+                    *this.error = Some(err);
+                    *this.deadline = None;
+                    *this.draining = true;
+                    return Poll::Ready(Some(Ok(take(this.buf, chunk_size))));
+                }
+                Poll::Ready(None) => {
+                    *this.deadline = None;
+                    *this.ended = true;
+                    if this.buf.is_empty() {
+                        return Poll::Ready(None);
+                    }
+                    *this.draining = true;
+                    return Poll::Ready(Some(Ok(take(this.buf, chunk_size))));
+                }
+                Poll::Pending => {
+                    let expired = this
+                        .deadline
+                        .as_mut()
+                        .map_or(false, |deadline| deadline.as_mut().poll(cx).is_ready());
+                    if expired {
+                        *this.deadline = None;
+                        *this.draining = true;
+                        return Poll::Ready(Some(Ok(take(this.buf, chunk_size))));
+                    }
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        self.project().inner.poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.buf.is_empty() && self.error.is_none() && (self.ended || self.inner.is_end_stream())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::StreamBody;
+    use futures::Stream;
+    use std::convert::Infallible;
+
+    type TestBody<S> = Pin<Box<BatchedBody<StreamBody<S>>>>;
+
+    fn batched<S>(stream: S, size: usize, time: Duration) -> TestBody<S>
+    where
+        S: Stream<Item = Result<&'static str, Infallible>> + Send + 'static,
+    {
+        let flush = Flush {
+            size,
+            time,
+            chunk_size: 0,
+        };
+        Box::pin(BatchedBody::new(StreamBody::new(stream), flush))
+    }
+
+    async fn next<S>(body: &mut TestBody<S>) -> Option<Bytes>
+    where
+        S: Stream<Item = Result<&'static str, Infallible>> + Send + 'static,
+    {
+        tokio::time::timeout(Duration::from_secs(1), body.data())
+            .await
+            .expect("batch was not flushed")
+            .map(|chunk| chunk.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_batch_small_events() {
+        let events = futures::stream::iter(["data: 1\n\n", "data: 2\n\n", "data: 3\n\n"].map(Ok));
+        let mut body = batched(events, 16, Duration::from_secs(60));
+
+        assert_eq!(next(&mut body).await.unwrap(), "data: 1\n\ndata: 2\n\n");
+        // The end of the body flushes the rest
+        assert_eq!(next(&mut body).await.unwrap(), "data: 3\n\n");
+        assert!(next(&mut body).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_batch_time_flush() {
+        let events = async_stream::stream! {
+            yield Ok("data: 1\n\n");
+            tokio::time::sleep(Duration::from_secs(3600)).await;
+        };
+        let mut body = batched(events, 4096, Duration::from_millis(20));
+
+        assert_eq!(next(&mut body).await.unwrap(), "data: 1\n\n");
+    }
+
+    #[tokio::test]
+    async fn test_done_flushes_immediately() {
+        let events = async_stream::stream! {
+            yield Ok("data: 1\n\ndata: [DO");
+            yield Ok("NE]\n\n");
+            tokio::time::sleep(Duration::from_secs(3600)).await;
+        };
+        let mut body = batched(events, 4096, Duration::from_secs(60));
+
+        assert_eq!(
+            next(&mut body).await.unwrap(),
+            "data: 1\n\ndata: [DONE]\n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chunk_size_splits_flushes() {
+        let events = futures::stream::iter(["data: 1234567890\n\n", "data: 2\n\n"].map(Ok));
+        let flush = Flush {
+            size: 0,
+            time: Duration::ZERO,
+            chunk_size: 8,
+        };
+        let mut body: TestBody<_> = Box::pin(BatchedBody::new(StreamBody::new(events), flush));
+
+        assert_eq!(next(&mut body).await.unwrap(), "data: 12");
+        assert_eq!(next(&mut body).await.unwrap(), "34567890");
+        assert_eq!(next(&mut body).await.unwrap(), "\n\n");
+        assert_eq!(next(&mut body).await.unwrap(), "data: 2\n");
+        assert_eq!(next(&mut body).await.unwrap(), "\n");
+        assert!(next(&mut body).await.is_none());
+    }
+
+    /// Chunks written and time taken for a stream of token sized events, per flush mode.
+    /// `cargo test -p openai --release --lib bench_batched_body -- --ignored --nocapture`
+    #[ignore]
+    #[tokio::test]
+    async fn bench_batched_body() {
+        const EVENTS: usize = 200_000;
+        const EVENT: &str = "data: {\"id\":\"chatcmpl-0\",\"object\":\"chat.completion.chunk\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\" token\"}}]}\n\n";
+
+        let mut writes = Vec::new();
+        for (mode, size) in [
+            ("immediate", 0),
+            ("batched 4096", 4096),
+            ("batched 16384", 16384),
+        ] {
+            let events = futures::stream::iter(std::iter::repeat(Ok(EVENT)).take(EVENTS));
+            let mut body = batched(events, size, Duration::from_secs(60));
+            let start = std::time::Instant::now();
+            let (mut chunks, mut bytes) = (0, 0);
+            while let Some(chunk) = next(&mut body).await {
+                chunks += 1;
+                bytes += chunk.len();
+            }
+            println!(
+                "{mode}: {chunks} writes, {bytes} bytes in {:?}",
+                start.elapsed()
+            );
+            assert_eq!(bytes, EVENTS * EVENT.len());
+            writes.push(chunks);
+        }
+        assert_eq!(writes[0], EVENTS);
+        assert!(writes[1] < EVENTS / 10 && writes[2] < writes[1]);
+    }
+}
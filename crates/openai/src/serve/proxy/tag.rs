@@ -0,0 +1,229 @@
+use crate::context::args::UpstreamTagRule;
+use crate::serve::model_policy::{self, glob_match, Key};
+use crate::{debug, info, warn};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue};
+use std::sync::OnceLock;
+
+/// Upstream request tagger, `None` when `upstream_tag_header` is unset
+static TAGGER: OnceLock<Option<Tagger>> = OnceLock::new();
+
+pub(crate) fn init(header: Option<&str>, rules: &[UpstreamTagRule]) -> anyhow::Result<()> {
+    let tagger = match header {
+        Some(header) => {
+            info!("Upstream tag header: {header}, {} rules", rules.len());
+            Some(Tagger::new(header, rules)?)
+        }
+        None => {
+            if !rules.is_empty() {
+                warn!("upstream_tag_rules are ignored, upstream_tag_header is unset");
+            }
+            None
+        }
+    };
+    let _ = TAGGER.set(tagger);
+    Ok(())
+}
+
+/// Tag the upstream request headers with the inbound identity
+pub(super) fn apply(inbound: &HeaderMap, outbound: &mut HeaderMap) {
+    if let Some(Some(tagger)) = TAGGER.get() {
+        tagger.apply(inbound, outbound)
+    }
+}
+
+/// Inbound identity field matched by the upstream tag and priority rules
+pub(crate) enum Source {
+    /// Stable key identifier, the email of an access token or the hashed `sk-`/`sess-` key
+    KeyId,
+    /// Email of an access token
+    Email,
+    /// Name of the model policy of the key
+    Policy,
+    /// Inbound request header
+    Header(HeaderName),
+}
+
+impl Source {
+    pub(crate) fn parse(source: &str) -> anyhow::Result<Self> {
+        Ok(match source {
+            "key_id" => Self::KeyId,
+            "email" => Self::Email,
+            "policy" => Self::Policy,
+            _ => match source.strip_prefix("header:") {
+                Some(name) => Self::Header(HeaderName::try_from(name)?),
+                None => anyhow::bail!("Invalid identity source `{source}`"),
+            },
+        })
+    }
+
+    pub(crate) fn value(&self, inbound: &HeaderMap, key: Option<&Key>) -> Option<String> {
+        match self {
+            Self::KeyId => key.map(Key::id),
+            Self::Email => match key? {
+                Key::Email(email) => Some(email.to_ascii_lowercase()),
+                Key::Secret(_) => None,
+            },
+            Self::Policy => model_policy::find(key?).map(|policy| policy.name().to_owned()),
+            Self::Header(name) => inbound
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned),
+        }
+    }
+
+    pub(crate) fn needs_key(&self) -> bool {
+        !matches!(self, Self::Header(_))
+    }
+}
+
+struct Rule {
+    source: Option<Source>,
+    pattern: String,
+    tag: String,
+}
+
+struct Tagger {
+    header: HeaderName,
+    rules: Vec<Rule>,
+}
+
+impl Tagger {
+    fn new(header: &str, rules: &[UpstreamTagRule]) -> anyhow::Result<Self> {
+        let header = HeaderName::try_from(header)
+            .map_err(|err| anyhow::anyhow!("Invalid upstream_tag_header `{header}`: {err}"))?;
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                rule.validate()?;
+                Ok(Rule {
+                    source: rule.source.as_deref().map(Source::parse).transpose()?,
+                    pattern: rule.pattern.clone(),
+                    tag: rule.tag.clone(),
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self { header, rules })
+    }
+
+    /// Tag of the first matching rule, none when no rule matches
+    fn tag(&self, inbound: &HeaderMap) -> Option<String> {
+        // The key is only decoded if a rule needs it
+        let mut key = None;
+        for rule in &self.rules {
+            let value = match rule.source.as_ref() {
+                Some(source) => {
+                    if source.needs_key() && key.is_none() {
+                        key = Some(bearer(inbound).and_then(Key::of_bearer));
+                    }
+                    match source.value(inbound, key.as_ref().and_then(Option::as_ref)) {
+                        Some(value) => value,
+                        None => continue,
+                    }
+                }
+                None => String::new(),
+            };
+            if glob_match(&rule.pattern, &value) {
+                return Some(rule.tag.replace("{value}", &value));
+            }
+        }
+        None
+    }
+
+    fn apply(&self, inbound: &HeaderMap, outbound: &mut HeaderMap) {
+        let tag = match self.tag(inbound) {
+            Some(tag) => tag,
+            None => return,
+        };
+        match HeaderValue::from_str(&tag) {
+            Ok(value) => {
+                outbound.insert(self.header.clone(), value);
+            }
+            Err(_) => debug!("Upstream tag `{tag}` is not a valid header value"),
+        }
+    }
+}
+
+pub(crate) fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(source: Option<&str>, pattern: &str, tag: &str) -> UpstreamTagRule {
+        UpstreamTagRule {
+            source: source.map(str::to_owned),
+            pattern: pattern.to_owned(),
+            tag: tag.to_owned(),
+        }
+    }
+
+    fn inbound(authorization: &str, tenant: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {authorization}")).unwrap(),
+        );
+        if let Some(tenant) = tenant {
+            headers.insert("x-tenant", HeaderValue::from_str(tenant).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_first_matching_rule() {
+        let tagger = Tagger::new(
+            "x-upstream-tag",
+            &[
+                rule(Some("header:x-tenant"), "acme-*", "tenant-{value}"),
+                rule(Some("key_id"), "key:*", "{value}"),
+                rule(None, "*", "untagged"),
+            ],
+        )
+        .unwrap();
+
+        let mut outbound = HeaderMap::new();
+        tagger.apply(&inbound("sk-test", Some("acme-eu")), &mut outbound);
+        assert_eq!(outbound["x-upstream-tag"], "tenant-acme-eu");
+
+        // The header rule does not match, the key id applies
+        let key_id = Key::parse("sk-test").id();
+        let mut outbound = HeaderMap::new();
+        tagger.apply(&inbound("sk-test", Some("other")), &mut outbound);
+        assert_eq!(outbound["x-upstream-tag"], key_id.as_str());
+        // The key itself is never sent in the tag
+        assert!(!key_id.contains("sk-test"));
+
+        let mut outbound = HeaderMap::new();
+        tagger.apply(&HeaderMap::new(), &mut outbound);
+        assert_eq!(outbound["x-upstream-tag"], "untagged");
+    }
+
+    #[test]
+    fn test_no_matching_rule() {
+        let tagger = Tagger::new(
+            "x-upstream-tag",
+            &[
+                rule(Some("email"), "*", "{value}"),
+                rule(Some("header:x-tenant"), "*", "bad\ntag"),
+            ],
+        )
+        .unwrap();
+
+        // No email for an `sk-` key, the tag of the header rule is not a header value
+        let mut outbound = HeaderMap::new();
+        tagger.apply(&inbound("sk-test", Some("acme")), &mut outbound);
+        assert!(outbound.get("x-upstream-tag").is_none());
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        assert!(Tagger::new("bad header", &[]).is_err());
+        assert!(Tagger::new("x-tag", &[rule(Some("ip"), "*", "{value}")]).is_err());
+        assert!(Tagger::new("x-tag", &[rule(Some("header:bad header"), "*", "")]).is_err());
+    }
+}
@@ -36,6 +36,7 @@ use crate::{
     uuid::uuid,
 };
 
+use super::egress;
 use super::ext::{Context, RequestExt, ResponseExt};
 use super::header_convert;
 use crate::URL_CHATGPT_API;
@@ -57,8 +58,11 @@ pub(super) fn support(req: &RequestExt) -> bool {
     false
 }
 
-/// Send request to ChatGPT API
-pub(super) async fn send_request(req: RequestExt) -> Result<ResponseExt, ResponseError> {
+/// Send request to ChatGPT API with `client`, the upstream client of the request
+pub(super) async fn send_request(
+    client: reqwest::Client,
+    req: RequestExt,
+) -> Result<ResponseExt, ResponseError> {
     // Exstract the token from the Authorization header
     let baerer = req
         .bearer_auth()
@@ -96,9 +100,6 @@ pub(super) async fn send_request(req: RequestExt) -> Result<ResponseExt, Respons
         messages.push(message)
     }
 
-    // Request client
-    let client = with_context!(api_client);
-
     // OpenAI API to ChatGPT API model mapper
     let gpt_model = GPTModel::from_str(&body.model)?;
 
@@ -147,11 +148,7 @@ pub(super) async fn send_request(req: RequestExt) -> Result<ResponseExt, Respons
     }
 
     // Send request
-    let resp = builder
-        .json(&req_body)
-        .send()
-        .await
-        .map_err(ResponseError::InternalServerError)?;
+    let resp = egress::send(builder.json(&req_body)).await?;
 
     Ok(ResponseExt::builder()
         .inner(resp)
@@ -16,10 +16,7 @@ use axum::http::Response;
 use axum::http::StatusCode;
 use axum::middleware;
 use axum::response::IntoResponse;
-use axum::routing::any;
-use axum::routing::{get, post};
 use axum::Json;
-use axum::Router;
 use axum::TypedHeader;
 use axum_csrf::CsrfConfig;
 use axum_csrf::CsrfLayer;
@@ -47,6 +44,7 @@ use crate::serve::error::ProxyError;
 use crate::serve::error::ResponseError;
 use crate::serve::middleware::csrf;
 use crate::serve::proxy::header_convert;
+use crate::serve::routes::{any, get, post, RouteGroup, Routes};
 use crate::serve::turnstile;
 use crate::serve::whitelist;
 use crate::with_context;
@@ -76,7 +74,7 @@ const TEMP_SHARE: &str = "share.htm";
 static TEMPLATE: OnceLock<tera::Tera> = OnceLock::new();
 
 // this function could be located in a different module
-pub(super) fn config(router: Router, args: &Args) -> Router {
+pub(super) fn config(router: Routes, args: &Args) -> Routes {
     // If the UI is disabled, then return the router directly
     if !args.enable_webui {
         return router;
@@ -85,6 +83,8 @@ pub(super) fn config(router: Router, args: &Args) -> Router {
     // Configure csrf
     let config = CsrfConfig::default().with_key(Some(Key::generate()));
 
+    let router = router.group(RouteGroup::Ui);
+
     // Configure arkose routing
     let router =     // If the auth key is empty, then the auth page is not required
     if with_context!(auth_key).is_some() {
@@ -98,13 +98,16 @@ pub(super) fn config(router: Router, args: &Args) -> Router {
         .route("/auth/login", get(login_index))
         .route(
             "/auth/login",
-            post(login).layer(
-                ServiceBuilder::new()
-                    .map_request_body(body::boxed)
-                    .layer(middleware::from_fn(csrf::csrf_middleware)),
-            ),
+            post(login).map(|router| {
+                router.layer(
+                    ServiceBuilder::new()
+                        .map_request_body(body::boxed)
+                        .layer(middleware::from_fn(csrf::csrf_middleware)),
+                )
+            }),
         )
-        .layer(CsrfLayer::new(config))
+        .with(&["csrf_check"])
+        .layer(&["csrf"], |router| router.layer(CsrfLayer::new(config)))
         .route("/auth/login/token", post(login_token))
         .route("/auth/logout", get(logout))
         .route("/auth/session", get(session))
@@ -141,8 +144,6 @@ pub(super) fn config(router: Router, args: &Args) -> Router {
         .route("/fonts/*path", get(get_static_resource))
         .route("/ulp/*path", get(get_static_resource))
         .route("/sweetalert2/*path", get(get_static_resource))
-        // 404 endpoint
-        .fallback(error_404)
 }
 
 /// Forwards the request to the auth provider
@@ -597,7 +598,7 @@ async fn redirect_to_home() -> Result<Response<Body>, ResponseError> {
 }
 
 /// 404 error
-async fn error_404() -> Result<Response<Body>, ResponseError> {
+pub(super) async fn error_404() -> Result<Response<Body>, ResponseError> {
     let mut ctx = tera::Context::new();
     let props = props::error_404_props().to_string();
     ctx.insert("props", &props);
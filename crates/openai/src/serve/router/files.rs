@@ -1,19 +1,24 @@
 use axum::http::header;
-use axum::{response::IntoResponse, routing::any, Router};
+use axum::response::IntoResponse;
 
 use crate::{
     context::args::Args,
     serve::{
-        error::ResponseError, proxy::ext::RequestExt, proxy::ext::SendRequestExt,
+        error::ResponseError,
+        proxy::ext::RequestExt,
+        proxy::ext::SendRequestExt,
         proxy::resp::response_convert,
+        routes::{any, RouteGroup, Routes},
     },
     with_context,
 };
 
 /// file endpoint proxy
-pub(super) fn config(router: Router, args: &Args) -> Router {
+pub(super) fn config(router: Routes, args: &Args) -> Routes {
     if args.enable_file_proxy {
-        router.route("/files/*path", any(proxy))
+        router
+            .group(RouteGroup::Files)
+            .route("/files/*path", any(proxy))
     } else {
         router
     }
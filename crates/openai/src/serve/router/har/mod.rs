@@ -3,12 +3,12 @@ mod token;
 use crate::context::args::Args;
 use crate::context::arkose::har;
 use crate::serve::error::{ProxyError, ResponseError};
+use crate::serve::routes::{get, post, RouteGroup, Routes};
 use crate::{arkose, warn, with_context};
 use axum::body::Body;
 use axum::extract::{Multipart, Query};
+use axum::response::Html;
 use axum::response::{IntoResponse, Redirect, Response};
-use axum::routing::post;
-use axum::{response::Html, routing::get, Router};
 use axum::{Form, Json, TypedHeader};
 use std::str::FromStr;
 
@@ -26,8 +26,9 @@ const ERROR_PAGE: &'static str = include_str!("../../../../frontend/har/error.ht
 const FAILED_UPLOAD_TITLE: &'static str = "Failed to upload file";
 const FAILED_AUTH_TITLE: &'static str = "Failed Authenticate";
 
-pub(super) fn config(router: Router, _: &Args) -> Router {
+pub(super) fn config(router: Routes, _: &Args) -> Routes {
     router
+        .group(RouteGroup::Har)
         .route("/har/login", get(login).post(post_login))
         .route("/har/upload", get(upload).post(post_upload))
         .route("/har/list", get(get_files))
@@ -2,19 +2,81 @@ mod chat;
 mod files;
 mod har;
 
-use crate::context::args::Args;
+use crate::context::args::{Args, NotFoundResponse};
 use crate::serve::error::ResponseError;
-use axum::http::header;
+use crate::serve::routes::{self, Routes};
 use axum::http::StatusCode;
-use axum::{body::Body, extract::Path, http::Response, Router};
+use axum::http::{header, HeaderMap, Method, Uri};
+use axum::{body::Body, extract::Path, http::Response};
 use std::collections::HashMap;
 use tokio::sync::OnceCell;
 
-pub(super) fn config(router: Router, args: &Args) -> Router {
+pub(super) fn config(router: Routes, args: &Args) -> Routes {
     let router = files::config(router, args);
     let router = har::config(router, args);
     let router = chat::config(router, args);
-    router
+
+    // 404 endpoint
+    let (response, enable_webui) = (args.not_found_response, args.enable_webui);
+    router.fallback(routes::any(
+        move |method: Method, uri: Uri, headers: HeaderMap| {
+            not_found(response, enable_webui, method, uri, headers)
+        },
+    ))
+}
+
+/// Response for unmatched routes
+async fn not_found(
+    response: NotFoundResponse,
+    enable_webui: bool,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Result<Response<Body>, ResponseError> {
+    if !wants_html(response, &headers) {
+        let body = serde_json::to_vec(&not_found_error(&method, &uri))?;
+        return create_response_with_data(
+            StatusCode::NOT_FOUND,
+            mime::APPLICATION_JSON.as_ref(),
+            body,
+        );
+    }
+
+    if enable_webui {
+        let mut resp = chat::error_404().await?;
+        *resp.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(resp);
+    }
+
+    create_response_with_data(
+        StatusCode::NOT_FOUND,
+        mime::TEXT_HTML_UTF_8.as_ref(),
+        "<!DOCTYPE html><html><head><title>404 Not Found</title></head><body><h1>404 Not Found</h1></body></html>",
+    )
+}
+
+/// Whether the unmatched route is answered with an HTML page
+fn wants_html(response: NotFoundResponse, headers: &HeaderMap) -> bool {
+    match response {
+        NotFoundResponse::Json => false,
+        NotFoundResponse::Html => true,
+        NotFoundResponse::Auto => headers
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .map_or(false, |v| v.contains(mime::TEXT_HTML.as_ref())),
+    }
+}
+
+/// OpenAI API error schema for unknown request URLs
+fn not_found_error(method: &Method, uri: &Uri) -> serde_json::Value {
+    serde_json::json!({
+        "error": {
+            "message": format!("Unknown request URL: {method} {}. Please check the URL for typos, or see the docs at https://platform.openai.com/docs/api-reference/.", uri.path()),
+            "type": "invalid_request_error",
+            "param": null,
+            "code": "unknown_url"
+        }
+    })
 }
 
 include!(concat!(env!("OUT_DIR"), "/generated.rs"));
@@ -63,3 +125,35 @@ fn create_response_with_data(
         .body(data.into())
         .map_err(ResponseError::InternalServerError)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_not_found_error() {
+        let uri = Uri::from_static("/v2/unknown?x=1");
+        let body = not_found_error(&Method::POST, &uri);
+        assert_eq!(body["error"]["code"], "unknown_url");
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+        assert!(body["error"]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Unknown request URL: POST /v2/unknown."));
+    }
+
+    #[test]
+    fn test_wants_html() {
+        let mut browser = HeaderMap::new();
+        browser.insert(
+            header::ACCEPT,
+            "text/html,application/xhtml+xml,*/*;q=0.8".parse().unwrap(),
+        );
+        let api = HeaderMap::new();
+
+        assert!(!wants_html(NotFoundResponse::Json, &browser));
+        assert!(wants_html(NotFoundResponse::Html, &api));
+        assert!(wants_html(NotFoundResponse::Auto, &browser));
+        assert!(!wants_html(NotFoundResponse::Auto, &api));
+    }
+}
@@ -0,0 +1,376 @@
+//! Route registry. Routes and layers are mounted through [`Routes`], which records
+//! them as they are applied, so the listing of `GET /admin/routes` is the router as
+//! it is served.
+
+use crate::context::args::RouteLimit;
+use crate::serve::error::{ProxyError, ResponseError};
+use crate::serve::model_policy::glob_match;
+use crate::with_context;
+use axum::handler::Handler;
+use axum::headers::authorization::Bearer;
+use axum::headers::Authorization;
+use axum::routing::{self, MethodRouter};
+use axum::{Json, Router, TypedHeader};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+/// Served route table, set once the routers are built
+static TABLE: OnceLock<Vec<RouteInfo>> = OnceLock::new();
+
+/// Group of routes sharing the same purpose and route layers
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum RouteGroup {
+    /// Upstream API proxy, behind the access token auth and token bucket
+    Proxy,
+    /// Upstream public API proxy
+    Public,
+    /// Access token endpoints
+    Auth,
+    /// Metrics and admin endpoints
+    Admin,
+    /// WebUI pages and static resources
+    Ui,
+    /// HAR file management
+    Har,
+    /// File endpoint proxy
+    Files,
+    /// Routes registered by the embedder, the paths are not known
+    Custom,
+    /// Unmatched requests
+    Fallback,
+}
+
+/// Listener a route is served on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum Listener {
+    Main,
+    Admin,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub(super) struct RouteInfo {
+    /// Methods, `*` for any
+    pub(super) methods: Vec<&'static str>,
+    /// Path pattern, `:name` matches a segment and `*name` the rest of the path
+    pub(super) path: String,
+    pub(super) group: RouteGroup,
+    pub(super) listener: Listener,
+    /// Middlewares the request goes through, outermost first
+    pub(super) middlewares: Vec<&'static str>,
+    /// Request timeout in seconds
+    pub(super) timeout: Option<u64>,
+    /// `route_limits` patterns the requests can match, the longest matching applies
+    pub(super) route_limits: Vec<String>,
+}
+
+impl RouteInfo {
+    fn new(methods: Vec<&'static str>, path: &str, group: RouteGroup) -> Self {
+        Self {
+            methods,
+            path: path.to_owned(),
+            group,
+            listener: Listener::Main,
+            middlewares: Vec::new(),
+            timeout: None,
+            route_limits: Vec::new(),
+        }
+    }
+}
+
+/// Method router with the methods it serves, built like `axum::routing::{get, post, any}`
+pub(super) struct Endpoint {
+    methods: Vec<&'static str>,
+    router: MethodRouter,
+}
+
+pub(super) fn get<H: Handler<T, ()>, T: 'static>(handler: H) -> Endpoint {
+    Endpoint {
+        methods: vec!["GET"],
+        router: routing::get(handler),
+    }
+}
+
+pub(super) fn post<H: Handler<T, ()>, T: 'static>(handler: H) -> Endpoint {
+    Endpoint {
+        methods: vec!["POST"],
+        router: routing::post(handler),
+    }
+}
+
+pub(super) fn any<H: Handler<T, ()>, T: 'static>(handler: H) -> Endpoint {
+    Endpoint {
+        methods: vec!["*"],
+        router: routing::any(handler),
+    }
+}
+
+impl Endpoint {
+    pub(super) fn get<H: Handler<T, ()>, T: 'static>(mut self, handler: H) -> Self {
+        self.methods.push("GET");
+        self.router = self.router.get(handler);
+        self
+    }
+
+    pub(super) fn post<H: Handler<T, ()>, T: 'static>(mut self, handler: H) -> Self {
+        self.methods.push("POST");
+        self.router = self.router.post(handler);
+        self
+    }
+
+    /// Apply a layer to the method router (`MethodRouter::layer`)
+    pub(super) fn map(mut self, layer: impl FnOnce(MethodRouter) -> MethodRouter) -> Self {
+        self.router = layer(self.router);
+        self
+    }
+}
+
+/// Router with the table of its routes
+pub(super) struct Routes {
+    router: Router,
+    group: RouteGroup,
+    table: Vec<RouteInfo>,
+}
+
+impl Routes {
+    pub(super) fn new(group: RouteGroup) -> Self {
+        Self {
+            router: Router::new(),
+            group,
+            table: Vec::new(),
+        }
+    }
+
+    /// Group of the routes mounted next
+    pub(super) fn group(mut self, group: RouteGroup) -> Self {
+        self.group = group;
+        self
+    }
+
+    pub(super) fn route(mut self, path: &str, endpoint: Endpoint) -> Self {
+        self.router = self.router.route(path, endpoint.router);
+        self.table
+            .push(RouteInfo::new(endpoint.methods, path, self.group));
+        self
+    }
+
+    /// Middlewares of the method router of the last mounted route, outermost first
+    pub(super) fn with(mut self, middlewares: &[&'static str]) -> Self {
+        if let Some(route) = self.table.last_mut() {
+            route.middlewares.extend_from_slice(middlewares);
+        }
+        self
+    }
+
+    /// Apply a layer to the routes mounted so far (`Router::layer` or `Router::route_layer`),
+    /// its middlewares wrap the ones already applied
+    pub(super) fn layer(
+        mut self,
+        middlewares: &[&'static str],
+        layer: impl FnOnce(Router) -> Router,
+    ) -> Self {
+        self.router = layer(self.router);
+        for route in &mut self.table {
+            route.middlewares.splice(0..0, middlewares.iter().copied());
+        }
+        self
+    }
+
+    /// Apply the request timeout layer (`timeout` middleware) of `secs` seconds to the
+    /// routes mounted so far, a shorter timeout already applied is kept
+    pub(super) fn timeout(self, secs: u64, layer: impl FnOnce(Router) -> Router) -> Self {
+        let mut routes = self.layer(&["timeout"], layer);
+        for route in &mut routes.table {
+            route.timeout = Some(route.timeout.map_or(secs, |timeout| timeout.min(secs)));
+        }
+        routes
+    }
+
+    /// Attach the `route_limits` patterns to the rate limited routes (`token_bucket`
+    /// middleware) mounted so far. A pattern no such route can match is an error, its
+    /// requests would never reach the bucket.
+    pub(super) fn route_limits(
+        mut self,
+        limits: &BTreeMap<String, RouteLimit>,
+    ) -> anyhow::Result<Self> {
+        for pattern in limits.keys() {
+            let mut matched = false;
+            for route in &mut self.table {
+                if route.middlewares.contains(&"token_bucket") && overlaps(&route.path, pattern) {
+                    route.route_limits.push(pattern.clone());
+                    matched = true;
+                }
+            }
+            if !matched {
+                anyhow::bail!("Invalid route limit `{pattern}`, it matches no rate limited route")
+            }
+        }
+        Ok(self)
+    }
+
+    /// Merge routes of another table
+    pub(super) fn merge(mut self, other: Routes) -> Self {
+        self.router = self.router.merge(other.router);
+        self.table.extend(other.table);
+        self
+    }
+
+    /// Merge a router whose routes are not known, listed as a single `*` route
+    pub(super) fn merge_router(mut self, router: Router) -> Self {
+        self.router = self.router.merge(router);
+        self.table
+            .push(RouteInfo::new(vec!["*"], "*", RouteGroup::Custom));
+        self
+    }
+
+    /// Handler of the requests no route matched
+    pub(super) fn fallback(mut self, endpoint: Endpoint) -> Self {
+        self.router = self.router.fallback_service(endpoint.router);
+        self.table
+            .push(RouteInfo::new(endpoint.methods, "*", RouteGroup::Fallback));
+        self
+    }
+
+    /// Serve the routes on the admin listener
+    pub(super) fn on_admin_listener(mut self) -> Self {
+        for route in &mut self.table {
+            route.listener = Listener::Admin;
+        }
+        self
+    }
+
+    pub(super) fn into_parts(self) -> (Router, Vec<RouteInfo>) {
+        (self.router, self.table)
+    }
+}
+
+/// Whether the request paths of a route pattern can match a `route_limits` glob
+/// pattern, compared up to the first parameter of the route and the first wildcard
+/// of the glob
+fn overlaps(route: &str, pattern: &str) -> bool {
+    match route.find([':', '*']) {
+        None => glob_match(pattern, route),
+        Some(end) => {
+            let route = &route[..end];
+            let pattern = &pattern[..pattern.find(['*', '?']).unwrap_or(pattern.len())];
+            route.starts_with(pattern) || pattern.starts_with(route)
+        }
+    }
+}
+
+/// Publish the served route table
+pub(super) fn init(table: Vec<RouteInfo>) {
+    let _ = TABLE.set(table);
+}
+
+#[derive(Serialize)]
+pub(super) struct RouteTable {
+    routes: &'static [RouteInfo],
+}
+
+/// GET /admin/routes
+pub(super) async fn admin(
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<Json<RouteTable>, ResponseError> {
+    // Require auth key
+    if let Some(auth_key) = with_context!(auth_key) {
+        let bearer =
+            bearer.ok_or_else(|| ResponseError::Unauthorized(ProxyError::AuthKeyRequired))?;
+        if auth_key.ne(bearer.token()) {
+            return Err(ResponseError::Forbidden(ProxyError::AuthKeyError));
+        }
+    }
+
+    Ok(Json(RouteTable {
+        routes: TABLE.get().map_or(&[], Vec::as_slice),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GLOBAL: &[&str] = &["trace", "timeout"];
+
+    fn limit() -> RouteLimit {
+        RouteLimit {
+            capacity: 10,
+            fill_rate: 1,
+        }
+    }
+
+    #[test]
+    fn test_layers_wrap_mounted_routes() {
+        let proxy = Routes::new(RouteGroup::Proxy)
+            .route("/v1/*path", any(|| async {}))
+            .layer(&["token_bucket"], |router| router)
+            .layer(&["auth"], |router| router);
+        let (_, table) = Routes::new(RouteGroup::Auth)
+            .merge(proxy)
+            .route("/auth/token", post(|| async {}).get(|| async {}))
+            .with(&["csrf_check"])
+            .layer(&["csrf"], |router| router)
+            .group(RouteGroup::Ui)
+            .route("/", get(|| async {}))
+            .timeout(30, |router| router)
+            .layer(&["trace"], |router| router)
+            .into_parts();
+
+        let route = |path: &str| table.iter().find(|route| route.path == path).unwrap();
+        assert_eq!(
+            route("/v1/*path").middlewares,
+            [GLOBAL, &["csrf", "auth", "token_bucket"]].concat()
+        );
+        assert_eq!(
+            route("/auth/token").middlewares,
+            [GLOBAL, &["csrf", "csrf_check"]].concat()
+        );
+        assert_eq!(route("/").middlewares, GLOBAL);
+        // The methods come from the method router
+        assert_eq!(route("/auth/token").methods, ["POST", "GET"]);
+        assert_eq!(route("/v1/*path").methods, ["*"]);
+        assert_eq!(route("/").timeout, Some(30));
+        assert_eq!(table[2].group, RouteGroup::Ui);
+    }
+
+    #[test]
+    fn test_route_limits() {
+        let routes = || {
+            Routes::new(RouteGroup::Proxy)
+                .route("/v1/*path", any(|| async {}))
+                .layer(&["token_bucket"], |router| router)
+                .group(RouteGroup::Auth)
+                .route("/auth/token", post(|| async {}))
+        };
+        let limits = |patterns: &[&str]| {
+            patterns
+                .iter()
+                .map(|pattern| (pattern.to_string(), limit()))
+                .collect::<BTreeMap<_, _>>()
+        };
+
+        let (_, table) = routes()
+            .route_limits(&limits(&["/v1/chat/*", "/v1/files"]))
+            .unwrap()
+            .into_parts();
+        assert_eq!(table[0].route_limits, ["/v1/chat/*", "/v1/files"]);
+        assert!(table[1].route_limits.is_empty());
+
+        // Not rate limited, or no route
+        assert!(routes().route_limits(&limits(&["/auth/*"])).is_err());
+        assert!(routes().route_limits(&limits(&["/v2/*"])).is_err());
+        assert!(routes().route_limits(&limits(&["v1/*"])).is_err());
+    }
+
+    #[test]
+    fn test_overlaps() {
+        assert!(overlaps("/v1/*path", "/v1/chat/completions"));
+        assert!(overlaps("/v1/*path", "/v1*"));
+        assert!(overlaps("/v1/*path", "/*"));
+        assert!(overlaps("/auth/token", "/auth/*"));
+        assert!(!overlaps("/auth/token", "/auth/refresh_token"));
+        assert!(!overlaps("/v1/*path", "/dashboard/*"));
+    }
+}
@@ -0,0 +1,393 @@
+use crate::serve::lifecycle;
+use crate::{error, info};
+use anyhow::Context;
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::{DateTime, Datelike, Duration, Timelike, Utc};
+use chrono_tz::Tz;
+use hotwatch::{Event, EventKind, Hotwatch};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError, RwLock};
+
+const MINUTES_PER_DAY: usize = 24 * 60;
+const MINUTES_PER_WEEK: usize = 7 * MINUTES_PER_DAY;
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const WEEKDAY_NAMES: [&str; 7] = [
+    "monday",
+    "tuesday",
+    "wednesday",
+    "thursday",
+    "friday",
+    "saturday",
+    "sunday",
+];
+
+/// Loaded access schedules, replaced on reload
+static SCHEDULES: RwLock<Vec<AccessSchedule>> = RwLock::new(Vec::new());
+
+/// Access schedules file (toml format file)
+#[derive(Deserialize)]
+struct ScheduleFile {
+    #[serde(default)]
+    schedule: Vec<ScheduleConfig>,
+}
+
+/// A group of keys (token emails) and its allowed time windows
+#[derive(Deserialize)]
+struct ScheduleConfig {
+    name: String,
+    emails: Vec<String>,
+    timezone: String,
+    windows: Vec<String>,
+}
+
+/// Access schedule of a key group, evaluated against a precomputed
+/// minute-of-week bitmap in the schedule's local time
+pub(super) struct AccessSchedule {
+    name: String,
+    emails: Vec<String>,
+    timezone: Tz,
+    minutes: Box<[u64; (MINUTES_PER_WEEK + 63) / 64]>,
+    /// Last `resumes_at`, by the minute it was computed for: the denied requests of
+    /// a minute share one scan
+    resumes: Mutex<Option<(DateTime<Utc>, Option<DateTime<Utc>>)>>,
+}
+
+impl AccessSchedule {
+    fn new(config: ScheduleConfig) -> anyhow::Result<Self> {
+        let timezone = config
+            .timezone
+            .parse::<Tz>()
+            .map_err(|err| anyhow::anyhow!("schedule `{}`: {err}", config.name))?;
+        let mut schedule = Self {
+            name: config.name,
+            emails: config.emails,
+            timezone,
+            minutes: Box::new([0; (MINUTES_PER_WEEK + 63) / 64]),
+            resumes: Mutex::new(None),
+        };
+        for window in &config.windows {
+            schedule
+                .add_window(window)
+                .with_context(|| format!("schedule `{}`: window `{window}`", schedule.name))?;
+        }
+        Ok(schedule)
+    }
+
+    /// Add a `Mon-Fri 08:00-19:00` window, days are a range (`Fri-Mon` wraps),
+    /// a list (`Mon,Wed`) or `*`. An end before the start runs past midnight, an end
+    /// equal to the start is a 24 hours window from the start.
+    fn add_window(&mut self, window: &str) -> anyhow::Result<()> {
+        let (days, time) = window
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| anyhow::anyhow!("expected `<days> <HH:MM>-<HH:MM>`"))?;
+        let (start, end) = time
+            .trim()
+            .split_once('-')
+            .ok_or_else(|| anyhow::anyhow!("expected `<HH:MM>-<HH:MM>`"))?;
+        let (start, end) = (parse_minute(start)?, parse_minute(end)?);
+        let length = if end > start {
+            end - start
+        } else {
+            end + MINUTES_PER_DAY - start
+        };
+
+        for day in parse_days(days)? {
+            let from = day * MINUTES_PER_DAY + start;
+            for minute in from..from + length {
+                let minute = minute % MINUTES_PER_WEEK;
+                self.minutes[minute / 64] |= 1 << (minute % 64);
+            }
+        }
+        Ok(())
+    }
+
+    fn matches(&self, email: &str) -> bool {
+        self.emails.iter().any(|e| e.eq_ignore_ascii_case(email))
+    }
+
+    /// Whether access is allowed at the given instant
+    pub(super) fn allows(&self, now: DateTime<Utc>) -> bool {
+        let minute = self.minute_of_week(now);
+        self.minutes[minute / 64] & (1 << (minute % 64)) != 0
+    }
+
+    /// The next instant access is allowed, `None` if the schedule has no window
+    pub(super) fn resumes_at(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        if self.minutes.iter().all(|bits| *bits == 0) {
+            return None;
+        }
+        let start = now.with_second(0)?.with_nanosecond(0)?;
+        let mut resumes = self.resumes.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some((minute, resumes_at)) = *resumes {
+            if minute == start {
+                return resumes_at;
+            }
+        }
+        // One extra day covers a week shifted by a DST transition
+        let resumes_at = (1..=(MINUTES_PER_WEEK + MINUTES_PER_DAY) as i64)
+            .map(|n| start + Duration::minutes(n))
+            .find(|at| self.allows(*at));
+        *resumes = Some((start, resumes_at));
+        resumes_at
+    }
+
+    fn minute_of_week(&self, now: DateTime<Utc>) -> usize {
+        let local = now.with_timezone(&self.timezone);
+        local.weekday().num_days_from_monday() as usize * MINUTES_PER_DAY
+            + local.hour() as usize * 60
+            + local.minute() as usize
+    }
+}
+
+/// Parse `HH:MM`, `24:00` is the end of the day
+fn parse_minute(s: &str) -> anyhow::Result<usize> {
+    let (hour, minute) = s
+        .trim()
+        .split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("invalid time `{s}`"))?;
+    let (hour, minute) = (hour.parse::<usize>()?, minute.parse::<usize>()?);
+    if minute >= 60 || hour > 24 || (hour == 24 && minute > 0) {
+        anyhow::bail!("invalid time `{s}`")
+    }
+    Ok(hour * 60 + minute)
+}
+
+/// Parse the weekdays (0 = Monday)
+fn parse_days(s: &str) -> anyhow::Result<Vec<usize>> {
+    if s.trim() == "*" {
+        return Ok((0..7).collect());
+    }
+
+    let weekday = |s: &str| {
+        let s = s.trim().to_ascii_lowercase();
+        WEEKDAYS
+            .iter()
+            .zip(WEEKDAY_NAMES)
+            .position(|(short, full)| s == *short || s == full)
+            .ok_or_else(|| anyhow::anyhow!("invalid weekday `{s}`"))
+    };
+
+    let mut days = Vec::new();
+    for part in s.split(',') {
+        match part.split_once('-') {
+            Some((from, to)) => {
+                let (from, to) = (weekday(from)?, weekday(to)?);
+                let mut day = from;
+                loop {
+                    days.push(day);
+                    if day == to {
+                        break;
+                    }
+                    day = (day + 1) % 7;
+                }
+            }
+            None => days.push(weekday(part)?),
+        }
+    }
+    Ok(days)
+}
+
+fn load(path: &Path) -> anyhow::Result<Vec<AccessSchedule>> {
+    let data = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read access schedules {}", path.display()))?;
+    let file = toml::from_str::<ScheduleFile>(&data)
+        .with_context(|| format!("Failed to parse access schedules {}", path.display()))?;
+    file.schedule.into_iter().map(AccessSchedule::new).collect()
+}
+
+/// Load the access schedules file and hot-reload it when it changes.
+/// The previous schedules stay in use if the reload fails.
+pub(super) fn init(path: PathBuf) -> anyhow::Result<Hotwatch> {
+    let schedules = load(&path)?;
+    info!("Loaded {} access schedules", schedules.len());
+    *SCHEDULES.write().expect("access schedules lock poisoned") = schedules;
+
+    let mut hotwatch = Hotwatch::new()?;
+    info!("Start watching access schedules file: {}", path.display());
+    hotwatch.watch(&path.clone(), move |event: Event| {
+        if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+            return;
+        }
+        match load(&path) {
+            Ok(schedules) => {
+                info!("Reloaded {} access schedules", schedules.len());
+                if let Ok(mut lock) = SCHEDULES.write() {
+                    *lock = schedules;
+                }
+                lifecycle::notify(lifecycle::Kind::Reload, Some("access_schedules"));
+            }
+            Err(err) => error!("Failed to reload access schedules: {err}"),
+        }
+    })?;
+    Ok(hotwatch)
+}
+
+/// Check the key's access schedule, returns when access resumes if the request
+/// is outside the schedule
+pub(super) fn check_schedule(email: &str) -> Result<(), Option<DateTime<Utc>>> {
+    let schedules = match SCHEDULES.read() {
+        Ok(schedules) => schedules,
+        Err(_) => return Ok(()),
+    };
+    let now = Utc::now();
+    match schedules.iter().find(|s| s.matches(email)) {
+        Some(schedule) if !schedule.allows(now) => Err(schedule.resumes_at(now)),
+        _ => Ok(()),
+    }
+}
+
+/// 403 response for requests outside the key's access schedule
+pub(super) fn outside_schedule_response(resumes_at: Option<DateTime<Utc>>) -> Response {
+    let body = serde_json::json!({
+        "error": {
+            "message": "Access is not allowed at this time",
+            "type": "access_denied",
+            "param": null,
+            "code": "outside_schedule"
+        }
+    });
+    let mut resp = (StatusCode::FORBIDDEN, Json(body)).into_response();
+    if let Some(resumes_at) = resumes_at {
+        let retry_after = (resumes_at - Utc::now()).num_seconds().max(0);
+        if let Ok(value) = HeaderValue::from_str(&resumes_at.to_rfc3339()) {
+            resp.headers_mut().insert("x-access-resumes-at", value);
+        }
+        resp.headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+    }
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn schedule(timezone: &str, windows: &[&str]) -> AccessSchedule {
+        AccessSchedule::new(ScheduleConfig {
+            name: "test".to_owned(),
+            emails: vec!["user@example.com".to_owned()],
+            timezone: timezone.to_owned(),
+            windows: windows.iter().map(|w| w.to_string()).collect(),
+        })
+        .unwrap()
+    }
+
+    fn local(tz: Tz, y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        tz.with_ymd_and_hms(y, m, d, h, min, 0)
+            .earliest()
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_business_hours() {
+        let tz = chrono_tz::Europe::Berlin;
+        let s = schedule("Europe/Berlin", &["Mon-Fri 08:00-19:00"]);
+        // 2024-01-15 is a Monday
+        assert!(!s.allows(local(tz, 2024, 1, 15, 7, 59)));
+        assert!(s.allows(local(tz, 2024, 1, 15, 8, 0)));
+        assert!(s.allows(local(tz, 2024, 1, 15, 18, 59)));
+        assert!(!s.allows(local(tz, 2024, 1, 15, 19, 0)));
+        assert!(!s.allows(local(tz, 2024, 1, 20, 12, 0)));
+
+        // Friday evening resumes on Monday morning
+        let resumes = s.resumes_at(local(tz, 2024, 1, 19, 20, 30)).unwrap();
+        assert_eq!(resumes, local(tz, 2024, 1, 22, 8, 0));
+    }
+
+    #[test]
+    fn test_midnight_boundary() {
+        let tz = chrono_tz::Asia::Shanghai;
+        let s = schedule("Asia/Shanghai", &["Fri 22:00-02:00", "Sun 20:00-24:00"]);
+        // 2024-01-19 is a Friday
+        assert!(s.allows(local(tz, 2024, 1, 19, 23, 59)));
+        assert!(s.allows(local(tz, 2024, 1, 20, 0, 0)));
+        assert!(s.allows(local(tz, 2024, 1, 20, 1, 59)));
+        assert!(!s.allows(local(tz, 2024, 1, 20, 2, 0)));
+        // Sunday until midnight, wraps to Monday 00:00 closed
+        assert!(s.allows(local(tz, 2024, 1, 21, 23, 59)));
+        assert!(!s.allows(local(tz, 2024, 1, 22, 0, 0)));
+    }
+
+    #[test]
+    fn test_dst_transitions() {
+        let tz = chrono_tz::America::New_York;
+        let s = schedule("America/New_York", &["* 01:00-04:00"]);
+
+        // Spring forward 2024-03-10: 02:00 local jumps to 03:00
+        let before = local(tz, 2024, 3, 10, 1, 59);
+        assert!(s.allows(before));
+        assert!(s.allows(before + Duration::minutes(1))); // 03:00 EDT
+        assert!(!s.allows(local(tz, 2024, 3, 10, 4, 0)));
+
+        // Fall back 2024-11-03: 01:00-02:00 local happens twice
+        let first = local(tz, 2024, 11, 3, 1, 30);
+        assert!(s.allows(first));
+        assert!(s.allows(first + Duration::hours(1)));
+
+        // Closed at 04:00 local, resumes at 01:00 local on the next day (EST)
+        let resumes = s.resumes_at(local(tz, 2024, 11, 3, 4, 0)).unwrap();
+        assert_eq!(resumes, local(tz, 2024, 11, 4, 1, 0));
+    }
+
+    #[test]
+    fn test_full_day_window() {
+        let tz = chrono_tz::Europe::Berlin;
+        // The end equal to the start, 24 hours from Monday 06:00
+        let s = schedule("Europe/Berlin", &["Mon 06:00-06:00"]);
+        assert!(!s.allows(local(tz, 2024, 1, 15, 5, 59)));
+        assert!(s.allows(local(tz, 2024, 1, 15, 6, 0)));
+        assert!(s.allows(local(tz, 2024, 1, 16, 5, 59)));
+        assert!(!s.allows(local(tz, 2024, 1, 16, 6, 0)));
+
+        // The same minute answers from the last scan
+        let closed = local(tz, 2024, 1, 16, 12, 0);
+        let resumes = s.resumes_at(closed).unwrap();
+        assert_eq!(resumes, local(tz, 2024, 1, 22, 6, 0));
+        assert_eq!(s.resumes_at(closed + Duration::seconds(30)), Some(resumes));
+
+        // No window, never resumes
+        assert_eq!(schedule("UTC", &[]).resumes_at(closed), None);
+    }
+
+    #[test]
+    fn test_parse_days() {
+        assert_eq!(parse_days("Mon-Wed").unwrap(), vec![0, 1, 2]);
+        assert_eq!(parse_days("Friday,sunday").unwrap(), vec![4, 6]);
+        assert_eq!(parse_days("Sat-Mon").unwrap(), vec![5, 6, 0]);
+        assert!(parse_days("monkey").is_err());
+        assert!(parse_days("Satur").is_err());
+    }
+
+    #[test]
+    fn test_invalid_window() {
+        for window in [
+            "Mon-Fri",
+            "Mon 08:00",
+            "Xyz 08:00-09:00",
+            "Mon 25:00-26:00",
+            "monkey 08:00-09:00",
+            "Tues 08:00-09:00",
+        ] {
+            assert!(AccessSchedule::new(ScheduleConfig {
+                name: "test".to_owned(),
+                emails: vec![],
+                timezone: "UTC".to_owned(),
+                windows: vec![window.to_owned()],
+            })
+            .is_err());
+        }
+        assert!(AccessSchedule::new(ScheduleConfig {
+            name: "test".to_owned(),
+            emails: vec![],
+            timezone: "Mars/Olympus".to_owned(),
+            windows: vec![],
+        })
+        .is_err());
+    }
+}
@@ -20,28 +20,37 @@ pub(super) async fn graceful_shutdown(handle: Handle) {
         let mut sigquit = signal(SignalKind::quit()).expect("SIGQUIT signal hanlde error");
         let mut sigchld = signal(SignalKind::child()).expect("SIGCHLD signal hanlde error");
         let mut sighup = signal(SignalKind::hangup()).expect("SIGHUP signal hanlde error");
-        tokio::select! {
-            _ = sigterm.recv() => {
-                sending_graceful_shutdown_signal(handle, "SIGTERM").await;
-            },
-            _ = sigquit.recv() => {
-                sending_graceful_shutdown_signal(handle, "SIGQUIT").await;
-            },
-            _ = sigchld.recv() => {
-                sending_graceful_shutdown_signal(handle, "SIGCHLD").await;
-            },
-            _ = sighup.recv() => {
-                sending_graceful_shutdown_signal(handle, "SIGHUP").await;
-            },
-            _ = tokio::signal::ctrl_c() => {
-                sending_graceful_shutdown_signal(handle, "SIGINT").await;
-            }
-        };
+        loop {
+            tokio::select! {
+                _ = sigterm.recv() => {
+                    sending_graceful_shutdown_signal(handle, "SIGTERM").await;
+                },
+                _ = sigquit.recv() => {
+                    sending_graceful_shutdown_signal(handle, "SIGQUIT").await;
+                },
+                _ = sigchld.recv() => {
+                    sending_graceful_shutdown_signal(handle, "SIGCHLD").await;
+                },
+                _ = sighup.recv() => {
+                    // With IP list files, SIGHUP reloads them instead of shutting down
+                    if super::ip_filter::reload() {
+                        info!("SIGHUP received: reloaded the IP lists");
+                        continue;
+                    }
+                    sending_graceful_shutdown_signal(handle, "SIGHUP").await;
+                },
+                _ = tokio::signal::ctrl_c() => {
+                    sending_graceful_shutdown_signal(handle, "SIGINT").await;
+                }
+            };
+            break;
+        }
     }
 }
 
 async fn sending_graceful_shutdown_signal(handle: Handle, signal: &'static str) {
     info!("{signal} received: starting graceful shutdown");
+    super::lifecycle::shutdown(Some(signal)).await;
 
     // Signal the server to shutdown using Handle.
     handle.graceful_shutdown(Some(Duration::from_secs(3)));
@@ -0,0 +1,149 @@
+//! `Server-Timing` response header (`expose_server_timing`). The segments of a request
+//! are recorded in a task-local set by the middleware, so the queue and the upstream
+//! requests record their time without it being threaded through the handlers.
+
+use crate::info;
+use axum::http::{HeaderName, HeaderValue, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::cell::Cell;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+const SERVER_TIMING: HeaderName = HeaderName::from_static("server-timing");
+
+static ENABLED: OnceLock<bool> = OnceLock::new();
+
+tokio::task_local! {
+    static TIMING: Timing;
+}
+
+/// Time of the segments of a request, until its response headers
+#[derive(Default)]
+struct Timing {
+    /// Wait for a concurrent limit slot
+    queue: Cell<Duration>,
+    /// Upstream requests until their response headers, retries included
+    upstream: Cell<Duration>,
+    /// Upstream requests sent
+    upstream_requests: Cell<u32>,
+}
+
+pub(super) fn init(enabled: bool) {
+    if enabled {
+        info!("Server-Timing header enabled");
+    }
+    let _ = ENABLED.set(enabled);
+}
+
+/// Record the wait for a concurrent limit slot of the current request
+pub(super) fn record_queue(wait: Duration) {
+    let _ = TIMING.try_with(|timing| timing.queue.set(timing.queue.get() + wait));
+}
+
+/// Record an upstream request of the current request
+pub(super) fn record_upstream(elapsed: Duration) {
+    let _ = TIMING.try_with(|timing| {
+        timing.upstream.set(timing.upstream.get() + elapsed);
+        timing
+            .upstream_requests
+            .set(timing.upstream_requests.get() + 1);
+    });
+}
+
+/// Add the `Server-Timing` header to the responses
+pub(super) async fn server_timing<B>(request: Request<B>, next: Next<B>) -> Response {
+    if !ENABLED.get().copied().unwrap_or(false) {
+        return next.run(request).await;
+    }
+
+    let start = Instant::now();
+    let (mut resp, timing) = TIMING
+        .scope(Timing::default(), async move {
+            let resp = next.run(request).await;
+            (resp, TIMING.with(Timing::snapshot))
+        })
+        .await;
+    if let Ok(value) = HeaderValue::from_str(&timing.header(start.elapsed())) {
+        resp.headers_mut().append(SERVER_TIMING, value);
+    }
+    resp
+}
+
+impl Timing {
+    fn snapshot(&self) -> Self {
+        Self {
+            queue: Cell::new(self.queue.get()),
+            upstream: Cell::new(self.upstream.get()),
+            upstream_requests: Cell::new(self.upstream_requests.get()),
+        }
+    }
+
+    /// Header value, durations in milliseconds. `proxy` is the time spent in the
+    /// proxy itself, the total less the queue and upstream segments.
+    fn header(&self, total: Duration) -> String {
+        let (queue, upstream) = (self.queue.get(), self.upstream.get());
+        let proxy = total.saturating_sub(queue).saturating_sub(upstream);
+        let mut value = format!("queue;dur={:.1}", millis(queue));
+        match self.upstream_requests.get() {
+            0 => {}
+            1 => value.push_str(&format!(", upstream;dur={:.1}", millis(upstream))),
+            requests => value.push_str(&format!(
+                ", upstream;dur={:.1};desc=\"{requests} requests\"",
+                millis(upstream)
+            )),
+        }
+        value.push_str(&format!(
+            ", proxy;dur={:.1}, total;dur={:.1}",
+            millis(proxy),
+            millis(total)
+        ));
+        value
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header() {
+        let timing = Timing::default();
+        timing.queue.set(Duration::from_micros(2_500));
+        timing.upstream.set(Duration::from_millis(120));
+        timing.upstream_requests.set(2);
+        assert_eq!(
+            timing.header(Duration::from_millis(130)),
+            "queue;dur=2.5, upstream;dur=120.0;desc=\"2 requests\", proxy;dur=7.5, total;dur=130.0"
+        );
+
+        // Answered without an upstream request
+        assert_eq!(
+            Timing::default().header(Duration::from_millis(3)),
+            "queue;dur=0.0, proxy;dur=3.0, total;dur=3.0"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_recorded_in_scope() {
+        let timing = TIMING
+            .scope(Timing::default(), async {
+                record_queue(Duration::from_millis(5));
+                record_upstream(Duration::from_millis(40));
+                tokio::task::yield_now().await;
+                record_upstream(Duration::from_millis(10));
+                TIMING.with(Timing::snapshot)
+            })
+            .await;
+        assert_eq!(timing.queue.get(), Duration::from_millis(5));
+        assert_eq!(timing.upstream.get(), Duration::from_millis(50));
+        assert_eq!(timing.upstream_requests.get(), 2);
+
+        // Outside of a request, e.g. a spawned mirror request
+        record_upstream(Duration::from_millis(10));
+    }
+}
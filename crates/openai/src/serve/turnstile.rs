@@ -1,5 +1,41 @@
-use crate::{serve::error::ProxyError, with_context};
+use crate::{
+    info,
+    serve::{error::ProxyError, privacy},
+    warn, with_context,
+};
 use std::net::IpAddr;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Longest wait of a verification for a `cf_verify_concurrency` slot
+const VERIFY_QUEUE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Turnstile token verification endpoint
+const SITEVERIFY_URL: &str = "https://challenges.cloudflare.com/turnstile/v0/siteverify";
+
+/// Slots of the concurrent siteverify calls, `None` is unlimited
+static VERIFY_SLOTS: OnceLock<Option<Semaphore>> = OnceLock::new();
+
+pub(super) fn init(concurrency: usize) {
+    if concurrency > 0 {
+        info!("Turnstile verify concurrency: {concurrency}");
+    }
+    let _ = VERIFY_SLOTS.set((concurrency > 0).then(|| Semaphore::new(concurrency)));
+}
+
+/// Wait for a verification slot, the excess verifications queue up to `timeout`
+async fn verify_slot(
+    slots: &Semaphore,
+    timeout: Duration,
+) -> Result<SemaphorePermit<'_>, ProxyError> {
+    match tokio::time::timeout(timeout, slots.acquire()).await {
+        Ok(Ok(permit)) => Ok(permit),
+        _ => {
+            warn!("Turnstile verification queue timed out, cf_verify_concurrency reached");
+            Err(ProxyError::CfVerifyBusy)
+        }
+    }
+}
 
 pub(super) async fn cf_turnstile_check(
     addr: IpAddr,
@@ -9,7 +45,8 @@ pub(super) async fn cf_turnstile_check(
     struct CfCaptchaForm<'a> {
         secret: &'a str,
         response: &'a str,
-        remoteip: &'a IpAddr,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        remoteip: Option<&'a IpAddr>,
         idempotency_key: String,
     }
 
@@ -23,19 +60,111 @@ pub(super) async fn cf_turnstile_check(
         let form = CfCaptchaForm {
             secret: &turnsile.secret_key,
             response,
-            remoteip: &addr,
+            // The client address is optional for the siteverify
+            remoteip: (!privacy::enabled()).then_some(&addr),
             idempotency_key: crate::uuid::uuid(),
         };
 
-        let _ = ctx
-            .api_client()
-            .post("https://challenges.cloudflare.com/turnstile/v0/siteverify")
-            .form(&form)
-            .send()
-            .await
-            .map_err(ProxyError::RequestError)?
-            .error_for_status()
-            .map_err(ProxyError::CfError)?;
+        let _permit = match VERIFY_SLOTS.get() {
+            Some(Some(slots)) => Some(verify_slot(slots, VERIFY_QUEUE_TIMEOUT).await?),
+            _ => None,
+        };
+        siteverify(&ctx.api_client(), SITEVERIFY_URL, &form).await?;
     }
     Ok(())
 }
+
+/// Verify a token with the siteverify endpoint. An invalid token is answered with a
+/// 200 and `success: false`, the status alone does not verify it.
+async fn siteverify(
+    client: &reqwest::Client,
+    url: &str,
+    form: &impl serde::Serialize,
+) -> Result<(), ProxyError> {
+    #[derive(serde::Deserialize)]
+    struct Siteverify {
+        success: bool,
+        #[serde(default, rename = "error-codes")]
+        error_codes: Vec<String>,
+    }
+
+    let verify = client
+        .post(url)
+        .form(form)
+        .send()
+        .await
+        .map_err(ProxyError::RequestError)?
+        .error_for_status()
+        .map_err(ProxyError::CfError)?
+        .json::<Siteverify>()
+        .await
+        .map_err(ProxyError::CfError)?;
+    match verify.success {
+        true => Ok(()),
+        false => Err(ProxyError::CfVerifyFailed(verify.error_codes.join(", "))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Siteverify endpoint answering 200 with `body` to each request
+    async fn siteverify_mock(body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let _ = stream.read(&mut [0u8; 4096]).await;
+                let resp = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = stream.write_all(resp.as_bytes()).await;
+            }
+        });
+        format!("http://{addr}/turnstile/v0/siteverify")
+    }
+
+    #[tokio::test]
+    async fn test_siteverify_success_required() {
+        let client = reqwest::Client::new();
+        let form = [("secret", "secret"), ("response", "token")];
+
+        let url = siteverify_mock(r#"{"success":true,"hostname":"example.com"}"#).await;
+        assert!(siteverify(&client, &url, &form).await.is_ok());
+
+        // An invalid token is a 200 too
+        let url =
+            siteverify_mock(r#"{"success":false,"error-codes":["invalid-input-response"]}"#).await;
+        let err = siteverify(&client, &url, &form).await.unwrap_err();
+        assert!(err.to_string().contains("invalid-input-response"), "{err}");
+
+        // Not a siteverify reply
+        let url = siteverify_mock("<html></html>").await;
+        assert!(siteverify(&client, &url, &form).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_excess_verifications_queue() {
+        let slots = Semaphore::new(1);
+        let held = verify_slot(&slots, Duration::from_millis(50))
+            .await
+            .unwrap();
+
+        // Queued, then given up
+        assert!(verify_slot(&slots, Duration::from_millis(50))
+            .await
+            .is_err());
+
+        // Queued until the slot is released
+        let release = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(held);
+        };
+        let (permit, ()) = tokio::join!(verify_slot(&slots, Duration::from_secs(1)), release);
+        assert!(permit.is_ok());
+    }
+}
@@ -0,0 +1,305 @@
+use crate::info;
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+
+/// Migration backups directory, relative to the worker directory
+pub const BACKUP_DIR: &str = "backup";
+
+/// Persisted store in the worker directory, versioned by a `<name>.version` marker
+pub struct Store {
+    /// Store name, also the marker file name
+    pub name: &'static str,
+    /// Files and directories of the store, relative to the worker directory
+    pub paths: &'static [&'static str],
+    /// Current schema version
+    pub version: u32,
+    /// Ordered migrations, `migrations[n]` upgrades version `n` to `n + 1`
+    pub migrations: &'static [Migration],
+}
+
+pub struct Migration {
+    pub description: &'static str,
+    pub apply: fn(state_dir: &Path) -> anyhow::Result<()>,
+}
+
+/// Unversioned stores (version 0) have the same layout as version 1
+const ADD_VERSION_MARKER: Migration = Migration {
+    description: "add the schema version marker",
+    apply: |_| Ok(()),
+};
+
+/// Persisted stores of the server
+pub static STORES: &[Store] = &[
+    Store {
+        name: "arkose",
+        paths: &["arkose.db"],
+        version: 1,
+        migrations: &[ADD_VERSION_MARKER],
+    },
+    Store {
+        name: "token_bucket",
+        paths: &["token_bucket.db"],
+        version: 1,
+        migrations: &[ADD_VERSION_MARKER],
+    },
+    Store {
+        name: "budget",
+        paths: &["budget.db"],
+        version: 1,
+        migrations: &[ADD_VERSION_MARKER],
+    },
+    Store {
+        name: "idempotency",
+        paths: &["idempotency.db"],
+        version: 1,
+        migrations: &[ADD_VERSION_MARKER],
+    },
+    Store {
+        name: "checkpoint",
+        paths: &["checkpoint.json"],
+        version: 1,
+        migrations: &[ADD_VERSION_MARKER],
+    },
+    Store {
+        name: "har",
+        paths: &["gpt3", "gpt4", "auth", "platform", "signup"],
+        version: 1,
+        migrations: &[ADD_VERSION_MARKER],
+    },
+];
+
+/// Migrate the persisted stores of the worker directory to the current versions.
+/// The store is backed up under `backup/` before the first migration step.
+pub fn run(state_dir: &Path) -> anyhow::Result<()> {
+    for store in STORES {
+        migrate(state_dir, store)?;
+    }
+    Ok(())
+}
+
+/// Migrate a single store
+pub fn migrate(state_dir: &Path, store: &Store) -> anyhow::Result<()> {
+    debug_assert_eq!(store.migrations.len(), store.version as usize);
+
+    let exists = store.paths.iter().any(|p| state_dir.join(p).exists());
+    let version = match read_version(state_dir, store)? {
+        Some(version) => version,
+        // Fresh store, nothing to migrate
+        None if !exists => return write_version(state_dir, store, store.version),
+        // Written before the version markers
+        None => 0,
+    };
+
+    if version > store.version {
+        anyhow::bail!(
+            "state `{}` was written by a newer version (schema version {version}, supported {}), upgrade ninja or restore a backup",
+            store.name,
+            store.version
+        )
+    }
+
+    if version == store.version {
+        return Ok(());
+    }
+
+    if exists {
+        let backup = backup(state_dir, store, version)?;
+        info!(
+            "State `{}` backed up to {} before migrating",
+            store.name,
+            backup.display()
+        );
+    }
+
+    for (from, migration) in store.migrations.iter().enumerate().skip(version as usize) {
+        let to = from as u32 + 1;
+        info!(
+            "Migrating state `{}`: v{from} -> v{to}, {}",
+            store.name, migration.description
+        );
+        (migration.apply)(state_dir).with_context(|| {
+            format!(
+                "Failed to migrate state `{}` from v{from} to v{to}, the backup is in {}",
+                store.name,
+                state_dir.join(BACKUP_DIR).display()
+            )
+        })?;
+        write_version(state_dir, store, to)?;
+    }
+
+    Ok(())
+}
+
+fn marker(state_dir: &Path, store: &Store) -> PathBuf {
+    state_dir.join(format!("{}.version", store.name))
+}
+
+fn read_version(state_dir: &Path, store: &Store) -> anyhow::Result<Option<u32>> {
+    let path = marker(state_dir, store);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let data = std::fs::read_to_string(&path)?;
+    let version = data
+        .trim()
+        .parse::<u32>()
+        .with_context(|| format!("Invalid state version marker {}", path.display()))?;
+    Ok(Some(version))
+}
+
+fn write_version(state_dir: &Path, store: &Store, version: u32) -> anyhow::Result<()> {
+    std::fs::create_dir_all(state_dir)?;
+    std::fs::write(marker(state_dir, store), format!("{version}\n"))?;
+    Ok(())
+}
+
+/// Copy the store into `backup/<name>-v<version>-<timestamp>`
+fn backup(state_dir: &Path, store: &Store, version: u32) -> anyhow::Result<PathBuf> {
+    let backup = state_dir.join(BACKUP_DIR).join(format!(
+        "{}-v{version}-{}",
+        store.name,
+        crate::now_duration()?.as_secs()
+    ));
+    std::fs::create_dir_all(&backup)?;
+    for path in store.paths {
+        let src = state_dir.join(path);
+        if src.exists() {
+            copy_all(&src, &backup.join(path))
+                .with_context(|| format!("Failed to back up {}", src.display()))?;
+        }
+    }
+    if let Ok(Some(_)) = read_version(state_dir, store) {
+        std::fs::copy(
+            marker(state_dir, store),
+            backup.join(format!("{}.version", store.name)),
+        )?;
+    }
+    Ok(backup)
+}
+
+fn copy_all(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    if src.is_dir() {
+        std::fs::create_dir_all(dst)?;
+        for entry in std::fs::read_dir(src)? {
+            let entry = entry?;
+            copy_all(&entry.path(), &dst.join(entry.file_name()))?;
+        }
+    } else {
+        std::fs::copy(src, dst)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "ninja-migrate-{}-{}",
+            std::process::id(),
+            rand::random::<u32>()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// v1 stores `key=value` lines, v2 stores JSON
+    static KV_STORE: Store = Store {
+        name: "kv",
+        paths: &["kv.db"],
+        version: 2,
+        migrations: &[
+            ADD_VERSION_MARKER,
+            Migration {
+                description: "convert key=value lines to json",
+                apply: |dir| {
+                    let data = std::fs::read_to_string(dir.join("kv.db"))?;
+                    let map = data
+                        .lines()
+                        .filter_map(|line| line.split_once('='))
+                        .collect::<std::collections::BTreeMap<_, _>>();
+                    std::fs::write(dir.join("kv.db"), serde_json::to_vec(&map)?)?;
+                    Ok(())
+                },
+            },
+        ],
+    };
+
+    #[test]
+    fn test_migrate_unversioned_store() {
+        let dir = temp_dir();
+        std::fs::write(dir.join("kv.db"), "a=1\nb=2\n").unwrap();
+
+        migrate(&dir, &KV_STORE).unwrap();
+
+        let data = std::fs::read(dir.join("kv.db")).unwrap();
+        let map: std::collections::BTreeMap<String, String> =
+            serde_json::from_slice(&data).unwrap();
+        assert_eq!(map["a"], "1");
+        assert_eq!(map["b"], "2");
+        assert_eq!(read_version(&dir, &KV_STORE).unwrap(), Some(2));
+
+        // The pre-migration state is backed up
+        let backup = std::fs::read_dir(dir.join("backup"))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        assert!(backup
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("kv-v0-"));
+        assert_eq!(
+            std::fs::read_to_string(backup.join("kv.db")).unwrap(),
+            "a=1\nb=2\n"
+        );
+
+        // Already current, the migration is not applied again
+        migrate(&dir, &KV_STORE).unwrap();
+        assert_eq!(std::fs::read(dir.join("kv.db")).unwrap(), data);
+    }
+
+    #[test]
+    fn test_migrate_fresh_store() {
+        let dir = temp_dir();
+        migrate(&dir, &KV_STORE).unwrap();
+        assert_eq!(read_version(&dir, &KV_STORE).unwrap(), Some(2));
+        assert!(!dir.join("backup").exists());
+    }
+
+    #[test]
+    fn test_migrate_newer_version() {
+        let dir = temp_dir();
+        std::fs::write(dir.join("kv.db"), "{}").unwrap();
+        write_version(&dir, &KV_STORE, 3).unwrap();
+
+        let err = migrate(&dir, &KV_STORE).unwrap_err().to_string();
+        assert!(err.contains("written by a newer version"), "{err}");
+    }
+
+    #[test]
+    fn test_migrate_after_state_import() {
+        use crate::secret::Secret;
+        use crate::state::{export, import, ImportMode};
+
+        // Export a state written before the version markers
+        let old = temp_dir();
+        std::fs::write(old.join("arkose.db"), b"arkose").unwrap();
+        let key = Secret::new("state-key");
+        let mut archive = Vec::new();
+        export(&old, &key, &mut archive).unwrap();
+
+        // A newer server imports it and migrates on startup
+        let dir = temp_dir();
+        import(archive.as_slice(), &dir, &key, ImportMode::Replace).unwrap();
+        run(&dir).unwrap();
+        for store in STORES {
+            assert_eq!(read_version(&dir, store).unwrap(), Some(store.version));
+        }
+        assert_eq!(std::fs::read(dir.join("arkose.db")).unwrap(), b"arkose");
+    }
+}
@@ -0,0 +1,483 @@
+//! Runtime state archive, moves the worker directory (`~/.ninja`: arkose
+//! versions, token buckets, HAR files, ...) between hosts.
+//!
+//! The archive is a tar file with a plaintext `manifest.json` and the state
+//! files under `state/`, each encrypted with AES-256-CBC and authenticated with
+//! HMAC-SHA256 (encrypt-then-MAC) using keys derived from the state key with
+//! PBKDF2-HMAC-SHA256.
+
+pub mod migrate;
+
+use crate::secret::Secret;
+use crate::LIB_VERSION;
+use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use anyhow::{anyhow, Context};
+use hmac::{Hmac, Mac};
+use rand::random;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// Current state archive format version. Version 1 derived the keys with a single
+/// SHA-256, its archives are still imported.
+pub const STATE_FORMAT_VERSION: u32 = 2;
+
+/// PBKDF2 iterations of the archives exported
+#[cfg(not(test))]
+const KDF_ITERATIONS: u32 = 600_000;
+#[cfg(test)]
+const KDF_ITERATIONS: u32 = 1_000;
+
+const MANIFEST: &str = "manifest.json";
+const STATE_PREFIX: &str = "state/";
+
+type Aes256CbcEnc = cbc::Encryptor<aes::Aes256>;
+type Aes256CbcDec = cbc::Decryptor<aes::Aes256>;
+type HmacSha256 = Hmac<Sha256>;
+
+/// State archive manifest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Archive format version
+    pub format_version: u32,
+    /// Version of the exporting server
+    pub ninja_version: String,
+    /// Export time (unix seconds)
+    pub created_at: u64,
+    /// Key derivation salt (hex)
+    pub salt: String,
+    /// PBKDF2 iterations, absent from the version 1 archives
+    #[serde(default)]
+    pub kdf_iterations: u32,
+    /// Archived state files
+    pub files: Vec<ManifestFile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestFile {
+    /// Path relative to the state directory
+    pub path: String,
+    /// Plaintext size
+    pub size: u64,
+    /// Plaintext SHA-256 (hex)
+    pub sha256: String,
+}
+
+/// How imported state is combined with the existing state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Archived files overwrite the same files, other existing files are kept
+    Merge,
+    /// The existing state directory is cleared first
+    Replace,
+}
+
+/// Export the state directory into `out`
+pub fn export(state_dir: &Path, key: &Secret, out: impl Write) -> anyhow::Result<Manifest> {
+    if key.is_empty() {
+        anyhow::bail!("state key is required")
+    }
+
+    let salt = random::<[u8; 16]>();
+    let keys = Keys::derive(key, &salt, KDF_ITERATIONS);
+
+    // Migration backups are not part of the state
+    let mut files = Vec::new();
+    collect_files(state_dir, state_dir, &mut files)?;
+    files.retain(|path| !path.starts_with(migrate::BACKUP_DIR));
+    files.sort();
+
+    let mut manifest = Manifest {
+        format_version: STATE_FORMAT_VERSION,
+        ninja_version: LIB_VERSION.to_owned(),
+        created_at: crate::now_duration()?.as_secs(),
+        salt: hex(&salt),
+        kdf_iterations: KDF_ITERATIONS,
+        files: Vec::with_capacity(files.len()),
+    };
+
+    let mut entries = Vec::with_capacity(files.len());
+    for relative in files {
+        let data = std::fs::read(state_dir.join(&relative))
+            .with_context(|| format!("Failed to read state file {}", relative.display()))?;
+        let path = relative_to_string(&relative)?;
+        manifest.files.push(ManifestFile {
+            path: path.clone(),
+            size: data.len() as u64,
+            sha256: hex(&Sha256::digest(&data)),
+        });
+        entries.push((path, keys.seal(&data)?));
+    }
+
+    let mut builder = tar::Builder::new(out);
+    append(
+        &mut builder,
+        MANIFEST,
+        &serde_json::to_vec_pretty(&manifest)?,
+    )?;
+    for (path, data) in entries {
+        append(&mut builder, &format!("{STATE_PREFIX}{path}"), &data)?;
+    }
+    builder.into_inner()?.flush()?;
+
+    Ok(manifest)
+}
+
+/// Read the manifest of a state archive without decrypting it
+pub fn read_manifest(archive: impl Read) -> anyhow::Result<Manifest> {
+    let mut archive = tar::Archive::new(archive);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.as_os_str() == MANIFEST {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            return Ok(serde_json::from_slice(&data)?);
+        }
+    }
+    anyhow::bail!("state archive has no manifest")
+}
+
+/// Import a state archive into the state directory
+pub fn import(
+    archive: impl Read,
+    state_dir: &Path,
+    key: &Secret,
+    mode: ImportMode,
+) -> anyhow::Result<Manifest> {
+    let mut manifest = None;
+    let mut sealed = Vec::new();
+
+    let mut archive = tar::Archive::new(archive);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        if path == MANIFEST {
+            manifest = Some(serde_json::from_slice::<Manifest>(&data)?);
+        } else if let Some(path) = path.strip_prefix(STATE_PREFIX) {
+            sealed.push((path.to_owned(), data));
+        }
+    }
+
+    let manifest = manifest.ok_or_else(|| anyhow!("state archive has no manifest"))?;
+    check_version(&manifest)?;
+
+    let salt = unhex(&manifest.salt)?;
+    let keys = match manifest.format_version {
+        1 => Keys::derive_v1(key, &salt),
+        _ if manifest.kdf_iterations == 0 => anyhow::bail!("state archive has no kdf iterations"),
+        _ => Keys::derive(key, &salt, manifest.kdf_iterations),
+    };
+
+    // Decrypt and verify everything before touching the state directory
+    let mut files = Vec::with_capacity(manifest.files.len());
+    for file in &manifest.files {
+        let relative = safe_relative_path(&file.path)?;
+        let data = sealed
+            .iter()
+            .find(|(path, _)| path == &file.path)
+            .ok_or_else(|| anyhow!("state file {} is missing from the archive", file.path))?;
+        let data = keys
+            .open(&data.1)
+            .with_context(|| format!("state file {}", file.path))?;
+        if hex(&Sha256::digest(&data)) != file.sha256 {
+            anyhow::bail!("state file {} checksum mismatch", file.path)
+        }
+        files.push((relative, data));
+    }
+
+    if mode == ImportMode::Replace && state_dir.exists() {
+        std::fs::remove_dir_all(state_dir)
+            .with_context(|| format!("Failed to clear state directory {}", state_dir.display()))?;
+    }
+
+    for (relative, data) in files {
+        let path = state_dir.join(relative);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, data)
+            .with_context(|| format!("Failed to write state file {}", path.display()))?;
+    }
+
+    Ok(manifest)
+}
+
+fn check_version(manifest: &Manifest) -> anyhow::Result<()> {
+    if manifest.format_version > STATE_FORMAT_VERSION {
+        anyhow::bail!(
+            "state archive format version {} (ninja {}) is newer than the supported version {STATE_FORMAT_VERSION}, upgrade ninja first",
+            manifest.format_version,
+            manifest.ninja_version
+        )
+    }
+    if manifest.format_version == 0 {
+        anyhow::bail!("invalid state archive format version 0")
+    }
+    Ok(())
+}
+
+struct Keys {
+    enc: [u8; 32],
+    mac: [u8; 32],
+}
+
+impl Keys {
+    /// Encryption and MAC keys, the two halves of a PBKDF2-HMAC-SHA256 output
+    fn derive(key: &Secret, salt: &[u8], iterations: u32) -> Self {
+        let mut derived = [0u8; 64];
+        pbkdf2::pbkdf2_hmac::<Sha256>(key.expose().as_bytes(), salt, iterations, &mut derived);
+        let (enc, mac) = derived.split_at(32);
+        Self {
+            enc: enc.try_into().expect("32 bytes"),
+            mac: mac.try_into().expect("32 bytes"),
+        }
+    }
+
+    /// Keys of the version 1 archives
+    fn derive_v1(key: &Secret, salt: &[u8]) -> Self {
+        let derive = |label: &[u8]| -> [u8; 32] {
+            let mut hasher = Sha256::new();
+            hasher.update(label);
+            hasher.update(salt);
+            hasher.update(key.expose().as_bytes());
+            hasher.finalize().into()
+        };
+        Self {
+            enc: derive(b"ninja-state-enc"),
+            mac: derive(b"ninja-state-mac"),
+        }
+    }
+
+    /// iv (16) || ciphertext || hmac (32)
+    fn seal(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let iv = random::<[u8; 16]>();
+        let mut buf = vec![0u8; data.len() + 16];
+        let ciphertext = Aes256CbcEnc::new_from_slices(&self.enc, &iv)?
+            .encrypt_padded_b2b_mut::<Pkcs7>(data, &mut buf)
+            .map_err(|err| anyhow!(err))?;
+        let mut sealed = Vec::with_capacity(iv.len() + ciphertext.len() + 32);
+        sealed.extend_from_slice(&iv);
+        sealed.extend_from_slice(ciphertext);
+        let tag = self.tag(&sealed)?;
+        sealed.extend_from_slice(&tag);
+        Ok(sealed)
+    }
+
+    fn open(&self, sealed: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if sealed.len() < 16 + 32 {
+            anyhow::bail!("truncated data")
+        }
+        let (body, tag) = sealed.split_at(sealed.len() - 32);
+        let mut mac = HmacSha256::new_from_slice(&self.mac)?;
+        mac.update(body);
+        mac.verify_slice(tag)
+            .map_err(|_| anyhow!("wrong state key or corrupted archive"))?;
+        let (iv, ciphertext) = body.split_at(16);
+        let mut buf = ciphertext.to_vec();
+        let len = Aes256CbcDec::new_from_slices(&self.enc, iv)?
+            .decrypt_padded_mut::<Pkcs7>(&mut buf)
+            .map_err(|_| anyhow!("wrong state key or corrupted archive"))?
+            .len();
+        buf.truncate(len);
+        Ok(buf)
+    }
+
+    fn tag(&self, data: &[u8]) -> anyhow::Result<[u8; 32]> {
+        let mut mac = HmacSha256::new_from_slice(&self.mac)?;
+        mac.update(data);
+        Ok(mac.finalize().into_bytes().into())
+    }
+}
+
+fn collect_files(root: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(root, &path, files)?;
+        } else if path.is_file() {
+            files.push(path.strip_prefix(root)?.to_path_buf());
+        }
+    }
+    Ok(())
+}
+
+fn append<W: Write>(builder: &mut tar::Builder<W>, path: &str, data: &[u8]) -> anyhow::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o600);
+    header.set_cksum();
+    builder.append_data(&mut header, path, data)?;
+    Ok(())
+}
+
+fn relative_to_string(path: &Path) -> anyhow::Result<String> {
+    let parts = path
+        .components()
+        .map(|c| match c {
+            Component::Normal(part) => part
+                .to_str()
+                .map(str::to_owned)
+                .ok_or_else(|| anyhow!("state file {} is not utf8", path.display())),
+            _ => Err(anyhow!("invalid state file path {}", path.display())),
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(parts.join("/"))
+}
+
+/// Reject absolute paths and `..` in archived paths
+fn safe_relative_path(path: &str) -> anyhow::Result<PathBuf> {
+    let relative = PathBuf::from(path);
+    if path.is_empty()
+        || !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+    {
+        anyhow::bail!("invalid state file path {path}")
+    }
+    Ok(relative)
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn unhex(s: &str) -> anyhow::Result<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        anyhow::bail!("invalid hex string")
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(Into::into))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "ninja-state-{name}-{}-{}",
+            std::process::id(),
+            random::<u32>()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn state_dir() -> PathBuf {
+        let dir = temp_dir("src");
+        std::fs::write(dir.join("arkose.db"), b"arkose versions").unwrap();
+        std::fs::write(dir.join("token_bucket.db"), vec![7u8; 4096]).unwrap();
+        std::fs::create_dir_all(dir.join("gpt4")).unwrap();
+        std::fs::write(dir.join("gpt4/tokens.har"), b"{}").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_export_import_round_trip() {
+        let src = state_dir();
+        let key = Secret::new("state-key");
+        let mut archive = Vec::new();
+        let manifest = export(&src, &key, &mut archive).unwrap();
+        assert_eq!(manifest.files.len(), 3);
+
+        // The archive does not contain plaintext state
+        assert!(!archive
+            .windows(b"arkose versions".len())
+            .any(|w| w == b"arkose versions"));
+
+        let dst = temp_dir("dst");
+        std::fs::write(dst.join("local.db"), b"local").unwrap();
+        import(archive.as_slice(), &dst, &key, ImportMode::Merge).unwrap();
+        for file in ["arkose.db", "token_bucket.db", "gpt4/tokens.har"] {
+            assert_eq!(
+                std::fs::read(src.join(file)).unwrap(),
+                std::fs::read(dst.join(file)).unwrap()
+            );
+        }
+        assert!(dst.join("local.db").exists());
+
+        import(archive.as_slice(), &dst, &key, ImportMode::Replace).unwrap();
+        assert!(!dst.join("local.db").exists());
+        assert!(dst.join("arkose.db").exists());
+    }
+
+    #[test]
+    fn test_import_wrong_key() {
+        let src = state_dir();
+        let mut archive = Vec::new();
+        export(&src, &Secret::new("state-key"), &mut archive).unwrap();
+
+        let dst = temp_dir("wrong");
+        let err = import(
+            archive.as_slice(),
+            &dst,
+            &Secret::new("other"),
+            ImportMode::Merge,
+        )
+        .unwrap_err();
+        assert!(format!("{err:#}").contains("wrong state key"), "{err:#}");
+        assert!(!dst.join("arkose.db").exists());
+    }
+
+    #[test]
+    fn test_import_newer_format_version() {
+        let src = state_dir();
+        let key = Secret::new("state-key");
+        let mut archive = Vec::new();
+        let mut manifest = export(&src, &key, &mut archive).unwrap();
+        assert_eq!(read_manifest(archive.as_slice()).unwrap().files.len(), 3);
+
+        manifest.format_version = STATE_FORMAT_VERSION + 1;
+        let err = check_version(&manifest).unwrap_err().to_string();
+        assert!(err.contains("newer than the supported version"), "{err}");
+    }
+
+    #[test]
+    fn test_import_format_version_1() {
+        // Keys derived with a single SHA-256, no kdf iterations in the manifest
+        let key = Secret::new("state-key");
+        let salt = random::<[u8; 16]>();
+        let data = b"arkose versions";
+        let manifest = serde_json::json!({
+            "format_version": 1,
+            "ninja_version": "0.9.0",
+            "created_at": 0,
+            "salt": hex(&salt),
+            "files": [{"path": "arkose.db", "size": data.len(), "sha256": hex(&Sha256::digest(data))}],
+        });
+        let mut builder = tar::Builder::new(Vec::new());
+        append(
+            &mut builder,
+            MANIFEST,
+            &serde_json::to_vec(&manifest).unwrap(),
+        )
+        .unwrap();
+        let sealed = Keys::derive_v1(&key, &salt).seal(data).unwrap();
+        append(&mut builder, "state/arkose.db", &sealed).unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let dst = temp_dir("v1");
+        import(archive.as_slice(), &dst, &key, ImportMode::Merge).unwrap();
+        assert_eq!(std::fs::read(dst.join("arkose.db")).unwrap(), data);
+
+        // The keys of the current version are stretched
+        let current = Keys::derive(&key, &salt, KDF_ITERATIONS);
+        assert_ne!(current.enc, Keys::derive_v1(&key, &salt).enc);
+        assert_ne!(current.enc, current.mac);
+    }
+
+    #[test]
+    fn test_safe_relative_path() {
+        assert!(safe_relative_path("gpt4/tokens.har").is_ok());
+        assert!(safe_relative_path("../etc/passwd").is_err());
+        assert!(safe_relative_path("/etc/passwd").is_err());
+    }
+}
@@ -0,0 +1,84 @@
+#![cfg(feature = "serve")]
+
+mod common;
+
+use axum::http::StatusCode;
+use axum::{routing::get, Router};
+use openai::serve::{Args, Serve};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// Log output of the test
+#[derive(Clone, Default)]
+struct Capture(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Capture {
+    /// Response events of a request path
+    fn responses(&self, path: &str) -> Vec<String> {
+        String::from_utf8(self.0.lock().unwrap().clone())
+            .unwrap()
+            .lines()
+            .filter(|line| line.contains(&format!("uri={path} ")))
+            .filter(|line| line.contains("finished processing request"))
+            .map(str::to_owned)
+            .collect()
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_unsampled_requests_log_server_errors_only() {
+    common::temp_home();
+    let capture = Capture::default();
+    let writer = capture.clone();
+    tracing::subscriber::set_global_default(
+        tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish(),
+    )
+    .unwrap();
+
+    let args = Args::builder()
+        .bind("127.0.0.1:0".parse::<SocketAddr>().unwrap())
+        .log_sample_rate(0.0)
+        .build();
+    let server = Serve::new(args)
+        .merge(
+            Router::new()
+                .route("/ok", get(|| async { "ok" }))
+                .route("/fail", get(|| async { StatusCode::BAD_GATEWAY })),
+        )
+        .run_async()
+        .await
+        .unwrap();
+    let addr = server.bound_addr();
+
+    let client = reqwest::Client::new();
+    for path in ["/ok", "/fail"] {
+        client
+            .get(format!("http://{addr}{path}"))
+            .send()
+            .await
+            .unwrap();
+    }
+    server.shutdown().await.unwrap();
+
+    // Nothing is sampled, the server error is logged in its request span anyway
+    assert!(capture.responses("/ok").is_empty());
+    let fail = capture.responses("/fail");
+    assert_eq!(fail.len(), 1, "{fail:?}");
+    assert!(fail[0].contains("method=GET"), "{}", fail[0]);
+    assert!(fail[0].contains("status=502"), "{}", fail[0]);
+}
@@ -0,0 +1,18 @@
+use std::path::PathBuf;
+
+/// Point `HOME` to a fresh temporary directory, so the server state (`~/.ninja`)
+/// of a test is not the one of the user running it. Call it before starting the
+/// server, the state directory is resolved on startup.
+pub fn temp_home() -> PathBuf {
+    let home = std::env::temp_dir().join(format!(
+        "ninja-home-{}-{}",
+        std::process::id(),
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+    ));
+    std::fs::create_dir_all(&home).unwrap();
+    std::env::set_var("HOME", &home);
+    home
+}
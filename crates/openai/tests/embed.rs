@@ -0,0 +1,56 @@
+#![cfg(feature = "serve")]
+
+mod common;
+
+use axum::{routing::get, Router};
+use openai::serve::{Args, Serve};
+use std::net::SocketAddr;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_embedded_server() {
+    common::temp_home();
+    let args = Args::builder()
+        .bind("127.0.0.1:0".parse::<SocketAddr>().unwrap())
+        .build();
+    let server = Serve::new(args)
+        .merge(Router::new().route("/healthz", get(|| async { "ok" })))
+        .run_async()
+        .await
+        .unwrap();
+
+    let addr = server.bound_addr();
+    assert_ne!(addr.port(), 0);
+
+    let client = reqwest::Client::new();
+
+    // Routes registered by the embedder
+    let resp = client
+        .get(format!("http://{addr}/healthz"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.text().await.unwrap(), "ok");
+
+    // Built-in routes
+    let resp = client
+        .get(format!("http://{addr}/metrics"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    server.shutdown().await.unwrap();
+    assert!(client
+        .get(format!("http://{addr}/healthz"))
+        .send()
+        .await
+        .is_err());
+
+    // The server state is global, a second server is refused
+    let args = Args::builder()
+        .bind("127.0.0.1:0".parse::<SocketAddr>().unwrap())
+        .build();
+    let err = Serve::new(args).run_async().await.err().unwrap();
+    assert!(err.to_string().contains("already started"), "{err}");
+}
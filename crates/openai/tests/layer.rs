@@ -0,0 +1,57 @@
+#![cfg(feature = "serve")]
+
+mod common;
+
+use axum::http::{HeaderMap, HeaderValue, Request};
+use axum::middleware::{from_fn, Next};
+use axum::response::Response;
+use axum::{routing::get, Router};
+use openai::serve::{Args, Serve};
+use std::net::SocketAddr;
+
+/// Append `name` to the `x-layers` request header
+async fn visit<B>(name: &'static str, mut request: Request<B>, next: Next<B>) -> Response {
+    let visited = match request.headers().get("x-layers") {
+        Some(value) => format!("{},{name}", value.to_str().unwrap()),
+        None => name.to_owned(),
+    };
+    request
+        .headers_mut()
+        .insert("x-layers", HeaderValue::from_str(&visited).unwrap());
+    next.run(request).await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_custom_layers_order() {
+    common::temp_home();
+    let args = Args::builder()
+        .bind("127.0.0.1:0".parse::<SocketAddr>().unwrap())
+        .build();
+    let server = Serve::new(args)
+        .layer(from_fn(|req, next| visit("outer", req, next)))
+        .layer(from_fn(|req, next| visit("inner", req, next)))
+        .merge(Router::new().route(
+            "/layers",
+            get(|headers: HeaderMap| async move {
+                headers
+                    .get("x-layers")
+                    .map(|value| value.to_str().unwrap().to_owned())
+                    .unwrap_or_default()
+            }),
+        ))
+        .run_async()
+        .await
+        .unwrap();
+    let addr = server.bound_addr();
+
+    // The first registered layer is the outermost, both run before the handler
+    let resp = reqwest::Client::new()
+        .get(format!("http://{addr}/layers"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.text().await.unwrap(), "outer,inner");
+
+    server.shutdown().await.unwrap();
+}
@@ -0,0 +1,32 @@
+#![cfg(feature = "serve")]
+
+mod common;
+
+use openai::serve::{Args, Serve};
+use std::net::SocketAddr;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_chunked_body_over_budget() {
+    common::temp_home();
+    let args = Args::builder()
+        .bind("127.0.0.1:0".parse::<SocketAddr>().unwrap())
+        .max_buffered_memory(1024u64)
+        .upstream_origin("http://127.0.0.1:9".to_owned())
+        .build();
+    let server = Serve::new(args).run_async().await.unwrap();
+    let addr = server.bound_addr();
+
+    // No Content-Length: the budget is only exceeded while the body is read
+    let chunks = (0..8).map(|_| Ok::<_, std::io::Error>(vec![b' '; 1024]));
+    let resp = reqwest::Client::new()
+        .post(format!("http://{addr}/v1/chat/completions"))
+        .header("authorization", "Bearer sk-test")
+        .header("content-type", "application/json")
+        .body(reqwest::Body::wrap_stream(futures::stream::iter(chunks)))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 503);
+
+    server.shutdown().await.unwrap();
+}
@@ -0,0 +1,143 @@
+#![cfg(feature = "serve")]
+
+mod common;
+
+use openai::context::args::MirrorConfig;
+use openai::serve::{Args, Serve};
+use reqwest::header::{HeaderMap, HeaderValue};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+/// Client identifiers that must never reach the upstream
+const SENTINELS: [&str; 4] = [
+    "203.0.113.77",
+    "sentinel-agent/1.0",
+    "sentinel-client-id",
+    "x-sentinel-lang",
+];
+
+fn client_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for name in [
+        "x-forwarded-for",
+        "x-real-ip",
+        "true-client-ip",
+        "forwarded",
+    ] {
+        headers.insert(name, HeaderValue::from_static("203.0.113.77"));
+    }
+    headers.insert("user-agent", HeaderValue::from_static("sentinel-agent/1.0"));
+    headers.insert(
+        "x-client-id",
+        HeaderValue::from_static("sentinel-client-id"),
+    );
+    headers.insert(
+        "accept-language",
+        HeaderValue::from_static("x-sentinel-lang"),
+    );
+    headers.insert("authorization", HeaderValue::from_static("Bearer sk-test"));
+    headers
+}
+
+/// Mock upstream, sends each request it receives, head and body, as it is on the wire
+async fn mock_upstream() -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut received = Vec::new();
+                let mut buf = [0u8; 4096];
+                let body_len = loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        return;
+                    }
+                    received.extend_from_slice(&buf[..n]);
+                    if let Some(end) = received.windows(4).position(|w| w == b"\r\n\r\n") {
+                        let head = String::from_utf8_lossy(&received[..end]).to_lowercase();
+                        let len = head
+                            .lines()
+                            .find_map(|line| line.strip_prefix("content-length:"))
+                            .map_or(0, |len| len.trim().parse::<usize>().unwrap());
+                        break end + 4 + len;
+                    }
+                };
+                while received.len() < body_len {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    received.extend_from_slice(&buf[..n]);
+                }
+                let _ = tx.send(String::from_utf8_lossy(&received).to_lowercase());
+                stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: 2\r\nconnection: close\r\n\r\n{}",
+                    )
+                    .await
+                    .unwrap();
+            });
+        }
+    });
+    (addr, rx)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_no_client_identifiers_upstream() {
+    common::temp_home();
+    let (upstream, mut upstream_received) = mock_upstream().await;
+    let (mirror, mut mirror_received) = mock_upstream().await;
+    let args = Args::builder()
+        .bind("127.0.0.1:0".parse::<SocketAddr>().unwrap())
+        .privacy_mode(true)
+        .privacy_strip_headers(vec!["x-client-id".to_owned()])
+        .upstream_origin(format!("http://{upstream}"))
+        .mirror(MirrorConfig {
+            origin: Some(format!("http://{mirror}").parse().unwrap()),
+            percent: 100.0,
+            ..Default::default()
+        })
+        .build();
+    let server = Serve::new(args).run_async().await.unwrap();
+    let addr = server.bound_addr();
+
+    let client = reqwest::Client::new();
+    // The platform routes (/v1/*, /dashboard/*) and the ChatGPT routes (/backend-api/*)
+    let requests = [
+        client
+            .post(format!("http://{addr}/v1/chat/completions"))
+            .header("content-type", "application/json")
+            .body(r#"{"model":"gpt-4o","messages":[{"role":"user","content":"hi"}]}"#),
+        client.get(format!("http://{addr}/dashboard/billing/credit_grants")),
+        client.get(format!("http://{addr}/backend-api/models")),
+    ];
+    for request in requests {
+        let resp = request.headers(client_headers()).send().await.unwrap();
+        assert_eq!(resp.status(), 200);
+
+        for (upstream, received) in [
+            ("upstream", &mut upstream_received),
+            ("mirror", &mut mirror_received),
+        ] {
+            let received = tokio::time::timeout(Duration::from_secs(5), received.recv())
+                .await
+                .unwrap_or_else(|_| panic!("{upstream} received nothing"))
+                .unwrap();
+            for sentinel in SENTINELS {
+                assert!(
+                    !received.contains(sentinel),
+                    "{upstream} received `{sentinel}`:\n{received}"
+                );
+            }
+        }
+    }
+
+    server.shutdown().await.unwrap();
+}
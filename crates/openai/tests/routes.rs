@@ -0,0 +1,133 @@
+#![cfg(feature = "serve")]
+
+mod common;
+
+use axum::{routing::get, Router};
+use openai::context::args::RouteLimit;
+use openai::serve::{Args, Serve};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_route_listing() {
+    common::temp_home();
+    let args = Args::builder()
+        .bind("127.0.0.1:0".parse::<SocketAddr>().unwrap())
+        .timeout(30usize)
+        .route_limits(BTreeMap::from([(
+            "/v1/chat/*".to_owned(),
+            RouteLimit {
+                capacity: 10,
+                fill_rate: 1,
+            },
+        )]))
+        .build();
+    let server = Serve::new(args)
+        .merge(Router::new().route("/healthz", get(|| async { "ok" })))
+        .run_async()
+        .await
+        .unwrap();
+    let addr = server.bound_addr();
+    let client = reqwest::Client::new();
+
+    // Routes served in the integration tests
+    for (path, status) in [
+        ("/healthz", 200),
+        ("/metrics", 200),
+        ("/admin/routes", 200),
+        // Without a state key
+        ("/admin/state", 404),
+        ("/unknown", 404),
+    ] {
+        let resp = client
+            .get(format!("http://{addr}{path}"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), status, "{path}");
+    }
+
+    let listing: Value = client
+        .get(format!("http://{addr}/admin/routes"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let routes = listing["routes"].as_array().unwrap();
+    let find = |group: &str, path: &str| {
+        routes
+            .iter()
+            .find(|route| route["group"] == group && route["path"] == path)
+            .unwrap_or_else(|| panic!("{group} {path} is not listed"))
+    };
+    let chain = |route: &Value| -> Vec<String> {
+        route["middlewares"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|name| name.as_str().unwrap().to_owned())
+            .collect()
+    };
+
+    // The global layers wrap every route of the main listener
+    let global = chain(find("fallback", "*"));
+    for name in ["trace", "ip_filter", "http2_downgrade", "timing", "timeout"] {
+        assert!(global.iter().any(|global| global == name), "{name}");
+    }
+    assert_eq!(global.last().unwrap(), "body_limit");
+
+    for path in ["/metrics", "/admin/routes"] {
+        let route = find("admin", path);
+        assert_eq!(route["listener"], "main");
+        assert_eq!(route["methods"][0], "GET");
+        // Gated, the admin endpoints share the public listener
+        assert_eq!(
+            chain(route),
+            [&global[..], &["admin_gate".to_owned()]].concat()
+        );
+    }
+    // The embedder routes are listed without their paths
+    assert_eq!(chain(find("custom", "*")), global);
+    let proxy = find("proxy", "/v1/*path");
+    assert_eq!(chain(proxy)[..global.len()], global);
+    assert_eq!(
+        chain(proxy)[global.len()..],
+        ["auth", "token_bucket", "idempotency"]
+    );
+    assert_eq!(proxy["timeout"], 30);
+    assert_eq!(proxy["route_limits"][0], "/v1/chat/*");
+    assert_eq!(
+        find("proxy", "/dashboard/*path")["route_limits"],
+        Value::Array(vec![])
+    );
+    assert_eq!(chain(find("auth", "/auth/token")), global);
+    assert_eq!(find("auth", "/auth/token")["methods"][0], "POST");
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_invalid_route_limit_refused() {
+    common::temp_home();
+    let args = Args::builder()
+        .bind("127.0.0.1:0".parse::<SocketAddr>().unwrap())
+        .route_limits(BTreeMap::from([(
+            "/v1/chat/*".to_owned(),
+            RouteLimit {
+                capacity: 0,
+                fill_rate: 1,
+            },
+        )]))
+        .build();
+    // Refused at startup, not only by `serve check`
+    let Err(err) = Serve::new(args).run_async().await else {
+        panic!("started with a route limit of capacity 0")
+    };
+    assert!(
+        err.to_string().contains("capacity must be at least 1"),
+        "{err}"
+    );
+}
@@ -0,0 +1,47 @@
+#![cfg(feature = "serve")]
+
+mod common;
+
+use openai::serve::{Args, Serve};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_unloadable_certs_refuse_connections() {
+    common::temp_home();
+    let dir = std::env::temp_dir().join(format!("ninja-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+    std::fs::write(&cert, "not a certificate").unwrap();
+    std::fs::copy(
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tls/key.pem"),
+        &key,
+    )
+    .unwrap();
+
+    // A listener declared as TLS requires both the certificate and the key. Checked
+    // before the server state is initialized, the next server can still start.
+    let args = Args::builder()
+        .bind("127.0.0.1:0".parse::<SocketAddr>().unwrap())
+        .tls_cert(cert.clone())
+        .build();
+    let err = Serve::new(args).run_async().await.err().unwrap();
+    assert!(err.to_string().contains("configured together"), "{err}");
+
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let args = Args::builder()
+        .bind(addr)
+        .tls_cert(cert)
+        .tls_key(key)
+        .build();
+
+    // The TLS listener does not start, it never falls back to plaintext
+    let err = Serve::new(args).run_async().await.err().unwrap();
+    assert!(err.to_string().contains("certificate"), "{err}");
+    assert!(TcpStream::connect(addr).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,24 @@
+use axum::{routing::get, Router};
+use openai::serve::{Args, Serve};
+use std::net::SocketAddr;
+
+fn main() -> anyhow::Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+
+    runtime.block_on(async {
+        let args = Args::builder()
+            .bind("127.0.0.1:0".parse::<SocketAddr>()?)
+            .build();
+
+        let server = Serve::new(args)
+            .merge(Router::new().route("/healthz", get(|| async { "ok" })))
+            .run_async()
+            .await?;
+        println!("Listening on http://{}", server.bound_addr());
+
+        tokio::signal::ctrl_c().await?;
+        server.shutdown().await
+    })
+}
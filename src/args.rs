@@ -1,7 +1,17 @@
 use crate::parse;
 use clap::{Args, Subcommand};
-use openai::{arkose::funcaptcha::solver::Solver, proxy};
+use openai::{
+    arkose::funcaptcha::solver::Solver,
+    context::args::{
+        AdminListen, AuthMechanism, BodyTransform, CookieMode, EgressAuthConfig, MirrorConfig,
+        NotFoundResponse, Pricing, PriorityRule, RedactConfig, ResponseHeaderPolicy, RouteLimit,
+        SseFlushMode, StreamAdapt, UpstreamTagRule,
+    },
+    proxy,
+    secret::Secret,
+};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 
 #[cfg(all(feature = "serve", not(feature = "terminal")))]
@@ -43,21 +53,42 @@ pub mod cmd {
 pub enum ServeSubcommand {
     /// Run the HTTP server
     Run(ServeArgs),
+    /// Check the configuration (TLS keypair, etc.) without starting the HTTP server
+    Check(ServeArgs),
     /// Stop the HTTP server daemon
     #[cfg(target_family = "unix")]
-    Stop,
+    Stop {
+        /// Seconds to wait for the daemon to exit after SIGINT, then it is killed (SIGKILL)
+        #[clap(long, default_value = "360")]
+        stop_timeout: u64,
+    },
     /// Start the HTTP server daemon
     #[cfg(target_family = "unix")]
     Start(ServeArgs),
     /// Restart the HTTP server daemon
     #[cfg(target_family = "unix")]
-    Restart(ServeArgs),
+    Restart {
+        #[clap(flatten)]
+        args: ServeArgs,
+        /// Seconds to wait for the daemon to exit after SIGINT, then it is killed (SIGKILL)
+        #[clap(long, default_value = "360")]
+        stop_timeout: u64,
+    },
     /// Status of the Http server daemon process
     #[cfg(target_family = "unix")]
     Status,
     /// Show the Http server daemon log
     #[cfg(target_family = "unix")]
     Log,
+    /// Export or import the runtime state (worker directory)
+    #[clap(subcommand)]
+    State(StateSubcommand),
+    /// TLS certificate tools
+    #[clap(subcommand)]
+    Tls(TlsSubcommand),
+    /// Configuration tools
+    #[clap(subcommand)]
+    Config(ConfigSubcommand),
     /// Generate MITM CA certificate
     Genca,
     /// Show the impersonate user-agent list
@@ -72,24 +103,179 @@ pub enum ServeSubcommand {
     Update,
 }
 
+#[derive(Subcommand)]
+pub enum StateSubcommand {
+    /// Export the runtime state into an encrypted archive
+    Export {
+        /// Archive output file path
+        #[clap(short, long)]
+        out: PathBuf,
+        /// State key, encrypts the archive (plain value or secret reference)
+        #[clap(long, env = "STATE_KEY")]
+        state_key: Secret,
+    },
+    /// Import the runtime state from an archive
+    Import {
+        /// Archive file path
+        #[clap(value_parser = parse::parse_file_path)]
+        file: PathBuf,
+        /// State key the archive was exported with (plain value or secret reference)
+        #[clap(long, env = "STATE_KEY")]
+        state_key: Secret,
+        /// Clear the existing state instead of merging into it
+        #[clap(long)]
+        replace: bool,
+        /// Import even if the server daemon is running
+        #[clap(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigSubcommand {
+    /// Print the effective configuration as shell-sourceable environment variable exports
+    ExportEnv {
+        #[clap(flatten)]
+        args: ServeArgs,
+        /// Export the keys, passwords and tokens instead of redacting them
+        #[clap(long)]
+        include_secrets: bool,
+    },
+    /// Print each resolved setting with its value and the source that provided it
+    Explain {
+        #[clap(flatten)]
+        args: ServeArgs,
+        /// Print as JSON instead of a table
+        #[clap(long)]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum TlsSubcommand {
+    /// Check that the configured TLS certificate and private key match and are valid
+    Check {
+        #[clap(flatten)]
+        args: ServeArgs,
+        /// Warn when the certificate expires within the days
+        #[clap(long, default_value = "30")]
+        warn_days: u64,
+    },
+}
+
 #[derive(Args, Debug, Default, Serialize, Deserialize)]
 pub struct ServeArgs {
     /// Log level (info/debug/warn/trace/error)
     #[clap(short = 'L', long, global = true, env = "LOG", default_value = "info")]
     pub(super) level: String,
 
+    /// Fraction of the requests that are access-logged (0.0 - 1.0), server errors are always logged
+    #[clap(long, default_value = "1.0")]
+    #[serde(default = "default_log_sample_rate")]
+    pub(super) log_sample_rate: f64,
+
     /// Configuration file path (toml format file)
     #[clap(short = 'C', long, env = "CONFIG", value_parser = parse::parse_file_path)]
     pub(super) config: Option<PathBuf>,
 
+    /// Reject configuration fields of the cargo features this binary was built without,
+    /// instead of ignoring them with a warning
+    #[clap(long)]
+    #[serde(default)]
+    pub(super) strict_config: bool,
+
     /// Server bind address
     #[clap(short, long, env = "BIND", default_value = "0.0.0.0:7999", value_parser = parse::parse_socket_addr)]
     pub(super) bind: Option<std::net::SocketAddr>,
 
+    /// Try the next N ports when the bind port is in use
+    #[clap(long, default_value = "0")]
+    #[serde(default)]
+    pub(super) port_retry: u16,
+
+    /// Serve the admin endpoints (/metrics, /admin/*) on a separate listener only,
+    /// host:port or unix:/path (a Unix socket accessible by the owner only)
+    #[clap(long, env = "ADMIN_LISTEN", verbatim_doc_comment)]
+    pub(super) admin_listen: Option<AdminListen>,
+
+    /// Skip the write permission check of the paths written by `serve start`
+    #[clap(long, default_value = "false")]
+    #[serde(default)]
+    pub(super) no_write_check: bool,
+
+    /// User the `serve start` daemon drops its privileges to, the user running sudo
+    /// (SUDO_USER) by default
+    #[clap(long, env = "RUN_AS_USER")]
+    #[serde(default)]
+    pub(super) run_as_user: Option<String>,
+
+    /// Let the `serve start` daemon keep running as root when neither run_as_user nor
+    /// SUDO_USER names a user
+    #[clap(long)]
+    #[serde(default)]
+    pub(super) allow_root: bool,
+
+    /// Server runtime worker threads, 0 uses all CPU cores
+    #[clap(long, env = "WORKERS", default_value = "0")]
+    #[serde(default)]
+    pub(super) workers: usize,
+
+    /// Background job lease store strategy (mem/file), file coordinates the instances sharing leader_dir
+    #[clap(long, default_value = "mem")]
+    #[serde(default = "default_leader_store")]
+    pub(super) leader_store: String,
+
+    /// Background job lease directory of the file store, shared by the instances
+    #[clap(long)]
+    pub(super) leader_dir: Option<PathBuf>,
+
+    /// Background job lease time (seconds), a job is taken over this long after its leader died
+    #[clap(long, default_value = "30")]
+    #[serde(default = "default_leader_lease_ttl")]
+    pub(super) leader_lease_ttl: u64,
+
     /// Server Enforces a limit on the concurrent number of requests the underlying
     #[clap(long, default_value = "1024")]
     pub(super) concurrent_limit: usize,
 
+    /// Request header naming the priority class (high/normal/low) of the concurrent limit queue,
+    /// trusted from priority_trusted_peers only
+    #[clap(long)]
+    pub(super) priority_header: Option<String>,
+
+    /// Peers whose priority header is trusted (CIDR), loopback if unset, repeatable
+    #[clap(long = "priority-trusted-peer", requires = "priority_header")]
+    #[serde(default)]
+    pub(super) priority_trusted_peers: Vec<cidr::IpCidr>,
+
+    /// Time (seconds) a waiting request takes to rise one priority class, 0 disables aging
+    #[clap(long, default_value = "5")]
+    #[serde(default = "default_priority_aging")]
+    pub(super) priority_aging: u64,
+
+    /// Requests waiting for a concurrent limit slot, 0 is unlimited, low priority
+    /// requests are shed from half of it
+    #[clap(long, default_value = "0")]
+    #[serde(default)]
+    pub(super) priority_max_queue: usize,
+
+    /// Rules mapping routes and inbound identities to priority classes, configuration
+    /// file only (`[[priority_rules]]`)
+    #[clap(skip)]
+    #[serde(default)]
+    pub(super) priority_rules: Vec<PriorityRule>,
+
+    /// Server concurrent streaming responses limit, streaming requests over it get 429, 0 is unlimited
+    #[clap(long, default_value = "0")]
+    #[serde(default)]
+    pub(super) max_concurrent_streams_inflight: usize,
+
+    /// Memory budget of the buffered request and response bodies (byte), requests that
+    /// would buffer over it get 503, 0 is unlimited
+    #[clap(long, default_value = "0")]
+    #[serde(default)]
+    pub(super) max_buffered_memory: u64,
+
     /// Server/Client timeout (seconds)
     #[clap(long, default_value = "360")]
     pub(super) timeout: usize,
@@ -98,6 +284,32 @@ pub struct ServeArgs {
     #[clap(long, default_value = "5")]
     pub(super) connect_timeout: usize,
 
+    /// Server header read timeout (seconds), bounds how long a client has to send
+    /// the full request headers after connecting, 0 disables it
+    #[clap(long, default_value = "30")]
+    #[serde(default = "default_header_read_timeout")]
+    pub(super) header_read_timeout: usize,
+
+    /// Server TLS handshake timeout (seconds), 0 disables it
+    #[clap(long, default_value = "5")]
+    #[serde(default = "default_tls_handshake_timeout")]
+    pub(super) tls_handshake_timeout: usize,
+
+    /// Server concurrent TLS handshakes limit, connections over the limit are dropped, 0 is unlimited
+    #[clap(long, default_value = "1024")]
+    #[serde(default = "default_max_concurrent_handshakes")]
+    pub(super) max_concurrent_handshakes: usize,
+
+    /// Server open connections limit, new connections are closed at the limit, 0 is unlimited
+    #[clap(long, default_value = "0")]
+    #[serde(default)]
+    pub(super) max_connections: usize,
+
+    /// Admin listener open connections limit, accepts pause while it is reached, 0 is unlimited
+    #[clap(long, default_value = "0")]
+    #[serde(default)]
+    pub(super) admin_max_connections: usize,
+
     /// Server/Client TCP keepalive (seconds)
     #[clap(long, default_value = "60")]
     pub(super) tcp_keepalive: usize,
@@ -110,6 +322,100 @@ pub struct ServeArgs {
     #[clap(long, default_value = "90")]
     pub(super) pool_idle_timeout: usize,
 
+    /// Negotiate HTTP/2 with the upstream, falls back to HTTP/1.1 when not negotiated
+    #[clap(long, action = clap::ArgAction::Set, default_value = "true")]
+    #[serde(default = "default_upstream_http2")]
+    pub(super) upstream_http2: bool,
+
+    /// Upstream HTTP/2 keepalive ping interval (seconds), 0 disables it
+    #[clap(long, default_value = "0")]
+    #[serde(default)]
+    pub(super) upstream_http2_keepalive_interval: usize,
+
+    /// Upstream HTTP/2 initial stream window size (bytes), defaults to the impersonated client value
+    #[clap(long)]
+    pub(super) upstream_http2_stream_window: Option<u32>,
+
+    /// Upstream HTTP/2 initial connection window size (bytes), defaults to the impersonated client value
+    #[clap(long)]
+    pub(super) upstream_http2_connection_window: Option<u32>,
+
+    /// Upstream HTTP/2 adaptive flow control window, overrides the window sizes
+    #[clap(long)]
+    #[serde(default)]
+    pub(super) upstream_http2_adaptive_window: bool,
+
+    /// Upstream requests multiplexed on an HTTP/2 connection before another connection
+    /// is opened, 0 is unlimited
+    #[clap(long, default_value = "0")]
+    #[serde(default)]
+    pub(super) upstream_http2_max_streams_per_conn: usize,
+
+    /// Upstream keepalive ping interval (seconds), keeps the upstream session warm, 0 disables it
+    #[clap(long, default_value = "0")]
+    #[serde(default)]
+    pub(super) upstream_keepalive_interval: usize,
+
+    /// Upstream access token used by the keepalive ping (plain value or secret reference)
+    #[clap(long, env = "UPSTREAM_KEEPALIVE_TOKEN")]
+    pub(super) upstream_keepalive_token: Option<Secret>,
+
+    /// Upstream retries of a request whose response matched a retry body pattern
+    #[clap(long, default_value = "0")]
+    #[serde(default)]
+    pub(super) upstream_retries: usize,
+
+    /// Replay the response of a retried POST with the same Idempotency-Key header
+    #[clap(long)]
+    #[serde(default)]
+    pub(super) idempotency_enable: bool,
+
+    /// Idempotent responses store strategy (mem/redb)
+    #[clap(long, default_value = "mem", requires = "idempotency_enable")]
+    #[serde(default = "default_idempotency_store")]
+    pub(super) idempotency_store: String,
+
+    /// Idempotent responses replay window (seconds)
+    #[clap(long, default_value = "600", requires = "idempotency_enable")]
+    #[serde(default = "default_idempotency_window")]
+    pub(super) idempotency_window: u64,
+
+    /// Save the in-memory stores (token buckets, budget spend) to the state directory
+    /// on shutdown, and restore them on startup unless older than tb_expired
+    #[clap(long)]
+    #[serde(default)]
+    pub(super) checkpoint_enable: bool,
+
+    /// Checkpoint interval (seconds) of the in-memory stores, 0 saves on shutdown only
+    #[clap(long, default_value = "300", requires = "checkpoint_enable")]
+    #[serde(default = "default_checkpoint_interval")]
+    pub(super) checkpoint_interval: u64,
+
+    /// Privacy mode, never send client identifiers (forwarded addresses, user agent) upstream
+    #[clap(long, env = "PRIVACY_MODE")]
+    #[serde(default)]
+    pub(super) privacy_mode: bool,
+
+    /// Extra request headers stripped from the upstream requests in privacy mode, repeatable
+    #[clap(long = "privacy-strip-header", requires = "privacy_mode")]
+    #[serde(default)]
+    pub(super) privacy_strip_headers: Vec<String>,
+
+    /// Upstream request header carrying the tag of the inbound identity (see upstream_tag_rules)
+    #[clap(long, env = "UPSTREAM_TAG_HEADER")]
+    pub(super) upstream_tag_header: Option<String>,
+
+    /// Rules mapping the inbound identity to the upstream tag, configuration file only
+    /// (`[[upstream_tag_rules]]`)
+    #[clap(skip)]
+    #[serde(default)]
+    pub(super) upstream_tag_rules: Vec<UpstreamTagRule>,
+
+    /// Retry non-streaming responses whose body matches the pattern (regex), even on 2xx, repeatable
+    #[clap(long = "retry-on-body-pattern")]
+    #[serde(default)]
+    pub(super) retry_on_body_patterns: Vec<String>,
+
     /// Client proxy, support multiple proxy, use ',' to separate, Format: proto|type
     /// Proto: all/api/auth/arkose, default: all
     /// Type: interface/proxy/ipv6 subnet，proxy type only support: socks5/http/https
@@ -129,6 +435,12 @@ pub struct ServeArgs {
     #[clap(long, env = "COOKIE_STORE")]
     pub(super) cookie_store: bool,
 
+    /// Upstream cookies of the proxied requests: passthrough relays _puid and cf_clearance,
+    /// strip drops them, jar keeps them per access token and never sends them to the client
+    #[clap(long, default_value = "passthrough")]
+    #[serde(default)]
+    pub(super) cookie_mode: CookieMode,
+
     /// Use fastest DNS resolver
     #[clap(long, env = "FASTEST_DNS")]
     pub(super) fastest_dns: bool,
@@ -141,6 +453,31 @@ pub struct ServeArgs {
     #[clap(long, env = "TLS_KEY", requires = "tls_cert")]
     pub(super) tls_key: Option<PathBuf>,
 
+    /// TLS private key password (encrypted PKCS#8 private key), plain value or secret reference
+    #[clap(
+        long,
+        env = "TLS_KEY_PASSWORD",
+        requires = "tls_key",
+        conflicts_with = "tls_key_password_file"
+    )]
+    pub(super) tls_key_password: Option<Secret>,
+
+    /// TLS private key password file path (encrypted PKCS#8 private key)
+    #[clap(long, env = "TLS_KEY_PASSWORD_FILE", requires = "tls_key", value_parser = parse::parse_file_path)]
+    pub(super) tls_key_password_file: Option<PathBuf>,
+
+    /// ALPN protocol advertised by the TLS listener, repeatable in preference order,
+    /// h2 and http/1.1 by default
+    #[clap(long = "tls-alpn-protocol", requires = "tls_cert")]
+    #[serde(default)]
+    pub(super) tls_alpn_protocols: Vec<String>,
+
+    /// User-Agent pattern of the clients served HTTP/1.1 only, repeatable, `*` and `?`
+    /// wildcards. Applies to their next TLS connections, ALPN precedes the User-Agent
+    #[clap(long = "http2-disable-for", requires = "tls_cert")]
+    #[serde(default)]
+    pub(super) http2_disable_for: Vec<String>,
+
     /// Cloudflare turnstile captcha site key
     #[clap(long, env = "CF_SECRET_KEY", requires = "cf_secret_key")]
     pub(super) cf_site_key: Option<String>,
@@ -149,6 +486,27 @@ pub struct ServeArgs {
     #[clap(long, env = "CF_SITE_KEY", requires = "cf_site_key")]
     pub(super) cf_secret_key: Option<String>,
 
+    /// Concurrent Turnstile siteverify calls, the excess waits up to 5 seconds for a slot,
+    /// 0 is unlimited
+    #[clap(long, default_value = "16")]
+    #[serde(default = "default_cf_verify_concurrency")]
+    pub(super) cf_verify_concurrency: usize,
+
+    /// Auth mechanism of the proxied requests (access_token/sign/turnstile), repeatable in
+    /// evaluation order, the first that validates a request admits it
+    #[clap(long = "auth-chain")]
+    #[serde(default)]
+    pub(super) auth_chain: Vec<AuthMechanism>,
+
+    /// HMAC-SHA256 secret of the signed requests of the `sign` auth mechanism (plain value
+    /// or secret reference)
+    #[clap(long, env = "AUTH_SIGN_SECRET")]
+    pub(super) auth_sign_secret: Option<Secret>,
+
+    /// State key of the `GET /admin/state` backups (plain value or secret reference)
+    #[clap(long, env = "STATE_KEY")]
+    pub(super) state_key: Option<Secret>,
+
     /// Login/Arkose/HAR Authentication Key
     #[clap(short = 'A', long, env = "AUTH_KEY")]
     pub(super) auth_key: Option<String>,
@@ -165,6 +523,110 @@ pub struct ServeArgs {
     #[clap(short = 'G', long, env = "ENABLE_ARKOSE_PROXY")]
     pub(super) enable_arkose_proxy: bool,
 
+    /// Response for unmatched routes (json/html/auto), auto answers HTML when the client accepts it
+    #[clap(long, default_value = "json")]
+    #[serde(default)]
+    pub(super) not_found_response: NotFoundResponse,
+
+    /// Event stream flush mode (immediate/batched), batched coalesces small events
+    #[clap(long, default_value = "immediate")]
+    #[serde(default)]
+    pub(super) sse_flush_mode: SseFlushMode,
+
+    /// Batched event stream flush size (byte)
+    #[clap(long, default_value = "4096")]
+    #[serde(default = "default_sse_batch_size")]
+    pub(super) sse_batch_size: usize,
+
+    /// Batched event stream flush time (millisecond)
+    #[clap(long, default_value = "10")]
+    #[serde(default = "default_sse_batch_time")]
+    pub(super) sse_batch_time: usize,
+
+    /// Largest event stream chunk written to the client (byte), larger flushes are
+    /// split, 0 for no limit
+    #[clap(long, default_value = "0")]
+    #[serde(default)]
+    pub(super) stream_chunk_size: usize,
+
+    /// Upstream streaming mode of chat completions (off/stream/json), the response is
+    /// converted to the client's `stream` mode
+    #[clap(long, default_value = "off")]
+    #[serde(default)]
+    pub(super) stream_adapt: StreamAdapt,
+
+    /// Requested model aliases rewritten to their canonical name, configuration file only
+    /// (`[model_aliases]`)
+    #[clap(skip)]
+    #[serde(default)]
+    pub(super) model_aliases: BTreeMap<String, String>,
+
+    /// Requested model name patterns (`*` and `?` globs), other models are rejected with 400
+    /// before forwarding, repeatable, all models if unset
+    #[clap(long = "model-allow")]
+    #[serde(default)]
+    pub(super) model_allowlist: Vec<String>,
+
+    /// JSON request body transforms (set/default/clamp/remove by JSONPath) applied in
+    /// order before forwarding, configuration file only (`[[body_transforms]]`)
+    #[clap(skip)]
+    #[serde(default)]
+    pub(super) body_transforms: Vec<BodyTransform>,
+
+    /// Largest request body (byte) the body transforms are applied to, larger bodies
+    /// of the routes with rules are rejected (413), except the uploads
+    #[clap(long, default_value = "1048576")]
+    #[serde(default = "default_body_transform_limit")]
+    pub(super) body_transform_limit: usize,
+
+    /// Upstream response header policy, configuration file only (`[response_headers]`)
+    #[clap(skip)]
+    #[serde(default)]
+    pub(super) response_headers: ResponseHeaderPolicy,
+
+    /// Egress request signing, configuration file only (`[egress_auth]`)
+    #[clap(skip)]
+    #[serde(default)]
+    pub(super) egress_auth: Option<EgressAuthConfig>,
+
+    /// Response redaction, configuration file only (`[redact]`)
+    #[clap(skip)]
+    #[serde(default)]
+    pub(super) redact: RedactConfig,
+
+    /// Dry-run traffic mirroring to a secondary upstream, configuration file only (`[mirror]`)
+    #[clap(skip)]
+    #[serde(default)]
+    pub(super) mirror: MirrorConfig,
+
+    /// Per-model token prices (USD per 1M tokens) of the key budgets, configuration file only (`[pricing]`)
+    #[clap(skip)]
+    #[serde(default)]
+    pub(super) pricing: Pricing,
+
+    /// Access schedules file path (toml format file), restricts key groups to time windows, hot-reloaded
+    #[clap(long, env = "ACCESS_SCHEDULES", value_parser = parse::parse_file_path)]
+    pub(super) access_schedules: Option<PathBuf>,
+
+    /// Model policies file path (toml format file), restricts key groups to allowed models, budgets and priority classes, hot-reloaded
+    #[clap(long, env = "MODEL_POLICIES", value_parser = parse::parse_file_path)]
+    pub(super) model_policies: Option<PathBuf>,
+
+    /// Client IP allow list file path (one CIDR per line, `#` comments), only the listed
+    /// addresses are served when it has entries, hot-reloaded
+    #[clap(long, env = "ALLOW_IPS_FILE", value_parser = parse::parse_file_path)]
+    pub(super) allow_ips_file: Option<PathBuf>,
+
+    /// Client IP deny list file path (one CIDR per line, `#` comments), the listed
+    /// addresses are rejected with 403, hot-reloaded
+    #[clap(long, env = "DENY_IPS_FILE", value_parser = parse::parse_file_path)]
+    pub(super) deny_ips_file: Option<PathBuf>,
+
+    /// Key spend store strategy of the budgets (mem/redb)
+    #[clap(long, default_value = "redb")]
+    #[serde(default = "default_budget_store")]
+    pub(super) budget_store: String,
+
     /// Visitor email whitelist
     #[clap(short = 'W', long, env = "VISITOR_EMAIL_WHITELIST", value_parser = parse::parse_email_whitelist)]
     pub(super) visitor_email_whitelist: Option<std::vec::Vec<String>>,
@@ -217,28 +679,66 @@ pub struct ServeArgs {
     /// Enable token bucket flow limitation
     #[clap(short = 'T', long)]
     #[cfg(feature = "limit")]
+    #[serde(default)]
     pub(super) tb_enable: bool,
 
     /// Token bucket store strategy (mem/redb)
     #[clap(long, default_value = "mem", requires = "tb_enable")]
     #[cfg(feature = "limit")]
+    #[serde(default = "default_tb_strategy")]
     pub(super) tb_strategy: String,
 
     /// Token bucket capacity
     #[clap(long, default_value = "60", requires = "tb_enable")]
     #[cfg(feature = "limit")]
+    #[serde(default = "default_tb_capacity")]
     pub(super) tb_capacity: u32,
 
     /// Token bucket fill rate
     #[clap(long, default_value = "1", requires = "tb_enable")]
     #[cfg(feature = "limit")]
+    #[serde(default = "default_tb_fill_rate")]
     pub(super) tb_fill_rate: u32,
 
     /// Token bucket expired (seconds)
     #[clap(long, default_value = "86400", requires = "tb_enable")]
     #[cfg(feature = "limit")]
+    #[serde(default = "default_tb_expired")]
     pub(super) tb_expired: u32,
 
+    /// Concurrent token bucket operations on the redb store, the excess queue up to 2
+    /// seconds then are answered 503. 0 is unbounded
+    #[clap(long, default_value = "32", requires = "tb_enable")]
+    #[cfg(feature = "limit")]
+    #[serde(default = "default_tb_redb_max_concurrency")]
+    pub(super) tb_redb_max_concurrency: usize,
+
+    /// Token bucket capacity and fill rate by request path pattern, overriding the
+    /// tb_* values with their own buckets, configuration file only (`[route_limits]`)
+    #[clap(skip)]
+    #[cfg(feature = "limit")]
+    #[serde(default)]
+    pub(super) route_limits: BTreeMap<String, RouteLimit>,
+
+    /// Expose the token bucket key in the X-RateLimit-Key response header, debug only,
+    /// it leaks the client address to the client and any intermediary
+    #[clap(long, requires = "tb_enable")]
+    #[cfg(feature = "limit")]
+    #[serde(default)]
+    pub(super) tb_debug_key: bool,
+
+    /// Expose the Server-Timing response header (queue, upstream and proxy time), it
+    /// discloses the server internals to the clients
+    #[clap(long)]
+    #[serde(default)]
+    pub(super) expose_server_timing: bool,
+
+    /// Webhook URL receiving a JSON event when the server is ready, reloads a file
+    /// and shuts down, e.g. https://ops.example.com/hooks/ninja
+    #[clap(long, env = "LIFECYCLE_WEBHOOK", value_parser = parse::parse_webhook_url)]
+    #[serde(default)]
+    pub(super) lifecycle_webhook: Option<String>,
+
     /// Preauth MITM server bind address
     #[clap(
     short = 'B',
@@ -268,3 +768,92 @@ pub struct ServeArgs {
     #[clap(long, default_value = "ca/key.pem", requires = "pbind")]
     pub(super) pkey: PathBuf,
 }
+
+fn default_budget_store() -> String {
+    "redb".to_owned()
+}
+
+fn default_log_sample_rate() -> f64 {
+    1.0
+}
+
+fn default_upstream_http2() -> bool {
+    true
+}
+
+fn default_header_read_timeout() -> usize {
+    30
+}
+
+fn default_tls_handshake_timeout() -> usize {
+    5
+}
+
+fn default_max_concurrent_handshakes() -> usize {
+    1024
+}
+
+fn default_sse_batch_size() -> usize {
+    4096
+}
+
+fn default_sse_batch_time() -> usize {
+    10
+}
+
+fn default_body_transform_limit() -> usize {
+    1048576
+}
+
+fn default_priority_aging() -> u64 {
+    5
+}
+
+fn default_cf_verify_concurrency() -> usize {
+    16
+}
+
+fn default_leader_store() -> String {
+    "mem".to_owned()
+}
+
+fn default_leader_lease_ttl() -> u64 {
+    30
+}
+
+fn default_idempotency_store() -> String {
+    "mem".to_owned()
+}
+
+fn default_idempotency_window() -> u64 {
+    600
+}
+
+fn default_checkpoint_interval() -> u64 {
+    300
+}
+
+#[cfg(feature = "limit")]
+fn default_tb_strategy() -> String {
+    "mem".to_owned()
+}
+
+#[cfg(feature = "limit")]
+fn default_tb_capacity() -> u32 {
+    60
+}
+
+#[cfg(feature = "limit")]
+fn default_tb_fill_rate() -> u32 {
+    1
+}
+
+#[cfg(feature = "limit")]
+fn default_tb_expired() -> u32 {
+    86400
+}
+
+#[cfg(feature = "limit")]
+fn default_tb_redb_max_concurrency() -> usize {
+    32
+}
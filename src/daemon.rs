@@ -2,15 +2,52 @@
 use crate::utils;
 use crate::{
     args::{self, ServeArgs},
+    env, explain, features, parse,
     utils::unix::fix_relative_path,
 };
 use clap::CommandFactory;
-use openai::{arkose::funcaptcha::solver::ArkoseSolver, context::args::Args, proxy, serve::Serve};
+use openai::{
+    arkose::funcaptcha::solver::ArkoseSolver,
+    context::args::{Args, AuthMechanism},
+    proxy,
+    secret::Secret,
+    serve::{tls, Serve},
+};
 use reqwest::impersonate::Impersonate;
 use std::{net::IpAddr, ops::Not, path::PathBuf, str::FromStr};
+#[cfg(target_family = "unix")]
+use std::{os::unix::net::UnixStream, sync::Mutex};
 use url::Url;
 
-pub(super) fn serve(mut args: ServeArgs, relative_path: bool) -> anyhow::Result<()> {
+/// Load the serve args, the configuration file takes precedence
+fn load_args(args: ServeArgs, relative_path: bool) -> anyhow::Result<ServeArgs> {
+    let mut args = read_args(args, relative_path)?;
+    args.upstream_keepalive_token =
+        resolve_secret("upstream_keepalive_token", args.upstream_keepalive_token)?;
+    args.tls_key_password = resolve_secret("tls_key_password", args.tls_key_password)?;
+    args.auth_sign_secret = resolve_secret("auth_sign_secret", args.auth_sign_secret)?;
+    args.state_key = resolve_secret("state_key", args.state_key)?;
+    if let Some(egress_auth) = args.egress_auth.as_mut() {
+        egress_auth.secret = egress_auth
+            .secret
+            .clone()
+            .resolve()
+            .map_err(|err| anyhow::anyhow!("Failed to resolve egress_auth secret: {err}"))?;
+    }
+
+    Ok(args)
+}
+
+/// Read the serve args from the configuration file, the secret references are not resolved
+fn read_args(args: ServeArgs, relative_path: bool) -> anyhow::Result<ServeArgs> {
+    read_config(args, relative_path).map(|(args, _)| args)
+}
+
+/// Read the serve args along with the configuration file table they were read from
+fn read_config(
+    mut args: ServeArgs,
+    relative_path: bool,
+) -> anyhow::Result<(ServeArgs, Option<toml::Table>)> {
     if relative_path {
         fix_relative_path(&mut args);
     }
@@ -18,9 +55,202 @@ pub(super) fn serve(mut args: ServeArgs, relative_path: bool) -> anyhow::Result<
     if let Some(config_path) = args.config {
         let bytes = std::fs::read(config_path)?;
         let data = String::from_utf8(bytes)?;
-        args = toml::from_str::<ServeArgs>(&data)?;
+        let table = toml::from_str::<toml::Table>(&data)?;
+
+        // Fields of the features not compiled in
+        let strict = args.strict_config
+            || table
+                .get("strict_config")
+                .and_then(toml::Value::as_bool)
+                .unwrap_or_default();
+        for warning in features::check_disabled_fields(&table, strict)? {
+            eprintln!("Warning: {warning}");
+        }
+
+        args = table.clone().try_into::<ServeArgs>()?;
+        return Ok((args, Some(table)));
+    }
+
+    Ok((args, None))
+}
+
+/// Resolve a `vault://` or `awssm://` secret reference, plain values are kept as is
+fn resolve_secret(name: &str, secret: Option<Secret>) -> anyhow::Result<Option<Secret>> {
+    secret
+        .map(Secret::resolve)
+        .transpose()
+        .map_err(|err| anyhow::anyhow!("Failed to resolve {name}: {err}"))
+}
+
+/// Resolve the TLS private key password from the args or the password file
+fn load_tls_key_password(args: &ServeArgs) -> anyhow::Result<Option<Secret>> {
+    if let Some(path) = args.tls_key_password_file.as_ref() {
+        let password = std::fs::read_to_string(path).map_err(|err| {
+            anyhow::anyhow!(
+                "Failed to read TLS key password file {}: {err}",
+                path.display()
+            )
+        })?;
+        return Ok(Some(Secret::new(
+            password.trim_end_matches(&['\r', '\n'][..]),
+        )));
+    }
+    Ok(args.tls_key_password.clone())
+}
+
+pub(super) fn serve_check(args: ServeArgs, relative_path: bool) -> anyhow::Result<()> {
+    let args = load_args(args, relative_path)?;
+
+    if let (Some(cert), Some(key)) = (args.tls_cert.as_ref(), args.tls_key.as_ref()) {
+        let password = load_tls_key_password(&args)?;
+        tls::check_keypair(cert, key, password.as_ref())?;
+        println!("TLS keypair: ok");
+    }
+    let alpn_protocols = tls::alpn_protocols(&args.tls_alpn_protocols)?;
+    if !args.http2_disable_for.is_empty()
+        && !alpn_protocols
+            .iter()
+            .any(|protocol| protocol.as_slice() == b"http/1.1")
+    {
+        anyhow::bail!("http2_disable_for requires `http/1.1` in tls_alpn_protocols")
+    }
+
+    if let Some(url) = args.lifecycle_webhook.as_deref() {
+        parse::parse_webhook_url(url)?;
+    }
+    args.response_headers.validate()?;
+    args.mirror.validate()?;
+    args.redact.validate()?;
+    for rule in &args.upstream_tag_rules {
+        rule.validate()?;
+    }
+    for rule in &args.priority_rules {
+        rule.validate()?;
+    }
+    for rule in &args.body_transforms {
+        rule.validate()?;
+    }
+    AuthMechanism::validate_chain(
+        &args.auth_chain,
+        args.auth_sign_secret.is_some(),
+        args.cf_site_key.is_some() && args.cf_secret_key.is_some(),
+    )?;
+    #[cfg(feature = "limit")]
+    for (route, limit) in &args.route_limits {
+        limit.validate(route)?;
+    }
+    if let Some(egress_auth) = args.egress_auth.as_ref() {
+        egress_auth.validate()?;
+    }
+    args.pricing.validate()?;
+    if let Some(admin_listen) = args.admin_listen.as_ref() {
+        admin_listen.validate(args.auth_key.is_some())?;
+    }
+
+    println!("Configuration: ok");
+    Ok(())
+}
+
+pub(super) fn config(command: args::ConfigSubcommand, relative_path: bool) -> anyhow::Result<()> {
+    match command {
+        args::ConfigSubcommand::ExportEnv {
+            args,
+            include_secrets,
+        } => {
+            // Secret references are exported as they are, resolved when the server starts
+            let args = read_args(args, relative_path)?;
+            print!("{}", env::export(&args, include_secrets)?);
+        }
+        args::ConfigSubcommand::Explain { args, json } => {
+            // The derived args drop the value sources, parse the command line again for them
+            let matches = args::cmd::Opt::command().get_matches();
+            let (args, table) = read_config(args, relative_path)?;
+            let settings = explain::explain(&args, table.as_ref(), explain::leaf(&matches))?;
+            match json {
+                true => println!("{}", serde_json::to_string_pretty(&settings)?),
+                false => print!("{}", explain::table(&settings)),
+            }
+        }
+    }
+    Ok(())
+}
+
+pub(super) fn tls(command: args::TlsSubcommand, relative_path: bool) -> anyhow::Result<()> {
+    match command {
+        args::TlsSubcommand::Check { args, warn_days } => {
+            let args = load_args(args, relative_path)?;
+            let (cert, key) = match (args.tls_cert.as_ref(), args.tls_key.as_ref()) {
+                (Some(cert), Some(key)) => (cert, key),
+                _ => anyhow::bail!("tls_cert and tls_key are not configured"),
+            };
+
+            let password = load_tls_key_password(&args)?;
+            let check = tls::check_keypair(cert, key, password.as_ref())?;
+            let info = &check.certificate;
+
+            println!("Subject: {}", info.subject);
+            println!("Issuer: {}", info.issuer);
+            println!(
+                "Subject alternative names: {}",
+                info.subject_alt_names.join(", ")
+            );
+            println!("Not before: {}", info.not_before);
+            println!("Not after: {}", info.not_after);
+            println!(
+                "Chain: {} certificate(s), {}",
+                check.chain_len,
+                if check.chain_complete {
+                    "complete"
+                } else {
+                    "incomplete"
+                }
+            );
+
+            for warning in tls_warnings(&check, cert, warn_days)? {
+                eprintln!("Warning: {warning}");
+            }
+            println!("TLS keypair: ok ({} days left)", info.expires_in / 86400);
+        }
+    }
+    Ok(())
+}
+
+/// Warnings of a matching keypair, an error when the certificate is not valid now
+fn tls_warnings(
+    check: &tls::KeypairCheck,
+    cert: &std::path::Path,
+    warn_days: u32,
+) -> anyhow::Result<Vec<String>> {
+    let info = &check.certificate;
+    if info.valid_in > 0 {
+        anyhow::bail!("TLS certificate is not valid before {}", info.not_before)
+    }
+    if info.expires_in <= 0 {
+        anyhow::bail!("TLS certificate expired on {}", info.not_after)
     }
 
+    let mut warnings = Vec::new();
+    let days_left = info.expires_in / 86400;
+    if days_left < warn_days as i64 {
+        warnings.push(format!("TLS certificate expires in {days_left} days"));
+    }
+    if !check.chain_complete {
+        warnings.push(format!(
+            "TLS certificate chain is incomplete, the intermediate certificates are missing from {}",
+            cert.display()
+        ));
+    }
+    Ok(warnings)
+}
+
+pub(super) fn serve(args: ServeArgs, relative_path: bool) -> anyhow::Result<()> {
+    build_serve(args, relative_path)?.run()
+}
+
+fn build_serve(args: ServeArgs, relative_path: bool) -> anyhow::Result<Serve> {
+    let args = load_args(args, relative_path)?;
+    let tls_key_password = load_tls_key_password(&args)?;
+
     let arkose_solver = match args.arkose_solver_key.as_ref() {
         Some(client_key) => Some(ArkoseSolver::new(
             args.arkose_solver,
@@ -53,22 +283,68 @@ pub(super) fn serve(mut args: ServeArgs, relative_path: bool) -> anyhow::Result<
 
     let builder = Args::builder()
         .bind(args.bind)
+        .log_sample_rate(args.log_sample_rate)
+        .port_retry(args.port_retry)
+        .admin_listen(args.admin_listen)
         .fastest_dns(args.fastest_dns)
         .proxies(args.proxies.unwrap_or_default())
         .enable_direct(args.enable_direct)
         .cookie_store(args.cookie_store)
+        .cookie_mode(args.cookie_mode)
         .tcp_keepalive(args.tcp_keepalive)
         .no_keepalive(args.no_keepalive)
         .pool_idle_timeout(args.pool_idle_timeout)
+        .upstream_http2(args.upstream_http2)
+        .upstream_http2_keepalive_interval(args.upstream_http2_keepalive_interval)
+        .upstream_http2_stream_window(args.upstream_http2_stream_window)
+        .upstream_http2_connection_window(args.upstream_http2_connection_window)
+        .upstream_http2_adaptive_window(args.upstream_http2_adaptive_window)
+        .upstream_http2_max_streams_per_conn(args.upstream_http2_max_streams_per_conn)
+        .upstream_keepalive_interval(args.upstream_keepalive_interval)
+        .upstream_keepalive_token(args.upstream_keepalive_token)
+        .upstream_retries(args.upstream_retries)
+        .idempotency_enable(args.idempotency_enable)
+        .idempotency_store(args.idempotency_store)
+        .idempotency_window(args.idempotency_window)
+        .checkpoint_enable(args.checkpoint_enable)
+        .checkpoint_interval(args.checkpoint_interval)
+        .privacy_mode(args.privacy_mode)
+        .privacy_strip_headers(args.privacy_strip_headers)
+        .upstream_tag_header(args.upstream_tag_header)
+        .upstream_tag_rules(args.upstream_tag_rules)
+        .retry_on_body_patterns(args.retry_on_body_patterns)
         .timeout(args.timeout)
         .connect_timeout(args.connect_timeout)
+        .header_read_timeout(args.header_read_timeout)
+        .tls_handshake_timeout(args.tls_handshake_timeout)
+        .max_concurrent_handshakes(args.max_concurrent_handshakes)
+        .tls_alpn_protocols(args.tls_alpn_protocols)
+        .http2_disable_for(args.http2_disable_for)
+        .max_connections(args.max_connections)
+        .admin_max_connections(args.admin_max_connections)
+        .workers(args.workers)
+        .leader_store(args.leader_store)
+        .leader_dir(args.leader_dir)
+        .leader_lease_ttl(args.leader_lease_ttl)
         .concurrent_limit(args.concurrent_limit)
+        .max_concurrent_streams_inflight(args.max_concurrent_streams_inflight)
+        .max_buffered_memory(args.max_buffered_memory)
+        .priority_header(args.priority_header)
+        .priority_trusted_peers(args.priority_trusted_peers)
+        .priority_aging(args.priority_aging)
+        .priority_max_queue(args.priority_max_queue)
+        .priority_rules(args.priority_rules)
         .tls_cert(args.tls_cert)
         .tls_key(args.tls_key)
+        .tls_key_password(tls_key_password)
         .auth_key(args.auth_key)
         .visitor_email_whitelist(args.visitor_email_whitelist)
         .cf_site_key(args.cf_site_key)
         .cf_secret_key(args.cf_secret_key)
+        .cf_verify_concurrency(args.cf_verify_concurrency)
+        .auth_chain(args.auth_chain)
+        .auth_sign_secret(args.auth_sign_secret)
+        .state_key(args.state_key)
         .enable_webui(args.enable_webui)
         .arkose_endpoint(args.arkose_endpoint)
         .arkose_gpt3_experiment(args.arkose_gpt3_experiment)
@@ -77,7 +353,29 @@ pub(super) fn serve(mut args: ServeArgs, relative_path: bool) -> anyhow::Result<
         .arkose_solver_tguess_endpoint(args.arkose_solver_tguess_endpoint)
         .arkose_solver_image_dir(args.arkose_solver_image_dir)
         .enable_file_proxy(args.enable_file_proxy)
+        .response_headers(args.response_headers)
+        .mirror(args.mirror)
+        .redact(args.redact)
+        .egress_auth(args.egress_auth)
+        .not_found_response(args.not_found_response)
+        .sse_flush_mode(args.sse_flush_mode)
+        .sse_batch_size(args.sse_batch_size)
+        .sse_batch_time(args.sse_batch_time)
+        .stream_chunk_size(args.stream_chunk_size)
+        .stream_adapt(args.stream_adapt)
+        .body_transforms(args.body_transforms)
+        .body_transform_limit(args.body_transform_limit)
+        .model_aliases(args.model_aliases)
+        .model_allowlist(args.model_allowlist)
+        .access_schedules(args.access_schedules)
+        .model_policies(args.model_policies)
+        .allow_ips_file(args.allow_ips_file)
+        .deny_ips_file(args.deny_ips_file)
+        .pricing(args.pricing)
+        .budget_store(args.budget_store)
         .enable_arkose_proxy(args.enable_arkose_proxy)
+        .expose_server_timing(args.expose_server_timing)
+        .lifecycle_webhook(args.lifecycle_webhook)
         .pbind(args.pbind)
         .pupstream(args.pupstream)
         .pcert(args.pcert)
@@ -89,7 +387,10 @@ pub(super) fn serve(mut args: ServeArgs, relative_path: bool) -> anyhow::Result<
         .tb_strategy(args.tb_strategy)
         .tb_capacity(args.tb_capacity)
         .tb_fill_rate(args.tb_fill_rate)
-        .tb_expired(args.tb_expired);
+        .tb_expired(args.tb_expired)
+        .tb_redb_max_concurrency(args.tb_redb_max_concurrency)
+        .route_limits(args.route_limits)
+        .tb_debug_key(args.tb_debug_key);
 
     // Parse the impersonate user agents
     if let Some(impersonate_list) = args.impersonate_uas {
@@ -111,19 +412,56 @@ pub(super) fn serve(mut args: ServeArgs, relative_path: bool) -> anyhow::Result<
         }
 
         let args = builder.impersonate_uas(impersonate_uas).build();
-        Serve::new(args).run()
+        Ok(Serve::new(args))
     } else {
-        Serve::new(builder.build()).run()
+        Ok(Serve::new(builder.build()))
+    }
+}
+
+/// User the daemon drops its privileges to: `run_as_user`, else the user running sudo
+/// (`SUDO_USER`). `None` keeps root, which requires `allow_root`.
+#[cfg(target_family = "unix")]
+fn daemon_user(
+    args: &ServeArgs,
+    sudo_user: Option<&str>,
+) -> anyhow::Result<Option<nix::unistd::User>> {
+    use nix::unistd::User;
+
+    if let Some(name) = args.run_as_user.as_deref() {
+        return match User::from_name(name) {
+            Ok(Some(user)) => Ok(Some(user)),
+            Ok(None) => anyhow::bail!("run_as_user `{name}` does not exist"),
+            Err(err) => anyhow::bail!("Failed to look up run_as_user `{name}`: {err}"),
+        };
+    }
+
+    let reason = match sudo_user {
+        Some(name) => match User::from_name(name) {
+            Ok(Some(user)) if user.uid.is_root() => format!("SUDO_USER `{name}` is root"),
+            Ok(Some(user)) => return Ok(Some(user)),
+            Ok(None) => format!("SUDO_USER `{name}` does not exist"),
+            Err(err) => format!("Failed to look up SUDO_USER `{name}`: {err}"),
+        },
+        None => "SUDO_USER is not set".to_owned(),
+    };
+    if !args.allow_root {
+        anyhow::bail!(
+            "{reason} and run_as_user is not set, refusing to run the daemon as root. \
+             Set run_as_user (--run-as-user) or pass --allow-root to keep root"
+        )
     }
+    eprintln!("Warning: {reason}, the daemon keeps running as root (allow_root)");
+    Ok(None)
 }
 
 #[cfg(target_family = "unix")]
 pub(super) fn serve_start(mut args: ServeArgs) -> anyhow::Result<()> {
     use crate::utils::unix::{check_root, get_pid};
-    use daemonize::Daemonize;
+    use daemonize::{Daemonize, Outcome};
     use std::{
         fs::{File, Permissions},
         os::unix::prelude::PermissionsExt,
+        sync::Arc,
     };
 
     check_root();
@@ -133,6 +471,14 @@ pub(super) fn serve_start(mut args: ServeArgs) -> anyhow::Result<()> {
         return Ok(());
     }
 
+    fix_relative_path(&mut args);
+
+    // The configuration file is read before detaching, so its errors and warnings are
+    // printed and the daemon user is resolved from it
+    let args = read_args(args, false)?;
+    let user = daemon_user(&args, std::env::var("SUDO_USER").ok().as_deref())?;
+    utils::preflight::check(&args, user.as_ref())?;
+
     let pid_file = File::create(utils::unix::PID_PATH)?;
     pid_file.set_permissions(Permissions::from_mode(0o755))?;
 
@@ -142,6 +488,12 @@ pub(super) fn serve_start(mut args: ServeArgs) -> anyhow::Result<()> {
     let stderr = File::create(utils::unix::DEFAULT_STDERR_PATH)?;
     stdout.set_permissions(Permissions::from_mode(0o755))?;
 
+    // Supplementary groups of the user, set before the privileges are dropped
+    let groups = user
+        .as_ref()
+        .map(|user| std::ffi::CString::new(user.name.as_str()).map(|name| (name, user.gid)))
+        .transpose()?;
+
     let mut daemonize = Daemonize::new()
         .pid_file(utils::unix::PID_PATH) // Every method except `new` and `start`
         .chown_pid_file(true) // is optional, see `Daemonize` documentation
@@ -149,52 +501,216 @@ pub(super) fn serve_start(mut args: ServeArgs) -> anyhow::Result<()> {
         .umask(0o777) // Set umask, `0o027` by default.
         .stdout(stdout) // Redirect stdout to `/tmp/daemon.out`.
         .stderr(stderr) // Redirect stderr to `/tmp/daemon.err`.
-        .privileged_action(|| "Executed before drop privileges");
+        .privileged_action(move || initgroups(groups.as_ref()));
 
-    if let Ok(user) = std::env::var("SUDO_USER") {
-        if let Ok(Some(real_user)) = nix::unistd::User::from_name(&user) {
-            daemonize = daemonize
-                .user(real_user.name.as_str())
-                .group(real_user.gid.as_raw());
-        }
+    if let Some(real_user) = user {
+        daemonize = daemonize
+            .user(real_user.name.as_str())
+            .group(real_user.gid.as_raw());
     }
 
-    fix_relative_path(&mut args);
+    // Readiness channel, the daemon reports the startup result back to the parent
+    let (mut ready_rx, ready_tx) = UnixStream::pair()?;
 
-    if let Some(err) = daemonize.start().err() {
-        eprintln!("Error: {err}")
+    match daemonize.execute() {
+        Outcome::Parent(Ok(_)) => {
+            drop(ready_tx);
+            match wait_ready(&mut ready_rx)? {
+                Ok(addr) => {
+                    println!("Ninja is running at {addr}");
+                    Ok(())
+                }
+                Err(err) => {
+                    eprintln!("Error: {err}");
+                    std::process::exit(1)
+                }
+            }
+        }
+        Outcome::Parent(Err(err)) => anyhow::bail!("Failed to start daemon: {err}"),
+        Outcome::Child(Ok(_)) => {
+            drop(ready_rx);
+            let ready_tx = Arc::new(Mutex::new(Some(ready_tx)));
+            let result = build_serve(args, false).and_then(|serve| {
+                let ready_tx = ready_tx.clone();
+                serve
+                    .on_ready(move |addr| notify_ready(&ready_tx, &format!("ok {addr}")))
+                    .run()
+            });
+            if let Err(err) = result.as_ref() {
+                notify_ready(&ready_tx, &format!("err {err}"));
+            }
+            result
+        }
+        Outcome::Child(Err(err)) => {
+            notify_ready(&Mutex::new(Some(ready_tx)), &format!("err {err}"));
+            anyhow::bail!("Failed to start daemon: {err}")
+        }
+    }
+}
+
+/// Set the supplementary groups of the daemon user, the write permission check counts them
+#[cfg(all(target_family = "unix", not(target_vendor = "apple")))]
+fn initgroups(user: Option<&(std::ffi::CString, nix::unistd::Gid)>) {
+    if let Some((name, gid)) = user {
+        if let Err(err) = nix::unistd::initgroups(name, *gid) {
+            eprintln!("Warning: failed to set the supplementary groups of {name:?}: {err}");
+        }
     }
+}
+
+#[cfg(target_vendor = "apple")]
+fn initgroups(_: Option<&(std::ffi::CString, nix::unistd::Gid)>) {}
 
-    serve(args, false)
+/// Wait for the startup result of the daemon, the bound address or the startup error.
+/// A daemon that exits without reporting is an error.
+#[cfg(target_family = "unix")]
+fn wait_ready(ready_rx: &mut impl std::io::Read) -> anyhow::Result<Result<String, String>> {
+    let mut status = String::new();
+    ready_rx.read_to_string(&mut status)?;
+    Ok(match status.split_once(' ') {
+        Some(("ok", addr)) => Ok(addr.to_owned()),
+        Some(("err", err)) => Err(err.to_owned()),
+        _ => Err("Ninja exited before it was ready, see the daemon log".to_owned()),
+    })
 }
 
+/// Send the startup result to the `start` parent process, only the first one is sent
 #[cfg(target_family = "unix")]
-pub(super) fn serve_stop() -> anyhow::Result<()> {
+fn notify_ready(ready_tx: &Mutex<Option<UnixStream>>, status: &str) {
+    use std::io::Write;
+    if let Some(mut stream) = ready_tx.lock().ok().and_then(|mut tx| tx.take()) {
+        let _ = stream.write_all(status.as_bytes());
+    }
+}
+
+#[cfg(target_family = "unix")]
+pub(super) fn serve_stop(stop_timeout: u64) -> anyhow::Result<()> {
     use crate::utils::unix::{check_root, get_pid};
-    use nix::sys::signal;
+    use nix::errno::Errno;
+    use nix::sys::signal::{self, Signal};
     use nix::unistd::Pid;
+    use std::time::Duration;
 
     check_root();
 
-    if let Some(pid) = get_pid() {
-        let pid = pid.parse::<i32>()?;
-        for _ in 0..360 {
-            if signal::kill(Pid::from_raw(pid), signal::SIGINT).is_err() {
-                break;
-            }
-            std::thread::sleep(std::time::Duration::from_secs(1))
+    let pid = match get_pid() {
+        Some(pid) => Pid::from_raw(pid.parse::<i32>()?),
+        None => {
+            println!("Ninja is not running");
+            return Ok(());
+        }
+    };
+
+    // The PID may have been reused by another process since the PID file was written
+    let exe = std::env::current_exe()?;
+    if signal::kill(pid, None) != Err(Errno::ESRCH) {
+        verify_exe(pid, &exe)?;
+    }
+
+    match signal::kill(pid, Signal::SIGINT) {
+        Ok(()) => {
+            println!("Sent SIGINT to pid {pid}, waiting up to {stop_timeout}s for it to exit")
+        }
+        Err(Errno::ESRCH) => {
+            println!("Ninja is not running with pid {pid}, removing the stale PID file");
+            return remove_pid_file();
         }
-        let _ = std::fs::remove_file(utils::unix::PID_PATH);
+        Err(err) => anyhow::bail!("Failed to send SIGINT to pid {pid}: {err}"),
+    }
+
+    if wait_exit(pid, Duration::from_secs(stop_timeout)) {
+        println!("Ninja stopped");
+    } else {
+        println!("Ninja did not exit within {stop_timeout}s, sending SIGKILL to pid {pid}");
+        kill(pid, &exe)?;
+        println!("Ninja killed");
+    }
+
+    remove_pid_file()
+}
+
+/// Send SIGKILL to `pid` once it is verified to run `exe`, and wait for it to exit
+#[cfg(target_family = "unix")]
+fn kill(pid: nix::unistd::Pid, exe: &std::path::Path) -> anyhow::Result<()> {
+    use nix::errno::Errno;
+    use nix::sys::signal::{self, Signal};
+    use std::time::Duration;
+
+    if signal::kill(pid, None) == Err(Errno::ESRCH) {
+        return Ok(());
+    }
+    verify_exe(pid, exe)?;
+    match signal::kill(pid, Signal::SIGKILL) {
+        Ok(()) | Err(Errno::ESRCH) => {}
+        Err(err) => anyhow::bail!("Failed to send SIGKILL to pid {pid}: {err}"),
+    }
+    if !wait_exit(pid, Duration::from_secs(5)) {
+        anyhow::bail!(
+            "Ninja is still running with pid {pid} after SIGKILL, keeping the PID file {}",
+            utils::unix::PID_PATH
+        )
     }
+    Ok(())
+}
+
+/// Check that `pid` runs the executable `exe`, read from `/proc/<pid>/exe`. An executable
+/// replaced on disk, e.g. by an upgrade, still matches its path.
+#[cfg(target_os = "linux")]
+fn verify_exe(pid: nix::unistd::Pid, exe: &std::path::Path) -> anyhow::Result<()> {
+    let running = std::fs::read_link(format!("/proc/{pid}/exe"))
+        .map_err(|err| anyhow::anyhow!("Failed to read the executable of pid {pid}: {err}"))?;
+    let running = running.to_string_lossy();
+    let running = running.strip_suffix(" (deleted)").unwrap_or(&running);
+    if std::path::Path::new(running) != exe {
+        anyhow::bail!(
+            "Pid {pid} runs {running}, not {}, the PID file {} is stale, remove it if Ninja is not running",
+            exe.display(),
+            utils::unix::PID_PATH
+        )
+    }
+    Ok(())
+}
 
+/// Without `/proc` the executable of a process is not checked
+#[cfg(all(target_family = "unix", not(target_os = "linux")))]
+fn verify_exe(_pid: nix::unistd::Pid, _exe: &std::path::Path) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Wait up to `timeout` for a process to exit, probed with the null signal
+#[cfg(target_family = "unix")]
+fn wait_exit(pid: nix::unistd::Pid, timeout: std::time::Duration) -> bool {
+    use nix::errno::Errno;
+    use std::time::{Duration, Instant};
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        if nix::sys::signal::kill(pid, None) == Err(Errno::ESRCH) {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(200))
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn remove_pid_file() -> anyhow::Result<()> {
+    match std::fs::remove_file(utils::unix::PID_PATH) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(anyhow::anyhow!(
+            "Failed to remove the PID file {}: {err}",
+            utils::unix::PID_PATH
+        )),
+        _ => Ok(()),
+    }
+}
+
 #[cfg(target_family = "unix")]
-pub(super) fn serve_restart(args: ServeArgs) -> anyhow::Result<()> {
+pub(super) fn serve_restart(args: ServeArgs, stop_timeout: u64) -> anyhow::Result<()> {
     use crate::utils::unix::check_root;
     check_root();
-    serve_stop()?;
+    serve_stop(stop_timeout)?;
     serve_start(args)
 }
 
@@ -208,6 +724,62 @@ pub(super) fn serve_status() -> anyhow::Result<()> {
     Ok(())
 }
 
+pub(super) fn state(command: args::StateSubcommand) -> anyhow::Result<()> {
+    use openai::state::{self, ImportMode};
+
+    let state_dir = openai::homedir::home_dir()
+        .ok_or_else(|| anyhow::anyhow!("Failed to get home directory"))?
+        .join(openai::context::WORKER_DIR);
+
+    match command {
+        args::StateSubcommand::Export { out, state_key } => {
+            let state_key = state_key
+                .resolve()
+                .map_err(|err| anyhow::anyhow!("Failed to resolve state_key: {err}"))?;
+            let file = std::fs::File::create(&out)?;
+            let manifest = state::export(&state_dir, &state_key, std::io::BufWriter::new(file))?;
+            println!(
+                "Exported {} state files (format version {}) to {}",
+                manifest.files.len(),
+                manifest.format_version,
+                out.display()
+            );
+        }
+        args::StateSubcommand::Import {
+            file,
+            state_key,
+            replace,
+            force,
+        } => {
+            #[cfg(target_family = "unix")]
+            let running = crate::utils::unix::get_pid();
+            #[cfg(not(target_family = "unix"))]
+            let running = None::<String>;
+            if let Some(pid) = running.filter(|_| !force) {
+                anyhow::bail!("Ninja is running with pid {pid}, stop it first or use --force")
+            }
+
+            let state_key = state_key
+                .resolve()
+                .map_err(|err| anyhow::anyhow!("Failed to resolve state_key: {err}"))?;
+            let mode = if replace {
+                ImportMode::Replace
+            } else {
+                ImportMode::Merge
+            };
+            let archive = std::io::BufReader::new(std::fs::File::open(&file)?);
+            let manifest = state::import(archive, &state_dir, &state_key, mode)?;
+            println!(
+                "Imported {} state files exported by ninja {} into {}",
+                manifest.files.len(),
+                manifest.ninja_version,
+                state_dir.display()
+            );
+        }
+    }
+    Ok(())
+}
+
 #[cfg(target_family = "unix")]
 pub(super) fn serve_log() -> anyhow::Result<()> {
     use std::{
@@ -277,16 +849,33 @@ pub(super) fn generate_template(out: Option<PathBuf>) -> anyhow::Result<()> {
         concurrent_limit: 65535,
         timeout: 600,
         connect_timeout: 60,
+        header_read_timeout: 30,
+        tls_handshake_timeout: 5,
+        max_concurrent_handshakes: 1024,
+        sse_batch_size: 4096,
+        sse_batch_time: 10,
+        body_transform_limit: 1048576,
         tcp_keepalive: 60,
+        budget_store: "redb".to_string(),
+        idempotency_store: "mem".to_string(),
+        leader_store: "mem".to_string(),
+        leader_lease_ttl: 30,
+        priority_aging: 5,
+        cf_verify_concurrency: 16,
+        idempotency_window: 600,
+        checkpoint_interval: 300,
         tb_strategy: "mem".to_string(),
         tb_enable: false,
         tb_capacity: 60,
         tb_fill_rate: 1,
         tb_expired: 86400,
+        tb_redb_max_concurrency: 32,
         cookie_store: true,
         pool_idle_timeout: 90,
+        upstream_http2: true,
         arkose_solver_limit: 3,
         level: "info".to_owned(),
+        log_sample_rate: 1.0,
         pcert: PathBuf::from("ca/cert.crt"),
         pkey: PathBuf::from("ca/key.pem"),
         arkose_gpt3_experiment: false,
@@ -319,3 +908,162 @@ pub(super) fn generate_template(out: Option<PathBuf>) -> anyhow::Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keypair_check(valid_in: i64, expires_in: i64, chain_complete: bool) -> tls::KeypairCheck {
+        tls::KeypairCheck {
+            certificate: tls::CertificateInfo {
+                subject: "CN=localhost".to_owned(),
+                issuer: "CN=Test CA".to_owned(),
+                subject_alt_names: vec!["localhost".to_owned()],
+                not_before: "2026-01-01 00:00:00 UTC".to_owned(),
+                not_after: "2027-01-01 00:00:00 UTC".to_owned(),
+                expires_in,
+                valid_in,
+            },
+            chain_len: if chain_complete { 2 } else { 1 },
+            chain_complete,
+        }
+    }
+
+    #[test]
+    fn test_tls_warnings() {
+        const DAY: i64 = 86400;
+        let warnings = |valid_in, expires_in, chain_complete| {
+            let check = keypair_check(valid_in, expires_in, chain_complete);
+            tls_warnings(&check, std::path::Path::new("cert.pem"), 30)
+        };
+        assert!(warnings(-DAY, 90 * DAY, true).unwrap().is_empty());
+
+        // Expired or not yet valid, `tls check` exits non-zero
+        let err = warnings(-DAY, 0, true).unwrap_err().to_string();
+        assert!(err.contains("expired on 2027-01-01"), "{err}");
+        let err = warnings(DAY, 90 * DAY, true).unwrap_err().to_string();
+        assert!(err.contains("not valid before"), "{err}");
+
+        // Near expiry and incomplete chain only warn
+        let warnings = warnings(-DAY, 10 * DAY, false).unwrap();
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(warnings[0], "TLS certificate expires in 10 days");
+        assert!(warnings[1].contains(
+            "chain is incomplete, the intermediate certificates are missing from cert.pem"
+        ));
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_daemon_user() {
+        let user = |run_as_user: Option<&str>, sudo_user, allow_root| {
+            let args = ServeArgs {
+                run_as_user: run_as_user.map(str::to_owned),
+                allow_root,
+                ..Default::default()
+            };
+            daemon_user(&args, sudo_user).map(|user| user.map(|user| user.name))
+        };
+
+        // run_as_user wins over SUDO_USER, and never falls back
+        assert_eq!(
+            user(Some("root"), Some("nobody"), false)
+                .unwrap()
+                .as_deref(),
+            Some("root")
+        );
+        let err = user(Some("ninja-missing-user"), Some("root"), true).unwrap_err();
+        assert!(err.to_string().contains("does not exist"), "{err}");
+
+        assert_eq!(
+            user(None, Some("nobody"), false).unwrap().as_deref(),
+            Some("nobody")
+        );
+        // Root only with allow_root, SUDO_USER=root included
+        for sudo_user in [None, Some("root"), Some("ninja-missing-user")] {
+            let err = user(None, sudo_user, false).unwrap_err();
+            assert!(err.to_string().contains("refusing to run"), "{err}");
+            assert_eq!(user(None, sudo_user, true).unwrap(), None);
+        }
+        let err = user(None, Some("root"), false).unwrap_err();
+        assert!(
+            err.to_string().starts_with("SUDO_USER `root` is root"),
+            "{err}"
+        );
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_wait_exit() {
+        use nix::sys::signal::{self, Signal};
+        use std::time::Duration;
+
+        let mut child = std::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+        let pid = nix::unistd::Pid::from_raw(child.id() as i32);
+        assert!(!wait_exit(pid, Duration::from_millis(300)));
+
+        signal::kill(pid, Signal::SIGKILL).unwrap();
+        child.wait().unwrap();
+        assert!(wait_exit(pid, Duration::from_millis(300)));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_kill_verifies_exe() {
+        let child = std::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+        let pid = nix::unistd::Pid::from_raw(child.id() as i32);
+        let sleep = std::fs::read_link(format!("/proc/{pid}/exe")).unwrap();
+        // Reap the child once killed, a zombie still answers the null signal
+        let reaper = std::thread::spawn(move || {
+            let mut child = child;
+            child.wait().unwrap()
+        });
+
+        // A reused PID running another executable is not killed
+        let err = kill(pid, &std::env::current_exe().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("is stale"), "{err}");
+        assert!(!wait_exit(pid, std::time::Duration::ZERO));
+
+        kill(pid, &sleep).unwrap();
+        assert!(!reaper.join().unwrap().success());
+        // A process already gone is not an error
+        kill(pid, &sleep).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_wait_ready() {
+        // Bound, the parent exits 0
+        let (mut rx, tx) = UnixStream::pair().unwrap();
+        let tx = Mutex::new(Some(tx));
+        notify_ready(&tx, "ok 0.0.0.0:7999");
+        // Only the first result is sent
+        notify_ready(&tx, "err too late");
+        assert_eq!(wait_ready(&mut rx).unwrap(), Ok("0.0.0.0:7999".to_owned()));
+
+        // Startup error, the parent exits 1 with it
+        let (mut rx, tx) = UnixStream::pair().unwrap();
+        notify_ready(
+            &Mutex::new(Some(tx)),
+            "err Address 0.0.0.0:7999 already in use",
+        );
+        assert_eq!(
+            wait_ready(&mut rx).unwrap(),
+            Err("Address 0.0.0.0:7999 already in use".to_owned())
+        );
+
+        // The daemon died without reporting, the parent exits 1
+        let (mut rx, tx) = UnixStream::pair().unwrap();
+        drop(tx);
+        assert!(wait_ready(&mut rx)
+            .unwrap()
+            .unwrap_err()
+            .contains("exited before it was ready"));
+    }
+}
@@ -0,0 +1,167 @@
+//! Configuration fields of the optional cargo features.
+//!
+//! A configuration file is shared across binaries built with different features, so the
+//! fields of a feature that is not compiled in are still recognized and type checked,
+//! then ignored with a warning (or rejected with `--strict-config`).
+
+/// Configuration field that only exists when its cargo feature is enabled
+pub(super) struct FeatureGatedField {
+    pub(super) name: &'static str,
+    pub(super) feature: &'static str,
+    pub(super) enabled: bool,
+    validate: fn(&toml::Value) -> Result<(), toml::de::Error>,
+}
+
+/// Registers the `#[cfg(feature = ...)]` fields of `ServeArgs` with their types,
+/// keep it in sync when adding a feature-gated field.
+macro_rules! feature_gated_fields {
+    ($($feature:tt => { $($field:ident: $ty:ty),* $(,)? }),* $(,)?) => {
+        pub(super) const FEATURE_GATED_FIELDS: &[FeatureGatedField] = &[
+            $($(FeatureGatedField {
+                name: stringify!($field),
+                feature: $feature,
+                enabled: cfg!(feature = $feature),
+                validate: |value| value.clone().try_into::<$ty>().map(drop),
+            },)*)*
+        ];
+    };
+}
+
+feature_gated_fields! {
+    "limit" => {
+        tb_enable: bool,
+        tb_strategy: String,
+        tb_capacity: u32,
+        tb_fill_rate: u32,
+        tb_expired: u32,
+        tb_redb_max_concurrency: usize,
+        route_limits: std::collections::BTreeMap<String, openai::context::args::RouteLimit>,
+        tb_debug_key: bool,
+    },
+}
+
+/// Check the configuration fields of the features that are not compiled in.
+/// Returns the warnings of the ignored fields, or an error when `strict`.
+pub(super) fn check_disabled_fields(
+    table: &toml::Table,
+    strict: bool,
+) -> anyhow::Result<Vec<String>> {
+    let mut warnings = Vec::new();
+    for field in FEATURE_GATED_FIELDS.iter().filter(|field| !field.enabled) {
+        let Some(value) = table.get(field.name) else {
+            continue;
+        };
+
+        (field.validate)(value)
+            .map_err(|err| anyhow::anyhow!("Invalid `{}`: {}", field.name, err.message()))?;
+
+        let message = format!(
+            "`{}` requires the `{}` feature, this binary was built without it",
+            field.name, field.feature
+        );
+        if strict {
+            anyhow::bail!("{message}")
+        }
+        warnings.push(format!("{message}, ignored"));
+    }
+    Ok(warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::ServeArgs;
+
+    const CONFIG: &str = r#"
+        bind = "0.0.0.0:7999"
+        tb_enable = false
+        tb_strategy = "mem"
+        tb_capacity = 60
+    "#;
+
+    /// `(field, feature)` of the `#[cfg(feature = ...)]` fields of `ServeArgs`, read from
+    /// its source
+    fn gated_serve_args_fields() -> Vec<(String, String)> {
+        let source = include_str!("args.rs");
+        let start = source.find("pub struct ServeArgs {").unwrap();
+        let end = start + source[start..].find("\n}\n").unwrap();
+        let mut fields = Vec::new();
+        let mut feature = None;
+        for line in source[start..end].lines().map(str::trim) {
+            if let Some(name) = line
+                .strip_prefix("#[cfg(feature = \"")
+                .and_then(|rest| rest.strip_suffix("\")]"))
+            {
+                feature = Some(name.to_owned());
+            } else if let Some(field) = line.strip_prefix("pub(super) ") {
+                if let Some(feature) = feature.take() {
+                    let name = field.split(':').next().unwrap().trim().to_owned();
+                    fields.push((name, feature));
+                }
+            }
+        }
+        fields
+    }
+
+    #[test]
+    fn test_registry_matches_serve_args() {
+        // Every registered field of a compiled feature is a field of `ServeArgs`
+        let table = toml::Table::try_from(ServeArgs::default()).unwrap();
+        for field in FEATURE_GATED_FIELDS.iter().filter(|field| field.enabled) {
+            assert!(table.contains_key(field.name), "{}", field.name);
+        }
+
+        // Every feature-gated field of `ServeArgs` is registered with its feature
+        let gated = gated_serve_args_fields();
+        assert!(!gated.is_empty());
+        for (name, feature) in &gated {
+            assert!(
+                FEATURE_GATED_FIELDS
+                    .iter()
+                    .any(|field| field.name == name && field.feature == feature),
+                "`{name}` ({feature}) is not registered"
+            );
+        }
+        assert_eq!(gated.len(), FEATURE_GATED_FIELDS.len());
+    }
+
+    #[cfg(not(feature = "limit"))]
+    #[test]
+    fn test_disabled_fields_ignored() {
+        let table = CONFIG.parse::<toml::Table>().unwrap();
+        let warnings = check_disabled_fields(&table, false).unwrap();
+        assert_eq!(warnings.len(), 3);
+        assert!(warnings[0].contains("`tb_enable` requires the `limit` feature"));
+    }
+
+    #[cfg(not(feature = "limit"))]
+    #[test]
+    fn test_disabled_fields_strict() {
+        let table = CONFIG.parse::<toml::Table>().unwrap();
+        let err = check_disabled_fields(&table, true).unwrap_err().to_string();
+        assert!(err.contains("requires the `limit` feature"), "{err}");
+    }
+
+    #[cfg(not(feature = "limit"))]
+    #[test]
+    fn test_disabled_fields_type_checked() {
+        let table = "tb_capacity = \"sixty\"".parse::<toml::Table>().unwrap();
+        let err = check_disabled_fields(&table, false)
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("Invalid `tb_capacity`"), "{err}");
+    }
+
+    #[cfg(feature = "limit")]
+    #[test]
+    fn test_enabled_fields_loaded() {
+        let table = CONFIG.parse::<toml::Table>().unwrap();
+        assert!(check_disabled_fields(&table, true).unwrap().is_empty());
+
+        let mut table = toml::Table::try_from(ServeArgs::default()).unwrap();
+        table.extend(CONFIG.parse::<toml::Table>().unwrap());
+        let args = table.try_into::<ServeArgs>().unwrap();
+        assert_eq!(args.tb_strategy, "mem");
+        assert_eq!(args.tb_capacity, 60);
+    }
+}
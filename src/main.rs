@@ -9,6 +9,9 @@ pub mod store;
 
 mod args;
 mod daemon;
+mod env;
+mod explain;
+mod features;
 mod parse;
 mod update;
 mod utils;
@@ -20,16 +23,22 @@ fn main() -> anyhow::Result<()> {
     if let Some(command) = opt.command {
         match command {
             args::ServeSubcommand::Run(args) => daemon::serve(args, false)?,
+            args::ServeSubcommand::Check(args) => daemon::serve_check(args, false)?,
             #[cfg(target_family = "unix")]
-            args::ServeSubcommand::Stop => daemon::serve_stop()?,
+            args::ServeSubcommand::Stop { stop_timeout } => daemon::serve_stop(stop_timeout)?,
             #[cfg(target_family = "unix")]
             args::ServeSubcommand::Start(args) => daemon::serve_start(args)?,
             #[cfg(target_family = "unix")]
-            args::ServeSubcommand::Restart(args) => daemon::serve_restart(args)?,
+            args::ServeSubcommand::Restart { args, stop_timeout } => {
+                daemon::serve_restart(args, stop_timeout)?
+            }
             #[cfg(target_family = "unix")]
             args::ServeSubcommand::Status => daemon::serve_status()?,
             #[cfg(target_family = "unix")]
             args::ServeSubcommand::Log => daemon::serve_log()?,
+            args::ServeSubcommand::State(command) => daemon::state(command)?,
+            args::ServeSubcommand::Tls(command) => daemon::tls(command, false)?,
+            args::ServeSubcommand::Config(command) => daemon::config(command, false)?,
             args::ServeSubcommand::Genca => {
                 let _ = mitm::cagen::gen_ca();
             }
@@ -45,16 +54,22 @@ fn main() -> anyhow::Result<()> {
         match command {
             SubCommands::Serve(commands) => match commands {
                 args::ServeSubcommand::Run(args) => daemon::serve(args, true)?,
+                args::ServeSubcommand::Check(args) => daemon::serve_check(args, true)?,
                 #[cfg(target_family = "unix")]
-                args::ServeSubcommand::Stop => daemon::serve_stop()?,
+                args::ServeSubcommand::Stop { stop_timeout } => daemon::serve_stop(stop_timeout)?,
                 #[cfg(target_family = "unix")]
                 args::ServeSubcommand::Start(args) => daemon::serve_start(args)?,
                 #[cfg(target_family = "unix")]
-                args::ServeSubcommand::Restart(args) => daemon::serve_restart(args)?,
+                args::ServeSubcommand::Restart { args, stop_timeout } => {
+                    daemon::serve_restart(args, stop_timeout)?
+                }
                 #[cfg(target_family = "unix")]
                 args::ServeSubcommand::Status => daemon::serve_status()?,
                 #[cfg(target_family = "unix")]
                 args::ServeSubcommand::Log => daemon::serve_log()?,
+                args::ServeSubcommand::State(command) => daemon::state(command)?,
+                args::ServeSubcommand::Tls(command) => daemon::tls(command, true)?,
+                args::ServeSubcommand::Config(command) => daemon::config(command, true)?,
                 args::ServeSubcommand::Genca => {
                     let _ = openai::serve::preauth::cagen::gen_ca();
                 }
@@ -23,6 +23,15 @@ pub fn parse_url(s: &str) -> anyhow::Result<String> {
     }
 }
 
+// webhook url parse, http or https only
+pub fn parse_webhook_url(s: &str) -> anyhow::Result<String> {
+    let url = url::Url::parse(s).context("The webhook Url format must be `http(s)://host/path`")?;
+    match url.scheme() {
+        "http" | "https" => Ok(s.to_string()),
+        protocol => anyhow::bail!("Unsupported webhook protocol: {}", protocol),
+    }
+}
+
 // proxy proto, format: proto|type, support proto: all/api/auth/arkose, support type: ip/url/cidr
 pub fn parse_proxies_url(s: &str) -> anyhow::Result<Vec<proxy::Proxy>> {
     let split = s.split(',');
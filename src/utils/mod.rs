@@ -1 +1,3 @@
+#[cfg(target_family = "unix")]
+pub(crate) mod preflight;
 pub(crate) mod unix;
@@ -0,0 +1,314 @@
+//! Write permission check of `serve start`, made before the daemon detaches so that an
+//! unwritable path fails in the foreground instead of in the daemon log.
+
+use crate::args::ServeArgs;
+use crate::utils::unix::{DEFAULT_STDERR_PATH, DEFAULT_STDOUT_PATH, DEFAULT_WORK_DIR, PID_PATH};
+use nix::errno::Errno;
+use nix::unistd::{access, AccessFlags, Gid, User};
+use openai::context::{args::AdminListen, WORKER_DIR};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// How a path is used
+#[derive(Clone, Copy)]
+enum Usage {
+    /// File created or truncated
+    File,
+    /// Directory created when missing, files are created in it
+    Dir,
+    /// Working directory, entered but not written
+    WorkDir,
+}
+
+/// Identity a path is written by
+#[derive(Clone)]
+struct Identity {
+    uid: u32,
+    /// Primary and supplementary groups
+    groups: Vec<u32>,
+}
+
+impl Identity {
+    /// Identity of this process
+    fn current() -> Self {
+        let gid = nix::unistd::getegid();
+        let mut groups = nix::unistd::getgroups().unwrap_or_default();
+        groups.push(gid);
+        Self {
+            uid: nix::unistd::geteuid().as_raw(),
+            groups: groups.into_iter().map(Gid::as_raw).collect(),
+        }
+    }
+
+    /// Identity of the daemon once it runs as `user`, with the groups of its group
+    /// database entries
+    fn of(user: &User) -> Self {
+        Self {
+            uid: user.uid.as_raw(),
+            groups: user_groups(user).into_iter().map(Gid::as_raw).collect(),
+        }
+    }
+}
+
+/// Primary and supplementary groups of a user
+#[cfg(not(target_vendor = "apple"))]
+fn user_groups(user: &User) -> Vec<Gid> {
+    std::ffi::CString::new(user.name.as_str())
+        .ok()
+        .and_then(|name| nix::unistd::getgrouplist(&name, user.gid).ok())
+        .unwrap_or_else(|| vec![user.gid])
+}
+
+#[cfg(target_vendor = "apple")]
+fn user_groups(user: &User) -> Vec<Gid> {
+    vec![user.gid]
+}
+
+/// Check that every path written by the daemon can be created and written.
+/// The pid file and the logs are created before the privileges are dropped to `user`,
+/// the state directories after. `args` are read from the configuration file.
+pub(crate) fn check(args: &ServeArgs, user: Option<&User>) -> anyhow::Result<()> {
+    if args.no_write_check {
+        return Ok(());
+    }
+
+    let privileged = Identity::current();
+    let daemon = user.map_or_else(|| privileged.clone(), Identity::of);
+
+    let state_dir = openai::homedir::home_dir()
+        .unwrap_or_default()
+        .join(WORKER_DIR);
+    let mut paths = vec![
+        (
+            "pid file",
+            PathBuf::from(PID_PATH),
+            Usage::File,
+            &privileged,
+        ),
+        (
+            "stdout log",
+            PathBuf::from(DEFAULT_STDOUT_PATH),
+            Usage::File,
+            &privileged,
+        ),
+        (
+            "stderr log",
+            PathBuf::from(DEFAULT_STDERR_PATH),
+            Usage::File,
+            &privileged,
+        ),
+        (
+            "working directory",
+            PathBuf::from(DEFAULT_WORK_DIR),
+            Usage::WorkDir,
+            &privileged,
+        ),
+        ("state directory", state_dir.clone(), Usage::Dir, &daemon),
+    ];
+    if args.leader_store == "file" {
+        let dir = args
+            .leader_dir
+            .clone()
+            .unwrap_or_else(|| state_dir.join("leases"));
+        paths.push(("leader directory", dir, Usage::Dir, &daemon));
+    }
+    if let Some(AdminListen::Unix(path)) = args.admin_listen.as_ref() {
+        // The socket is bound under a temporary name next to it, then renamed
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            paths.push((
+                "admin socket directory",
+                dir.to_owned(),
+                Usage::Dir,
+                &daemon,
+            ));
+        }
+    }
+
+    let errors = paths
+        .iter()
+        .filter_map(|(name, path, usage, identity)| {
+            check_path(path, *usage, identity)
+                .err()
+                .map(|reason| format!("  {name} {}: {reason}", path.display()))
+        })
+        .collect::<Vec<_>>();
+    if !errors.is_empty() {
+        anyhow::bail!(
+            "Write permission check failed:\n{}\nFix the permissions, or skip the check with --no-write-check",
+            errors.join("\n")
+        )
+    }
+    Ok(())
+}
+
+/// Why the path cannot be used by `identity`
+fn check_path(path: &Path, usage: Usage, identity: &Identity) -> Result<(), String> {
+    // The path itself, or the nearest existing directory it would be created in
+    let existing = path
+        .ancestors()
+        .find(|path| path.exists())
+        .unwrap_or(Path::new("."));
+    let metadata = existing.metadata().map_err(|err| err.to_string())?;
+
+    for dir in existing.ancestors().skip(1) {
+        let searchable = dir
+            .metadata()
+            .map_or(false, |metadata| permits(&metadata, identity, 0o1));
+        if !searchable {
+            return Err(format!("{} is not searchable", dir.display()));
+        }
+    }
+
+    if existing != path {
+        if let Usage::WorkDir = usage {
+            return Err("does not exist".to_owned());
+        }
+        if !metadata.is_dir() {
+            return Err(format!("{} is not a directory", existing.display()));
+        }
+        if !permits(&metadata, identity, 0o3) {
+            return Err(format!(
+                "cannot be created, {} is not writable",
+                existing.display()
+            ));
+        }
+        return writable(existing);
+    }
+
+    match usage {
+        Usage::File if metadata.is_dir() => Err("is a directory".to_owned()),
+        Usage::File if !permits(&metadata, identity, 0o2) => Err("is not writable".to_owned()),
+        Usage::Dir | Usage::WorkDir if !metadata.is_dir() => Err("is not a directory".to_owned()),
+        Usage::Dir if !permits(&metadata, identity, 0o3) => Err("is not writable".to_owned()),
+        Usage::WorkDir if !permits(&metadata, identity, 0o1) => Err("is not searchable".to_owned()),
+        Usage::File | Usage::Dir => writable(path),
+        Usage::WorkDir => Ok(()),
+    }
+}
+
+/// Whether the mode bits grant `bits` (`0o1` search, `0o2` write) to `identity`
+fn permits(metadata: &std::fs::Metadata, identity: &Identity, bits: u32) -> bool {
+    if identity.uid == 0 {
+        return true;
+    }
+    let shift = if metadata.uid() == identity.uid {
+        6
+    } else if identity.groups.contains(&metadata.gid()) {
+        3
+    } else {
+        0
+    };
+    (metadata.mode() >> shift) & bits == bits
+}
+
+/// Mode bits do not cover read-only mounts
+fn writable(path: &Path) -> Result<(), String> {
+    match access(path, AccessFlags::W_OK) {
+        Err(Errno::EROFS) => Err("read-only file system".to_owned()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn chmod(path: &Path, mode: u32) {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    /// Identity that is not the owner of `metadata`, with the given groups
+    fn other(metadata: &std::fs::Metadata, groups: Vec<u32>) -> Identity {
+        Identity {
+            uid: metadata.uid().wrapping_add(1).max(1),
+            groups,
+        }
+    }
+
+    #[test]
+    fn test_permits_groups() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        chmod(&file, 0o660);
+        let metadata = file.metadata().unwrap();
+        let gid = metadata.gid();
+
+        let owner = Identity {
+            uid: metadata.uid(),
+            groups: vec![],
+        };
+        assert!(permits(&metadata, &owner, 0o2));
+        // Write granted by the primary or a supplementary group
+        assert!(permits(&metadata, &other(&metadata, vec![gid]), 0o2));
+        assert!(permits(
+            &metadata,
+            &other(&metadata, vec![gid.wrapping_add(1), gid]),
+            0o2
+        ));
+        assert!(!permits(
+            &metadata,
+            &other(&metadata, vec![gid.wrapping_add(1)]),
+            0o2
+        ));
+        // Root bypasses the mode bits
+        chmod(&file, 0o000);
+        let metadata = file.metadata().unwrap();
+        let root = Identity {
+            uid: 0,
+            groups: vec![],
+        };
+        assert!(permits(&metadata, &root, 0o2));
+        assert!(!permits(&metadata, &other(&metadata, vec![gid]), 0o2));
+    }
+
+    #[test]
+    fn test_check_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let current = Identity::current();
+
+        // Created in a writable directory
+        let missing = dir.path().join("state").join("leases");
+        assert_eq!(check_path(&missing, Usage::Dir, &current), Ok(()));
+        assert_eq!(
+            check_path(&dir.path().join("ninja.pid"), Usage::File, &current),
+            Ok(())
+        );
+        assert_eq!(
+            check_path(&missing, Usage::WorkDir, &current).unwrap_err(),
+            "does not exist"
+        );
+
+        // Under a file, or a directory used as a file
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        assert!(check_path(&file.join("pid"), Usage::File, &current)
+            .unwrap_err()
+            .ends_with("is not a directory"));
+        assert_eq!(
+            check_path(dir.path(), Usage::File, &current).unwrap_err(),
+            "is a directory"
+        );
+
+        // A directory writable only by its group, for a member and a non member
+        let shared = dir.path().join("shared");
+        std::fs::create_dir(&shared).unwrap();
+        chmod(&shared, 0o070);
+        let metadata = shared.metadata().unwrap();
+        chmod(dir.path(), 0o711);
+        let member = other(
+            &metadata,
+            vec![metadata.gid().wrapping_add(1), metadata.gid()],
+        );
+        assert_eq!(
+            check_path(&shared.join("state"), Usage::Dir, &member),
+            Ok(())
+        );
+        let outsider = other(&metadata, vec![metadata.gid().wrapping_add(1)]);
+        assert!(check_path(&shared.join("state"), Usage::Dir, &outsider)
+            .unwrap_err()
+            .starts_with("cannot be created"));
+        chmod(&shared, 0o700);
+    }
+}